@@ -10,7 +10,12 @@ use netidx::{
     resolver_client::{DesiredAuth, GlobSet},
 };
 use serde_derive::{Deserialize, Serialize};
-use std::{collections::HashMap, path::{PathBuf, Path as FilePath}, time::Duration};
+use std::{
+    cmp::max,
+    collections::HashMap,
+    path::{Path as FilePath, PathBuf},
+    time::Duration,
+};
 
 pub mod file {
     use arcstr::literal;
@@ -114,6 +119,9 @@ pub mod file {
         pub flush_frequency: Option<usize>,
         /// override the flush_interval for this shard
         pub flush_interval: Option<Duration>,
+        /// override the flush_policy for this shard
+        #[serde(default)]
+        pub flush_policy: Option<FlushPolicy>,
         /// override the rotate_interval for this shard
         pub rotate_interval: Option<RotateDirective>,
         /// how much channel slack between subscriber and the recorder
@@ -132,6 +140,7 @@ pub mod file {
                 image_frequency: None,
                 flush_frequency: None,
                 flush_interval: None,
+                flush_policy: None,
                 rotate_interval: None,
                 slack: default_slack(),
             }
@@ -155,6 +164,8 @@ pub mod file {
         pub flush_frequency: Option<usize>,
         #[serde(default = "default_flush_interval")]
         pub flush_interval: Option<Duration>,
+        #[serde(default)]
+        pub flush_policy: Option<FlushPolicy>,
         #[serde(default = "default_rotate_interval")]
         pub rotate_interval: RotateDirective,
         pub shards: HashMap<ArcStr, RecordShardConfig>,
@@ -167,6 +178,7 @@ pub mod file {
                 image_frequency: default_image_frequency(),
                 flush_frequency: default_flush_frequency(),
                 flush_interval: default_flush_interval(),
+                flush_policy: None,
                 rotate_interval: default_rotate_interval(),
                 shards: HashMap::from([("0".into(), RecordShardConfig::example())]),
             }
@@ -236,6 +248,54 @@ pub enum RotateDirective {
     Never,
 }
 
+/// When the recorder flushes the archive to disk. Only flushed
+/// records are marked committed, when an archive is reopened after a
+/// crash anything written after the last flush is discarded, even if
+/// it made it to disk. Whether the process or the whole machine
+/// crashed, the records that can be lost are,
+///
+/// - `EveryRecord`: at most the record being written. Each batch is
+///   flushed before the next one is written, at the cost of an fsync
+///   per batch.
+/// - `Records(n)`: at most `n` records, the `n - 1` written since the
+///   last flush and the one being written.
+/// - `Interval(d)`: every record written in the last `d`, plus
+///   whatever was written while the flush was in progress.
+/// - `Os`: every record written since the archive was opened or last
+///   rotated. The OS will write the data back whenever it likes, but
+///   it is only committed on rotation and clean shutdown.
+///
+/// Images are records too, and count toward `n`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub enum FlushPolicy {
+    EveryRecord,
+    Records(usize),
+    Interval(Duration),
+    Os,
+}
+
+impl FlushPolicy {
+    /// The number of records after which the archive should be
+    /// flushed, if the policy is record based
+    pub(crate) fn records(&self) -> Option<usize> {
+        match self {
+            FlushPolicy::EveryRecord => Some(1),
+            FlushPolicy::Records(n) => Some(max(*n, 1)),
+            FlushPolicy::Interval(_) | FlushPolicy::Os => None,
+        }
+    }
+
+    /// The interval at which the archive should be flushed, if the
+    /// policy is time based
+    pub(crate) fn interval(&self) -> Option<Duration> {
+        match self {
+            FlushPolicy::Interval(d) => Some(*d),
+            FlushPolicy::EveryRecord | FlushPolicy::Records(_) | FlushPolicy::Os => None,
+        }
+    }
+}
+
 /// Configuration of the publish part of the recorder
 #[derive(Debug, Clone, Builder)]
 pub struct PublishConfig {
@@ -312,6 +372,12 @@ pub struct RecordConfig {
     /// flush only on shutdown. Ignored if spec is empty.
     #[builder(default = "file::default_flush_interval()")]
     pub(crate) flush_interval: Option<Duration>,
+    /// flush the file according to the specified policy, see
+    /// `FlushPolicy` for how much can be lost in a crash under each
+    /// one. If set `flush_frequency` and `flush_interval` are
+    /// ignored. Ignored if spec is empty.
+    #[builder(default)]
+    pub(crate) flush_policy: Option<FlushPolicy>,
     /// rotate the log file at the specified interval or file size or
     /// never. Ignored if spec is empty.
     #[builder(default = "file::default_rotate_interval()")]
@@ -337,6 +403,7 @@ impl RecordConfig {
                 image_frequency,
                 flush_frequency,
                 flush_interval,
+                flush_policy,
                 rotate_interval,
                 slack,
             } = c;
@@ -349,6 +416,7 @@ impl RecordConfig {
                 image_frequency: image_frequency.or(f.image_frequency),
                 flush_frequency: flush_frequency.or(f.flush_frequency),
                 flush_interval: flush_interval.or(f.flush_interval),
+                flush_policy: flush_policy.or(f.flush_policy),
                 rotate_interval: rotate_interval.unwrap_or(f.rotate_interval),
                 slack,
             };
@@ -15,13 +15,15 @@ use anyhow::{Context, Error, Result};
 use arcstr::ArcStr;
 use bytes::{Buf, BufMut};
 use chrono::prelude::*;
+use enumflags2::BitFlags;
 use indexmap::IndexMap;
 use log::warn;
 use memmap2::Mmap;
 use netidx::{
     pack::{decode_varint, encode_varint, varint_len, Pack, PackError},
     path::Path,
-    subscriber::{Event, FromValue, Value},
+    resolver_client::GlobSet,
+    subscriber::{Event, FromValue, Typ, Value},
 };
 use netidx_derive::Pack;
 use nohash::IntMap;
@@ -50,7 +52,8 @@ pub struct FileHeader {
 
 static FILE_MAGIC: &'static [u8] = b"netidx archive";
 static COMMITTED_OFFSET: usize = FILE_MAGIC.len() + mem::size_of::<u32>();
-const FILE_VERSION: u32 = 0;
+// version 1 added the value types of each path to the path mappings
+const FILE_VERSION: u32 = 1;
 
 impl Pack for FileHeader {
     fn const_encoded_len() -> Option<usize> {
@@ -188,6 +191,31 @@ impl Pack for PathMapping {
     }
 }
 
+// The set of value types seen so far for a path. In version 1 files
+// these follow the path mappings in a path mappings record, and a new
+// one is written whenever a path gets a value of a type it hasn't had
+// before, so the schema can be read without decoding any batches.
+#[derive(Debug, Clone)]
+struct TypMapping(Id, BitFlags<Typ>);
+
+impl Pack for TypMapping {
+    fn encoded_len(&self) -> usize {
+        <Id as Pack>::encoded_len(&self.0) + varint_len(self.1.bits())
+    }
+
+    fn encode(&self, buf: &mut impl BufMut) -> Result<(), PackError> {
+        <Id as Pack>::encode(&self.0, buf)?;
+        Ok(encode_varint(self.1.bits(), buf))
+    }
+
+    fn decode(buf: &mut impl Buf) -> Result<Self, PackError> {
+        let id = <Id as Pack>::decode(buf)?;
+        // ignore types added by a later version
+        let typs = BitFlags::from_bits_truncate(decode_varint(buf)?);
+        Ok(TypMapping(id, typs))
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, PartialOrd)]
 pub struct BatchItem(pub Id, pub Event);
 
@@ -311,8 +339,43 @@ impl FromValue for Seek {
     }
 }
 
+/// Per path retention rules, applied by
+/// [ArchiveReader::compact](ArchiveReader::compact).
+///
+/// Each rule is a set of globs and how long to keep the matching
+/// paths. The first rule that matches a path decides its retention,
+/// paths that match no rule are kept forever. Rotation never
+/// rewrites archive files, it only starts new ones, so a file will
+/// usually hold both paths that should be kept and paths that have
+/// expired. Expired records are only dropped when the file is
+/// rewritten by compaction, and a file is only safe to delete once
+/// every path in it has expired.
+#[derive(Debug, Clone, Default)]
+pub struct Retention(Vec<(GlobSet, chrono::Duration)>);
+
+impl Retention {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep paths matching `globs` for `keep`, unless they are
+    /// matched by an earlier rule.
+    pub fn add(&mut self, globs: GlobSet, keep: chrono::Duration) -> &mut Self {
+        self.0.push((globs, keep));
+        self
+    }
+
+    /// Return the time before which records for `path` should be
+    /// dropped, or None if they should be kept forever.
+    pub fn cutoff(&self, now: DateTime<Utc>, path: &Path) -> Option<DateTime<Utc>> {
+        self.0.iter().find(|(globs, _)| globs.is_match(path)).map(|(_, keep)| now - *keep)
+    }
+}
+
 static PM_POOL: LazyLock<Pool<Vec<PathMapping>>> =
     LazyLock::new(|| Pool::new(10, 100_000));
+static TM_POOL: LazyLock<Pool<Vec<TypMapping>>> =
+    LazyLock::new(|| Pool::new(10, 100_000));
 pub static BATCH_POOL: LazyLock<Pool<Vec<BatchItem>>> =
     LazyLock::new(|| Pool::new(10, 100_000));
 pub(crate) static CURSOR_BATCH_POOL: LazyLock<
@@ -574,8 +637,10 @@ impl fmt::Display for RecordTooLarge {
 impl error::Error for RecordTooLarge {}
 
 fn scan_records(
+    version: u32,
     path_by_id: &mut IndexMap<Id, Path, nohash::BuildNoHashHasher<Id>>,
     id_by_path: &mut AHashMap<Path, Id>,
+    typs: &mut IntMap<Id, BitFlags<Typ>>,
     mut imagemap: Option<&mut ArrayMap<DateTime<Utc>, usize>>,
     mut deltamap: Option<&mut ArrayMap<DateTime<Utc>, usize>>,
     time_basis: &mut DateTime<Utc>,
@@ -637,6 +702,14 @@ fn scan_records(
                     }
                     *max_id = max(pm.1 .0, *max_id);
                 }
+                if version >= 1 {
+                    let mut m = <GPooled<Vec<TypMapping>> as Pack>::decode(buf)
+                        .map_err(Error::from)
+                        .context("invalid type mappings")?;
+                    for TypMapping(id, t) in m.drain(..) {
+                        *typs.entry(id).or_default() |= t;
+                    }
+                }
             }
         }
     };
@@ -657,9 +730,9 @@ fn scan_header(buf: &mut impl Buf) -> Result<FileHeader> {
     let header = <FileHeader as Pack>::decode(buf)
         .map_err(Error::from)
         .context("read file header")?;
-    // this is the first version, so no upgrading can be done yet
-    if header.version != FILE_VERSION {
-        bail!("file version mismatch, expected {} got {}", header.version, FILE_VERSION)
+    // version 0 files are still readable, they just have no schema
+    if header.version > FILE_VERSION {
+        bail!("unsupported file version {}, at most {}", header.version, FILE_VERSION)
     }
     Ok(header)
 }
@@ -675,8 +748,10 @@ pub fn read_file_header(path: impl AsRef<FilePath>) -> Result<FileHeader> {
 fn scan_file(
     indexed: &mut bool,
     compressed: &mut Option<CompressionHeader>,
+    version: &mut u32,
     path_by_id: &mut IndexMap<Id, Path, nohash::BuildNoHashHasher<Id>>,
     id_by_path: &mut AHashMap<Path, Id>,
+    typs: &mut IntMap<Id, BitFlags<Typ>>,
     imagemap: Option<&mut ArrayMap<DateTime<Utc>, usize>>,
     deltamap: Option<&mut ArrayMap<DateTime<Utc>, usize>>,
     time_basis: &mut DateTime<Utc>,
@@ -690,9 +765,12 @@ fn scan_file(
             Some(CompressionHeader::decode(buf).context("read compression header")?);
     }
     *indexed = header.indexed;
+    *version = header.version;
     scan_records(
+        header.version,
         path_by_id,
         id_by_path,
+        typs,
         imagemap,
         deltamap,
         time_basis,
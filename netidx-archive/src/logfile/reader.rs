@@ -1,20 +1,21 @@
 use super::{
     arraymap::ArrayMap, scan_file, scan_header, scan_records, ArchiveWriter, BatchItem,
-    Cursor, FileHeader, Id, PathMapping, RecordHeader, Seek, CURSOR_BATCH_POOL, IMG_POOL,
-    PM_POOL,
+    Cursor, FileHeader, Id, PathMapping, RecordHeader, Retention, Seek, TypMapping,
+    BATCH_POOL, CURSOR_BATCH_POOL, FILE_VERSION, IMG_POOL, PM_POOL, TM_POOL,
 };
 use ahash::AHashMap;
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use bytes::{Buf, BufMut};
 use chrono::prelude::*;
+use enumflags2::BitFlags;
 use fs3::FileExt;
 use indexmap::IndexMap;
-use log::{error, info};
+use log::{error, info, warn};
 use memmap2::Mmap;
 use netidx::{
     pack::{decode_varint, varint_len, Pack},
     path::Path,
-    subscriber::Event,
+    subscriber::{Event, Typ, Value},
 };
 use nohash::{IntMap, IntSet};
 use parking_lot::{
@@ -26,7 +27,7 @@ use std::{
     self,
     cell::RefCell,
     cmp::max,
-    collections::{BTreeMap, VecDeque},
+    collections::{BTreeMap, BTreeSet, VecDeque},
     fmt,
     fs::{File, OpenOptions},
     iter::IntoIterator,
@@ -57,25 +58,72 @@ impl fmt::Display for AlreadyCompressed {
 
 impl std::error::Error for AlreadyCompressed {}
 
+/// The number of snapshot checkpoints a reader keeps
+const CHECKPOINTS: usize = 8;
+
+/// The position of the batch holding each id's last write, as of the
+/// batch at `ts`
+#[derive(Debug)]
+struct Checkpoint {
+    ts: DateTime<Utc>,
+    last: IntMap<Id, usize>,
+}
+
+/// The checkpoints of recent snapshots, least recently used first. A
+/// snapshot starts from the closest checkpoint or image before it, so
+/// snapshots near an earlier one only read the deltas in between.
+#[derive(Debug, Default)]
+struct Checkpoints(VecDeque<Arc<Checkpoint>>);
+
+impl Checkpoints {
+    /// the latest checkpoint at or before `ts`
+    fn get(&mut self, ts: DateTime<Utc>) -> Option<Arc<Checkpoint>> {
+        let (i, _) = self
+            .0
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| c.ts <= ts)
+            .max_by_key(|(_, c)| c.ts)?;
+        let c = self.0.remove(i)?;
+        self.0.push_back(c.clone());
+        Some(c)
+    }
+
+    fn insert(&mut self, c: Arc<Checkpoint>) {
+        if !self.0.iter().any(|o| o.ts == c.ts) {
+            if self.0.len() >= CHECKPOINTS {
+                self.0.pop_front();
+            }
+            self.0.push_back(c);
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct ArchiveIndex {
+    version: u32,
     path_by_id: IndexMap<Id, Path, nohash::BuildNoHashHasher<Id>>,
     id_by_path: AHashMap<Path, Id>,
+    typs: IntMap<Id, BitFlags<Typ>>,
     imagemap: ArrayMap<DateTime<Utc>, usize>,
     deltamap: ArrayMap<DateTime<Utc>, usize>,
     time_basis: DateTime<Utc>,
     end: usize,
+    checkpoints: Mutex<Checkpoints>,
 }
 
 impl ArchiveIndex {
-    pub(super) fn new() -> Self {
+    pub(super) fn new(version: u32) -> Self {
         ArchiveIndex {
+            version,
             path_by_id: IndexMap::default(),
             id_by_path: AHashMap::default(),
+            typs: IntMap::default(),
             imagemap: ArrayMap::new(),
             deltamap: ArrayMap::new(),
             time_basis: DateTime::<Utc>::MIN_UTC,
             end: <FileHeader as Pack>::const_encoded_len().unwrap(),
+            checkpoints: Mutex::new(Checkpoints::default()),
         }
     }
 
@@ -223,15 +271,17 @@ impl ArchiveReader {
 
     fn open_with(file: Arc<File>) -> Result<Self> {
         let mmap = unsafe { Mmap::map(&*file).context("mmap file")? };
-        let mut index = ArchiveIndex::new();
+        let mut index = ArchiveIndex::new(FILE_VERSION);
         let mut max_id = 0;
         let mut compressed = None;
         let mut indexed = false;
         let end = scan_file(
             &mut indexed,
             &mut compressed,
+            &mut index.version,
             &mut index.path_by_id,
             &mut index.id_by_path,
+            &mut index.typs,
             Some(&mut index.imagemap),
             Some(&mut index.deltamap),
             &mut index.time_basis,
@@ -319,8 +369,10 @@ impl ArchiveReader {
             let mut max_id = 0;
             let r = &mut *index;
             r.end = scan_records(
+                r.version,
                 &mut r.path_by_id,
                 &mut r.id_by_path,
+                &mut r.typs,
                 Some(&mut r.imagemap),
                 Some(&mut r.deltamap),
                 &mut r.time_basis,
@@ -557,6 +609,119 @@ impl ArchiveReader {
         }
     }
 
+    /// Return the last value of every path in the archive as of
+    /// `ts`, inclusive. Paths that had no value at or before `ts`, or
+    /// whose last event before `ts` was `Unsubscribed`, are omitted.
+    ///
+    /// Like `build_image` this starts from the closest image at or
+    /// before `ts`, and reads only the deltas between it and `ts`. The
+    /// reader also remembers where the last few snapshots ended, and
+    /// starts from one of those instead when it is closer.
+    pub fn snapshot_at(&self, ts: DateTime<Utc>) -> Result<AHashMap<Path, Value>> {
+        self.check_remap_rescan(false)?;
+        let index = self.index.read();
+        let mmap = self.mmap.read();
+        let batch_at = |pos: usize| {
+            ArchiveReader::get_batch_at(
+                self.indexed,
+                &self.compressed,
+                &*mmap,
+                pos,
+                index.end,
+            )
+        };
+        let checkpoint = index.checkpoints.lock().get(ts);
+        let image =
+            index.imagemap.range((Bound::Unbounded, Bound::Included(ts))).next_back();
+        // a checkpoint includes the deltas at its time, an image doesn't
+        let (mut last, mut end, start) = match (checkpoint, image) {
+            (Some(c), Some((its, _))) if c.ts >= *its => {
+                (c.last.clone(), None, Bound::Excluded(c.ts))
+            }
+            (Some(c), None) => (c.last.clone(), None, Bound::Excluded(c.ts)),
+            (_, Some((its, pos))) => {
+                let (_, batch) = batch_at(*pos)?;
+                let last = batch.iter().map(|BatchItem(id, _)| (*id, *pos)).collect();
+                (last, Some(*its), Bound::Included(*its))
+            }
+            (None, None) => (IntMap::default(), None, Bound::Unbounded),
+        };
+        for (dts, pos) in index.deltamap.range((start, Bound::Included(ts))) {
+            let (_, batch) = batch_at(*pos)?;
+            for BatchItem(id, _) in batch.iter() {
+                last.insert(*id, *pos);
+            }
+            end = Some(*dts);
+        }
+        let c = Arc::new(Checkpoint { ts: end.unwrap_or(ts), last });
+        if end.is_some() {
+            index.checkpoints.lock().insert(c.clone());
+        }
+        let batches = c.last.values().copied().collect::<BTreeSet<_>>();
+        let mut snapshot = AHashMap::default();
+        for pos in batches {
+            let (_, mut batch) = batch_at(pos)?;
+            for BatchItem(id, ev) in batch.drain(..) {
+                if c.last.get(&id) != Some(&pos) {
+                    continue;
+                }
+                if let Some(path) = index.path_for_id(&id) {
+                    match ev {
+                        Event::Unsubscribed => {
+                            snapshot.remove(path);
+                        }
+                        Event::Update(v) => {
+                            snapshot.insert(path.clone(), v);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(snapshot)
+    }
+
+    /// Return the set of value types observed for each path in the
+    /// archive. A path with more than one type in its set changed
+    /// type at some point, so an exporter may need to widen or split
+    /// its column. Paths that never had a value are omitted.
+    ///
+    /// The schema is stored with the path mappings, so this doesn't
+    /// read any batches, except for files written before the schema
+    /// was recorded, where every batch has to be read.
+    pub fn schema(&self) -> Result<AHashMap<Path, BitFlags<Typ>>> {
+        let by_id = self.schema_by_id()?;
+        let index = self.index.read();
+        Ok(by_id
+            .into_iter()
+            .filter_map(|(id, typs)| index.path_for_id(&id).map(|p| (p.clone(), typs)))
+            .collect())
+    }
+
+    fn schema_by_id(&self) -> Result<IntMap<Id, BitFlags<Typ>>> {
+        self.check_remap_rescan(false)?;
+        let index = self.index.read();
+        if index.version >= 1 {
+            return Ok(index.typs.clone());
+        }
+        let mmap = self.mmap.read();
+        let mut by_id: IntMap<Id, BitFlags<Typ>> = IntMap::default();
+        for (_, pos) in index.deltamap.iter().chain(index.imagemap.iter()) {
+            let (_, batch) = ArchiveReader::get_batch_at(
+                self.indexed,
+                &self.compressed,
+                &*mmap,
+                *pos,
+                index.end,
+            )?;
+            for BatchItem(id, ev) in batch.iter() {
+                if let Event::Update(v) = ev {
+                    by_id.entry(*id).or_default().insert(Typ::get(v));
+                }
+            }
+        }
+        Ok(by_id)
+    }
+
     fn matching_idxs<'a, 'b: 'a>(
         indexed: bool,
         compressed: bool,
@@ -714,7 +879,7 @@ impl ArchiveReader {
         for (id, path) in index.path_by_id.iter() {
             pms.push(PathMapping(path.clone(), *id));
         }
-        output.add_raw_pathmappings(pms)?;
+        output.add_raw_pathmappings(pms, TM_POOL.take())?;
         let mmap = self.mmap.read();
         for (ts, (image, pos)) in unified_index.iter() {
             let (_, batch) =
@@ -724,6 +889,175 @@ impl ArchiveReader {
         Ok(())
     }
 
+    /// Merge the specified archives into a single indexed archive at
+    /// `dest`. Batches are written in time order, and path ids are
+    /// reassigned so that each path appears in exactly one mapping. If
+    /// more than one input contains a batch at the same timestamp
+    /// (e.g. overlapping rotated files) the batches are merged in the
+    /// order of `inputs`, a delta batch identical to one already
+    /// merged is only written once, and for images the first input to
+    /// mention a path wins. Truncated records at the end of an input
+    /// are ignored, and batches that cannot be decoded are logged and
+    /// skipped. Records of paths that have outlived their `retention`
+    /// are dropped.
+    ///
+    /// If `downsample` is specified then all the deltas in each
+    /// interval of that length are folded into one, keeping only the
+    /// last value of each path, written at the time of the last delta
+    /// in the interval. Images are kept as they are, and end the
+    /// interval. Like `build_index` the output is not compressed.
+    pub async fn compact(
+        inputs: impl IntoIterator<Item = impl AsRef<FilePath>>,
+        dest: impl AsRef<FilePath>,
+        retention: &Retention,
+        downsample: Option<chrono::Duration>,
+    ) -> Result<()> {
+        struct Window {
+            start: DateTime<Utc>,
+            last: DateTime<Utc>,
+            values: IndexMap<Id, Event>,
+        }
+        fn flush_window(
+            output: &mut ArchiveWriter,
+            w: &mut Option<Window>,
+        ) -> Result<()> {
+            if let Some(w) = w.take() {
+                let mut batch = BATCH_POOL.take();
+                batch.extend(w.values.into_iter().map(|(id, ev)| BatchItem(id, ev)));
+                output.add_batch(false, w.last, &batch)?
+            }
+            Ok(())
+        }
+        if let Some(d) = downsample {
+            if d <= chrono::Duration::zero() {
+                bail!("the downsample interval must be positive")
+            }
+        }
+        let inputs = inputs
+            .into_iter()
+            .map(|path| {
+                let path = path.as_ref();
+                let reader = ArchiveReader::open(path)
+                    .with_context(|| format!("opening {}", path.display()))?;
+                reader.check_remap_rescan(false)?;
+                Ok(reader)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        // every batch of every input keyed by time and kind, images
+        // sort before deltas at the same time
+        let mut unified_index: BTreeMap<(DateTime<Utc>, bool), Vec<(usize, usize)>> =
+            BTreeMap::new();
+        for (i, reader) in inputs.iter().enumerate() {
+            let index = reader.index.read();
+            let images = index.imagemap.iter().map(|(ts, pos)| ((*ts, false), *pos));
+            let deltas = index.deltamap.iter().map(|(ts, pos)| ((*ts, true), *pos));
+            for (key, pos) in images.chain(deltas) {
+                unified_index.entry(key).or_insert_with(Vec::new).push((i, pos));
+            }
+        }
+        let mut output = ArchiveWriter::open_full(dest, true, None, None::<&str>)?;
+        let idmaps = inputs
+            .iter()
+            .map(|reader| {
+                let index = reader.index.read();
+                output.add_paths(index.path_by_id.values())?;
+                Ok(index
+                    .path_by_id
+                    .iter()
+                    .filter_map(|(id, path)| Some((*id, output.id_for_path(path)?)))
+                    .collect::<IntMap<Id, Id>>())
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let now = Utc::now();
+        let mut cutoffs: IntMap<Id, DateTime<Utc>> = IntMap::default();
+        for reader in &inputs {
+            for path in reader.index.read().path_by_id.values() {
+                if let (Some(id), Some(cutoff)) =
+                    (output.id_for_path(path), retention.cutoff(now, path))
+                {
+                    cutoffs.insert(id, cutoff);
+                }
+            }
+        }
+        let mut merged = BATCH_POOL.take();
+        let mut ranges: Vec<(usize, usize)> = vec![];
+        let mut seen: IntSet<Id> = IntSet::default();
+        let mut window: Option<Window> = None;
+        for ((ts, delta), sources) in unified_index {
+            merged.clear();
+            ranges.clear();
+            seen.clear();
+            for (i, pos) in sources {
+                let reader = &inputs[i];
+                let index = reader.index.read();
+                let mmap = reader.mmap.read();
+                let res = Self::get_batch_at(
+                    reader.indexed,
+                    &reader.compressed,
+                    &*mmap,
+                    pos,
+                    index.end,
+                );
+                let mut batch = match res {
+                    Ok((_, batch)) => batch,
+                    Err(e) => {
+                        warn!(
+                            "skipping unreadable batch at {} in {:?}, {:?}",
+                            ts, reader, e
+                        );
+                        continue;
+                    }
+                };
+                batch.retain_mut(|BatchItem(id, _)| match idmaps[i].get(id) {
+                    Some(new_id) => {
+                        *id = *new_id;
+                        cutoffs.get(new_id).map(|cutoff| ts >= *cutoff).unwrap_or(true)
+                    }
+                    None => false,
+                });
+                if delta {
+                    if !ranges.iter().any(|(s, e)| merged[*s..*e] == batch[..]) {
+                        let start = merged.len();
+                        merged.extend(batch.drain(..));
+                        ranges.push((start, merged.len()));
+                    }
+                } else {
+                    for item in batch.drain(..) {
+                        if seen.insert(item.0) {
+                            merged.push(item)
+                        }
+                    }
+                }
+            }
+            match downsample {
+                Some(_) if !delta => {
+                    flush_window(&mut output, &mut window)?;
+                    output.add_batch(true, ts, &merged)?
+                }
+                None => output.add_batch(!delta, ts, &merged)?,
+                Some(interval) => {
+                    if window.as_ref().map(|w| ts - w.start >= interval).unwrap_or(false)
+                    {
+                        flush_window(&mut output, &mut window)?
+                    }
+                    if !merged.is_empty() {
+                        let w = window.get_or_insert_with(|| Window {
+                            start: ts,
+                            last: ts,
+                            values: IndexMap::new(),
+                        });
+                        w.last = ts;
+                        for BatchItem(id, ev) in merged.drain(..) {
+                            w.values.insert(id, ev);
+                        }
+                    }
+                }
+            }
+        }
+        flush_window(&mut output, &mut window)?;
+        output.flush()
+    }
+
     /// This function will create an archive with compressed batches
     /// and images. Compressed archives can be read as normal, but can
     /// no longer be written.
@@ -772,6 +1106,9 @@ impl ArchiveReader {
             bail!(AlreadyCompressed)
         }
         self.check_remap_rescan(false)?;
+        // batches are copied without decoding them, so the schema has
+        // to be carried over explicitly
+        let typs = self.schema_by_id()?;
         let (max_len, dict) = self.train()?;
         let pdict =
             Box::leak(Box::new(zstd::dict::EncoderDictionary::copy(&dict, 19))) as *mut _;
@@ -791,7 +1128,9 @@ impl ArchiveReader {
             for (id, path) in index.path_by_id.iter() {
                 pms.push(PathMapping(path.clone(), *id));
             }
-            output.add_raw_pathmappings(pms)?;
+            let mut tms = TM_POOL.take();
+            tms.extend(typs.into_iter().map(|(id, t)| TypMapping(id, t)));
+            output.add_raw_pathmappings(pms, tms)?;
             let ncpus = num_cpus::get();
             let mut compjobs = (0..ncpus * window)
                 .into_iter()
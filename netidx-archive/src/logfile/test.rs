@@ -91,3 +91,416 @@ fn basic_test() {
         fs::remove_file(file).unwrap();
     }
 }
+
+#[test]
+fn snapshot_at() {
+    let file = FilePath::new("test-data-snapshot");
+    if FilePath::is_file(&file) {
+        fs::remove_file(file).unwrap();
+    }
+    let (a, b, c) = (Path::from("/foo/a"), Path::from("/foo/b"), Path::from("/foo/c"));
+    let t0 = Utc::now();
+    let ts = |n: i64| t0 + chrono::Duration::seconds(n);
+    let mut t = ArchiveWriter::open(&file).unwrap();
+    t.add_paths([&a, &b, &c]).unwrap();
+    let mut add = |image: bool, n: i64, items: &[(&Path, Event)]| {
+        let mut batch = BATCH_POOL.take();
+        batch.extend(
+            items.iter().map(|(p, e)| BatchItem(t.id_for_path(p).unwrap(), e.clone())),
+        );
+        t.add_batch(image, ts(n), &batch).unwrap();
+    };
+    add(false, 0, &[(&a, Event::Update(Value::U64(0)))]);
+    add(
+        false,
+        2,
+        &[(&a, Event::Update(Value::U64(1))), (&b, Event::Update(Value::U64(2)))],
+    );
+    add(
+        true,
+        4,
+        &[(&a, Event::Update(Value::U64(1))), (&b, Event::Update(Value::U64(2)))],
+    );
+    add(false, 6, &[(&b, Event::Unsubscribed), (&c, Event::Update(Value::U64(3)))]);
+    t.flush().unwrap();
+    let r = t.reader().unwrap();
+    let snap = |n: i64| {
+        let mut s = r.snapshot_at(ts(n)).unwrap().into_iter().collect::<Vec<_>>();
+        s.sort();
+        s
+    };
+    assert_eq!(snap(-1), vec![]);
+    assert_eq!(snap(0), vec![(a.clone(), Value::U64(0))]);
+    assert_eq!(snap(1), vec![(a.clone(), Value::U64(0))]);
+    let ab = vec![(a.clone(), Value::U64(1)), (b.clone(), Value::U64(2))];
+    assert_eq!(snap(2), ab);
+    assert_eq!(snap(5), ab);
+    let ac = vec![(a.clone(), Value::U64(1)), (c.clone(), Value::U64(3))];
+    assert_eq!(snap(6), ac);
+    assert_eq!(snap(100), ac);
+    // earlier snapshots don't pick up the state of later ones
+    assert_eq!(snap(5), ab);
+    assert_eq!(snap(1), vec![(a.clone(), Value::U64(0))]);
+    assert_eq!(snap(3), ab);
+    // batches written after the index was built are added to it
+    let mut batch = BATCH_POOL.take();
+    batch.push(BatchItem(t.id_for_path(&a).unwrap(), Event::Update(Value::U64(4))));
+    t.add_batch(false, ts(8), &batch).unwrap();
+    t.flush().unwrap();
+    assert_eq!(snap(7), ac);
+    assert_eq!(snap(8), vec![(a.clone(), Value::U64(4)), (c.clone(), Value::U64(3))]);
+    drop(r);
+    drop(t);
+    if FilePath::is_file(&file) {
+        fs::remove_file(file).unwrap();
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn schema() {
+    use enumflags2::BitFlags;
+    use netidx::subscriber::Typ;
+    let file = FilePath::new("test-data-schema");
+    let compressed = FilePath::new("test-data-schema-compressed");
+    for f in [file, compressed] {
+        if FilePath::is_file(f) {
+            fs::remove_file(f).unwrap();
+        }
+    }
+    let (a, b, c) = (Path::from("/foo/a"), Path::from("/foo/b"), Path::from("/foo/c"));
+    let add = |t: &mut ArchiveWriter, image: bool, items: &[(&Path, Event)]| {
+        let mut batch = BATCH_POOL.take();
+        batch.extend(
+            items.iter().map(|(p, e)| BatchItem(t.id_for_path(p).unwrap(), e.clone())),
+        );
+        t.add_batch(image, Utc::now(), &batch).unwrap();
+    };
+    let mut t = ArchiveWriter::open(&file).unwrap();
+    t.add_paths([&a, &b, &c]).unwrap();
+    add(
+        &mut t,
+        false,
+        &[(&a, Event::Update(Value::U64(0))), (&b, Event::Update(Value::U64(0)))],
+    );
+    add(
+        &mut t,
+        true,
+        &[(&a, Event::Update(Value::U64(1))), (&b, Event::Update(Value::U64(0)))],
+    );
+    add(
+        &mut t,
+        false,
+        &[(&b, Event::Update(Value::F64(0.5))), (&c, Event::Unsubscribed)],
+    );
+    t.flush().unwrap();
+    let schema = t.reader().unwrap().schema().unwrap();
+    assert_eq!(schema.len(), 2);
+    assert_eq!(schema[&a], BitFlags::from(Typ::U64));
+    assert_eq!(schema[&b], Typ::U64 | Typ::F64);
+    assert!(!schema.contains_key(&c));
+    drop(t);
+    // the schema is read back from the file
+    assert_eq!(ArchiveReader::open(file).unwrap().schema().unwrap(), schema);
+    // a writer picks up the stored schema and extends it
+    let mut t = ArchiveWriter::open(&file).unwrap();
+    add(&mut t, false, &[(&a, Event::Update(Value::I64(-1)))]);
+    t.flush().unwrap();
+    let schema = t.reader().unwrap().schema().unwrap();
+    assert_eq!(schema[&a], Typ::U64 | Typ::I64);
+    assert_eq!(schema[&b], Typ::U64 | Typ::F64);
+    // compression copies batches without decoding them, the schema
+    // must come along
+    t.reader().unwrap().compress(1, compressed).await.unwrap();
+    drop(t);
+    assert_eq!(ArchiveReader::open(compressed).unwrap().schema().unwrap(), schema);
+    for f in [file, compressed] {
+        if FilePath::is_file(f) {
+            fs::remove_file(f).unwrap();
+        }
+    }
+}
+
+#[test]
+fn recover_uncommitted() {
+    let file = FilePath::new("test-data-crash");
+    let crashed = FilePath::new("test-data-crashed");
+    for f in [file, crashed] {
+        if FilePath::is_file(f) {
+            fs::remove_file(f).unwrap();
+        }
+    }
+    let paths = [Path::from("/foo/bar"), Path::from("/foo/baz")];
+    let mut t = ArchiveWriter::open(&file).unwrap();
+    t.add_paths(&paths).unwrap();
+    let mut batch = BATCH_POOL.take();
+    batch.extend(
+        paths
+            .iter()
+            .map(|p| BatchItem(t.id_for_path(p).unwrap(), Event::Update(Value::U64(42)))),
+    );
+    for _ in 0..9 {
+        t.add_batch(false, Utc::now(), &batch).unwrap();
+    }
+    t.flush().unwrap();
+    t.add_batch(false, Utc::now(), &batch).unwrap();
+    // kill the writer without letting it flush. The last record is in
+    // the page cache, so it is in the file a crashed process leaves
+    // behind, but it was never committed.
+    fs::write(crashed, fs::read(file).unwrap()).unwrap();
+    std::mem::forget(t);
+    {
+        // only the records up to the last flush are recovered
+        let t = ArchiveReader::open(crashed).unwrap();
+        check_contents(&t, &paths, 9);
+    }
+    {
+        // a recovered writer picks up after the last committed record
+        let mut t = ArchiveWriter::open(crashed).unwrap();
+        t.add_batch(false, Utc::now(), &batch).unwrap();
+        t.flush().unwrap();
+        check_contents(&t.reader().unwrap(), &paths, 10);
+    }
+    for f in [file, crashed] {
+        if FilePath::is_file(f) {
+            fs::remove_file(f).unwrap();
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn compact() {
+    let files = ["test-data-compact0", "test-data-compact1", "test-data-compact-out"];
+    for file in files {
+        if FilePath::is_file(FilePath::new(file)) {
+            fs::remove_file(file).unwrap();
+        }
+    }
+    let (a, b, c) = (Path::from("/foo/a"), Path::from("/foo/b"), Path::from("/foo/c"));
+    let t0 = Utc::now();
+    let ts = |n: i64| t0 + chrono::Duration::seconds(n);
+    let write = |file: &str, paths: [&Path; 2], batches: &[(i64, u64)]| {
+        let mut t = ArchiveWriter::open(file).unwrap();
+        t.add_paths(paths).unwrap();
+        for (n, v) in batches {
+            let mut batch = BATCH_POOL.take();
+            batch.extend(paths.iter().map(|p| {
+                BatchItem(t.id_for_path(p).unwrap(), Event::Update(Value::U64(*v)))
+            }));
+            t.add_batch(false, ts(*n), &batch).unwrap();
+        }
+        t.flush().unwrap();
+    };
+    // the ranges overlap at 2, and the ids of a differ between the files
+    write(files[0], [&a, &b], &[(0, 0), (1, 1), (2, 2)]);
+    write(files[1], [&c, &a], &[(2, 20), (3, 3)]);
+    ArchiveReader::compact(&files[0..2], files[2], &Retention::new(), None)
+        .await
+        .unwrap();
+    let r = ArchiveReader::open(files[2]).unwrap();
+    assert!(r.is_indexed());
+    assert_eq!(r.delta_batches(), 4);
+    // both batches at 2 are kept, the later input wins for a
+    let abc2 = vec![
+        (a.clone(), Value::U64(20)),
+        (b.clone(), Value::U64(2)),
+        (c.clone(), Value::U64(20)),
+    ];
+    let mut snap = r.snapshot_at(ts(2)).unwrap().into_iter().collect::<Vec<_>>();
+    snap.sort();
+    assert_eq!(snap, abc2);
+    let abc = vec![
+        (a.clone(), Value::U64(3)),
+        (b.clone(), Value::U64(2)),
+        (c.clone(), Value::U64(3)),
+    ];
+    let mut snap = r.snapshot_at(ts(3)).unwrap().into_iter().collect::<Vec<_>>();
+    snap.sort();
+    assert_eq!(snap, abc);
+    drop(r);
+    for file in files {
+        if FilePath::is_file(FilePath::new(file)) {
+            fs::remove_file(file).unwrap();
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn compact_retention() {
+    use netidx::resolver_client::{Glob, GlobSet};
+    let files = ["test-data-retention", "test-data-retention-out"];
+    for file in files {
+        if FilePath::is_file(FilePath::new(file)) {
+            fs::remove_file(file).unwrap();
+        }
+    }
+    let (cfg, metrics) = (Path::from("/config/a"), Path::from("/metrics/b"));
+    let now = Utc::now();
+    let days = |n: i64| now - chrono::Duration::days(n);
+    let mut t = ArchiveWriter::open(files[0]).unwrap();
+    t.add_paths([&cfg, &metrics]).unwrap();
+    for (n, v) in [(10, 0u64), (1, 1)] {
+        let mut batch = BATCH_POOL.take();
+        batch.extend(
+            [&cfg, &metrics].into_iter().map(|p| {
+                BatchItem(t.id_for_path(p).unwrap(), Event::Update(Value::U64(v)))
+            }),
+        );
+        t.add_batch(false, days(n), &batch).unwrap();
+    }
+    t.flush().unwrap();
+    drop(t);
+    let mut retention = Retention::new();
+    let globs = GlobSet::new(true, [Glob::new("/metrics/**".into()).unwrap()]).unwrap();
+    retention.add(globs, chrono::Duration::days(7));
+    ArchiveReader::compact([files[0]], files[1], &retention, None).await.unwrap();
+    let r = ArchiveReader::open(files[1]).unwrap();
+    assert_eq!(r.delta_batches(), 2);
+    let mut snap = r.snapshot_at(days(5)).unwrap().into_iter().collect::<Vec<_>>();
+    snap.sort();
+    assert_eq!(snap, vec![(cfg.clone(), Value::U64(0))]);
+    let mut snap = r.snapshot_at(now).unwrap().into_iter().collect::<Vec<_>>();
+    snap.sort();
+    assert_eq!(
+        snap,
+        vec![(cfg.clone(), Value::U64(1)), (metrics.clone(), Value::U64(1))]
+    );
+    drop(r);
+    for file in files {
+        if FilePath::is_file(FilePath::new(file)) {
+            fs::remove_file(file).unwrap();
+        }
+    }
+}
+
+fn write_batches(
+    file: &str,
+    paths: &[&Path],
+    batches: &[(bool, DateTime<Utc>, Vec<(&Path, u64)>)],
+) {
+    let mut t = ArchiveWriter::open(file).unwrap();
+    t.add_paths(paths.iter().copied()).unwrap();
+    for (image, ts, items) in batches {
+        let mut batch = BATCH_POOL.take();
+        batch.extend(items.iter().map(|(p, v)| {
+            BatchItem(t.id_for_path(p).unwrap(), Event::Update(Value::U64(*v)))
+        }));
+        t.add_batch(*image, *ts, &batch).unwrap();
+    }
+    t.flush().unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn compact_merge() {
+    let files = ["test-data-merge0", "test-data-merge1", "test-data-merge-out"];
+    for file in files {
+        if FilePath::is_file(FilePath::new(file)) {
+            fs::remove_file(file).unwrap();
+        }
+    }
+    let (a, b) = (Path::from("/foo/a"), Path::from("/foo/b"));
+    let t0 = Utc::now();
+    let ts = |n: i64| t0 + chrono::Duration::milliseconds(n);
+    // an image in one file at the same time as a delta in the other,
+    // and an identical delta in both
+    write_batches(
+        files[0],
+        &[&a, &b],
+        &[
+            (false, ts(0), vec![(&a, 0)]),
+            (false, ts(1000), vec![(&a, 1)]),
+            (false, ts(2000), vec![(&a, 2), (&b, 2)]),
+        ],
+    );
+    write_batches(
+        files[1],
+        &[&b, &a],
+        &[
+            (true, ts(1000), vec![(&b, 5)]),
+            (false, ts(2000), vec![(&a, 2), (&b, 2)]),
+            (false, ts(3000), vec![(&b, 3)]),
+        ],
+    );
+    ArchiveReader::compact(&files[0..2], files[2], &Retention::new(), None)
+        .await
+        .unwrap();
+    let r = ArchiveReader::open(files[2]).unwrap();
+    assert_eq!(r.image_batches(), 1);
+    assert_eq!(r.delta_batches(), 4);
+    let snap = |n: i64| {
+        let mut s = r.snapshot_at(ts(n)).unwrap().into_iter().collect::<Vec<_>>();
+        s.sort();
+        s
+    };
+    assert_eq!(snap(1500), vec![(a.clone(), Value::U64(1)), (b.clone(), Value::U64(5))]);
+    assert_eq!(snap(2500), vec![(a.clone(), Value::U64(2)), (b.clone(), Value::U64(2))]);
+    let mut cursor = Cursor::new();
+    cursor.set_start(std::ops::Bound::Included(ts(2000)));
+    cursor.set_end(std::ops::Bound::Excluded(ts(3000)));
+    let (_, batches) = r.read_deltas(None, &mut cursor, 10).unwrap();
+    assert_eq!(batches.len(), 1);
+    assert_eq!(batches[0].1.len(), 2);
+    drop(r);
+    for file in files {
+        if FilePath::is_file(FilePath::new(file)) {
+            fs::remove_file(file).unwrap();
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn compact_downsample() {
+    let files = ["test-data-downsample", "test-data-downsample-out"];
+    for file in files {
+        if FilePath::is_file(FilePath::new(file)) {
+            fs::remove_file(file).unwrap();
+        }
+    }
+    let (a, b) = (Path::from("/foo/a"), Path::from("/foo/b"));
+    let t0 = Utc::now();
+    let ts = |n: i64| t0 + chrono::Duration::seconds(n);
+    write_batches(
+        files[0],
+        &[&a, &b],
+        &[
+            (false, ts(0), vec![(&a, 0)]),
+            (false, ts(1), vec![(&b, 1)]),
+            (false, ts(2), vec![(&a, 2)]),
+            (true, ts(3), vec![(&a, 2), (&b, 1)]),
+            (false, ts(4), vec![(&a, 4)]),
+            (false, ts(5), vec![(&a, 5), (&b, 5)]),
+            (false, ts(10), vec![(&b, 10)]),
+        ],
+    );
+    let interval = chrono::Duration::seconds(2);
+    ArchiveReader::compact([files[0]], files[1], &Retention::new(), Some(interval))
+        .await
+        .unwrap();
+    let r = ArchiveReader::open(files[1]).unwrap();
+    // [0, 1] and [4, 5] are folded, 2 is cut short by the image at 3
+    assert_eq!(r.image_batches(), 1);
+    assert_eq!(r.delta_batches(), 4);
+    let snap = |n: i64| {
+        let mut s = r.snapshot_at(ts(n)).unwrap().into_iter().collect::<Vec<_>>();
+        s.sort();
+        s
+    };
+    assert_eq!(snap(0), vec![]);
+    assert_eq!(snap(1), vec![(a.clone(), Value::U64(0)), (b.clone(), Value::U64(1))]);
+    assert_eq!(snap(4), vec![(a.clone(), Value::U64(2)), (b.clone(), Value::U64(1))]);
+    assert_eq!(snap(5), vec![(a.clone(), Value::U64(5)), (b.clone(), Value::U64(5))]);
+    assert_eq!(snap(10), vec![(a.clone(), Value::U64(5)), (b.clone(), Value::U64(10))]);
+    let res = ArchiveReader::compact(
+        [files[0]],
+        files[1],
+        &Retention::new(),
+        Some(chrono::Duration::zero()),
+    )
+    .await;
+    assert!(res.is_err());
+    drop(r);
+    for file in files {
+        if FilePath::is_file(FilePath::new(file)) {
+            fs::remove_file(file).unwrap();
+        }
+    }
+}
@@ -1,19 +1,24 @@
 use super::{
     reader::ArchiveIndex, scan_file, ArchiveReader, BatchItem, CompressionHeader,
     FileHeader, Id, MonotonicTimestamper, PathMapping, RecordHeader, RecordIndex,
-    RecordTooLarge, RecordTyp, Timestamp, COMMITTED_OFFSET, FILE_VERSION, MAX_RECORD_LEN,
-    PM_POOL,
+    RecordTooLarge, RecordTyp, Timestamp, TypMapping, COMMITTED_OFFSET, FILE_VERSION,
+    MAX_RECORD_LEN, PM_POOL, TM_POOL,
 };
 use ahash::AHashMap;
 use anyhow::Result;
 use bytes::BufMut;
 use chrono::prelude::*;
+use enumflags2::BitFlags;
 use fs3::{allocation_granularity, FileExt};
 use indexmap::IndexMap;
 use log::warn;
 use memmap2::{Mmap, MmapMut};
-use netidx::{pack::Pack, path::Path};
-use nohash::IntSet;
+use netidx::{
+    pack::Pack,
+    path::Path,
+    subscriber::{Event, Typ},
+};
+use nohash::{IntMap, IntSet};
 use parking_lot::RwLock;
 use poolshark::global::GPooled;
 use std::{
@@ -38,9 +43,10 @@ use std::{
 /// `usize`.
 ///
 /// Files begin with a file header, which consists of the string
-/// "netidx archive" followed by the file format
-/// version. Currently there is 1 version, and the version number
-/// is 0.
+/// "netidx archive" followed by the file format version. The current
+/// version is 1, which added the set of value types seen for each
+/// path to the path mappings records. Version 0 files can still be
+/// read and appended to, but they have no schema.
 ///
 /// Following the header are a series of records. Every record begins
 /// with a (RecordHeader)[RecordHeader], which is followed by a data
@@ -103,8 +109,10 @@ use std::{
 /// 1289 bytes (264 bytes of overhead 20%)
 pub struct ArchiveWriter {
     time: MonotonicTimestamper,
+    version: u32,
     path_by_id: IndexMap<Id, Path, nohash::BuildNoHashHasher<Id>>,
     id_by_path: AHashMap<Path, Id>,
+    typs: IntMap<Id, BitFlags<Typ>>,
     file: Arc<File>,
     _external_lock: Option<Arc<File>>,
     end: Arc<AtomicUsize>,
@@ -164,8 +172,10 @@ impl ArchiveWriter {
             let mmap = unsafe { MmapMut::map_mut(&file)? };
             let mut t = ArchiveWriter {
                 time,
+                version: FILE_VERSION,
                 path_by_id: IndexMap::default(),
                 id_by_path: AHashMap::default(),
+                typs: IntMap::default(),
                 file: Arc::new(file),
                 _external_lock: external_lock,
                 end: Arc::new(AtomicUsize::new(0)),
@@ -181,8 +191,10 @@ impl ArchiveWriter {
             let end = scan_file(
                 &mut t.indexed,
                 &mut compress,
+                &mut t.version,
                 &mut t.path_by_id,
                 &mut t.id_by_path,
+                &mut t.typs,
                 None,
                 None,
                 &mut time_basis,
@@ -227,8 +239,10 @@ impl ArchiveWriter {
             mmap.flush()?;
             Ok(ArchiveWriter {
                 time,
+                version: FILE_VERSION,
                 path_by_id: IndexMap::default(),
                 id_by_path: AHashMap::default(),
+                typs: IntMap::default(),
                 file: Arc::new(file),
                 _external_lock: external_lock,
                 end: Arc::new(AtomicUsize::new(committed as usize)),
@@ -318,15 +332,26 @@ impl ArchiveWriter {
                 pms.push(PathMapping(path.clone(), id));
             }
         }
-        self.add_raw_pathmappings(pms)
+        self.add_raw_pathmappings(pms, TM_POOL.take())
     }
 
     pub(super) fn add_raw_pathmappings(
         &mut self,
         pms: GPooled<Vec<PathMapping>>,
+        mut tms: GPooled<Vec<TypMapping>>,
     ) -> Result<()> {
-        if pms.len() > 0 {
-            let record_length = <GPooled<Vec<PathMapping>> as Pack>::encoded_len(&pms);
+        if self.version < 1 {
+            tms.clear()
+        }
+        for TypMapping(id, t) in tms.iter() {
+            *self.typs.entry(*id).or_default() |= *t;
+        }
+        if pms.len() > 0 || tms.len() > 0 {
+            let mut record_length =
+                <GPooled<Vec<PathMapping>> as Pack>::encoded_len(&pms);
+            if self.version >= 1 {
+                record_length += <GPooled<Vec<TypMapping>> as Pack>::encoded_len(&tms);
+            }
             let len = self.check_reserve(record_length)?;
             let end = self.end.load(Ordering::Relaxed);
             let mut buf = &mut self.mmap[end..];
@@ -337,6 +362,9 @@ impl ArchiveWriter {
             };
             <RecordHeader as Pack>::encode(&rh, &mut buf)?;
             <GPooled<Vec<PathMapping>> as Pack>::encode(&pms, &mut buf)?;
+            if self.version >= 1 {
+                <GPooled<Vec<TypMapping>> as Pack>::encode(&tms, &mut buf)?;
+            }
             self.end.fetch_add(len, Ordering::AcqRel);
         }
         Ok(())
@@ -403,6 +431,9 @@ impl ArchiveWriter {
         batch: &GPooled<Vec<BatchItem>>,
     ) -> Result<()> {
         if batch.len() > 0 {
+            if self.version >= 1 {
+                self.add_new_types(batch)?
+            }
             let timestamp = self.time.timestamp(timestamp);
             let index = if self.indexed {
                 if !image {
@@ -435,6 +466,22 @@ impl ArchiveWriter {
         Ok(())
     }
 
+    // record any types in batch that its paths haven't had before
+    fn add_new_types(&mut self, batch: &GPooled<Vec<BatchItem>>) -> Result<()> {
+        let mut tms = TM_POOL.take();
+        for BatchItem(id, ev) in batch.iter() {
+            if let Event::Update(v) = ev {
+                let t = Typ::get(v);
+                let ts = self.typs.entry(*id).or_default();
+                if !ts.contains(t) {
+                    *ts |= t;
+                    tms.push(TypMapping(*id, *ts));
+                }
+            }
+        }
+        self.add_raw_pathmappings(PM_POOL.take(), tms)
+    }
+
     // this is used to build a compressed archive
     pub(super) fn add_batch_raw(
         &mut self,
@@ -480,7 +527,7 @@ impl ArchiveWriter {
     /// can be shared by all the readers.
     pub fn reader(&self) -> Result<ArchiveReader> {
         Ok(ArchiveReader {
-            index: Arc::new(RwLock::new(ArchiveIndex::new())),
+            index: Arc::new(RwLock::new(ArchiveIndex::new(self.version))),
             compressed: None,
             indexed: self.indexed,
             file: self.file.clone(),
@@ -174,6 +174,26 @@ fn write_image(
     Ok(())
 }
 
+// count a record written, and flush if the flush policy says enough
+// of them have been written since the last flush. The flush blocks on
+// an fsync, so this must only be called inside task::block_in_place.
+fn maybe_flush_records(
+    archive: &mut ArchiveCollectionWriter,
+    flush_records: Option<usize>,
+    unflushed: &mut usize,
+    last_flush: &mut usize,
+) -> Result<()> {
+    if let Some(n) = flush_records {
+        *unflushed += 1;
+        if *unflushed >= n {
+            archive.flush_current().context("flushing archive")?;
+            *last_flush = archive.len()?;
+            *unflushed = 0;
+        }
+    }
+    Ok(())
+}
+
 pub(super) async fn run(
     shards: Arc<Shards>,
     subscriber: Subscriber,
@@ -194,10 +214,17 @@ pub(super) async fn run(
     let mut subscribed: AHashMap<Path, Dval> = AHashMap::default();
     let bcast = shards.bcast[&shard_id].clone();
     let block_size = archive.block_size()?;
-    let flush_frequency = record_config.flush_frequency.map(|f| block_size * f);
+    let (flush_frequency, flush_interval, flush_records) =
+        match record_config.flush_policy {
+            None => (
+                record_config.flush_frequency.map(|f| block_size * f),
+                record_config.flush_interval,
+                None,
+            ),
+            Some(policy) => (None, policy.interval(), policy.records()),
+        };
     let mut poll = record_config.poll_interval.map(time::interval);
-    let mut flush =
-        record_config.flush_interval.map(|d| time::interval_at(Instant::now() + d, d));
+    let mut flush = flush_interval.map(|d| time::interval_at(Instant::now() + d, d));
     let mut rotate = match record_config.rotate_interval {
         RotateDirective::Never => None,
         RotateDirective::Interval(d) => Some(time::interval_at(Instant::now() + d, d)),
@@ -211,6 +238,8 @@ pub(super) async fn run(
     let mut remove_paths: Vec<Path> = vec![];
     let mut last_image = archive.len()?;
     let mut last_flush = archive.len()?;
+    // records written since the last flush
+    let mut unflushed = 0;
     let mut pending_list: Option<Fuse<oneshot::Receiver<Lst>>> = None;
     let mut batches = 0;
     let mut last_batches = Instant::now();
@@ -236,6 +265,7 @@ pub(super) async fn run(
                 if archive.len()? > last_flush {
                     task::block_in_place(|| -> Result<()> {
                         archive.flush_current().context("flushing archive")?;
+                        unflushed = 0;
                         Ok(last_flush = archive.len()?)
                     })?;
                 }
@@ -257,6 +287,7 @@ pub(super) async fn run(
                         archive.rotate(now).context("rotating log file")?;
                         last_image = 0;
                         last_flush = 0;
+                        unflushed = 0;
                         write_image(&mut archive, &by_subid, &image, now)
                             .context("writing image")?;
                         let reader = archive.current_reader()
@@ -320,6 +351,12 @@ pub(super) async fn run(
                             archive.add_batch(false, now, &tbatch)
                                 .context("adding archive batch")?;
                             let _ = bcast.send(BCastMsg::Batch(now, Arc::new(tbatch)));
+                            maybe_flush_records(
+                                &mut archive,
+                                flush_records,
+                                &mut unflushed,
+                                &mut last_flush
+                            )?;
                         }
                         match record_config.image_frequency {
                             None => (),
@@ -328,6 +365,12 @@ pub(super) async fn run(
                                 write_image(&mut archive, &by_subid, &image, Utc::now())
                                     .context("writing image")?;
                                 last_image = archive.len()?;
+                                maybe_flush_records(
+                                    &mut archive,
+                                    flush_records,
+                                    &mut unflushed,
+                                    &mut last_flush
+                                )?;
                             }
                         }
                         match flush_frequency {
@@ -1,6 +1,6 @@
 use crate::{
-    config::{ConfigBuilder, PublishConfigBuilder, RecordConfigBuilder},
-    logfile::{BatchItem, Seek, BATCH_POOL},
+    config::{ConfigBuilder, FlushPolicy, PublishConfigBuilder, RecordConfigBuilder},
+    logfile::{ArchiveReader, BatchItem, Cursor, Seek, BATCH_POOL},
     recorder::{Recorder, State},
     recorder_client::{Client, Speed},
 };
@@ -29,7 +29,7 @@ use netidx::{
     subscriber::{Event, Subscriber, SubscriberBuilder, UpdatesFlags},
 };
 use netidx_netproto::glob::GlobSet;
-use std::{fs, time::Duration};
+use std::{fs, path::Path as FilePath, time::Duration};
 use tokio::{task, time};
 
 struct Ctx {
@@ -300,3 +300,95 @@ async fn embedded_recorder() -> Result<()> {
     fs::remove_dir_all(PATH1)?;
     Ok(())
 }
+
+const PATH2: &str = "test-crash-recovery";
+
+#[tokio::test(flavor = "multi_thread")]
+async fn crash_recovery() -> Result<()> {
+    let _ = env_logger::try_init();
+    let _ = fs::remove_dir_all(PATH2);
+    let ctx = Ctx::new().await.context("build creating context")?;
+    let record = RecordConfigBuilder::default()
+        .try_spec(vec![literal!("/test/**")])
+        .context("compiling spec")?
+        .image_frequency(None)
+        .flush_policy(Some(FlushPolicy::Records(3)))
+        .build()
+        .context("build record config")?;
+    let cfg = ConfigBuilder::default()
+        .record([(SHARD.into(), record)])
+        .archive_directory(PATH2)
+        .build()
+        .context("build config")?;
+    let paths = [Path::from(D0), Path::from(D1)];
+    let ids = [
+        ctx.publisher.publish(paths[0].clone(), Value::Null)?,
+        ctx.publisher.publish(paths[1].clone(), Value::Null)?,
+    ];
+    ctx.publisher.flushed().await;
+    let recorder = Recorder::start_with(
+        cfg,
+        Some(ctx.publisher.clone()),
+        Some(ctx.subscriber.clone()),
+    )
+    .await
+    .context("creating recorder")?;
+    let current = FilePath::new(PATH2).join(SHARD).join("current");
+    let crashed = FilePath::new(PATH2).join("crashed");
+    // copy the archive out from under the running recorder, as if it
+    // had crashed, and read back what was committed
+    let committed = || -> Result<Vec<Vec<(Path, Event)>>> {
+        fs::copy(&current, &crashed)?;
+        let reader = ArchiveReader::open(&crashed)?;
+        let mut cursor = Cursor::new();
+        let (_, mut batches) =
+            reader.read_deltas(None, &mut cursor, reader.delta_batches())?;
+        let index = reader.index();
+        let mut res = vec![];
+        for (_, mut batch) in batches.drain(..) {
+            let mut events = vec![];
+            for BatchItem(id, ev) in batch.drain(..) {
+                match index.path_for_id(&id) {
+                    Some(path) => events.push((path.clone(), ev)),
+                    None => bail!("no path for {id:?}"),
+                }
+            }
+            res.push(events);
+        }
+        Ok(res)
+    };
+    let mut i = 0;
+    while committed()?.len() < 9 {
+        if i > 1000 {
+            bail!("the recorder never committed 9 records")
+        }
+        let mut b = ctx.publisher.start_batch();
+        for id in &ids {
+            id.update(&mut b, i)
+        }
+        b.commit(None).await;
+        i += 1;
+        time::sleep(Duration::from_millis(10)).await;
+    }
+    time::sleep(Duration::from_millis(100)).await;
+    let batches = committed()?;
+    // the recorder flushes after every 3rd record, anything written
+    // since then was never committed and is not recovered
+    assert_eq!(batches.len() % 3, 0);
+    // updates may be coalesced, but never reordered or invented
+    for path in &paths {
+        let mut expected = [Value::Null].into_iter().chain((0..i).map(Value::U64));
+        for (p, ev) in batches.iter().flatten() {
+            if p == path {
+                let v = match ev {
+                    Event::Update(v) => v,
+                    Event::Unsubscribed => bail!("unexpected unsubscribe"),
+                };
+                assert!(expected.any(|e| &e == v), "{path} {v} out of order");
+            }
+        }
+    }
+    drop(recorder);
+    fs::remove_dir_all(PATH2)?;
+    Ok(())
+}
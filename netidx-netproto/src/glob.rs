@@ -1,6 +1,7 @@
 use anyhow::Result;
 use arcstr::ArcStr;
 use bytes::{Buf, BufMut};
+use escaping::Escape;
 use globset;
 use netidx_core::{
     pack::{Pack, PackError},
@@ -9,6 +10,7 @@ use netidx_core::{
 use poolshark::global::{GPooled, Pool};
 use smallvec::SmallVec;
 use std::{
+    borrow::Cow,
     cmp::{Eq, PartialEq},
     ops::Deref,
     result,
@@ -17,6 +19,10 @@ use std::{
 
 use crate::value::{FromValue, Value};
 
+static GLOB_ESC: LazyLock<Escape> = LazyLock::new(|| {
+    Escape::new('\\', &['\\', '?', '*', '{', '}', '[', ']'], &[], None).unwrap()
+});
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Scope {
     Subtree,
@@ -94,6 +100,18 @@ impl Glob {
         }
     }
 
+    /// Escape every glob meta char in `s`, including the escape
+    /// character, so that it only matches itself.
+    ///
+    /// # Examples
+    /// ```
+    /// use netidx_netproto::glob::Glob;
+    /// assert_eq!("/foo/\\*bar\\?", &*Glob::escape("/foo/*bar?"));
+    /// ```
+    pub fn escape<'a, T: AsRef<str> + ?Sized>(s: &'a T) -> Cow<'a, str> {
+        GLOB_ESC.escape(s)
+    }
+
     pub fn new(raw: ArcStr) -> Result<Glob> {
         if !Path::is_absolute(&raw) {
             bail!("glob paths must be absolute")
@@ -115,7 +133,7 @@ impl Glob {
             }
         };
         let lvl = Path::levels(base);
-        let base = Path::from(ArcStr::from(base));
+        let base = Path::from(ArcStr::from(GLOB_ESC.unescape(base)));
         let scope =
             if Path::dirnames(&raw).skip(lvl).any(|p| Path::basename(p) == Some("**")) {
                 Scope::Subtree
@@ -1,13 +1,34 @@
 use crate::{resolver::UserInfo, value::Value};
+use arcstr::ArcStr;
 use bytes::Bytes;
 use netidx_core::path::Path;
 use netidx_derive::Pack;
-use std::net::SocketAddr;
+use poolshark::global::GPooled;
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+
+/// The version each side sends before the hello. It never changes,
+/// peers that predate version negotiation require exactly this
+/// value.
+pub const WIRE_VERSION: u64 = 3;
+
+/// The publisher protocol version spoken by this library. Each side
+/// advertises its version in the hello, and the connection uses the
+/// lower of the two. Version 4 adds `To::UnsubscribeMany`, version 5
+/// allows frames with a 64 bit length.
+pub const PROTOCOL_VERSION: u64 = 5;
+
+/// The oldest publisher protocol version this library can speak. A
+/// peer that doesn't advertise a version speaks this one.
+pub const MIN_PROTOCOL_VERSION: u64 = 3;
 
 atomic_id!(Id);
 
 atomic_id!(WriteId);
 
+/// Small, rarely changing information about a published value, for
+/// example its units, or a description.
+pub type Metadata = Arc<HashMap<ArcStr, Value>>;
+
 impl Default for WriteId {
     fn default() -> Self {
         WriteId::new()
@@ -19,14 +40,14 @@ pub enum Hello {
     /// No authentication will be provided. The publisher may drop
     /// the connection at this point, if it chooses to allow this
     /// then it will return Anonymous.
-    Anonymous,
+    Anonymous(#[pack(default)] u64),
     /// Authenticate using kerberos 5, following the hello, the
     /// subscriber and publisher will exchange tokens to complete the
     /// authentication.
-    Krb5(#[pack(default)] Option<UserInfo>),
+    Krb5(#[pack(default)] Option<UserInfo>, #[pack(default)] u64),
     /// Authenticate using a local unix socket, only valid for
     /// publishers on the same machine as the subscriber.
-    Local(#[pack(default)] Option<UserInfo>),
+    Local(#[pack(default)] Option<UserInfo>, #[pack(default)] u64),
     /// In order to prevent denial of service, spoofing, etc,
     /// authenticated publishers must prove that they are actually
     /// listening on the socket they claim to be listening on. To
@@ -44,7 +65,22 @@ pub enum Hello {
     /// Authenticate using transport layer security. In this case both
     /// the server AND the client must have certificates that are
     /// signed by a CA they mutually trust.
-    Tls(#[pack(default)] Option<UserInfo>),
+    Tls(#[pack(default)] Option<UserInfo>, #[pack(default)] u64),
+}
+
+impl Hello {
+    /// The protocol version advertised by the sender of this hello.
+    /// The trailing u64 of each variant carries it, peers that don't
+    /// send it speak `MIN_PROTOCOL_VERSION`.
+    pub fn version(&self) -> u64 {
+        match self {
+            Hello::Anonymous(v)
+            | Hello::Krb5(_, v)
+            | Hello::Local(_, v)
+            | Hello::Tls(_, v) => (*v).max(MIN_PROTOCOL_VERSION),
+            Hello::ResolverAuthenticate(_) => MIN_PROTOCOL_VERSION,
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Pack)]
@@ -67,6 +103,10 @@ pub enum To {
     Unsubscribe(Id),
     /// Send a write to the specified value.
     Write(Id, bool, Value, #[pack(default)] WriteId),
+    /// Unsubscribe from all of the specified values, as if an
+    /// Unsubscribe message had been sent for each one. Only sent to
+    /// publishers speaking protocol version 4 or later.
+    UnsubscribeMany(GPooled<Vec<Id>>),
 }
 
 #[derive(Debug, Clone, PartialEq, Pack)]
@@ -84,8 +124,9 @@ pub enum From {
     /// You are now subscribed to Path with subscription id `Id`, and
     /// The next message contains the first value for Id. All further
     /// communications about this subscription will only refer to the
-    /// Id.
-    Subscribed(Path, Id, Value),
+    /// Id. If the publisher attached metadata to the value it is
+    /// included, older publishers never send it.
+    Subscribed(Path, Id, Value, #[pack(default)] Option<Metadata>),
     /// A value update to Id
     Update(Id, Value),
     /// Indicates that the publisher is idle, but still
@@ -54,13 +54,49 @@ pub struct ClientHelloWrite {
     pub auth: AuthWrite,
     #[pack(default)]
     pub priority: PublisherPriority,
+    /// The client will read a reply to each heartbeat, and will
+    /// publish everything again if that reply is `FromWrite::Resync`
+    #[pack(default)]
+    pub resync: bool,
+    /// The client understands frames with a 64 bit length, and may
+    /// send messages too large for a 32 bit frame if the server
+    /// agrees.
+    #[pack(default)]
+    pub large_frames: bool,
+    /// The client will read a reply to each heartbeat, and wants it
+    /// to be `FromWrite::Ttl` unless a resync is required
+    #[pack(default)]
+    pub ttl_countdown: bool,
 }
 
+/// Sent by a resolver server in place of the protocol version when
+/// it is at its connection limit and configured to reject new
+/// clients rather than queue them. The server closes the connection
+/// once it has read the client's version.
+pub const AT_CAPACITY: u64 = u64::MAX;
+
+/// The server understands `ToRead::ListByAddr`
+pub const CAP_LIST_BY_ADDR: u64 = 0x01;
+
+/// The server understands `ToWrite::UnpublishSubtree`
+pub const CAP_UNPUBLISH_SUBTREE: u64 = 0x02;
+
+/// The server understands `ToWrite::Republish`
+pub const CAP_REPUBLISH: u64 = 0x04;
+
+/// The server understands `ToWrite::Status`
+pub const CAP_STATUS: u64 = 0x08;
+
+/// Every capability this version of the protocol defines
+pub const CAP_ALL: u64 =
+    CAP_LIST_BY_ADDR | CAP_UNPUBLISH_SUBTREE | CAP_REPUBLISH | CAP_STATUS;
+
 #[derive(Clone, Debug, PartialEq, Eq, Pack)]
 pub enum ClientHello {
     /// Instruct the resolver server that this connection will not
-    /// publish paths.
-    ReadOnly(AuthRead),
+    /// publish paths. The flag is set if the client understands
+    /// frames with a 64 bit length.
+    ReadOnly(AuthRead, #[pack(default)] bool),
     /// Instruct the resolver server that this connection will
     /// only publish paths. All published paths will use the
     /// specified address `write_addr`, and the publisher must
@@ -76,6 +112,99 @@ pub struct ServerHelloWrite {
     pub ttl_expired: bool,
     pub auth: AuthWrite,
     pub resolver_id: SocketAddr,
+    /// The server will reply to each heartbeat
+    #[pack(default)]
+    pub resync: bool,
+    /// Both sides may send frames with a 64 bit length
+    #[pack(default)]
+    pub large_frames: bool,
+    /// The server will reply to each heartbeat with the time left
+    /// until it expires the client
+    #[pack(default)]
+    pub ttl_countdown: bool,
+    /// The server's version, empty if the server is too old to say
+    #[pack(default)]
+    pub version: ArcStr,
+    /// The `CAP_*` bits of the messages the server understands beyond
+    /// the original protocol
+    #[pack(default)]
+    pub capabilities: u64,
+    /// Chosen at random each time the server starts, so a client can
+    /// tell a restarted server from the one it last said hello to at
+    /// the same address. Zero if the server is too old to say.
+    #[pack(default)]
+    pub instance: u64,
+}
+
+/// The server's reply to `ClientHello::ReadOnly`.
+///
+/// On the wire this is an `AuthRead` with the version, capabilities,
+/// denial, and large frames flag following the tag inside the same
+/// length prefix, so a client that expects a bare `AuthRead` skips
+/// them, and the bare `AuthRead` an older server sends decodes with
+/// an empty version, no capabilities, no denial, and no large frames.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServerHelloRead {
+    pub auth: AuthRead,
+    /// The server's version, empty if the server is too old to say
+    pub version: ArcStr,
+    /// The `CAP_*` bits of the messages the server understands beyond
+    /// the original protocol
+    pub capabilities: u64,
+    /// If set the server refused the client's credentials for this
+    /// reason, and will close the connection
+    pub denied: Option<ArcStr>,
+    /// Both sides may send frames with a 64 bit length
+    pub large_frames: bool,
+}
+
+impl Pack for ServerHelloRead {
+    fn encoded_len(&self) -> usize {
+        len_wrapped_len(
+            1 + Pack::encoded_len(&self.version)
+                + Pack::encoded_len(&self.capabilities)
+                + Pack::encoded_len(&self.denied)
+                + Pack::encoded_len(&self.large_frames),
+        )
+    }
+
+    fn encode(&self, buf: &mut impl BufMut) -> Result<()> {
+        len_wrapped_encode(buf, self, |buf| {
+            buf.put_u8(match self.auth {
+                AuthRead::Anonymous => 0,
+                AuthRead::Krb5 => 1,
+                AuthRead::Local => 2,
+                AuthRead::Tls => 3,
+            });
+            Pack::encode(&self.version, buf)?;
+            Pack::encode(&self.capabilities, buf)?;
+            Pack::encode(&self.denied, buf)?;
+            Pack::encode(&self.large_frames, buf)
+        })
+    }
+
+    fn decode(buf: &mut impl Buf) -> Result<Self> {
+        fn or_default<T: Default>(r: Result<T>) -> Result<T> {
+            match r {
+                Err(PackError::BufferShort) => Ok(T::default()),
+                r => r,
+            }
+        }
+        len_wrapped_decode(buf, |buf| {
+            let auth = match <u8 as Pack>::decode(buf)? {
+                0 => AuthRead::Anonymous,
+                1 => AuthRead::Krb5,
+                2 => AuthRead::Local,
+                3 => AuthRead::Tls,
+                _ => return Err(PackError::UnknownTag),
+            };
+            let version = or_default(Pack::decode(buf))?;
+            let capabilities = or_default(Pack::decode(buf))?;
+            let denied = or_default(Pack::decode(buf))?;
+            let large_frames = or_default(Pack::decode(buf))?;
+            Ok(ServerHelloRead { auth, version, capabilities, denied, large_frames })
+        })
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Pack)]
@@ -113,6 +242,25 @@ pub enum ToRead {
     ListMatching(GlobSet),
     /// Get the change nr for the specified path
     GetChangeNr(Path),
+    /// List every path, including defaults, published by the
+    /// publisher with the specified write address. Requires list
+    /// permission on the root of the resolver server.
+    ListByAddr(SocketAddr),
+}
+
+impl ToRead {
+    /// The `CAP_*` bit the server must advertise before this message
+    /// may be sent to it, or 0 if every server understands it
+    pub fn capability(&self) -> u64 {
+        match self {
+            ToRead::Resolve(_)
+            | ToRead::List(_)
+            | ToRead::Table(_)
+            | ToRead::ListMatching(_)
+            | ToRead::GetChangeNr(_) => 0,
+            ToRead::ListByAddr(_) => CAP_LIST_BY_ADDR,
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Pack)]
@@ -197,14 +345,36 @@ pub struct PublisherRef {
     pub token: Bytes,
 }
 
+/// What the resolver knows about the state of a resolved path
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Pack)]
+pub enum PathStatus {
+    /// The path has publishers, or the resolver is too old to say
+    Published,
+    /// The resolver has no record of the path ever being published
+    Unknown,
+    /// The path had publishers until `last_seen`, in seconds since
+    /// the unix epoch, and they have since unpublished or expired
+    Expired { last_seen: u64 },
+}
+
+impl Default for PathStatus {
+    fn default() -> Self {
+        PathStatus::Published
+    }
+}
+
 /// The result of resolving a path
 #[derive(Clone, Debug, PartialEq, Eq, Pack)]
 pub struct Resolved {
     pub resolver: SocketAddr,
+    /// sorted by publisher address
     pub publishers: GPooled<Vec<PublisherRef>>,
     pub timestamp: u64,
     pub flags: u32,
     pub permissions: u32,
+    /// Why `publishers` is empty, `PathStatus::Published` otherwise
+    #[pack(default)]
+    pub status: PathStatus,
 }
 
 #[derive(Clone, Debug, Pack)]
@@ -273,7 +443,13 @@ pub enum ToWrite {
     Unpublish(Path),
     /// Clear all values you've published
     Clear,
-    /// Tell the resolver that we are still alive
+    /// Tell the resolver that we are still alive. If resync or ttl
+    /// countdown was negotiated in the hello the resolver replies
+    /// with `Resync` if it is required, otherwise `Ttl` if ttl
+    /// countdown was negotiated, or `Published`. If neither was
+    /// negotiated there is no reply. If ttl countdown was negotiated
+    /// a heartbeat at the end of a larger batch is answered with
+    /// `Ttl` after the replies to the rest of the batch.
     Heartbeat,
     /// Publish the path and set associated flags
     PublishWithFlags(Path, u32),
@@ -281,6 +457,40 @@ pub enum ToWrite {
     PublishDefaultWithFlags(Path, u32),
     /// Unpublish a default publisher
     UnpublishDefault(Path),
+    /// Stop publishing every path, including defaults, at or under
+    /// the specified path
+    UnpublishSubtree(Path),
+    /// Publish `path` at `to`, which must be the sender, and stop
+    /// publishing it at `from` in the same step, so resolvers never
+    /// see the path without a publisher. `from` must belong to the
+    /// same authenticated user as the sender, anonymous senders may
+    /// not take paths from other publishers. The reply is
+    /// `Published`.
+    Republish { path: Path, from: SocketAddr, to: SocketAddr },
+    /// Ask the resolver what it knows about the sender. It must be
+    /// the only message in its batch, otherwise it is answered with
+    /// `Error`. The reply is `Status`.
+    Status,
+}
+
+impl ToWrite {
+    /// The `CAP_*` bit the server must advertise before this message
+    /// may be sent to it, or 0 if every server understands it
+    pub fn capability(&self) -> u64 {
+        match self {
+            ToWrite::Publish(_)
+            | ToWrite::PublishDefault(_)
+            | ToWrite::Unpublish(_)
+            | ToWrite::Clear
+            | ToWrite::Heartbeat
+            | ToWrite::PublishWithFlags(_, _)
+            | ToWrite::PublishDefaultWithFlags(_, _)
+            | ToWrite::UnpublishDefault(_) => 0,
+            ToWrite::UnpublishSubtree(_) => CAP_UNPUBLISH_SUBTREE,
+            ToWrite::Republish { .. } => CAP_REPUBLISH,
+            ToWrite::Status => CAP_STATUS,
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash, Pack)]
@@ -290,4 +500,35 @@ pub enum FromWrite {
     Referral(Referral),
     Denied,
     Error(ArcStr),
+    /// Sent in reply to a heartbeat when the resolver has lost the
+    /// paths published by the client, which should then publish
+    /// everything again
+    Resync,
+    /// Sent in reply to a heartbeat, the number of seconds the
+    /// resolver will wait for another message before it drops the
+    /// client
+    Ttl(u64),
+    /// Sent in reply to `ToWrite::Status`
+    Status(WriterStatus),
+}
+
+/// What the resolver knows about a write client, see `ToWrite::Status`
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Pack)]
+pub struct WriterStatus {
+    /// The number of paths the client is publishing, not including
+    /// defaults
+    pub published: u64,
+    /// The number of default publishers the client has
+    pub defaults: u64,
+    /// The number of seconds the resolver will wait for another
+    /// message before it drops the client
+    pub ttl: u64,
+    /// The `ttl_expired` flag sent in the hello of the current
+    /// connection. True means the resolver had no registration for
+    /// the client when it connected.
+    pub ttl_expired: bool,
+    /// True if the resolver evicted everything the client published
+    /// to make room for someone else. The client will be asked to
+    /// resync once there is room.
+    pub evicted: bool,
 }
@@ -59,10 +59,10 @@ mod resolver {
         glob::{Glob, GlobSet},
         resolver::{
             Auth, AuthChallenge, AuthRead, AuthWrite, ClientHello, ClientHelloWrite,
-            FromRead, FromWrite, GetChangeNr, HashMethod, ListMatching, Publisher,
-            PublisherId, PublisherPriority, PublisherRef, ReadyForOwnershipCheck,
-            Referral, Resolved, Secret, ServerHelloWrite, Table, TargetAuth, ToRead,
-            ToWrite,
+            FromRead, FromWrite, GetChangeNr, HashMethod, ListMatching, PathStatus,
+            Publisher, PublisherId, PublisherPriority, PublisherRef,
+            ReadyForOwnershipCheck, Referral, Resolved, Secret, ServerHelloRead,
+            ServerHelloWrite, Table, TargetAuth, ToRead, ToWrite, WriterStatus,
         },
     };
     use netidx_core::pack::PackError;
@@ -89,6 +89,7 @@ mod resolver {
         let _: Result<Referral> = Pack::decode(&mut &*b);
         let _: Result<Resolved> = Pack::decode(&mut &*b);
         let _: Result<Secret> = Pack::decode(&mut &*b);
+        let _: Result<ServerHelloRead> = Pack::decode(&mut &*b);
         let _: Result<ServerHelloWrite> = Pack::decode(&mut &*b);
         let _: Result<Table> = Pack::decode(&mut &*b);
         let _: Result<TargetAuth> = Pack::decode(&mut &*b);
@@ -130,31 +131,84 @@ mod resolver {
     }
 
     fn client_hello_write() -> impl Strategy<Value = ClientHelloWrite> {
-        (any::<SocketAddr>(), auth_write(), publisher_priority()).prop_map(
-            |(write_addr, auth, priority)| ClientHelloWrite {
-                write_addr,
-                auth,
-                priority,
-            },
+        (
+            any::<SocketAddr>(),
+            auth_write(),
+            publisher_priority(),
+            any::<bool>(),
+            any::<bool>(),
+            any::<bool>(),
         )
+            .prop_map(
+                |(write_addr, auth, priority, resync, large_frames, ttl_countdown)| {
+                    ClientHelloWrite {
+                        write_addr,
+                        auth,
+                        priority,
+                        resync,
+                        large_frames,
+                        ttl_countdown,
+                    }
+                },
+            )
     }
 
     fn client_hello() -> impl Strategy<Value = ClientHello> {
         prop_oneof![
-            auth_read().prop_map(ClientHello::ReadOnly),
+            (auth_read(), any::<bool>()).prop_map(|(auth, large_frames)| {
+                ClientHello::ReadOnly(auth, large_frames)
+            }),
             client_hello_write().prop_map(ClientHello::WriteOnly)
         ]
     }
 
     fn server_hello_write() -> impl Strategy<Value = ServerHelloWrite> {
-        (any::<u64>(), any::<bool>(), any::<SocketAddr>(), auth_write()).prop_map(
-            |(ttl, ttl_expired, resolver_id, auth)| ServerHelloWrite {
-                ttl,
-                ttl_expired,
-                auth,
-                resolver_id,
-            },
+        (
+            any::<u64>(),
+            any::<bool>(),
+            any::<SocketAddr>(),
+            auth_write(),
+            any::<bool>(),
+            any::<bool>(),
+            any::<bool>(),
+            arcstr(),
+            any::<u64>(),
+            any::<u64>(),
         )
+            .prop_map(
+                |(
+                    ttl,
+                    ttl_expired,
+                    resolver_id,
+                    auth,
+                    resync,
+                    large_frames,
+                    ttl_countdown,
+                    version,
+                    capabilities,
+                    instance,
+                )| {
+                    ServerHelloWrite {
+                        ttl,
+                        ttl_expired,
+                        auth,
+                        resolver_id,
+                        resync,
+                        large_frames,
+                        ttl_countdown,
+                        version,
+                        capabilities,
+                        instance,
+                    }
+                },
+            )
+    }
+
+    fn server_hello_read() -> impl Strategy<Value = ServerHelloRead> {
+        (auth_read(), arcstr(), any::<u64>(), prop::option::of(arcstr()), any::<bool>())
+            .prop_map(|(auth, version, capabilities, denied, large_frames)| {
+                ServerHelloRead { auth, version, capabilities, denied, large_frames }
+            })
     }
 
     fn glob() -> impl Strategy<Value = Glob> {
@@ -176,6 +230,7 @@ mod resolver {
             path().prop_map(ToRead::Table),
             globset().prop_map(ToRead::ListMatching),
             path().prop_map(ToRead::GetChangeNr),
+            any::<SocketAddr>().prop_map(ToRead::ListByAddr),
         ]
     }
 
@@ -222,6 +277,14 @@ mod resolver {
         (publisher_id(), bytes()).prop_map(|(id, token)| PublisherRef { id, token })
     }
 
+    fn path_status() -> impl Strategy<Value = PathStatus> {
+        prop_oneof![
+            Just(PathStatus::Published),
+            Just(PathStatus::Unknown),
+            any::<u64>().prop_map(|last_seen| PathStatus::Expired { last_seen }),
+        ]
+    }
+
     fn resolved() -> impl Strategy<Value = Resolved> {
         let resolver = any::<SocketAddr>();
         let publishers =
@@ -229,13 +292,14 @@ mod resolver {
         let timestamp = any::<u64>();
         let flags = any::<u32>();
         let permissions = any::<u32>();
-        (resolver, publishers, timestamp, flags, permissions).prop_map(
-            |(resolver, publishers, timestamp, flags, permissions)| Resolved {
+        (resolver, publishers, timestamp, flags, permissions, path_status()).prop_map(
+            |(resolver, publishers, timestamp, flags, permissions, status)| Resolved {
                 resolver,
                 publishers,
                 timestamp,
                 flags,
                 permissions,
+                status,
             },
         )
     }
@@ -330,17 +394,36 @@ mod resolver {
                 .prop_map(|(path, flags)| ToWrite::PublishWithFlags(path, flags)),
             (path(), any::<u32>())
                 .prop_map(|(path, flags)| ToWrite::PublishDefaultWithFlags(path, flags)),
-            path().prop_map(ToWrite::UnpublishDefault)
+            path().prop_map(ToWrite::UnpublishDefault),
+            path().prop_map(ToWrite::UnpublishSubtree),
+            (path(), any::<SocketAddr>(), any::<SocketAddr>())
+                .prop_map(|(path, from, to)| ToWrite::Republish { path, from, to }),
+            Just(ToWrite::Status)
         ]
     }
 
+    fn writer_status() -> impl Strategy<Value = WriterStatus> {
+        (any::<u64>(), any::<u64>(), any::<u64>(), any::<bool>(), any::<bool>()).prop_map(
+            |(published, defaults, ttl, ttl_expired, evicted)| WriterStatus {
+                published,
+                defaults,
+                ttl,
+                ttl_expired,
+                evicted,
+            },
+        )
+    }
+
     fn from_write() -> impl Strategy<Value = FromWrite> {
         prop_oneof![
             Just(FromWrite::Published),
             Just(FromWrite::Unpublished),
             referral().prop_map(FromWrite::Referral),
             Just(FromWrite::Denied),
-            arcstr().prop_map(FromWrite::Error)
+            arcstr().prop_map(FromWrite::Error),
+            Just(FromWrite::Resync),
+            any::<u64>().prop_map(FromWrite::Ttl),
+            writer_status().prop_map(FromWrite::Status)
         ]
     }
 
@@ -400,6 +483,24 @@ mod resolver {
             check(a)
         }
 
+        #[test]
+        fn test_server_hello_read(a in server_hello_read()) {
+            check(a)
+        }
+
+        #[test]
+        fn test_server_hello_read_compat(a in server_hello_read()) {
+            let mut b = pack(&a).unwrap();
+            assert_eq!(AuthRead::decode(&mut b).unwrap(), a.auth);
+            let mut b = pack(&a.auth).unwrap();
+            let old = ServerHelloRead::decode(&mut b).unwrap();
+            assert_eq!(old.auth, a.auth);
+            assert!(old.version.is_empty());
+            assert_eq!(old.capabilities, 0);
+            assert_eq!(old.denied, None);
+            assert!(!old.large_frames);
+        }
+
         #[test]
         fn test_to_read(a in to_read()) {
             check(a)
@@ -435,7 +536,7 @@ mod resolver {
 mod publisher {
     use super::*;
     use crate::{
-        publisher::{From, Hello, Id, To, WriteId},
+        publisher::{From, Hello, Id, Metadata, To, WriteId, MIN_PROTOCOL_VERSION},
         value::{Abstract, Value},
     };
     use chrono::prelude::*;
@@ -455,12 +556,34 @@ mod publisher {
         let _: Result<Value> = Pack::decode(&mut &*b);
     }
 
+    /// The hello as sent by peers that predate version negotiation
+    #[derive(Debug, Clone, PartialEq, Eq, netidx_derive::Pack)]
+    enum LegacyHello {
+        Anonymous,
+        Krb5(#[pack(default)] Option<UserInfo>),
+        Local(#[pack(default)] Option<UserInfo>),
+        ResolverAuthenticate(SocketAddr),
+        Tls(#[pack(default)] Option<UserInfo>),
+    }
+
+    impl LegacyHello {
+        fn from_hello(h: &Hello) -> Self {
+            match h {
+                Hello::Anonymous(_) => LegacyHello::Anonymous,
+                Hello::Krb5(u, _) => LegacyHello::Krb5(u.clone()),
+                Hello::Local(u, _) => LegacyHello::Local(u.clone()),
+                Hello::ResolverAuthenticate(a) => LegacyHello::ResolverAuthenticate(*a),
+                Hello::Tls(u, _) => LegacyHello::Tls(u.clone()),
+            }
+        }
+    }
+
     fn hello() -> impl Strategy<Value = Hello> {
         prop_oneof![
-            Just(Hello::Anonymous),
-            option(user_info()).prop_map(Hello::Krb5),
-            option(user_info()).prop_map(Hello::Local),
-            option(user_info()).prop_map(Hello::Tls),
+            any::<u64>().prop_map(Hello::Anonymous),
+            (option(user_info()), any::<u64>()).prop_map(|(u, v)| Hello::Krb5(u, v)),
+            (option(user_info()), any::<u64>()).prop_map(|(u, v)| Hello::Local(u, v)),
+            (option(user_info()), any::<u64>()).prop_map(|(u, v)| Hello::Tls(u, v)),
             any::<SocketAddr>().prop_map(Hello::ResolverAuthenticate)
         ]
     }
@@ -477,6 +600,11 @@ mod publisher {
                 }
             ),
             any::<u64>().prop_map(|i| To::Unsubscribe(Id::mk(i))),
+            collection::vec(any::<u64>(), (0, 10)).prop_map(|ids| {
+                To::UnsubscribeMany(GPooled::orphan(
+                    ids.into_iter().map(Id::mk).collect(),
+                ))
+            }),
             (any::<u64>(), value(), any::<bool>(), any::<u64>())
                 .prop_map(|(i, v, r, w)| To::Write(Id::mk(i), r, v, WriteId::mk(w)))
         ]
@@ -588,16 +716,17 @@ mod publisher {
         collection::vec(value(), 0..12)
     }
 
+    fn metadata() -> impl Strategy<Value = Metadata> {
+        collection::hash_map(arcstr(), value_leaf(), 0..4).prop_map(std::sync::Arc::new)
+    }
+
     fn from() -> impl Strategy<Value = From> {
         prop_oneof![
             path().prop_map(|p| From::NoSuchValue(p)),
             path().prop_map(|p| From::Denied(p)),
             any::<u64>().prop_map(|i| From::Unsubscribed(Id::mk(i))),
-            (path(), any::<u64>(), value()).prop_map(|(p, i, v)| From::Subscribed(
-                p,
-                Id::mk(i),
-                v
-            )),
+            (path(), any::<u64>(), value(), prop::option::of(metadata()))
+                .prop_map(|(p, i, v, m)| From::Subscribed(p, Id::mk(i), v, m)),
             (any::<u64>(), value()).prop_map(|(i, v)| From::Update(Id::mk(i), v)),
             Just(From::Heartbeat),
             (any::<u64>(), value(), any::<u64>())
@@ -695,6 +824,17 @@ mod publisher {
             check(a)
         }
 
+        #[test]
+        fn test_hello_compat(a in hello()) {
+            let legacy = LegacyHello::from_hello(&a);
+            let mut b = pack(&a).unwrap();
+            assert_eq!(LegacyHello::decode(&mut b).unwrap(), legacy);
+            let mut b = pack(&legacy).unwrap();
+            let new = Hello::decode(&mut b).unwrap();
+            assert_eq!(LegacyHello::from_hello(&new), legacy);
+            assert_eq!(new.version(), MIN_PROTOCOL_VERSION);
+        }
+
         #[test]
         fn test_to(a in to()) {
             check(a)
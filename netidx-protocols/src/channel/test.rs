@@ -16,6 +16,7 @@ pub(crate) struct Ctx {
     pub(crate) publisher: Publisher,
     pub(crate) subscriber: Subscriber,
     pub(crate) base: Path,
+    pub(crate) cfg: ClientConfig,
 }
 
 impl Ctx {
@@ -38,9 +39,9 @@ impl Ctx {
         )
         .await
         .unwrap();
-        let subscriber = Subscriber::new(cfg, DesiredAuth::Anonymous).unwrap();
+        let subscriber = Subscriber::new(cfg.clone(), DesiredAuth::Anonymous).unwrap();
         let base = Path::from("/channel");
-        Self { _server, publisher, subscriber, base }
+        Self { _server, publisher, subscriber, base, cfg }
     }
 }
 
@@ -489,6 +489,9 @@ pub mod client {
 
         `call` may safely be called concurrently on multiple
         instances of `Proc` that call the same procedure
+
+        If the subscriber has a `write_timeout` and the procedure
+        doesn't reply in time the call fails with `WriteTimedOut`
         **/
         pub async fn call<I, K>(&self, args: I) -> Result<Value>
         where
@@ -539,12 +542,7 @@ pub mod client {
                 set
             };
             trace!("calling procedure");
-            let res = self
-                .0
-                .call
-                .write_with_recipt(args.into())
-                .await
-                .map_err(|_| anyhow!("call cancelled before a reply was received"))?;
+            let res = self.0.call.write_with_recipt(args.into()).await?;
             trace!("procedure called");
             Ok(res)
         }
@@ -557,6 +555,10 @@ mod test {
 
     use super::server::*;
     use super::*;
+    use netidx::{
+        resolver_client::DesiredAuth,
+        subscriber::{SubscriberBuilder, WriteTimedOut},
+    };
     use tokio::{runtime::Runtime, time};
 
     #[test]
@@ -600,4 +602,35 @@ mod test {
             })
             .unwrap()
     }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn call_timeout() {
+        let ctx = Ctx::new().await;
+        let proc_name = Path::from("/rpc/silent");
+        let (tx, mut rx) = mpsc::channel(10);
+        let _server_proc = define_rpc!(
+            &ctx.publisher,
+            proc_name.clone(),
+            "test rpc procedure that never replies",
+            |c, a| Some((c, a)),
+            Some(tx),
+            arg1: Value = Value::Null; "arg1 doc"
+        )
+        .unwrap();
+        // hold on to the calls so they are never answered
+        task::spawn(async move {
+            let mut calls = vec![];
+            while let Some(call) = rx.next().await {
+                calls.push(call)
+            }
+        });
+        let subscriber = SubscriberBuilder::new(ctx.cfg.clone())
+            .desired_auth(DesiredAuth::Anonymous)
+            .write_timeout(Some(Duration::from_millis(100)))
+            .build()
+            .unwrap();
+        let proc = client::Proc::new(&subscriber, proc_name).unwrap();
+        let e = call_rpc!(proc, arg1: "hello rpc").await.unwrap_err();
+        assert!(e.is::<WriteTimedOut>());
+    }
 }
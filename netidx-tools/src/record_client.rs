@@ -9,7 +9,9 @@ use netidx::{
     subscriber::{Event, Subscriber, Value},
 };
 use netidx_archive::{
-    logfile::{self, AlreadyCompressed, ArchiveReader, BatchItem, Cursor, Seek},
+    logfile::{
+        self, AlreadyCompressed, ArchiveReader, BatchItem, Cursor, Retention, Seek,
+    },
     recorder_client::{Client, OneshotReplyShard},
 };
 use netidx_tools_core::ClientParams;
@@ -72,6 +74,27 @@ pub(crate) enum Cmd {
         keep: bool,
         file: PathBuf,
     },
+    #[structopt(
+        name = "compact",
+        about = "merge several archive files into one indexed archive"
+    )]
+    Compact {
+        #[structopt(long = "keep", help = "don't delete the input files")]
+        keep: bool,
+        #[structopt(long = "output", help = "the archive file to write")]
+        output: PathBuf,
+        #[structopt(
+            long = "retain",
+            help = "keep paths matching GLOB for SECS seconds, GLOB=SECS, may be repeated"
+        )]
+        retain: Vec<String>,
+        #[structopt(
+            long = "downsample",
+            help = "keep only the last value of each path every SECS seconds"
+        )]
+        downsample: Option<i64>,
+        file: Vec<PathBuf>,
+    },
     #[structopt(name = "dump", about = "print the contents of an archive")]
     Dump {
         file: PathBuf,
@@ -255,6 +278,38 @@ async fn index(file: PathBuf, keep: bool) -> Result<()> {
     Ok(())
 }
 
+async fn compact(
+    file: Vec<PathBuf>,
+    output: PathBuf,
+    retain: Vec<String>,
+    downsample: Option<i64>,
+    keep: bool,
+) -> Result<()> {
+    if file.contains(&output) {
+        bail!("the output file must not also be an input file")
+    }
+    let mut retention = Retention::new();
+    for r in retain {
+        let (glob, secs) = r
+            .split_once('=')
+            .ok_or_else(|| anyhow!("invalid retention {r}, expected GLOB=SECS"))?;
+        let globs = GlobSet::new(true, [Glob::new(glob.into())?])?;
+        retention.add(globs, chrono::Duration::seconds(secs.parse::<i64>()?));
+    }
+    let downsample = downsample.map(chrono::Duration::seconds);
+    ArchiveReader::compact(&file, &output, &retention, downsample).await?;
+    if let Err(e) = verify(&output) {
+        std::fs::remove_file(&output)?;
+        return Err(e).context("verifying contents");
+    }
+    if !keep {
+        for f in file {
+            std::fs::remove_file(f)?
+        }
+    }
+    Ok(())
+}
+
 fn dump(file: PathBuf, metadata: bool, check_index: bool) -> Result<()> {
     let reader = ArchiveReader::open(file)?;
     reader.check_remap_rescan(false)?;
@@ -321,6 +376,9 @@ pub(super) async fn run(cmd: Cmd) -> Result<()> {
             }
             Ok(())
         }
+        Cmd::Compact { file, output, retain, downsample, keep } => {
+            compact(file, output, retain, downsample, keep).await
+        }
         Cmd::Dump { file, metadata, check_index } => dump(file, metadata, check_index),
         Cmd::Verify { file } => verify(file),
         Cmd::Compressed { file } => compressed(file),
@@ -4,7 +4,12 @@ use daemonize::Daemonize;
 use futures::future;
 #[cfg(unix)]
 use netidx::resolver_server::config::file;
-use netidx::resolver_server::{config::Config, Server};
+use netidx::{
+    config::Config as ClientConfig,
+    path::Path,
+    publisher::PublisherBuilder,
+    resolver_server::{config::Config, Server},
+};
 #[cfg(unix)]
 use std::fs::File;
 use structopt::StructOpt;
@@ -27,13 +32,35 @@ pub(crate) struct Params {
         default_value = "0"
     )]
     id: usize,
+    #[structopt(
+        long = "stats-base",
+        help = "publish stats about the server under this path"
+    )]
+    stats_base: Option<String>,
+    #[structopt(
+        long = "stats-config",
+        help = "the client config used to publish stats (default: the default client config)"
+    )]
+    stats_config: Option<String>,
 }
 
 #[tokio::main]
 async fn tokio_run(config: Config, params: Params) -> Result<()> {
-    let _server = Server::new(config, params.delay_reads, params.id)
+    let mut server = Server::new(config, params.delay_reads, params.id)
         .await
         .context("starting server")?;
+    if let Some(base) = params.stats_base {
+        let cfg = match params.stats_config {
+            Some(path) => ClientConfig::load(path),
+            None => ClientConfig::load_default(),
+        }
+        .context("loading the stats client config")?;
+        let publisher = PublisherBuilder::new(cfg)
+            .build()
+            .await
+            .context("creating the stats publisher")?;
+        server.publish_stats(publisher, Path::from(base));
+    }
     future::pending::<Result<()>>().await
 }
 
@@ -7,7 +7,7 @@ use netidx::{
     resolver_client::{DesiredAuth, ResolverRead},
     subscriber::{Subscriber, UpdatesFlags},
 };
-use std::time::Duration;
+use std::{cmp::max, time::Duration};
 use structopt::StructOpt;
 use tokio::time::{self, Instant};
 
@@ -17,11 +17,20 @@ pub(super) struct Params {
     base: String,
 }
 
+// the resident set size of the process in bytes, only available on
+// linux, and rough, it assumes 4k pages
+fn rss() -> Option<usize> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages = statm.split_whitespace().nth(1)?.parse::<usize>().ok()?;
+    Some(pages * 4096)
+}
+
 #[tokio::main]
 pub(super) async fn run(config: Config, auth: DesiredAuth, p: Params) -> Result<()> {
     env_logger::init();
     let r = ResolverRead::new(config.clone(), auth.clone());
     let table = r.table(Path::from(p.base)).await.context("load table")?;
+    let rss_start = rss();
     let subscriber = Subscriber::new(config, auth).context("create subscriber")?;
     let subs = {
         let mut subs = Vec::with_capacity(table.rows.len() * table.cols.len());
@@ -46,12 +55,20 @@ pub(super) async fn run(config: Config, auth: DesiredAuth, p: Params) -> Result<
     let mut batch_size: usize = 0;
     let mut nbatches: usize = 0;
     let mut interval = time::interval(Duration::from_secs(1));
+    let mut mem_reported = false;
     loop {
         select_biased! {
             now = interval.tick().fuse() => {
                 let elapsed = now - last_stat;
                 let since_start = now - start;
                 let stats = subscriber.durable_stats();
+                if !mem_reported && stats.alive == subs.len() {
+                    mem_reported = true;
+                    if let (Some(start), Some(now)) = (rss_start, rss()) {
+                        let per_sub = now.saturating_sub(start) / max(1, subs.len());
+                        println!("mem/sub: {per_sub} bytes");
+                    }
+                }
                 println!(
                     "s: {} p: {} !s: {} rx_i: {:.0} rx_a: {:.0} btch_a: {:.0}",
                     stats.alive,
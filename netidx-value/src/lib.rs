@@ -854,6 +854,60 @@ impl Value {
         Value::Error(Arc::new(Value::String(e.into())))
     }
 
+    /// construct a Value::Error tagged with `kind`. The payload is
+    /// the pair `[kind, msg]`, which can be taken apart again with
+    /// `error_kind` and `error_message`.
+    pub fn error_with_kind<K: Into<ArcStr>, S: Into<ArcStr>>(kind: K, msg: S) -> Value {
+        let payload = [Value::String(kind.into()), Value::String(msg.into())];
+        Value::Error(Arc::new(Value::Array(payload.into())))
+    }
+
+    /// return true if the value is an error
+    pub fn is_error(&self) -> bool {
+        matches!(self, Value::Error(_))
+    }
+
+    /// If the value is an error constructed by `error_with_kind`
+    /// return its kind, otherwise return None.
+    pub fn error_kind(&self) -> Option<&str> {
+        match self {
+            Value::Error(e) => match &**e {
+                Value::Array(a) => match &a[..] {
+                    [Value::String(kind), Value::String(_)] => Some(kind.as_str()),
+                    _ => None,
+                },
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// If the value is an error whose payload is a string, or was
+    /// constructed by `error_with_kind`, return the message.
+    /// Otherwise return None.
+    pub fn error_message(&self) -> Option<&str> {
+        match self {
+            Value::Error(e) => match &**e {
+                Value::String(s) => Some(s.as_str()),
+                Value::Array(a) => match &a[..] {
+                    [Value::String(_), Value::String(msg)] => Some(msg.as_str()),
+                    _ => None,
+                },
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// return `default` if the value is an error, otherwise return
+    /// the value unchanged.
+    pub fn on_error(self, default: Value) -> Value {
+        match self {
+            Value::Error(_) => default,
+            v => v,
+        }
+    }
+
     /// return true if the value is some kind of number, otherwise
     /// false.
     pub fn number(&self) -> bool {
@@ -221,6 +221,13 @@ impl PartialOrd for Value {
     }
 }
 
+/// `Value` has a total order, so it can be sorted and used as the key
+/// of a `BTreeMap`. Values of different types are ordered by their
+/// `Typ`, there is no numeric conversion, so `I64(1)` and `U8(0)`
+/// compare according to their types, not their contents. Values of
+/// the same type are ordered by their contents. For floats, NaN
+/// compares equal to NaN, and less than any other value of the same
+/// type.
 impl Ord for Value {
     fn cmp(&self, other: &Self) -> Ordering {
         self.partial_cmp(other).unwrap()
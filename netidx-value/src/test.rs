@@ -291,3 +291,60 @@ fn checked_methods() {
     // cross-type checked
     assert!(matches!(Value::I64(i64::MAX).checked_add(Value::I32(1)), Value::Error(_)));
 }
+
+#[test]
+fn error_kinds() {
+    let e = Value::error_with_kind("parse", "expected a number");
+    assert!(e.is_error());
+    assert_eq!(e.error_kind(), Some("parse"));
+    assert_eq!(e.error_message(), Some("expected a number"));
+    let e = Value::error_with_kind("io", "open /tmp/x: not found");
+    assert_eq!(e.error_kind(), Some("io"));
+    assert_eq!(e.error_message(), Some("open /tmp/x: not found"));
+    let e = Value::error("no kind here");
+    assert_eq!(e.error_kind(), None);
+    assert_eq!(e.error_message(), Some("no kind here"));
+    // a colon in a plain error is not a kind
+    let e = Value::error("parse: expected a number");
+    assert_eq!(e.error_kind(), None);
+    assert_eq!(e.error_message(), Some("parse: expected a number"));
+    let e = Value::Error(Arc::new(Value::I64(42)));
+    assert!(e.is_error());
+    assert_eq!(e.error_kind(), None);
+    assert_eq!(e.error_message(), None);
+    assert!(!Value::I64(42).is_error());
+    assert_eq!(Value::I64(42).error_message(), None);
+    assert_eq!(e.on_error(Value::I64(0)), Value::I64(0));
+    assert_eq!(Value::I64(42).on_error(Value::I64(0)), Value::I64(42));
+}
+
+#[test]
+fn total_order() {
+    use std::{cmp::Ordering, collections::BTreeSet};
+    // NaN is equal to itself and below every other float
+    assert_eq!(Value::F64(f64::NAN).cmp(&Value::F64(f64::NAN)), Ordering::Equal);
+    assert!(Value::F64(f64::NAN) < Value::F64(f64::NEG_INFINITY));
+    assert!(Value::F32(f32::NAN) < Value::F32(-1.));
+    // values of different types are ordered by type, not contents
+    let a = Value::I64(1);
+    let b = Value::U8(100);
+    assert_eq!(a.cmp(&b), Typ::get(&a).cmp(&Typ::get(&b)));
+    assert_eq!(a.cmp(&b), b.cmp(&a).reverse());
+    let mut vals = vec![
+        Value::String(literal!("b")),
+        Value::F64(f64::NAN),
+        Value::I64(3),
+        Value::Null,
+        Value::F64(0.5),
+        Value::String(literal!("a")),
+        Value::I64(-3),
+        Value::Bool(true),
+    ];
+    let set = vals.iter().cloned().collect::<BTreeSet<_>>();
+    assert_eq!(set.len(), vals.len());
+    vals.sort();
+    assert_eq!(set.into_iter().collect::<Vec<_>>(), vals);
+    for w in vals.windows(2) {
+        assert!(w[0] < w[1]);
+    }
+}
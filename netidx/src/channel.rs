@@ -21,7 +21,10 @@ use std::{
     fmt::Debug,
     mem,
     ops::{Deref, DerefMut},
-    sync::{Arc, LazyLock},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, LazyLock,
+    },
     time::Duration,
 };
 use tokio::{
@@ -33,6 +36,16 @@ const BUF: usize = 8388608;
 const LEN_MASK: u32 = 0x7FFFFFFF;
 const MAX_BATCH: usize = 0x3FFFFFFF;
 const ENC_MASK: u32 = 0x80000000;
+/// A header whose length bits are all set is followed by a u64
+/// length. It is used for a chunk holding a message larger than the
+/// channel's max batch, which can only be queued once large frames
+/// were negotiated, and for any chunk of LARGE bytes or more, so a 32
+/// bit length is always less than LARGE. Such a header is only
+/// accepted once large frames were negotiated.
+const LARGE: u32 = LEN_MASK;
+/// The default limit on the length of a 64 bit frame, see
+/// `Channel::set_max_large_batch`.
+pub(crate) const MAX_LARGE_BATCH: u64 = 0x100000000;
 
 #[derive(Debug)]
 pub struct K5CtxWrap<C: K5Ctx + Debug + Send + Sync + 'static>(Arc<Mutex<C>>);
@@ -65,11 +78,11 @@ pub(crate) async fn write_raw<T: Pack, S: AsyncWrite + Unpin>(
     msg: &T,
 ) -> Result<()> {
     let len = msg.encoded_len();
-    if len > MAX_BATCH as usize {
+    if len > MAX_BATCH {
         bail!("message length {} exceeds max size {}", len, MAX_BATCH)
     }
     let buf = utils::pack(msg)?;
-    let len = buf.remaining() as u32;
+    let len = u32::try_from(buf.remaining())?;
     let lenb = len.to_be_bytes();
     let mut buf = Buf::chain(&lenb[..], buf);
     while buf.has_remaining() {
@@ -108,14 +121,23 @@ async fn flush_buf<B: Buf, S: AsyncWrite + Send + 'static>(
     soc: &mut WriteHalf<S>,
     buf: B,
     encrypted: bool,
+    large: bool,
 ) -> Result<()> {
-    let len = if encrypted {
-        buf.remaining() as u32 | ENC_MASK
+    let remaining = buf.remaining();
+    let hdr = match u32::try_from(remaining) {
+        Ok(len) if !large && len < LARGE => len,
+        Ok(_) | Err(_) => LARGE,
+    };
+    let hdr = if encrypted { hdr | ENC_MASK } else { hdr };
+    let mut lenb = [0u8; 12];
+    BigEndian::write_u32(&mut lenb[0..4], hdr);
+    let hlen = if hdr & LEN_MASK == LARGE {
+        BigEndian::write_u64(&mut lenb[4..12], remaining as u64);
+        12
     } else {
-        buf.remaining() as u32
+        4
     };
-    let lenb = len.to_be_bytes();
-    let mut buf = Buf::chain(&lenb[..], buf);
+    let mut buf = Buf::chain(&lenb[0..hlen], buf);
     while buf.has_remaining() {
         let i = buf.remaining();
         soc.write_buf(&mut buf).await?;
@@ -125,23 +147,34 @@ async fn flush_buf<B: Buf, S: AsyncWrite + Send + 'static>(
     Ok(())
 }
 
+enum ToFlush {
+    // the chunk, and whether it must be sent with a 64 bit length
+    Data(BytesMut, bool),
+    // flush everything before this, then shut down the socket
+    Shutdown(oneshot::Sender<Result<()>>),
+}
+
 fn flush_task<
     C: K5Ctx + Debug + Send + Sync + 'static,
     S: AsyncWrite + Send + 'static,
 >(
     ctx: Option<K5CtxWrap<C>>,
     mut soc: WriteHalf<S>,
-) -> Sender<BytesMut> {
-    let (tx, mut rx): (Sender<BytesMut>, Receiver<BytesMut>) = mpsc::channel(3);
+) -> Sender<ToFlush> {
+    let (tx, mut rx): (Sender<ToFlush>, Receiver<ToFlush>) = mpsc::channel(3);
     task::spawn(async move {
         let res = loop {
             match rx.next().await {
                 None => break Ok(()),
-                Some(data) => match ctx {
-                    None => try_cf!(flush_buf(&mut soc, data, false).await),
+                Some(ToFlush::Shutdown(done)) => {
+                    let _ = done.send(soc.shutdown().await.map_err(Error::from));
+                    break Ok(());
+                }
+                Some(ToFlush::Data(data, large)) => match ctx {
+                    None => try_cf!(flush_buf(&mut soc, data, false, large).await),
                     Some(ref ctx) => {
                         let msg = try_cf!(ctx.lock().wrap_iov(true, data));
-                        try_cf!(flush_buf(&mut soc, msg, true).await);
+                        try_cf!(flush_buf(&mut soc, msg, true, large).await);
                     }
                 },
             }
@@ -152,9 +185,12 @@ fn flush_task<
 }
 
 pub(crate) struct WriteChannel {
-    to_flush: Sender<BytesMut>,
+    to_flush: Sender<ToFlush>,
     buf: BytesMut,
     boundries: Vec<usize>,
+    large: bool,
+    max_batch: usize,
+    max_large_batch: u64,
 }
 
 impl WriteChannel {
@@ -169,6 +205,55 @@ impl WriteChannel {
             to_flush: flush_task(ctx, socket),
             buf: BytesMut::with_capacity(BUF),
             boundries: Vec::new(),
+            large: false,
+            max_batch: MAX_BATCH,
+            max_large_batch: MAX_LARGE_BATCH,
+        }
+    }
+
+    /// Allow messages larger than the 32 bit frame limit to be
+    /// sent. Such messages are sent alone in a frame with a 64 bit
+    /// length. Only enable this if the other side has said it
+    /// understands large frames, smaller messages are framed the
+    /// same way regardless.
+    pub(crate) fn set_large_frames(&mut self, large: bool) {
+        self.large = large;
+    }
+
+    /// Set the largest message that may be sent in a large
+    /// frame. Default 4 GiB.
+    pub(crate) fn set_max_large_batch(&mut self, max: u64) {
+        self.max_large_batch = max;
+    }
+
+    /// Lower the largest message that fits in a 32 bit frame, so
+    /// large frames can be tested without gigabyte messages.
+    #[cfg(test)]
+    pub(crate) fn set_max_batch(&mut self, max_batch: usize) {
+        self.max_batch = max_batch;
+    }
+
+    fn queue_send_large<T: Pack>(&mut self, msg: &T, len: usize) -> Result<()> {
+        if len as u64 > self.max_large_batch {
+            bail!("message length {} exceeds max size {}", len, self.max_large_batch)
+        }
+        self.buf.reserve(len);
+        let buf_len = self.buf.remaining();
+        let boundries_len = self.boundries.len();
+        let prev_len: usize = self.boundries.iter().sum();
+        if buf_len > prev_len {
+            self.boundries.push(buf_len - prev_len);
+        }
+        match msg.encode(&mut self.buf) {
+            Ok(()) => {
+                self.boundries.push(self.buf.remaining() - buf_len);
+                Ok(())
+            }
+            Err(e) => {
+                self.buf.resize(buf_len, 0x0);
+                self.boundries.truncate(boundries_len);
+                Err(Error::from(e))
+            }
         }
     }
 
@@ -176,16 +261,22 @@ impl WriteChannel {
     /// writes it to the buffer, you must call flush actually send it.
     pub(crate) fn queue_send<T: Pack>(&mut self, msg: &T) -> Result<()> {
         let len = msg.encoded_len();
-        if len > MAX_BATCH as usize {
-            return Err(anyhow!("message length {} exceeds max size {}", len, MAX_BATCH));
+        if len > self.max_batch && self.large {
+            return self.queue_send_large(msg, len);
+        }
+        if len > self.max_batch {
+            bail!("message length {} exceeds max size {}", len, self.max_batch)
         }
         if self.buf.remaining_mut() < len {
             self.buf.reserve(self.buf.capacity());
         }
         let buf_len = self.buf.remaining();
         let mut pushed = false;
-        if (buf_len - self.boundries.last().copied().unwrap_or(0)) + len > MAX_BATCH {
-            let prev_len: usize = self.boundries.iter().sum();
+        // boundries holds the length of each chunk, so the chunk
+        // being filled starts at their sum. Never end an empty chunk,
+        // it would be sent as an empty frame.
+        let prev_len: usize = self.boundries.iter().sum();
+        if buf_len > prev_len && (buf_len - prev_len) + len > self.max_batch {
             self.boundries.push(buf_len - prev_len);
             pushed = true;
         }
@@ -238,14 +329,20 @@ impl WriteChannel {
         while self.buf.has_remaining() {
             let boundry = self.boundries.first().copied().unwrap_or(self.buf.len());
             let chunk = self.buf.split_to(boundry);
-            match self.to_flush.try_send(chunk) {
+            // only a message queued by queue_send_large makes a chunk
+            // this big, and it is always alone in its chunk
+            let large = chunk.len() > self.max_batch;
+            match self.to_flush.try_send(ToFlush::Data(chunk, large)) {
                 Ok(()) => {
                     if self.boundries.len() > 0 {
                         self.boundries.remove(0);
                     }
                 }
                 Err(e) if e.is_full() => {
-                    let mut chunk = e.into_inner();
+                    let mut chunk = match e.into_inner() {
+                        ToFlush::Data(chunk, _) => chunk,
+                        ToFlush::Shutdown(_) => unreachable!(),
+                    };
                     chunk.unsplit(self.buf.split());
                     self.buf = chunk;
                     return Ok(false);
@@ -262,6 +359,20 @@ impl WriteChannel {
     pub(crate) async fn flush_timeout(&mut self, timeout: Duration) -> Result<()> {
         Ok(time::timeout(timeout, self.flush()).await??)
     }
+
+    /// Flush all outgoing messages, wait until they have been
+    /// written to the socket, and then shut down the write side of
+    /// the socket. For tcp this sends a FIN, so the other side reads
+    /// everything that was queued and then EOF.
+    pub(crate) async fn shutdown(mut self) -> Result<()> {
+        self.flush().await?;
+        let (tx, rx) = oneshot::channel();
+        self.to_flush
+            .send(ToFlush::Shutdown(tx))
+            .await
+            .map_err(|_| anyhow!("can't shutdown a closed connection"))?;
+        rx.await.map_err(|_| anyhow!("connection closed during shutdown"))?
+    }
 }
 
 struct PBuf {
@@ -380,6 +491,7 @@ fn read_task<C: K5Ctx + Debug + Send + Sync + 'static, S: AsyncRead + Send + 'st
     stop: oneshot::Receiver<()>,
     mut soc: ReadHalf<S>,
     ctx: Option<K5CtxWrap<C>>,
+    max_large: Arc<AtomicU64>,
 ) -> Receiver<PBuf> {
     trace!("starting read task");
     let (mut tx, rx) = mpsc::channel(3);
@@ -388,18 +500,36 @@ fn read_task<C: K5Ctx + Debug + Send + Sync + 'static, S: AsyncRead + Send + 'st
         let mut buf = PBuf::default();
         let res: Result<()> = 'main: loop {
             while buf.remaining() >= mem::size_of::<u32>() {
-                let (encrypted, len) = {
+                let (encrypted, hlen, len) = {
                     let hdr = BigEndian::read_u32(&*buf);
-                    if hdr > LEN_MASK {
-                        (true, (hdr & LEN_MASK) as usize)
+                    let encrypted = hdr > LEN_MASK;
+                    if hdr & LEN_MASK != LARGE {
+                        (encrypted, mem::size_of::<u32>(), (hdr & LEN_MASK) as usize)
+                    } else if buf.remaining() < mem::size_of::<u32>() + 8 {
+                        break;
                     } else {
-                        (false, hdr as usize)
+                        // zero until large frames are negotiated
+                        let max = max_large.load(Ordering::Relaxed);
+                        if max == 0 {
+                            break 'main Err(anyhow!("large frames were not negotiated"));
+                        }
+                        let len = BigEndian::read_u64(&buf[4..12]);
+                        match usize::try_from(len) {
+                            Ok(len) if len as u64 <= max => {
+                                (encrypted, mem::size_of::<u32>() + 8, len)
+                            }
+                            Ok(_) | Err(_) => {
+                                break 'main Err(anyhow!(
+                                    "frame length {len} is too large"
+                                ))
+                            }
+                        }
                     }
                 };
-                if buf.remaining() - mem::size_of::<u32>() < len {
+                if buf.remaining() - hlen < len {
                     trace!(
                         "read_task: {} is less than batch len {}, reading more",
-                        buf.remaining() - mem::size_of::<u32>(),
+                        buf.remaining() - hlen,
                         len
                     );
                     break;
@@ -407,14 +537,18 @@ fn read_task<C: K5Ctx + Debug + Send + Sync + 'static, S: AsyncRead + Send + 'st
                     if ctx.is_some() {
                         break 'main Err(anyhow!("encryption is required"));
                     }
-                    buf.advance(mem::size_of::<u32>());
-                    try_cf!(break, 'main, tx.send(buf.split_to(len)).await);
+                    buf.advance(hlen);
+                    // an empty frame carries no messages, and an empty
+                    // chunk would fail to decode on the other side
+                    if len > 0 {
+                        try_cf!(break, 'main, tx.send(buf.split_to(len)).await);
+                    }
                 } else {
                     let ctx = match ctx {
                         Some(ref ctx) => ctx,
                         None => break 'main Err(anyhow!("encryption is not supported")),
                     };
-                    buf.advance(mem::size_of::<u32>());
+                    buf.advance(hlen);
                     let encrypted_chunk = buf.split_to(len);
                     let decrypted =
                         try_cf!(break, 'main, ctx.lock().unwrap(&*encrypted_chunk));
@@ -442,10 +576,19 @@ fn read_task<C: K5Ctx + Debug + Send + Sync + 'static, S: AsyncRead + Send + 'st
     rx
 }
 
+type StartRead = Box<dyn FnOnce() -> Receiver<PBuf> + Send + Sync + 'static>;
+
 pub(crate) struct ReadChannel {
     buf: PBuf,
+    received: u64,
+    large: bool,
+    max_large_batch: u64,
+    max_large: Arc<AtomicU64>,
     _stop: oneshot::Sender<()>,
-    incoming: stream::Fuse<Receiver<PBuf>>,
+    // the read task starts on the first receive, so settings made
+    // before then, e.g. large frames, apply to every frame
+    start: Option<StartRead>,
+    incoming: Option<stream::Fuse<Receiver<PBuf>>>,
 }
 
 impl ReadChannel {
@@ -457,16 +600,55 @@ impl ReadChannel {
         socket: ReadHalf<S>,
     ) -> ReadChannel {
         let (stop_tx, stop_rx) = oneshot::channel();
+        let max_large = Arc::new(AtomicU64::new(0));
+        let start: StartRead = Box::new({
+            let max_large = Arc::clone(&max_large);
+            move || read_task(stop_rx, socket, k5ctx, max_large)
+        });
         ReadChannel {
             buf: PBuf::default(),
+            received: 0,
+            large: false,
+            max_large_batch: MAX_LARGE_BATCH,
+            max_large,
             _stop: stop_tx,
-            incoming: read_task(stop_rx, socket, k5ctx).fuse(),
+            start: Some(start),
+            incoming: None,
         }
     }
 
+    fn update_max_large(&self) {
+        let max = if self.large { self.max_large_batch } else { 0 };
+        self.max_large.store(max, Ordering::Relaxed)
+    }
+
+    /// Accept frames with a 64 bit length, up to the max large
+    /// batch. Only enable this if large frames were negotiated with
+    /// the other side, and before it could send one, otherwise a peer
+    /// could make us buffer gigabytes.
+    pub(crate) fn set_large_frames(&mut self, large: bool) {
+        self.large = large;
+        self.update_max_large()
+    }
+
+    /// Set the largest frame accepted when large frames are
+    /// enabled. Default 4 GiB.
+    pub(crate) fn set_max_large_batch(&mut self, max: u64) {
+        self.max_large_batch = max;
+        self.update_max_large()
+    }
+
     /// Read a load of bytes from the socket into the read buffer
     pub(crate) async fn fill_buffer(&mut self) -> Result<()> {
-        if let Some(chunk) = self.incoming.next().await {
+        if let Some(start) = self.start.take() {
+            self.incoming = Some(start().fuse());
+        }
+        let incoming = match &mut self.incoming {
+            Some(incoming) => incoming,
+            None => bail!("the read task is not running"),
+        };
+        if let Some(chunk) = incoming.next().await {
+            self.received += chunk.remaining() as u64;
             self.buf = chunk;
             Ok(())
         } else {
@@ -474,6 +656,11 @@ impl ReadChannel {
         }
     }
 
+    /// Return the total number of payload bytes received so far
+    pub(crate) fn bytes_received(&self) -> u64 {
+        self.received
+    }
+
     pub(crate) async fn receive<T: Pack + Debug>(&mut self) -> Result<T> {
         if !self.buf.has_remaining() {
             self.fill_buffer().await?;
@@ -545,6 +732,25 @@ impl Channel {
         (self.read, self.write)
     }
 
+    /// Send and accept large frames, see
+    /// `ReadChannel::set_large_frames`.
+    pub(crate) fn set_large_frames(&mut self, large: bool) {
+        self.write.set_large_frames(large);
+        self.read.set_large_frames(large)
+    }
+
+    /// Set the largest message sent or accepted in a large
+    /// frame. Default 4 GiB.
+    pub(crate) fn set_max_large_batch(&mut self, max: u64) {
+        self.write.set_max_large_batch(max);
+        self.read.set_max_large_batch(max)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn set_max_batch(&mut self, max_batch: usize) {
+        self.write.set_max_batch(max_batch)
+    }
+
     pub(crate) fn queue_send<T: Pack>(&mut self, msg: &T) -> Result<(), Error> {
         self.write.queue_send(msg)
     }
@@ -575,6 +781,14 @@ impl Channel {
         self.write.flush_timeout(timeout).await
     }
 
+    /// Flush all outgoing messages and then shut down the write side
+    /// of the socket, see `WriteChannel::shutdown`. Unlike dropping
+    /// the channel this waits until everything queued has been
+    /// written.
+    pub(crate) async fn shutdown(self) -> Result<(), Error> {
+        self.write.shutdown().await
+    }
+
     pub(crate) async fn receive<T: Pack + Debug>(&mut self) -> Result<T, Error> {
         self.read.receive().await
     }
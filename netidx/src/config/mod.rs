@@ -1,5 +1,6 @@
 //! Configuration file loading and management.
 use crate::{
+    channel,
     path::Path,
     protocol::resolver::{Auth, Referral},
     publisher,
@@ -11,8 +12,9 @@ use poolshark::global::GPooled;
 use serde_json::from_str;
 use std::{
     cmp::min, collections::BTreeMap, convert::AsRef, convert::Into, fs::read_to_string,
-    net::SocketAddr, path::Path as FsPath, str,
+    net::SocketAddr, path::Path as FsPath, str, time::Duration,
 };
+use tokio::{net::TcpStream, time};
 
 mod local_only;
 
@@ -399,6 +401,47 @@ impl Config {
         Config::parse(&read_to_string(file)?)
     }
 
+    /// Load the config from the specified file, and then check that
+    /// at least one of the resolver servers it names is reachable
+    /// using `verify`.
+    ///
+    /// Use this at startup to catch mistakes in the config early,
+    /// `load` does not touch the network and is suitable for offline
+    /// use.
+    pub async fn load_and_verify<P: AsRef<FsPath>>(
+        file: P,
+        timeout: Duration,
+    ) -> Result<Config> {
+        let cfg = Config::load(file)?;
+        cfg.verify(timeout).await?;
+        Ok(cfg)
+    }
+
+    /// Check that at least one of the resolver servers is reachable.
+    ///
+    /// Each address is tried in turn, and is considered reachable if
+    /// it accepts a connection and agrees on the protocol version
+    /// within `timeout`. Authentication is not attempted.
+    pub async fn verify(&self, timeout: Duration) -> Result<()> {
+        async fn hello(addr: SocketAddr) -> Result<()> {
+            let mut con = TcpStream::connect(addr).await?;
+            channel::write_raw(&mut con, &3u64).await?;
+            if channel::read_raw::<u64, _, 1024>(&mut con).await? != 3 {
+                bail!("incompatible protocol version")
+            }
+            Ok(())
+        }
+        let mut errors = vec![];
+        for (addr, _) in &self.addrs {
+            match time::timeout(timeout, hello(*addr)).await {
+                Ok(Ok(())) => return Ok(()),
+                Ok(Err(e)) => errors.push(format!("{addr}: {e}")),
+                Err(_) => errors.push(format!("{addr}: timed out")),
+            }
+        }
+        bail!("no resolver server responded, {}", errors.join(", "))
+    }
+
     /// Transform the config into a resolver Referral with a ttl that
     /// will never expire
     pub fn to_referral(self) -> Referral {
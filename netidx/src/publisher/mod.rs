@@ -1,11 +1,12 @@
 //! Publish values to subscribers.
 mod server;
 pub use crate::protocol::{
-    publisher::Id,
+    publisher::{Id, Metadata},
     value::{FromValue, Typ, Value},
 };
 pub use crate::resolver_client::DesiredAuth;
 use crate::{
+    channel::MAX_LARGE_BATCH,
     config::Config,
     path::Path,
     protocol::{publisher, resolver::UserInfo},
@@ -851,6 +852,7 @@ struct Client {
     msg_queue: MsgQ,
     subscribed: IntMap<Id, Permissions>,
     user: Option<UserInfo>,
+    version: u64,
 }
 
 /// Information about a published value including its current value and subscribers.
@@ -860,6 +862,7 @@ pub struct Published {
     subscribed: Subscribed,
     path: Path,
     aliases: Option<Box<AHashSet<Path>>>,
+    metadata: Option<Metadata>,
 }
 
 impl Published {
@@ -871,6 +874,10 @@ impl Published {
         &self.path
     }
 
+    pub fn metadata(&self) -> Option<&Metadata> {
+        self.metadata.as_ref()
+    }
+
     pub fn subscribed(&self) -> &IntSet<ClId> {
         &self.subscribed
     }
@@ -1060,6 +1067,7 @@ pub struct PublisherBuilder {
     priority: PublisherPriority,
     max_clients: usize,
     slack: usize,
+    max_large_frame: u64,
 }
 
 impl PublisherBuilder {
@@ -1071,6 +1079,7 @@ impl PublisherBuilder {
             priority: PublisherPriority::Normal,
             max_clients: 768,
             slack: 3,
+            max_large_frame: MAX_LARGE_BATCH,
         }
     }
 
@@ -1079,13 +1088,14 @@ impl PublisherBuilder {
         let desired_auth = self.desired_auth.take().unwrap_or_else(|| cfg.default_auth());
         let bind_cfg =
             self.bind_cfg.take().unwrap_or_else(|| cfg.default_bind_config.clone());
-        Publisher::new(
+        Publisher::new_internal(
             cfg,
             desired_auth,
             bind_cfg,
             self.priority,
             self.max_clients,
             self.slack,
+            self.max_large_frame,
         )
         .await
     }
@@ -1119,6 +1129,14 @@ impl PublisherBuilder {
         self
     }
 
+    /// The largest message, in bytes, a subscriber that negotiated
+    /// large frames may send in one frame. Messages up to 1 GiB are
+    /// always accepted. default 4 GiB.
+    pub fn max_large_frame(&mut self, max: u64) -> &mut Self {
+        self.max_large_frame = max;
+        self
+    }
+
     /// Set the priority of this publisher.
     ///
     /// There are three priority levels,
@@ -1173,6 +1191,27 @@ impl Publisher {
         priority: PublisherPriority,
         max_clients: usize,
         slack: usize,
+    ) -> Result<Publisher> {
+        Self::new_internal(
+            resolver,
+            desired_auth,
+            bind_cfg,
+            priority,
+            max_clients,
+            slack,
+            MAX_LARGE_BATCH,
+        )
+        .await
+    }
+
+    async fn new_internal(
+        resolver: Config,
+        desired_auth: DesiredAuth,
+        bind_cfg: BindCfg,
+        priority: PublisherPriority,
+        max_clients: usize,
+        slack: usize,
+        max_large_frame: u64,
     ) -> Result<Publisher> {
         let (public, private) = bind_cfg.select()?;
         utils::check_addr(public, &resolver.addrs)?;
@@ -1252,6 +1291,7 @@ impl Publisher {
                     tls_ctx,
                     max_clients,
                     slack,
+                    max_large_frame,
                 )
                 .await;
                 info!("accept loop shutdown");
@@ -1361,7 +1401,13 @@ impl Publisher {
             .clone();
         pb.by_id.insert(
             id,
-            Published { current: init, subscribed, path: path.clone(), aliases: None },
+            Published {
+                current: init,
+                subscribed,
+                path: path.clone(),
+                aliases: None,
+                metadata: None,
+            },
         );
         if destroy_on_idle {
             pb.destroy_on_idle.insert(id);
@@ -1656,6 +1702,21 @@ impl Publisher {
         self.0.lock().by_id.get(&id).map(|p| p.current.clone())
     }
 
+    /// Attach metadata, such as units or a description, to a
+    /// published `Val`. Metadata is sent to subscribers along with
+    /// the first value when they subscribe, so it is only seen by
+    /// subscriptions made after it is set. Pass `None` to remove it.
+    pub fn set_metadata(&self, id: Id, metadata: Option<Metadata>) {
+        if let Some(p) = self.0.lock().by_id.get_mut(&id) {
+            p.metadata = metadata;
+        }
+    }
+
+    /// Get the metadata attached to a published `Val`, if any.
+    pub fn metadata(&self, id: &Id) -> Option<Metadata> {
+        self.0.lock().by_id.get(&id).and_then(|p| p.metadata.clone())
+    }
+
     /// Get a list of clients subscribed to a published `Val`.
     pub fn subscribed(&self, id: &Id) -> Vec<ClId> {
         self.0
@@ -1696,6 +1757,12 @@ impl Publisher {
         self.0.lock().clients.get(client).and_then(|c| c.user.clone())
     }
 
+    /// Return the protocol version negotiated with the specified
+    /// client, or None if the client is not connected.
+    pub fn protocol_version(&self, client: &ClId) -> Option<u64> {
+        self.0.lock().clients.get(client).map(|c| c.version)
+    }
+
     /// Get the number of clients subscribed to a published `Val`.
     pub fn subscribed_len(&self, id: &Id) -> usize {
         self.0.lock().by_id.get(&id).map(|p| p.subscribed.len()).unwrap_or(0)
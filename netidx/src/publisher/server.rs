@@ -8,7 +8,7 @@ use crate::{
     path::Path,
     protocol::{
         self,
-        publisher::{self, Id, WriteId},
+        publisher::{self, Id, WriteId, MIN_PROTOCOL_VERSION},
         value::Value,
     },
     resolver_client::DesiredAuth,
@@ -37,6 +37,7 @@ use poolshark::global::GPooled;
 use protocol::resolver::{AuthChallenge, HashMethod, UserInfo};
 use std::{
     boxed::Box,
+    cmp::min,
     collections::{hash_map::Entry, BTreeSet, Bound, HashMap, HashSet},
     convert::From,
     default::Default,
@@ -123,7 +124,12 @@ fn subscribe(
                         e.insert(Arc::clone(&ut.subscribed));
                     }
                 }
-                let m = publisher::From::Subscribed(path, id, ut.current.clone());
+                let m = publisher::From::Subscribed(
+                    path,
+                    id,
+                    ut.current.clone(),
+                    ut.metadata.clone(),
+                );
                 con.queue_send(&m)?;
                 if let Some(waiters) = t.wait_clients.remove(&id) {
                     for tx in waiters {
@@ -300,6 +306,8 @@ struct ClientCtx {
     gc_on_write: Vec<ChanWrap<GPooled<Vec<WriteRequest>>>>,
     msg_sent: bool,
     tls_ctx: Option<tls::CachedAcceptor>,
+    version: u64,
+    max_large_frame: u64,
 }
 
 impl ClientCtx {
@@ -309,6 +317,7 @@ impl ClientCtx {
         publisher: PublisherWeak,
         desired_auth: DesiredAuth,
         tls_ctx: Option<tls::CachedAcceptor>,
+        max_large_frame: u64,
     ) -> ClientCtx {
         let mut deferred_subs: DeferredSubs =
             Batched::new(SelectAll::new(), MAX_DEFERRED);
@@ -329,12 +338,17 @@ impl ClientCtx {
             gc_on_write: Vec::new(),
             msg_sent: false,
             tls_ctx,
+            version: MIN_PROTOCOL_VERSION,
+            max_large_frame,
         }
     }
 
     fn client_arrived(&mut self) {
         if let Some(publisher) = self.publisher.upgrade() {
             let mut pb = publisher.0.lock();
+            if let Some(ci) = pb.clients.get_mut(&self.client) {
+                ci.version = self.version;
+            }
             for tx in pb.wait_any_client.drain(..) {
                 let _ = tx.send(());
             }
@@ -364,31 +378,34 @@ impl ClientCtx {
 
     // CR estokes: Implement periodic rekeying to improve security
     async fn hello(&mut self, mut con: TcpStream) -> Result<Channel> {
-        use protocol::publisher::Hello;
+        use protocol::publisher::{Hello, PROTOCOL_VERSION, WIRE_VERSION};
         static NO: &str = "authentication mechanism not supported";
         debug!("hello_client");
-        channel::write_raw(&mut con, &3u64).await?;
-        if channel::read_raw::<u64, _, 1024>(&mut con).await? != 3 {
+        channel::write_raw(&mut con, &WIRE_VERSION).await?;
+        if channel::read_raw::<u64, _, 1024>(&mut con).await? != WIRE_VERSION {
             bail!("incompatible protocol version")
         }
         let hello: Hello = channel::read_raw::<_, _, 8124>(&mut con).await?;
         debug!("hello_client received {:?}", hello);
+        self.version = min(hello.version(), PROTOCOL_VERSION);
         match hello {
-            Hello::Anonymous => {
-                channel::write_raw(&mut con, &Hello::Anonymous).await?;
+            Hello::Anonymous(_) => {
+                channel::write_raw(&mut con, &Hello::Anonymous(PROTOCOL_VERSION)).await?;
                 self.client_arrived();
                 Ok(Channel::new::<ServerCtx, TcpStream>(None, con))
             }
-            Hello::Local(uifo) => {
-                channel::write_raw(&mut con, &Hello::Local(None)).await?;
+            Hello::Local(uifo, _) => {
+                channel::write_raw(&mut con, &Hello::Local(None, PROTOCOL_VERSION))
+                    .await?;
                 self.set_user(uifo);
                 self.client_arrived();
                 Ok(Channel::new::<ServerCtx, TcpStream>(None, con))
             }
-            Hello::Krb5(uifo) => match &self.desired_auth {
+            Hello::Krb5(uifo, _) => match &self.desired_auth {
                 DesiredAuth::Anonymous | DesiredAuth::Tls { .. } => bail!(NO),
                 DesiredAuth::Local => {
-                    channel::write_raw(&mut con, &Hello::Local(None)).await?;
+                    channel::write_raw(&mut con, &Hello::Local(None, PROTOCOL_VERSION))
+                        .await?;
                     self.set_user(uifo);
                     self.client_arrived();
                     Ok(Channel::new::<ServerCtx, TcpStream>(None, con))
@@ -398,15 +415,16 @@ impl ClientCtx {
                     let ctx = krb5_authentication(HELLO_TIMEOUT, spn, &mut con).await?;
                     self.set_user(uifo);
                     let mut con = Channel::new(Some(K5CtxWrap::new(ctx)), con);
-                    con.send_one(&Hello::Krb5(None)).await?;
+                    con.send_one(&Hello::Krb5(None, PROTOCOL_VERSION)).await?;
                     self.client_arrived();
                     Ok(con)
                 }
             },
-            Hello::Tls(uifo) => match &self.desired_auth {
+            Hello::Tls(uifo, _) => match &self.desired_auth {
                 DesiredAuth::Anonymous | DesiredAuth::Krb5 { .. } => bail!(NO),
                 DesiredAuth::Local => {
-                    channel::write_raw(&mut con, &Hello::Local(None)).await?;
+                    channel::write_raw(&mut con, &Hello::Local(None, PROTOCOL_VERSION))
+                        .await?;
                     self.set_user(uifo);
                     self.client_arrived();
                     Ok(Channel::new::<ServerCtx, TcpStream>(None, con))
@@ -426,7 +444,7 @@ impl ClientCtx {
                         ServerCtx,
                         tokio_rustls::server::TlsStream<TcpStream>,
                     >(None, tls);
-                    con.send_one(&Hello::Tls(None)).await?;
+                    con.send_one(&Hello::Tls(None, PROTOCOL_VERSION)).await?;
                     self.client_arrived();
                     Ok(con)
                 }
@@ -563,6 +581,16 @@ impl ClientCtx {
                     unsubscribe(&mut *pb, self.client, id);
                     con.queue_send(&From::Unsubscribed(id))?;
                 }
+                UnsubscribeMany(_) if self.version < 4 => {
+                    bail!("UnsubscribeMany requires protocol version 4")
+                }
+                UnsubscribeMany(mut ids) => {
+                    gc = true;
+                    for id in ids.drain(..) {
+                        unsubscribe(&mut *pb, self.client, id);
+                        con.queue_send(&From::Unsubscribed(id))?;
+                    }
+                }
             }
         }
         if gc {
@@ -669,8 +697,10 @@ impl ClientCtx {
             }
         }
         let mut hb = time::interval(HB);
-        let (mut read_con, mut write_con) =
-            time::timeout(HELLO_TIMEOUT, self.hello(con)).await??.split();
+        let mut con = time::timeout(HELLO_TIMEOUT, self.hello(con)).await??;
+        con.set_max_large_batch(self.max_large_frame);
+        con.set_large_frames(self.version >= 5);
+        let (mut read_con, mut write_con) = con.split();
         loop {
             select_biased! {
                 r = flush(&mut write_con).fuse() => {
@@ -722,6 +752,7 @@ pub(super) async fn start(
     tls_ctx: Option<tls::CachedAcceptor>,
     max_clients: usize,
     slack: usize,
+    max_large_frame: u64,
 ) {
     let mut stop = stop.fuse();
     let stopped = loop {
@@ -746,6 +777,7 @@ pub(super) async fn start(
                             msg_queue: tx,
                             subscribed: HashMap::default(),
                             user: None,
+                            version: MIN_PROTOCOL_VERSION,
                         });
                         let desired_auth = desired_auth.clone();
                         let tls_ctx = tls_ctx.clone();
@@ -756,6 +788,7 @@ pub(super) async fn start(
                                 t_weak.clone(),
                                 desired_auth,
                                 tls_ctx,
+                                max_large_frame,
                             );
                             let r = ctx.run(s, rx).await;
                             info!("accept_loop client shutdown {:?}", r);
@@ -12,7 +12,14 @@ use futures::channel::oneshot;
 use netidx_core::pack::BoundedBytes;
 use nohash::IntMap;
 use poolshark::global::{GPooled, Pool};
-use std::{fmt::Debug, str::FromStr, sync::LazyLock, time::Duration};
+use std::{
+    error,
+    fmt::{self, Debug},
+    net::SocketAddr,
+    str::FromStr,
+    sync::LazyLock,
+    time::Duration,
+};
 use tokio::{net::TcpStream, task, time};
 
 pub(super) const HELLO_TO: Duration = Duration::from_secs(15);
@@ -85,10 +92,41 @@ impl FromStr for DesiredAuth {
     }
 }
 
+/// Every resolver server we tried explicitly refused our
+/// credentials. Retrying with the same credentials won't help. Use
+/// `Error::downcast_ref` to detect it.
+#[derive(Debug, Clone)]
+pub struct ResolverAuthError {
+    pub resolver: SocketAddr,
+    pub reason: String,
+}
+
+impl fmt::Display for ResolverAuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self { resolver, reason } = self;
+        write!(f, "authentication with resolver {resolver} failed: {reason}")
+    }
+}
+
+impl error::Error for ResolverAuthError {}
+
+/// The server sent an empty kerberos token, which means it refused
+/// us, and a hello saying why follows
+#[derive(Debug)]
+pub(super) struct Krb5Refused;
+
+impl fmt::Display for Krb5Refused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the server refused kerberos authentication")
+    }
+}
+
+impl error::Error for Krb5Refused {}
+
 pub(super) type Response<F> =
     (GPooled<IntMap<PublisherId, Publisher>>, GPooled<Vec<(usize, F)>>);
 
-pub(super) type ResponseChan<F> = oneshot::Receiver<Response<F>>;
+pub(super) type ResponseChan<F> = oneshot::Receiver<Result<Response<F>>>;
 
 pub(crate) async fn krb5_authentication(
     principal: Option<&str>,
@@ -117,6 +155,9 @@ pub(crate) async fn krb5_authentication(
     loop {
         let token: BoundedBytes<L> =
             time::timeout(HELLO_TO, channel::read_raw::<_, _, 1024>(con)).await??;
+        if token.is_empty() {
+            bail!(Krb5Refused)
+        }
         match task::spawn_blocking(move || ctx.step(&*token)).await?? {
             Step::Continue((nctx, token)) => {
                 ctx = nctx;
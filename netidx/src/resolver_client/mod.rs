@@ -13,13 +13,14 @@ use crate::{
     path::Path,
     protocol::resolver::{
         FromRead, FromWrite, Publisher, PublisherId, Referral, ToRead, ToWrite,
+        WriterStatus,
     },
     tls,
 };
 use ahash::{AHashMap, AHashSet};
 use anyhow::Result;
 use arcstr::ArcStr;
-pub use common::DesiredAuth;
+pub use common::{DesiredAuth, ResolverAuthError};
 use common::{
     ResponseChan, FROMREADPOOL, FROMWRITEPOOL, LISTPOOL, PATHPOOL, PUBLISHERPOOL,
     RAWFROMREADPOOL, RAWFROMWRITEPOOL, RAWTOREADPOOL, RAWTOWRITEPOOL, RESOLVEDPOOL,
@@ -61,7 +62,9 @@ impl ToPath for ToRead {
     fn path(&self) -> Option<&Path> {
         match self {
             ToRead::List(p) | ToRead::Table(p) | ToRead::Resolve(p) => Some(p),
-            ToRead::ListMatching(_) | ToRead::GetChangeNr(_) => None,
+            ToRead::ListMatching(_) | ToRead::GetChangeNr(_) | ToRead::ListByAddr(_) => {
+                None
+            }
         }
     }
 }
@@ -69,13 +72,15 @@ impl ToPath for ToRead {
 impl ToPath for ToWrite {
     fn path(&self) -> Option<&Path> {
         match self {
-            ToWrite::Clear | ToWrite::Heartbeat => None,
+            ToWrite::Clear | ToWrite::Heartbeat | ToWrite::Status => None,
             ToWrite::Publish(p)
             | ToWrite::Unpublish(p)
             | ToWrite::UnpublishDefault(p)
+            | ToWrite::UnpublishSubtree(p)
             | ToWrite::PublishDefault(p)
             | ToWrite::PublishWithFlags(p, _)
-            | ToWrite::PublishDefaultWithFlags(p, _) => Some(p),
+            | ToWrite::PublishDefaultWithFlags(p, _)
+            | ToWrite::Republish { path: p, .. } => Some(p),
         }
     }
 }
@@ -248,6 +253,7 @@ where
     priority: PublisherPriority,
     secrets: Arc<RwLock<AHashMap<SocketAddr, u128>>>,
     tls: Option<tls::CachedConnector>,
+    last_success: Option<Instant>,
     phantom: PhantomData<(T, F)>,
     f_pool: Pool<Vec<F>>,
     fi_pool: Pool<Vec<(usize, F)>>,
@@ -319,6 +325,7 @@ where
             priority,
             secrets,
             tls,
+            last_success: None,
             f_pool,
             fi_pool,
             ti_pool,
@@ -330,13 +337,38 @@ where
         Arc::clone(&self.0.lock().secrets)
     }
 
+    fn writer_addr(&self) -> SocketAddr {
+        self.0.lock().writer_addr
+    }
+
+    fn last_success(&self) -> Option<Instant> {
+        self.0.lock().last_success
+    }
+
     async fn send(
         &self,
         batch: &GPooled<Vec<T>>,
     ) -> Result<(GPooled<IntMap<PublisherId, Publisher>>, GPooled<Vec<F>>)> {
+        self.send_traced(batch, None).await
+    }
+
+    // if trace is specified then for each message in batch it will
+    // hold the resolver clusters it was sent to, in order, ending
+    // with the one that answered
+    async fn send_traced(
+        &self,
+        batch: &GPooled<Vec<T>>,
+        mut trace: Option<&mut Vec<Vec<Arc<Referral>>>>,
+    ) -> Result<(GPooled<IntMap<PublisherId, Publisher>>, GPooled<Vec<F>>)> {
+        if let Some(trace) = trace.as_mut() {
+            trace.clear();
+            trace.resize_with(batch.len(), Vec::new);
+        }
         let mut referrals = 0;
         loop {
             let mut waiters = Vec::new();
+            let mut servers = Vec::new();
+            let mut answered = Vec::new();
             let (mut finished, mut res) = {
                 let mut guard = self.0.lock();
                 let inner = &mut *guard;
@@ -344,14 +376,17 @@ where
                     inner.by_server.clear(); // a workable sledgehammer
                 }
                 for (r, batch) in inner.router.route_batch(&inner.ti_pool, batch) {
+                    if trace.is_some() {
+                        servers.push(r.clone().unwrap_or_else(|| inner.default.clone()))
+                    }
                     waiters.push(inner.send_to_server(r, batch))
                 }
                 (inner.fi_pool.take(), inner.f_pool.take())
             };
             let mut referral = false;
             let mut publishers = None;
-            for r in future::join_all(waiters).await {
-                let (mut p, mut r) = r?;
+            for (i, r) in future::join_all(waiters).await.into_iter().enumerate() {
+                let (mut p, mut r) = r??;
                 match publishers.as_mut() {
                     None => {
                         publishers = Some(p);
@@ -362,8 +397,16 @@ where
                 };
                 for (id, reply) in r.drain(..) {
                     match reply.referral() {
-                        Err(m) => finished.push((id, m)),
+                        Err(m) => {
+                            if trace.is_some() {
+                                answered.push((id, i));
+                            }
+                            finished.push((id, m))
+                        }
                         Ok(r) => {
+                            if let Some(trace) = trace.as_mut() {
+                                trace[id].push(servers[i].clone());
+                            }
                             self.0.lock().router.add_referral(Arc::new(r));
                             referral = true;
                         }
@@ -371,9 +414,15 @@ where
                 }
             }
             if !referral {
+                if let Some(trace) = trace.as_mut() {
+                    for (id, i) in answered {
+                        trace[id].push(servers[i].clone());
+                    }
+                }
                 finished.sort_by_key(|(id, _)| *id);
                 res.extend(finished.drain(..).map(|(_, m)| m));
                 let publishers = publishers.unwrap_or_else(|| PUBLISHERPOOL.take());
+                self.0.lock().last_success = Some(Instant::now());
                 break Ok((publishers, res));
             }
             referrals += 1;
@@ -433,6 +482,12 @@ impl ResolverRead {
         self.0.send(batch).await
     }
 
+    /// Return when the resolver cluster last answered a batch, or
+    /// `None` if it never has.
+    pub fn last_success(&self) -> Option<Instant> {
+        self.0.last_success()
+    }
+
     /// Resolve the specified paths to publisher addresses.
     ///
     /// Results are in send order.
@@ -466,6 +521,48 @@ impl ResolverRead {
         }
     }
 
+    /// Resolve the specified paths, and also return the chain of
+    /// resolver clusters consulted for each one. This is for
+    /// debugging referrals in federated setups, use `resolve`
+    /// otherwise.
+    ///
+    /// The chain for each path is in the order the clusters were
+    /// consulted, ending with the one that answered, and is in the
+    /// same order as the results. Referrals are cached, so a path
+    /// under a referral learned by an earlier call goes straight to
+    /// the cluster it was referred to.
+    pub async fn resolve_traced<I>(
+        &self,
+        batch: I,
+    ) -> Result<(
+        GPooled<IntMap<PublisherId, Publisher>>,
+        GPooled<Vec<Resolved>>,
+        Vec<Vec<Arc<Referral>>>,
+    )>
+    where
+        I: IntoIterator<Item = Path>,
+    {
+        let mut to = RAWTOREADPOOL.take();
+        to.extend(batch.into_iter().map(ToRead::Resolve));
+        let mut trace = Vec::new();
+        let (publishers, mut result) = self.0.send_traced(&to, Some(&mut trace)).await?;
+        let mut out = RESOLVEDPOOL.take();
+        for r in result.drain(..) {
+            match r {
+                FromRead::Resolved(r) => out.push(r),
+                m => bail!("unexpected resolve response {:?}", m),
+            }
+        }
+        if out.len() != to.len() {
+            bail!(
+                "unexpected number of resolve results {} expected {}",
+                out.len(),
+                to.len()
+            )
+        }
+        Ok((publishers, out, trace))
+    }
+
     /// List immediate children of the specified path.
     ///
     /// Order is unspecified.
@@ -492,6 +589,29 @@ impl ResolverRead {
         }
     }
 
+    /// List every path, including defaults, published by the
+    /// publisher with the specified write address. This requires
+    /// list permission on the root of the resolver server, and only
+    /// covers the resolver server cluster this client is configured
+    /// for, referrals are not followed.
+    ///
+    /// Order is unspecified.
+    pub async fn list_by_addr(&self, addr: SocketAddr) -> Result<GPooled<Vec<Path>>> {
+        let mut to = RAWTOREADPOOL.take();
+        to.push(ToRead::ListByAddr(addr));
+        let (_, mut result) = self.send(&to).await?;
+        if result.len() != 1 {
+            bail!("expected 1 result from list_by_addr got {}", result.len());
+        } else {
+            match result.pop().unwrap() {
+                FromRead::List(paths) => Ok(paths),
+                FromRead::Denied => bail!("permission denied"),
+                FromRead::Error(e) => bail!("list_by_addr failed {e}"),
+                m => bail!("unexpected result from list_by_addr {:?}", m),
+            }
+        }
+    }
+
     async fn send_and_aggregate<F: FnMut(FromRead) -> Result<GPooled<Vec<Referral>>>>(
         &self,
         message: ToRead,
@@ -786,6 +906,42 @@ impl ResolverWrite {
         self.send_expect(batch, FromWrite::Unpublished, ToWrite::UnpublishDefault).await
     }
 
+    /// Unpublish every path, including default publishers, at or
+    /// under each path in the batch that was published by this
+    /// publisher. Paths published by other publishers are not affected.
+    /// If part of a subtree is referred to another resolver cluster
+    /// the rest of it is still unpublished, but an error naming the
+    /// referred path is returned, and that path should be passed to
+    /// `unpublish_subtree` separately.
+    pub async fn unpublish_subtree<I: IntoIterator<Item = Path>>(
+        &self,
+        batch: I,
+    ) -> Result<()> {
+        self.send_expect(batch, FromWrite::Unpublished, ToWrite::UnpublishSubtree).await
+    }
+
+    /// Move a batch of paths to this publisher from the publisher
+    /// at the paired address. Each path is published here and
+    /// unpublished there in one step, so a resolve during the move
+    /// always finds at least one publisher. Both publishers must
+    /// belong to the same authenticated user, anonymous publishers
+    /// can't take paths from each other. If the old publisher isn't
+    /// publishing the path this is the same as `publish`. The old
+    /// publisher should still unpublish the paths itself, otherwise
+    /// it will publish them again if it ever has to resync.
+    pub async fn republish<I: IntoIterator<Item = (Path, SocketAddr)>>(
+        &self,
+        batch: I,
+    ) -> Result<()> {
+        let to = self.0.writer_addr();
+        self.send_expect(batch, FromWrite::Published, |(path, from)| ToWrite::Republish {
+            path,
+            from,
+            to,
+        })
+        .await
+    }
+
     /// Clear all published paths from this publisher.
     ///
     // CR estokes: this is broken on complex clusters
@@ -803,6 +959,26 @@ impl ResolverWrite {
         }
     }
 
+    /// Ask the resolver what it knows about this publisher, how
+    /// many paths it has, how long until its registration expires,
+    /// and whether the registration was lost before the current
+    /// connection was made. If the resolver is a cluster the first
+    /// server to answer is reported.
+    pub async fn status(&self) -> Result<WriterStatus> {
+        let mut batch = RAWTOWRITEPOOL.take();
+        batch.push(ToWrite::Status);
+        let (_, mut r) = self.0.send(&batch).await?;
+        if r.len() != 1 {
+            bail!("unexpected response to status command {:?}", r)
+        } else {
+            match r.pop().unwrap() {
+                FromWrite::Status(st) => Ok(st),
+                FromWrite::Error(e) => bail!("status failed {e}"),
+                m => bail!("unexpected response to status command {:?}", m),
+            }
+        }
+    }
+
     pub(crate) fn secrets(&self) -> Arc<RwLock<AHashMap<SocketAddr, u128>>> {
         self.0.secrets()
     }
@@ -1,27 +1,29 @@
 use super::common::{
-    krb5_authentication, DesiredAuth, Response, ResponseChan, FROMREADPOOL, HELLO_TO,
-    PUBLISHERPOOL, RAWFROMREADPOOL,
+    krb5_authentication, DesiredAuth, Krb5Refused, ResolverAuthError, Response,
+    ResponseChan, FROMREADPOOL, HELLO_TO, PUBLISHERPOOL, RAWFROMREADPOOL,
 };
 use crate::{
     channel::{self, Channel, K5CtxWrap},
     os::local_auth::AuthClient,
     protocol::resolver::{
-        Auth, AuthRead, ClientHello, FromRead, Publisher, Referral, ToRead,
+        Auth, AuthRead, ClientHello, FromRead, Publisher, Referral, ServerHelloRead,
+        ToRead, AT_CAPACITY,
     },
     tls,
     utils::Either,
 };
 use ahash::AHashSet;
 use anyhow::{Context, Error, Result};
+use arcstr::literal;
 use cross_krb5::ClientCtx;
 use futures::{
     channel::{mpsc, oneshot},
     prelude::*,
 };
-use log::{info, warn};
+use log::{debug, info, warn};
 use poolshark::{global::GPooled, local::LPooled};
 use rand::{rng, seq::SliceRandom, RngExt};
-use std::{cmp::max, fmt::Debug, net::SocketAddr, sync::Arc, time::Duration};
+use std::{cmp::max, fmt::Debug, io, net::SocketAddr, sync::Arc, time::Duration};
 use tokio::{net::TcpStream, task, time};
 
 // continue with timeout
@@ -35,18 +37,54 @@ macro_rules! cwt {
     };
 }
 
+// the server refused our credentials, remember why and continue
+macro_rules! refused {
+    ($refused:ident, $addr:expr, $reason:expr) => {{
+        let reason = $reason.to_string();
+        warn!("resolver server {} refused authentication: {}", $addr, reason);
+        $refused.push(ResolverAuthError { resolver: *$addr, reason });
+        continue;
+    }};
+}
+
+// continue if the server refused our credentials, remembering why
+macro_rules! check_denied {
+    ($refused:ident, $addr:expr, $hello:expr) => {
+        if let Some(reason) = &$hello.denied {
+            refused!($refused, $addr, reason)
+        }
+    };
+}
+
+// the alert a tls server sent if it refused the handshake
+fn tls_alert(e: &io::Error) -> Option<rustls::AlertDescription> {
+    match e.get_ref()?.downcast_ref::<rustls::Error>()? {
+        rustls::Error::AlertReceived(alert) => Some(*alert),
+        _ => None,
+    }
+}
+
 async fn connect(
     bad_addrs: &mut AHashSet<SocketAddr>,
     resolver: &Referral,
     desired_auth: &DesiredAuth,
     tls: &Option<tls::CachedConnector>,
-) -> Result<Channel> {
+) -> Result<(Channel, u64)> {
     let mut addrs = resolver.addrs.clone();
     addrs.as_mut_slice().shuffle(&mut rng());
     let mut n = 0;
+    // the servers that refused our credentials in this round
+    let mut refused: Vec<ResolverAuthError> = vec![];
     loop {
         let (addr, auth) = &addrs[n % addrs.len()];
         let tries = n / addrs.len();
+        if n > 0 && n % addrs.len() == 0 {
+            // retrying with the same credentials won't help
+            if refused.len() == addrs.len() || (tries >= 3 && !refused.is_empty()) {
+                return Err(refused.swap_remove(0).into());
+            }
+            refused.clear();
+        }
         if tries >= 3 {
             bail!("can't connect to any resolver servers");
         }
@@ -79,20 +117,29 @@ async fn connect(
         };
         try_cf!("no delay", con.set_nodelay(true));
         cwt!("send version", channel::write_raw(&mut con, &3u64));
-        if cwt!("recv version", channel::read_raw::<u64, _, 1024>(&mut con)) != 3 {
-            continue;
+        match cwt!("recv version", channel::read_raw::<u64, _, 1024>(&mut con)) {
+            3 => (),
+            AT_CAPACITY => {
+                warn!("resolver server {} is at capacity", addr);
+                bad_addrs.insert(*addr);
+                continue;
+            }
+            _ => continue,
         }
-        let con = match (desired_auth, auth) {
+        let (mut con, hello) = match (desired_auth, auth) {
             (DesiredAuth::Anonymous, _) => {
                 let mut con = Channel::new::<ClientCtx, TcpStream>(None, con);
-                cwt!("hello", con.send_one(&ClientHello::ReadOnly(AuthRead::Anonymous)));
-                match cwt!("reply", con.receive::<AuthRead>()) {
+                let hello = ClientHello::ReadOnly(AuthRead::Anonymous, true);
+                cwt!("hello", con.send_one(&hello));
+                let hello = cwt!("reply", con.receive::<ServerHelloRead>());
+                check_denied!(refused, addr, hello);
+                match hello.auth {
                     AuthRead::Anonymous => (),
                     AuthRead::Local | AuthRead::Krb5 | AuthRead::Tls => {
                         bail!("protocol error")
                     }
                 }
-                con
+                (con, hello)
             }
             (
                 DesiredAuth::Krb5 { .. } | DesiredAuth::Local | DesiredAuth::Tls { .. },
@@ -106,15 +153,18 @@ async fn connect(
             ) => {
                 let mut con = Channel::new::<ClientCtx, TcpStream>(None, con);
                 let tok = cwt!("local token", AuthClient::token(&*path));
-                cwt!("hello", con.send_one(&ClientHello::ReadOnly(AuthRead::Local)));
+                let hello = ClientHello::ReadOnly(AuthRead::Local, true);
+                cwt!("hello", con.send_one(&hello));
                 cwt!("token", con.send_one(&tok));
-                match cwt!("reply", con.receive::<AuthRead>()) {
+                let hello = cwt!("reply", con.receive::<ServerHelloRead>());
+                check_denied!(refused, addr, hello);
+                match hello.auth {
                     AuthRead::Local => (),
                     AuthRead::Krb5 | AuthRead::Anonymous | AuthRead::Tls => {
                         bail!("protocol error")
                     }
                 }
-                con
+                (con, hello)
             }
             (DesiredAuth::Local, Auth::Krb5 { .. } | Auth::Tls { .. }) => {
                 bail!("local auth not supported")
@@ -124,12 +174,25 @@ async fn connect(
             }
             (DesiredAuth::Krb5 { upn, .. }, Auth::Krb5 { spn }) => {
                 let upn = upn.as_ref().map(|s| s.as_str());
-                let hello = ClientHello::ReadOnly(AuthRead::Krb5);
+                let hello = ClientHello::ReadOnly(AuthRead::Krb5, true);
                 cwt!("hello", channel::write_raw(&mut con, &hello));
-                let ctx = cwt!("k5auth", krb5_authentication(upn, &*spn, &mut con));
-                match cwt!("reply", channel::read_raw::<AuthRead, _, 1024>(&mut con)) {
-                    AuthRead::Krb5 => Channel::new(Some(K5CtxWrap::new(ctx)), con),
-                    AuthRead::Local | AuthRead::Anonymous | AuthRead::Tls => {
+                let auth = async {
+                    match krb5_authentication(upn, &*spn, &mut con).await {
+                        Ok(ctx) => Ok(Some(ctx)),
+                        Err(e) if e.is::<Krb5Refused>() => Ok(None),
+                        Err(e) => Err(e),
+                    }
+                };
+                let ctx = cwt!("k5auth", auth);
+                let reply = channel::read_raw::<ServerHelloRead, _, 1024>(&mut con);
+                let hello = cwt!("reply", reply);
+                check_denied!(refused, addr, hello);
+                match (hello.auth, ctx) {
+                    (AuthRead::Krb5, Some(ctx)) => {
+                        (Channel::new(Some(K5CtxWrap::new(ctx)), con), hello)
+                    }
+                    (AuthRead::Krb5, None)
+                    | (AuthRead::Local | AuthRead::Anonymous | AuthRead::Tls, _) => {
                         bail!("protocol error")
                     }
                 }
@@ -146,29 +209,62 @@ async fn connect(
                 })
                 .await
                 .context("loading tls connector")??;
-                let hello = ClientHello::ReadOnly(AuthRead::Tls);
+                let hello = ClientHello::ReadOnly(AuthRead::Tls, true);
                 cwt!("hello", channel::write_raw(&mut con, &hello));
                 let name = rustls_pki_types::ServerName::try_from(&**name)
                     .context("creating rustls servername")?
                     .to_owned();
-                let tls = ctx.connect(name, con).await?;
-                let mut con = Channel::new::<
+                let mut tls = match ctx.connect(name, con).await {
+                    Ok(tls) => tls,
+                    Err(e) => match tls_alert(&e) {
+                        Some(alert) => {
+                            refused!(refused, addr, format!("tls alert {alert:?}"))
+                        }
+                        None => return Err(e.into()),
+                    },
+                };
+                // read the reply raw, a channel would hide the alert
+                // the server sends if it refuses our certificate
+                let reply = channel::read_raw::<ServerHelloRead, _, 1024>(&mut tls);
+                let hello = match time::timeout(HELLO_TO, reply).await {
+                    Ok(Ok(hello)) => hello,
+                    Ok(Err(e)) => match e.downcast_ref().and_then(tls_alert) {
+                        Some(alert) => {
+                            refused!(refused, addr, format!("tls alert {alert:?}"))
+                        }
+                        None => {
+                            info!("reply: {}", e);
+                            continue;
+                        }
+                    },
+                    Err(e) => {
+                        info!("reply: {}", e);
+                        continue;
+                    }
+                };
+                check_denied!(refused, addr, hello);
+                let con = Channel::new::<
                     ClientCtx,
                     tokio_rustls::client::TlsStream<TcpStream>,
                 >(None, tls);
-                match cwt!("reply", con.receive::<AuthRead>()) {
-                    AuthRead::Tls => con,
+                match hello.auth {
+                    AuthRead::Tls => (con, hello),
                     AuthRead::Local | AuthRead::Anonymous | AuthRead::Krb5 { .. } => {
                         bail!("protocol error")
                     }
                 }
             }
         };
-        break Ok(con);
+        debug!(
+            "resolver server {} version {:?} capabilities {:#x}",
+            addr, hello.version, hello.capabilities
+        );
+        con.set_large_frames(hello.large_frames);
+        break Ok((con, hello.capabilities));
     }
 }
 
-type Batch = (GPooled<Vec<(usize, ToRead)>>, oneshot::Sender<Response<FromRead>>);
+type Batch = (GPooled<Vec<(usize, ToRead)>>, oneshot::Sender<Result<Response<FromRead>>>);
 
 fn partition_publishers(m: FromRead) -> Either<FromRead, Publisher> {
     match m {
@@ -191,6 +287,7 @@ async fn connection(
     tls: Option<tls::CachedConnector>,
 ) {
     let mut con: Option<Channel> = None;
+    let mut capabilities = 0;
     let mut bad_addrs: LPooled<AHashSet<SocketAddr>> = LPooled::take();
     'main: loop {
         match receiver.next().await {
@@ -212,10 +309,16 @@ async fn connection(
                             match connect(&mut *bad_addrs, &resolver, &desired_auth, &tls)
                                 .await
                             {
-                                Ok(c) => {
+                                Ok((c, caps)) => {
                                     con = Some(c);
+                                    capabilities = caps;
                                     con.as_mut().unwrap()
                                 }
+                                Err(e) if e.is::<ResolverAuthError>() => {
+                                    con = None;
+                                    let _ = reply.send(Err(e));
+                                    continue 'main;
+                                }
                                 Err(e) => {
                                     con = None;
                                     warn!(
@@ -230,9 +333,19 @@ async fn connection(
                     };
                     let mut timeout =
                         max(HELLO_TO, Duration::from_micros(tx_batch.len() as u64 * 50));
+                    // an older server would drop the connection on a
+                    // message it doesn't understand, answer those here
+                    let supported = |m: &ToRead| m.capability() & !capabilities == 0;
+                    let mut sent = 0;
                     for (_, m) in &*tx_batch {
+                        if !supported(m) {
+                            continue;
+                        }
+                        sent += 1;
                         match m {
-                            ToRead::List(_) | ToRead::ListMatching(_) => {
+                            ToRead::List(_)
+                            | ToRead::ListMatching(_)
+                            | ToRead::ListByAddr(_) => {
                                 timeout += HELLO_TO;
                             }
                             _ => (),
@@ -254,7 +367,7 @@ async fn connection(
                         Ok(()) => {
                             let mut rx_batch = RAWFROMREADPOOL.take();
                             let mut publishers = PUBLISHERPOOL.take();
-                            while rx_batch.len() < tx_batch.len() {
+                            while rx_batch.len() < sent {
                                 let f =
                                     c.receive_batch_fn(|m| {
                                         match partition_publishers(m) {
@@ -279,13 +392,18 @@ async fn connection(
                                 }
                             }
                             let mut result = FROMREADPOOL.take();
-                            result.extend(
-                                rx_batch
-                                    .drain(..)
-                                    .enumerate()
-                                    .map(|(i, m)| (tx_batch[i].0, m)),
-                            );
-                            let _ = reply.send((publishers, result));
+                            let mut rx = rx_batch.drain(..);
+                            for (i, m) in &*tx_batch {
+                                let m = if supported(m) {
+                                    rx.next().unwrap()
+                                } else {
+                                    FromRead::Error(literal!(
+                                        "not supported by the resolver server"
+                                    ))
+                                };
+                                result.push((*i, m))
+                            }
+                            let _ = reply.send(Ok((publishers, result)));
                             break;
                         }
                     }
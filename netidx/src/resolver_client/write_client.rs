@@ -9,12 +9,13 @@ use crate::{
     protocol::resolver::{
         Auth, AuthChallenge, AuthWrite, ClientHello, ClientHelloWrite, FromWrite,
         HashMethod, ReadyForOwnershipCheck, Referral, Secret, ServerHelloWrite, ToWrite,
+        AT_CAPACITY,
     },
     tls, utils,
 };
 use ahash::{AHashMap, AHasher};
 use anyhow::{anyhow, Result};
-use arcstr::ArcStr;
+use arcstr::{literal, ArcStr};
 use cross_krb5::{ClientCtx, K5Ctx};
 use futures::{
     channel::{mpsc, oneshot},
@@ -29,7 +30,11 @@ use parking_lot::{Mutex, RwLock};
 use poolshark::global::GPooled;
 use rand::{rng, RngExt};
 use std::{
-    cmp::max, fmt::Debug, hash::BuildHasherDefault, net::SocketAddr, sync::Arc,
+    cmp::{max, min},
+    fmt::Debug,
+    hash::BuildHasherDefault,
+    net::SocketAddr,
+    sync::Arc,
     time::Duration,
 };
 use tokio::{
@@ -41,7 +46,8 @@ use tokio::{
 
 const TTL: u64 = 120;
 
-type Batch = (GPooled<Vec<(usize, ToWrite)>>, oneshot::Sender<Response<FromWrite>>);
+type Batch =
+    (GPooled<Vec<(usize, ToWrite)>>, oneshot::Sender<Result<Response<FromWrite>>>);
 
 struct ToCon {
     batch: GPooled<Vec<(usize, ToWrite)>>,
@@ -68,6 +74,9 @@ struct Connection {
     con: Option<Channel>,
     resolver_addr: SocketAddr,
     resolver_auth: Auth,
+    // the instance of the resolver we last said hello to, zero if
+    // we haven't or it didn't say
+    resolver_instance: u64,
     write_addr: SocketAddr,
     published: IndexMap<Path, ToWrite, BuildHasherDefault<AHasher>>,
     secrets: Arc<RwLock<AHashMap<SocketAddr, u128>>>,
@@ -75,7 +84,12 @@ struct Connection {
     tls: Option<tls::CachedConnector>,
     desired_auth: DesiredAuth,
     degraded: bool,
+    resync: bool,
+    ttl_countdown: bool,
+    capabilities: u64,
     active: bool,
+    // the heartbeat interval implied by the ttl in the hello
+    hello_heartbeat: Duration,
     heartbeat: Interval,
     disconnect: Interval,
     priority: PublisherPriority,
@@ -86,10 +100,26 @@ impl Connection {
         let linger = Duration::from_secs(max(1, ttl / 10));
         let heartbeat = Duration::from_secs(max(1, ttl / 2));
         let now = Instant::now();
+        self.hello_heartbeat = heartbeat;
         self.heartbeat = time::interval_at(now + heartbeat, heartbeat);
         self.disconnect = time::interval_at(now + linger, linger);
     }
 
+    /// The resolver will drop us if we are silent for `secs`, make
+    /// sure we heartbeat often enough even if that is sooner than the
+    /// ttl we were told in the hello, and go back to the hello's
+    /// interval once the resolver gives us that long again.
+    fn adapt_heartbeat(&mut self, secs: u64) {
+        let heartbeat = min(self.hello_heartbeat, Duration::from_secs(max(1, secs / 2)));
+        if heartbeat != self.heartbeat.period() {
+            info!(
+                "resolver {:?} expires us in {}s, heartbeating every {:?}",
+                self.resolver_addr, secs, heartbeat
+            );
+            self.heartbeat = time::interval_at(Instant::now() + heartbeat, heartbeat);
+        }
+    }
+
     async fn republish(&mut self, con: &mut Channel, ttl_expired: bool) -> Result<()> {
         let len = self.published.len();
         if len == 0 {
@@ -121,13 +151,16 @@ impl Connection {
                     ToWrite::Publish(_)
                     | ToWrite::PublishDefault(_)
                     | ToWrite::PublishWithFlags(_, _)
-                    | ToWrite::PublishDefaultWithFlags(_, _) => match reply {
+                    | ToWrite::PublishDefaultWithFlags(_, _)
+                    | ToWrite::Republish { .. } => match reply {
                         FromWrite::Published => success += 1,
                         r => {
                             warn!("republish unexpected response to {:?} from resolver {:?}", msg, r)
                         }
                     },
-                    ToWrite::Unpublish(p) | ToWrite::UnpublishDefault(p) => match reply {
+                    ToWrite::Unpublish(p)
+                    | ToWrite::UnpublishDefault(p)
+                    | ToWrite::UnpublishSubtree(p) => match reply {
                         FromWrite::Unpublished => {
                             success += 1;
                             to_remove.push(Some(p.clone()));
@@ -146,7 +179,7 @@ impl Connection {
                             warn!("republish unexpected response to {:?} from resolver {:?}", msg, r)
                         }
                     },
-                    ToWrite::Heartbeat => (),
+                    ToWrite::Heartbeat | ToWrite::Status => (),
                 }
             }
             for p in to_remove {
@@ -195,8 +228,10 @@ impl Connection {
         debug!("writing protocol version 3");
         wt!("write version", channel::write_raw(&mut con, &3u64))??;
         debug!("reading protocol version");
-        if wt!("read version", channel::read_raw::<u64, _, 1024>(&mut con))?? != 3 {
-            bail!("incompatible protocol version")
+        match wt!("read version", channel::read_raw::<u64, _, 1024>(&mut con))?? {
+            3 => (),
+            AT_CAPACITY => bail!("resolver server at capacity"),
+            _ => bail!("incompatible protocol version"),
         }
         let sec = Duration::from_secs(1);
         let hello = |auth| {
@@ -204,6 +239,9 @@ impl Connection {
                 auth,
                 write_addr: self.write_addr,
                 priority: self.priority,
+                resync: true,
+                large_frames: true,
+                ttl_countdown: true,
             });
             debug!("write_con connection established hello {:?}", h);
             h
@@ -389,12 +427,25 @@ impl Connection {
                 con.send_one(&ReadyForOwnershipCheck)
             )??;
         }
-        if !r.ttl_expired && !self.degraded {
+        self.resync = r.resync;
+        self.ttl_countdown = r.ttl_countdown;
+        self.capabilities = r.capabilities;
+        con.set_large_frames(r.large_frames);
+        // a restarted server has never seen anything we published
+        let restarted = self.resolver_instance != 0
+            && r.instance != 0
+            && self.resolver_instance != r.instance;
+        if restarted && !r.ttl_expired {
+            info!("resolver {:?} restarted, republishing", self.resolver_addr);
+        }
+        let ttl_expired = r.ttl_expired || restarted;
+        self.resolver_instance = r.instance;
+        if !ttl_expired && !self.degraded {
             info!("connected to resolver {:?} for write", self.resolver_addr);
             self.con = Some(con);
             Ok(self.set_ttl(r.ttl))
         } else {
-            self.republish(&mut con, r.ttl_expired).await?;
+            self.republish(&mut con, ttl_expired).await?;
             self.con = Some(con);
             Ok(self.set_ttl(r.ttl))
         }
@@ -406,10 +457,31 @@ impl Connection {
         warn!("write connection {:?} failed {}", self.resolver_addr, e);
     }
 
+    async fn heartbeat(&mut self) -> Result<()> {
+        let mut con = match self.con.take() {
+            Some(con) => con,
+            None => bail!("not connected"),
+        };
+        con.send_one(&ToWrite::Heartbeat).await?;
+        if self.resync || self.ttl_countdown {
+            match time::timeout(HELLO_TO, con.receive()).await?? {
+                FromWrite::Published => (),
+                FromWrite::Ttl(secs) => self.adapt_heartbeat(secs),
+                FromWrite::Resync => {
+                    info!("resolver {:?} requested resync", self.resolver_addr);
+                    self.republish(&mut con, true).await?
+                }
+                m => bail!("unexpected response to heartbeat {:?}", m),
+            }
+        }
+        self.con = Some(con);
+        Ok(())
+    }
+
     async fn send_heartbeat(&mut self) {
         for _ in 0..3 {
             match self.con {
-                Some(ref mut c) => match c.send_one(&ToWrite::Heartbeat).await {
+                Some(_) => match self.heartbeat().await {
                     Ok(()) => break,
                     Err(e) => {
                         info!("write_con heartbeat send error {}", e);
@@ -441,7 +513,11 @@ impl Connection {
                 }
             },
         };
-        for (_, tx) in tx.batch.iter() {
+        // an older server would drop the connection on a message it
+        // doesn't understand, answer those here
+        let capabilities = self.capabilities;
+        let supported = |m: &ToWrite| m.capability() & !capabilities == 0;
+        for (_, tx) in tx.batch.iter().filter(|(_, m)| supported(m)) {
             match tx {
                 ToWrite::Publish(p)
                 | ToWrite::PublishDefault(p)
@@ -449,25 +525,53 @@ impl Connection {
                 | ToWrite::PublishDefaultWithFlags(p, _) => {
                     self.published.insert(p.clone(), tx.clone());
                 }
+                // once moved the path is ours, so if we have to
+                // resync just publish it again
+                ToWrite::Republish { path, .. } => {
+                    self.published.insert(path.clone(), ToWrite::Publish(path.clone()));
+                }
                 ToWrite::Unpublish(p) | ToWrite::UnpublishDefault(p) => {
                     self.published.swap_remove(p);
                 }
+                ToWrite::UnpublishSubtree(p) => {
+                    self.published.retain(|k, _| !Path::is_parent(p, k));
+                }
                 ToWrite::Clear => {
                     self.published.clear();
                 }
-                ToWrite::Heartbeat => (),
+                ToWrite::Heartbeat | ToWrite::Status => (),
             }
         }
         let timeout = max(HELLO_TO, Duration::from_micros(tx.batch.len() as u64 * 100));
-        for (_, m) in &*tx.batch {
+        let mut sent = 0;
+        for (_, m) in tx.batch.iter().filter(|(_, m)| supported(m)) {
             c.queue_send(m)?;
+            sent += 1;
+        }
+        // ask how long we have left after this batch, since we won't
+        // heartbeat while we are active. Status must be sent alone.
+        let ttl_heartbeat = self.ttl_countdown
+            && sent > 0
+            && !tx.batch.iter().any(|(_, m)| matches!(m, ToWrite::Status));
+        if ttl_heartbeat {
+            c.queue_send(&ToWrite::Heartbeat)?;
+            sent += 1;
         }
         c.flush_timeout(timeout).await?;
         let mut rx_batch = RAWFROMWRITEPOOL.take();
-        while rx_batch.len() < tx.batch.len() {
+        while rx_batch.len() < sent {
             time::timeout(timeout, c.receive_batch(&mut *rx_batch)).await??
         }
-        for ((_, tx), rx) in tx.batch.iter().zip(rx_batch.iter()) {
+        let mut resync = false;
+        if ttl_heartbeat {
+            match rx_batch.pop() {
+                Some(FromWrite::Ttl(secs)) => self.adapt_heartbeat(secs),
+                Some(FromWrite::Resync) => resync = true,
+                m => bail!("unexpected response to heartbeat {:?}", m),
+            }
+        }
+        let sent = tx.batch.iter().filter(|(_, m)| supported(m));
+        for ((_, tx), rx) in sent.zip(rx_batch.iter()) {
             match tx {
                 ToWrite::Publish(_) => match rx {
                     FromWrite::Published => (),
@@ -481,12 +585,25 @@ impl Connection {
         let mut result = FROMWRITEPOOL.take();
         // not relevant for writes
         let publishers = PUBLISHERPOOL.take();
-        for (i, m) in rx_batch.drain(..).enumerate() {
-            result.push((tx.batch[i].0, m))
+        let mut rx = rx_batch.drain(..);
+        for (i, m) in tx.batch.iter() {
+            let m = if supported(m) {
+                rx.next().unwrap()
+            } else {
+                FromWrite::Error(literal!("not supported by the resolver server"))
+            };
+            result.push((*i, m))
         }
         if let Some(reply) = tx.replies.lock().pop() {
             let _ = reply.send((publishers, result));
         }
+        if resync {
+            info!("resolver {:?} requested resync", self.resolver_addr);
+            if let Some(mut con) = self.con.take() {
+                self.republish(&mut con, true).await?;
+                self.con = Some(con);
+            }
+        }
         Ok(())
     }
 
@@ -512,8 +629,13 @@ impl Connection {
             security_context: None,
             tls,
             con: None,
+            resolver_instance: 0,
             degraded: false,
+            resync: false,
+            ttl_countdown: false,
+            capabilities: 0,
             active: false,
+            hello_heartbeat: HB,
             heartbeat: time::interval_at(now + HB, HB),
             disconnect: time::interval_at(now + LINGER, LINGER),
         };
@@ -545,19 +667,27 @@ impl Connection {
                         Err(e) => {
                             t.con = None;
                             t.degraded = true;
-                            for (_, tx) in batch.batch.iter() {
+                            let caps = t.capabilities;
+                            let sent = batch
+                                .batch
+                                .iter()
+                                .filter(|(_, m)| m.capability() & !caps == 0);
+                            for (_, tx) in sent {
                                 match tx {
                                     ToWrite::Publish(_)
                                     | ToWrite::PublishDefault(_)
                                     | ToWrite::PublishWithFlags(_, _)
-                                    | ToWrite::PublishDefaultWithFlags(_, _) => (),
-                                    ToWrite::Unpublish(p) | ToWrite::UnpublishDefault(p) => {
+                                    | ToWrite::PublishDefaultWithFlags(_, _)
+                                    | ToWrite::Republish { .. } => (),
+                                    ToWrite::Unpublish(p)
+                                    | ToWrite::UnpublishDefault(p)
+                                    | ToWrite::UnpublishSubtree(p) => {
                                         t.published.insert(p.clone(), tx.clone());
                                     }
                                     ToWrite::Clear => {
                                         t.published.insert(Path::from(""), ToWrite::Clear);
                                     },
-                                    ToWrite::Heartbeat => (),
+                                    ToWrite::Heartbeat | ToWrite::Status => (),
                                 }
                             }
                             warn!("write batch failed {}", e)
@@ -614,7 +744,7 @@ async fn write_mgr(
         match select_ok(waiters).await {
             Err(e) => warn!("write_mgr: write failed on all writers {}", e),
             Ok((rx_batch, _)) => {
-                let _ = reply.send(rx_batch);
+                let _ = reply.send(Ok(rx_batch));
             }
         }
     }
@@ -0,0 +1,289 @@
+//! An append only record of every publish and unpublish the resolver
+//! processes, and who asked for it.
+//!
+//! The log is a sequence of lines, each a json object with the fields,
+//!
+//! - `time`: when the write was processed, rfc3339 in utc
+//! - `principal`: the authenticated user, or null for anonymous
+//! - `publisher`: the address of the publisher the write was for
+//! - `op`: one of publish, publish_default, unpublish,
+//!   unpublish_default, unpublish_subtree, republish, clear, evict
+//! - `path`: the path, null for clear and evict
+//! - `from`: the old publisher address of a republish, otherwise null
+//! - `result`: one of ok, denied, error, referral. Every write in a
+//!   batch the resolver failed to process is recorded as an error,
+//!   even though some of them may have been applied.
+//! - `prev`: the hex encoded sha3-512 hash of the previous line,
+//!   without its newline, or "" for the first line ever written
+//!
+//! Chaining each line to the one before makes any edit, insertion,
+//! or deletion detectable by recomputing the hashes. Only the last
+//! line can be removed without a trace, so ship the log somewhere
+//! else if that matters.
+//!
+//! When the file grows beyond the configured size it is renamed to
+//! `<file>.<utc time>`, e.g. `audit.log.20240101T120000.000000Z`, and
+//! a new file is started. The chain continues across the rename, the
+//! first line of the new file holds the hash of the last line of the
+//! old one. When the resolver starts it picks the chain up from the
+//! last line of the existing file.
+//!
+//! Lines are written to the file by a background thread, which syncs
+//! it to disk once a second, or after every megabyte, whichever comes
+//! first. A crash may lose the last second of the log.
+//!
+//! The log fails closed. Once a line can't be written or synced the
+//! resolver refuses every further write, replying with an error,
+//! until it is restarted.
+use super::auth::UserInfo;
+use crate::{
+    path::Path,
+    protocol::resolver::{FromWrite, Publisher, ToWrite},
+};
+use anyhow::{anyhow, Result};
+use chrono::prelude::*;
+use log::error;
+use netidx_core::utils::make_sha3_token;
+use serde_derive::Serialize;
+use std::{
+    fmt::Write as _,
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    mem,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+const SYNC_BYTES: u64 = 1024 * 1024;
+
+#[derive(Serialize)]
+struct Record<'a> {
+    time: DateTime<Utc>,
+    principal: Option<&'a str>,
+    publisher: SocketAddr,
+    op: &'static str,
+    path: Option<&'a str>,
+    from: Option<SocketAddr>,
+    result: &'static str,
+    prev: &'a str,
+}
+
+fn hash(line: &[u8]) -> String {
+    let mut s = String::new();
+    for b in make_sha3_token([line]).iter() {
+        write!(s, "{b:02x}").unwrap()
+    }
+    s
+}
+
+// read the last non empty line of the file without reading the
+// whole thing
+fn last_line(file: &mut File) -> Result<Option<Vec<u8>>> {
+    let len = file.metadata()?.len();
+    let mut chunk = 4096;
+    loop {
+        let start = len.saturating_sub(chunk);
+        let mut buf = vec![];
+        file.seek(SeekFrom::Start(start))?;
+        (&mut *file).take(len - start).read_to_end(&mut buf)?;
+        let tail = buf.trim_ascii_end();
+        match tail.iter().rposition(|c| *c == b'\n') {
+            Some(i) => break Ok(Some(tail[i + 1..].to_vec())),
+            None if start == 0 => {
+                break Ok(if tail.is_empty() { None } else { Some(tail.to_vec()) });
+            }
+            None => chunk *= 2,
+        }
+    }
+}
+
+struct Writer {
+    path: PathBuf,
+    max_size: u64,
+    file: File,
+    size: u64,
+    // when the oldest write that isn't on disk yet was made
+    unsynced_since: Option<Instant>,
+    unsynced: u64,
+    failed: Arc<AtomicBool>,
+}
+
+impl Writer {
+    fn write(&mut self, buf: &[u8]) -> Result<()> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.sync()?;
+            let mut rotated = self.path.clone().into_os_string();
+            rotated.push(Utc::now().format(".%Y%m%dT%H%M%S%.6fZ").to_string());
+            fs::rename(&self.path, &rotated)?;
+            self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+            self.size = 0;
+        }
+        self.file.write_all(buf)?;
+        self.size += buf.len() as u64;
+        self.unsynced += buf.len() as u64;
+        self.unsynced_since.get_or_insert_with(Instant::now);
+        if self.unsynced >= SYNC_BYTES {
+            self.sync()?
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        if self.unsynced > 0 {
+            self.file.sync_data()?;
+            self.unsynced = 0;
+            self.unsynced_since = None;
+        }
+        Ok(())
+    }
+
+    fn run(mut self, rx: Receiver<Vec<u8>>) {
+        loop {
+            let res = match self.unsynced_since {
+                None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+                Some(since) => {
+                    let wait = SYNC_INTERVAL.saturating_sub(since.elapsed());
+                    rx.recv_timeout(wait)
+                }
+            };
+            let res = match res {
+                Ok(buf) => self.write(&buf),
+                Err(RecvTimeoutError::Timeout) => self.sync(),
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            if let Err(e) = res {
+                error!("failed to write the audit log, refusing writes {e:?}");
+                self.failed.store(true, Ordering::Relaxed);
+                break;
+            }
+        }
+        if let Err(e) = self.sync() {
+            error!("failed to sync the audit log {e:?}");
+            self.failed.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+pub(super) struct AuditLog {
+    prev: String,
+    buf: Vec<u8>,
+    tx: Sender<Vec<u8>>,
+    failed: Arc<AtomicBool>,
+}
+
+impl AuditLog {
+    pub(super) fn open(path: PathBuf, max_size: u64) -> Result<Self> {
+        let mut file =
+            OpenOptions::new().create(true).read(true).append(true).open(&path)?;
+        let prev = last_line(&mut file)?.map(|l| hash(&l)).unwrap_or_default();
+        let size = file.metadata()?.len();
+        let failed = Arc::new(AtomicBool::new(false));
+        let writer = Writer {
+            path,
+            max_size,
+            file,
+            size,
+            unsynced_since: None,
+            unsynced: 0,
+            failed: failed.clone(),
+        };
+        let (tx, rx) = mpsc::channel();
+        thread::Builder::new().name("audit-log".into()).spawn(move || writer.run(rx))?;
+        Ok(Self { prev, buf: vec![], tx, failed })
+    }
+
+    /// True if the log could not be written, after which no more
+    /// writes may be processed.
+    pub(super) fn failed(&self) -> bool {
+        self.failed.load(Ordering::Relaxed)
+    }
+
+    fn push(
+        &mut self,
+        principal: Option<&str>,
+        publisher: &Publisher,
+        op: &'static str,
+        path: Option<&Path>,
+        from: Option<SocketAddr>,
+        result: &'static str,
+    ) -> Result<()> {
+        let time = Utc::now();
+        let publisher = publisher.addr;
+        let path = path.map(|p| &**p);
+        let prev = &*self.prev;
+        let rec = Record { time, principal, publisher, op, path, from, result, prev };
+        let start = self.buf.len();
+        serde_json::to_writer(&mut self.buf, &rec)?;
+        self.prev = hash(&self.buf[start..]);
+        self.buf.push(b'\n');
+        Ok(())
+    }
+
+    /// Record a batch of writes from one publisher along with the
+    /// replies the shards sent. Heartbeats and status queries are not
+    /// recorded.
+    pub(super) fn record(
+        &mut self,
+        uifo: &UserInfo,
+        publisher: &Publisher,
+        msgs: &[ToWrite],
+        replies: &[(u64, FromWrite)],
+    ) -> Result<()> {
+        let principal = uifo.user_info.as_ref().map(|u| &*u.name);
+        let msgs = msgs.iter().filter(|m| !matches!(m, ToWrite::Heartbeat));
+        for (n, m) in msgs.enumerate() {
+            let result = match replies.iter().find(|(i, _)| *i == n as u64) {
+                None => "error",
+                Some((_, r)) => match r {
+                    FromWrite::Published | FromWrite::Unpublished => "ok",
+                    FromWrite::Denied => "denied",
+                    FromWrite::Referral(_) => "referral",
+                    _ => "error",
+                },
+            };
+            let (op, path, from) = match m {
+                ToWrite::Heartbeat | ToWrite::Status => continue,
+                ToWrite::Clear => ("clear", None, None),
+                ToWrite::Publish(p) | ToWrite::PublishWithFlags(p, _) => {
+                    ("publish", Some(p), None)
+                }
+                ToWrite::PublishDefault(p) | ToWrite::PublishDefaultWithFlags(p, _) => {
+                    ("publish_default", Some(p), None)
+                }
+                ToWrite::Unpublish(p) => ("unpublish", Some(p), None),
+                ToWrite::UnpublishDefault(p) => ("unpublish_default", Some(p), None),
+                ToWrite::UnpublishSubtree(p) => ("unpublish_subtree", Some(p), None),
+                ToWrite::Republish { path, from, .. } => {
+                    ("republish", Some(path), Some(*from))
+                }
+            };
+            self.push(principal, publisher, op, path, from, result)?
+        }
+        self.flush()
+    }
+
+    /// Record that the resolver evicted everything the specified
+    /// anonymous publisher published to make room for someone else.
+    pub(super) fn evicted(&mut self, publisher: &Publisher) -> Result<()> {
+        self.push(None, publisher, "evict", None, None, "ok")?;
+        self.flush()
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        self.tx.send(mem::take(&mut self.buf)).map_err(|_| {
+            self.failed.store(true, Ordering::Relaxed);
+            anyhow!("the audit log writer stopped")
+        })
+    }
+}
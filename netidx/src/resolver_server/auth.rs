@@ -384,3 +384,49 @@ impl PMap {
         })
     }
 }
+
+/// Where each principal may publish, see `file::Config::namespaces`
+#[derive(Debug, Default)]
+pub(super) struct Namespaces {
+    by_user: HashMap<ArcStr, Vec<ArcStr>>,
+    default: Option<Vec<ArcStr>>,
+}
+
+impl Namespaces {
+    pub(super) fn from_file(file: &HashMap<ArcStr, Vec<ArcStr>>) -> Result<Self> {
+        let mut t = Namespaces::default();
+        for (user, bases) in file.iter() {
+            for base in bases.iter() {
+                if !Path::is_absolute(&**base) {
+                    bail!("namespace {base} for {user} must be an absolute path")
+                }
+            }
+            if user == "$[user]" {
+                t.default = Some(bases.clone());
+            } else {
+                t.by_user.insert(user.clone(), bases.clone());
+            }
+        }
+        Ok(t)
+    }
+
+    /// Return true if `user` may publish `path`. Principals with no
+    /// namespace, and anonymous users, may publish anywhere.
+    pub(super) fn allowed(&self, path: &str, user: &UserInfo) -> bool {
+        let name = match &user.user_info {
+            None => return true,
+            Some(uifo) => &uifo.name,
+        };
+        let bases = match self.by_user.get(name).or(self.default.as_ref()) {
+            None => return true,
+            Some(bases) => bases,
+        };
+        bases.iter().any(|base| {
+            if base.contains("$[user]") {
+                Path::is_parent(&base.replace("$[user]", name), path)
+            } else {
+                Path::is_parent(&**base, path)
+            }
+        })
+    }
+}
@@ -21,8 +21,8 @@ use std::{
     convert::Into,
     default::Default,
     fs::read_to_string,
-    net::{IpAddr, SocketAddr},
-    path::Path as FsPath,
+    net::SocketAddr,
+    path::{Path as FsPath, PathBuf},
     time::Duration,
 };
 
@@ -112,6 +112,7 @@ pub mod file {
     use derive_builder::Builder;
     use poolshark::global::GPooled;
     use std::{
+        collections::HashMap,
         net::{IpAddr, Ipv4Addr, SocketAddr},
         path::PathBuf,
     };
@@ -209,6 +210,35 @@ pub mod file {
         IpAddr::V4(Ipv4Addr::UNSPECIFIED)
     }
 
+    /// What clients may do without authenticating
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub enum AnonymousAccess {
+        /// Anonymous clients may resolve and publish
+        ReadWrite,
+        /// Anonymous clients may resolve, but publishers must
+        /// authenticate
+        ReadOnly,
+        /// Every client must authenticate
+        Deny,
+    }
+
+    /// What to do with new connections when a server already has
+    /// `max_connections` clients
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub enum AtCapacity {
+        /// Accept the connection, but don't accept any more until a
+        /// client disconnects. The new client waits, and connections
+        /// queue in the listen backlog.
+        Queue,
+        /// Tell the client the server is at capacity and close the
+        /// connection. Clients with more than one resolver server
+        /// configured will try another one. If many clients are
+        /// already being told, new ones are just disconnected.
+        Reject,
+    }
+
     /// The type of user id mapping to perform
     #[derive(Debug, Clone, Serialize, Deserialize)]
     #[serde(deny_unknown_fields)]
@@ -249,6 +279,30 @@ pub mod file {
         120
     }
 
+    fn default_reuse_addr() -> bool {
+        true
+    }
+
+    fn default_anonymous_access() -> AnonymousAccess {
+        AnonymousAccess::ReadWrite
+    }
+
+    fn default_at_capacity() -> AtCapacity {
+        AtCapacity::Queue
+    }
+
+    fn default_listen_backlog() -> u32 {
+        1024
+    }
+
+    fn default_audit_log_max_size() -> u64 {
+        64 * 1024 * 1024
+    }
+
+    fn default_max_large_frame() -> u64 {
+        4 * 1024 * 1024 * 1024
+    }
+
     /// Describes a member of the local resolver cluster
     #[derive(Debug, Clone, Serialize, Deserialize, Builder)]
     #[serde(deny_unknown_fields)]
@@ -276,6 +330,11 @@ pub mod file {
         #[serde(default = "default_max_connections")]
         #[builder(default = "default_max_connections()")]
         pub max_connections: usize,
+        /// What to do with new connections once there are
+        /// max_connections clients, Queue or Reject. (default Queue)
+        #[serde(default = "default_at_capacity")]
+        #[builder(default = "default_at_capacity()")]
+        pub at_capacity: AtCapacity,
         /// The name to append to the pid file (default ""). If you
         /// are running more that one server on the same host as the
         /// same user you may need to set this.
@@ -292,6 +351,15 @@ pub mod file {
         #[serde(default = "default_writer_ttl")]
         #[builder(default = "default_writer_ttl()")]
         pub writer_ttl: u64,
+        /// Add a random amount, up to this percentage of writer_ttl,
+        /// to the ttl of each writer connection. After a restart
+        /// every publisher reconnects at about the same time, and
+        /// without jitter the idle ones all expire together, a burst
+        /// of cleanup work that repeats every ttl. (default 0, at
+        /// most 100)
+        #[serde(default)]
+        #[builder(default)]
+        pub writer_ttl_jitter: u8,
         /// The command to run to map netidx names to platform
         /// names. The command will be passed the netidx name and must
         /// output the same format as /bin/id on posix platforms. If
@@ -314,6 +382,85 @@ pub mod file {
         #[serde(default = "default_id_map_timeout")]
         #[builder(default = "default_id_map_timeout()")]
         pub id_map_timeout: u64,
+        /// The maximum number of paths this server will store,
+        /// counting each publisher of a path separately and not
+        /// counting default publishers. Once the limit is reached new
+        /// publishes will be rejected with an error. (default
+        /// unlimited)
+        #[serde(default)]
+        #[builder(setter(strip_option), default)]
+        pub max_published: Option<usize>,
+        /// If max_published is set and the server is full, evict all
+        /// the paths of the anonymous publisher that has been idle
+        /// the longest to make room for new publishes. (default
+        /// false)
+        #[serde(default)]
+        #[builder(default)]
+        pub evict_idle_anonymous: bool,
+        /// Additional addresses and ports to listen on, in addition
+        /// to bind_addr. This can be used, for example, to listen on
+        /// both an IPv4 and an IPv6 address. Connections accepted on
+        /// any address are handled the same way. (default none)
+        #[serde(default)]
+        #[builder(default)]
+        pub additional_bind_addrs: Vec<SocketAddr>,
+        /// The maximum number of publish and unpublish operations a
+        /// publisher may send in one batch. A larger batch is rejected
+        /// and every operation in it gets an error reply. Writes are
+        /// processed one batch at a time, so this bounds how long one
+        /// publisher can hold up the others. Keep in mind that
+        /// publishers republish all their paths in a single batch when
+        /// they reconnect, so this must be larger than the number of
+        /// paths any one publisher publishes. (default unlimited)
+        #[serde(default)]
+        #[builder(setter(strip_option), default)]
+        pub max_write_batch: Option<usize>,
+        /// Set SO_REUSEADDR on the listening sockets, so a restarted
+        /// server can bind while connections from the previous
+        /// instance are still in TIME_WAIT. Ignored on
+        /// windows. (default true)
+        #[serde(default = "default_reuse_addr")]
+        #[builder(default = "default_reuse_addr()")]
+        pub reuse_addr: bool,
+        /// The maximum number of connections waiting to be accepted
+        /// on each listening socket. The operating system may cap
+        /// this. (default 1024)
+        #[serde(default = "default_listen_backlog")]
+        #[builder(default = "default_listen_backlog()")]
+        pub listen_backlog: u32,
+        /// What clients may do without authenticating, e.g. ReadOnly
+        /// to allow anonymous reads but require publishers to
+        /// authenticate with auth. Anonymous clients are still
+        /// subject to perms. Must be ReadWrite if auth is
+        /// Anonymous. (default ReadWrite)
+        #[serde(default = "default_anonymous_access")]
+        #[builder(default = "default_anonymous_access()")]
+        pub anonymous_access: AnonymousAccess,
+        /// If specified, append a record of every publish and
+        /// unpublish this server processes, and the authenticated
+        /// user that asked for it, to this file. Each line is a json
+        /// object with the fields time, principal, publisher, op,
+        /// path, from, result, and prev. prev is the hex encoded
+        /// sha3-512 hash of the previous line, chaining the records
+        /// together so edits can be detected. This is a security
+        /// record, separate from and not affected by debug
+        /// logging. If the log can't be written the resolver refuses
+        /// every further write until it is restarted. (default none)
+        #[serde(default)]
+        #[builder(setter(into, strip_option), default)]
+        pub audit_log: Option<PathBuf>,
+        /// When the audit log grows beyond this many bytes it is
+        /// renamed to `<audit_log>.<utc time>` and a new file is
+        /// started. Old files are never deleted. (default 64 MiB)
+        #[serde(default = "default_audit_log_max_size")]
+        #[builder(default = "default_audit_log_max_size()")]
+        pub audit_log_max_size: u64,
+        /// The largest message, in bytes, a client that negotiated
+        /// large frames may send in one frame. Messages up to 1 GiB
+        /// are always accepted. (default 4 GiB)
+        #[serde(default = "default_max_large_frame")]
+        #[builder(default = "default_max_large_frame()")]
+        pub max_large_frame: u64,
     }
 
     /// The toplevel config object
@@ -341,6 +488,20 @@ pub mod file {
         #[serde(default)]
         #[builder(default)]
         pub perms: PMap,
+        /// Confine authenticated principals to parts of the
+        /// namespace (default empty). Maps a principal to the base
+        /// paths it may publish at or under, e.g. `"alice":
+        /// ["/tenants/alice"]`. `$[user]` in a base is replaced by
+        /// the principal's name, and the principal `$[user]` applies
+        /// to every principal not listed by name, so `"$[user]":
+        /// ["/tenants/$[user]"]` gives everyone their own
+        /// subtree. Publishes outside a principal's namespace are
+        /// denied whatever the permissions say. Principals with no
+        /// namespace, and anonymous publishers, are only limited by
+        /// the permissions.
+        #[serde(default)]
+        #[builder(default)]
+        pub namespaces: HashMap<ArcStr, Vec<ArcStr>>,
     }
 }
 
@@ -357,12 +518,23 @@ pub enum IdMap {
 #[derive(Debug, Clone)]
 pub struct MemberServer {
     pub(super) addr: SocketAddr,
-    pub(super) bind_addr: IpAddr,
+    pub(super) bind_addrs: Vec<SocketAddr>,
     pub(super) auth: Auth,
     pub(super) hello_timeout: Duration,
     pub(super) max_connections: usize,
+    pub(super) at_capacity: file::AtCapacity,
     pub(super) reader_ttl: Duration,
     pub(super) writer_ttl: Duration,
+    pub(super) writer_ttl_jitter: u8,
+    pub(super) max_published: Option<usize>,
+    pub(super) evict_idle_anonymous: bool,
+    pub(super) max_write_batch: Option<usize>,
+    pub(super) reuse_addr: bool,
+    pub(super) listen_backlog: u32,
+    pub(super) anonymous_access: file::AnonymousAccess,
+    pub(super) audit_log: Option<PathBuf>,
+    pub(super) audit_log_max_size: u64,
+    pub(super) max_large_frame: u64,
     #[allow(dead_code)]
     pub(crate) id_map: IdMap,
     pub(crate) id_map_timeout: chrono::Duration,
@@ -374,6 +546,7 @@ pub struct Config {
     pub(super) parent: Option<Referral>,
     pub(super) children: BTreeMap<Path, Referral>,
     pub(super) perms: PMap,
+    pub(super) namespaces: HashMap<ArcStr, Vec<ArcStr>>,
     pub member_servers: Vec<MemberServer>,
 }
 
@@ -492,23 +665,70 @@ impl Config {
                 if m.writer_ttl == 0 {
                     bail!("writer_ttl must be positive")
                 }
+                if m.writer_ttl_jitter > 100 {
+                    bail!("writer_ttl_jitter must be at most 100")
+                }
                 if m.hello_timeout == 0 {
                     bail!("hello_timeout must be positive")
                 }
+                if m.max_published == Some(0) {
+                    bail!("max_published must be positive")
+                }
+                if m.max_write_batch == Some(0) {
+                    bail!("max_write_batch must be positive")
+                }
+                if m.listen_backlog == 0 {
+                    bail!("listen_backlog must be positive")
+                }
+                if m.audit_log_max_size == 0 {
+                    bail!("audit_log_max_size must be positive")
+                }
+                if let file::Auth::Anonymous = &m.auth {
+                    if m.anonymous_access != file::AnonymousAccess::ReadWrite {
+                        bail!("anonymous_access must be ReadWrite with anonymous auth")
+                    }
+                }
+                let mut bind_addrs = vec![SocketAddr::new(m.bind_addr, m.addr.port())];
+                for addr in m.additional_bind_addrs.iter() {
+                    if !addr.ip().is_unspecified() {
+                        utils::check_addr::<()>(addr.ip(), &[])?
+                    }
+                    if bind_addrs.contains(addr) {
+                        bail!("duplicate bind address {}", addr)
+                    }
+                    bind_addrs.push(*addr);
+                }
                 Ok(MemberServer {
                     addr: m.addr,
-                    bind_addr: m.bind_addr,
+                    bind_addrs,
                     auth: m.auth.into(),
                     hello_timeout: Duration::from_secs(m.hello_timeout),
                     max_connections: m.max_connections,
+                    at_capacity: m.at_capacity,
                     reader_ttl: Duration::from_secs(m.reader_ttl),
                     writer_ttl: Duration::from_secs(m.writer_ttl),
+                    writer_ttl_jitter: m.writer_ttl_jitter,
+                    max_published: m.max_published,
+                    evict_idle_anonymous: m.evict_idle_anonymous,
+                    max_write_batch: m.max_write_batch,
+                    reuse_addr: m.reuse_addr,
+                    listen_backlog: m.listen_backlog,
+                    anonymous_access: m.anonymous_access,
+                    audit_log: m.audit_log,
+                    audit_log_max_size: m.audit_log_max_size,
+                    max_large_frame: m.max_large_frame,
                     id_map,
 		    id_map_timeout: chrono::Duration::seconds(m.id_map_timeout as i64),
                 })
             })
             .collect::<Result<Vec<_>>>()?;
-        Ok(Config { parent, children, perms: cfg.perms, member_servers })
+        Ok(Config {
+            parent,
+            children,
+            perms: cfg.perms,
+            namespaces: cfg.namespaces,
+            member_servers,
+        })
     }
 
     /// Parse a file::Config and translate it into a validated netidx cluster Config
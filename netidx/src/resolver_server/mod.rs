@@ -2,12 +2,14 @@
 use crate::{
     channel::{self, Channel, K5CtxWrap},
     pack::Pack,
+    path::Path,
     protocol::{
         publisher,
         resolver::{
             AuthChallenge, AuthRead, AuthWrite, ClientHello, ClientHelloWrite, FromWrite,
             HashMethod, Publisher, PublisherId, ReadyForOwnershipCheck, Secret,
-            ServerHelloWrite, ToRead, ToWrite,
+            ServerHelloRead, ServerHelloWrite, ToRead, ToWrite, WriterStatus,
+            AT_CAPACITY, CAP_ALL,
         },
     },
     tls, utils,
@@ -15,8 +17,12 @@ use crate::{
 use ahash::AHashMap;
 use anyhow::{Context, Result};
 use arcstr::{literal, ArcStr};
+use audit::AuditLog;
 use auth::{UserInfo, ANONYMOUS};
-use config::{Config, MemberServer};
+use config::{
+    file::{AnonymousAccess, AtCapacity},
+    Config, MemberServer, PMap,
+};
 use cross_krb5::{AcceptFlags, K5ServerCtx, ServerCtx, Step};
 use futures::{channel::oneshot, prelude::*, select_biased};
 use log::{debug, error, info, trace, warn};
@@ -37,16 +43,18 @@ use std::{
     time::Duration,
 };
 use tokio::{
-    net::{TcpListener, TcpStream},
-    sync::{Mutex, RwLock},
+    net::{TcpListener, TcpSocket, TcpStream},
+    sync::{Mutex, RwLock, Semaphore},
     task,
     time::{self, Instant},
 };
 
+mod audit;
 pub(crate) mod auth;
 pub mod config;
 pub(crate) mod secctx;
 mod shard_store;
+mod stats;
 mod store;
 #[cfg(test)]
 mod test;
@@ -250,27 +258,113 @@ struct Ctx {
     secctx: SecCtx,
     cfg: MemberServer,
     id: SocketAddr,
+    // random, and different every time the server starts, see
+    // ServerHelloWrite::instance
+    instance: u64,
     store: Store,
     delay_reads: Option<Instant>,
 }
 
+// Operations are logged as key=value pairs so that operators can
+// easily grep and parse them.
+fn log_write_batch(client: SocketAddr, publisher: &Publisher, batch: &[ToWrite]) {
+    if log::log_enabled!(log::Level::Debug) {
+        let (mut publish, mut unpublish) = (0, 0);
+        for m in batch {
+            match m {
+                ToWrite::Publish(_)
+                | ToWrite::PublishDefault(_)
+                | ToWrite::PublishWithFlags(_, _)
+                | ToWrite::PublishDefaultWithFlags(_, _)
+                | ToWrite::Republish { .. } => publish += 1,
+                ToWrite::Unpublish(_)
+                | ToWrite::UnpublishDefault(_)
+                | ToWrite::UnpublishSubtree(_) => unpublish += 1,
+                ToWrite::Heartbeat | ToWrite::Clear | ToWrite::Status => (),
+            }
+        }
+        let id = publisher.id;
+        if publish > 0 {
+            debug!("client={client} publisher={id:?} op=publish paths={publish}")
+        }
+        if unpublish > 0 {
+            debug!("client={client} publisher={id:?} op=unpublish paths={unpublish}")
+        }
+    }
+}
+
+fn log_read_batch(client: SocketAddr, batch: &[ToRead]) {
+    if log::log_enabled!(log::Level::Debug) {
+        let (mut resolve, mut list, mut table, mut list_matching, mut change_nr) =
+            (0, 0, 0, 0, 0);
+        let mut list_by_addr = 0;
+        for m in batch {
+            match m {
+                ToRead::Resolve(_) => resolve += 1,
+                ToRead::List(_) => list += 1,
+                ToRead::Table(_) => table += 1,
+                ToRead::ListMatching(_) => list_matching += 1,
+                ToRead::GetChangeNr(_) => change_nr += 1,
+                ToRead::ListByAddr(_) => list_by_addr += 1,
+            }
+        }
+        for (op, n) in [
+            ("resolve", resolve),
+            ("list", list),
+            ("table", table),
+            ("list_matching", list_matching),
+            ("get_change_nr", change_nr),
+            ("list_by_addr", list_by_addr),
+        ] {
+            if n > 0 {
+                debug!("client={client} op={op} paths={n}")
+            }
+        }
+    }
+}
+
+// the writer ttl plus up to writer_ttl_jitter percent of it, so
+// publishers that connected together don't all expire together
+fn jittered_writer_ttl(cfg: &MemberServer) -> Duration {
+    let ttl = cfg.writer_ttl;
+    if cfg.writer_ttl_jitter == 0 {
+        return ttl;
+    }
+    let max = ttl.as_millis() as u64 * cfg.writer_ttl_jitter as u64 / 100;
+    ttl + Duration::from_millis(rng().random_range(0..=max))
+}
+
 async fn client_loop_write(
     ctx: Arc<Ctx>,
     connection_id: CId,
+    client: SocketAddr,
     con: Channel,
     server_stop: oneshot::Receiver<()>,
     rx_stop: oneshot::Receiver<()>,
     uifo: Arc<UserInfo>,
     publisher: Arc<Publisher>,
+    ttl_expired: bool,
+    hello: &ClientHelloWrite,
 ) -> Result<()> {
-    debug!("starting write loop for {:?}", connection_id);
+    debug!(
+        "client={client} publisher={:?} starting write loop for {:?}",
+        publisher.id, connection_id
+    );
     let mut con = Some(con);
     let mut server_stop = server_stop.fuse();
     let mut rx_stop = rx_stop.fuse();
     let mut batch = WRITE_BATCHES.take();
     let mut act = false;
-    let mut timeout =
-        time::interval_at(Instant::now() + ctx.cfg.writer_ttl, ctx.cfg.writer_ttl);
+    // the ttl reported in the hello is the configured one, so jitter
+    // only ever gives the client longer than it expects
+    let ttl = jittered_writer_ttl(&ctx.cfg);
+    let mut timeout = time::interval_at(Instant::now() + ttl, ttl);
+    let mut last_tick = Instant::now();
+    // the next tick clears act, the one after that expires us
+    let ttl_left = |last_tick: Instant| {
+        let expires = last_tick + ttl + ttl;
+        expires.saturating_duration_since(Instant::now()).as_secs()
+    };
     async fn receive_batch(
         con: &mut Option<Channel>,
         batch: &mut Vec<ToWrite>,
@@ -287,12 +381,13 @@ async fn client_loop_write(
         select_biased! {
             _ = server_stop => break Ok(()),
             _ = rx_stop => break Ok(()),
-            _ = timeout.tick().fuse() => {
+            now = timeout.tick().fuse() => {
+                last_tick = now;
                 if act {
                     trace!("checking timeout, {:?} was active", connection_id);
                     act = false;
                 } else {
-                    trace!("dropping inactive connection {:?} ", connection_id);
+                    info!("client={client} dropping inactive connection {:?}", connection_id);
                     drop(con);
                     ctx.ctracker.close(connection_id);
                     ctx.clinfos.lock().await.remove(&ctx, &publisher, &uifo).await?;
@@ -304,19 +399,73 @@ async fn client_loop_write(
                     batch.clear();
                     con = None;
                     ctx.ctracker.close(connection_id);
-                    info!("write client loop error reading message: {}", e)
+                    info!("client={client} op=write error reading message: {}", e)
                 },
                 Ok(()) => {
                     trace!("{:?} received a batch {batch:?}", connection_id);
                     act = true;
+                    ctx.store.writer_active(&publisher);
                     if batch.len() == 1 && batch[0] == ToWrite::Heartbeat {
                         trace!("{:?} batch is just a heartbeat", connection_id);
+                        batch.clear();
+                        if hello.resync || hello.ttl_countdown {
+                            let c = match con.as_mut() {
+                                Some(c) => c,
+                                None => unreachable!("bug, con is none and we received a batch"),
+                            };
+                            let m = if hello.resync && ctx.store.take_evicted(&publisher) {
+                                info!("client={client} publisher={:?} op=resync", publisher.id);
+                                FromWrite::Resync
+                            } else if hello.ttl_countdown {
+                                FromWrite::Ttl(ttl_left(last_tick))
+                            } else {
+                                FromWrite::Published
+                            };
+                            c.send_one(&m).await?
+                        }
+                        continue 'main
+                    }
+                    if batch.len() == 1 && batch[0] == ToWrite::Status {
+                        batch.clear();
+                        let c = match con.as_mut() {
+                            Some(c) => c,
+                            None => unreachable!("bug, con is none and we received a batch"),
+                        };
+                        let (published, defaults) = ctx.store.published_by(&publisher).await?;
+                        let m = FromWrite::Status(WriterStatus {
+                            published: published as u64,
+                            defaults: defaults as u64,
+                            ttl: ttl_left(last_tick),
+                            ttl_expired,
+                            evicted: ctx.store.is_evicted(&publisher),
+                        });
+                        c.send_one(&m).await?;
                         continue 'main
                     }
+                    // a ttl countdown client ends a batch with a heartbeat
+                    // to learn how long it has after the batch
+                    let ttl_reply = hello.ttl_countdown
+                        && batch.last() == Some(&ToWrite::Heartbeat);
                     let c = match con.as_mut() {
                         Some(c) => c,
                         None => unreachable!("bug, con is none and we received a batch"),
                     };
+                    if let Some(max) = ctx.cfg.max_write_batch {
+                        let len = batch.len() - ttl_reply as usize;
+                        if len > max {
+                            warn!("client={client} op=write batch of {} exceeds max {}", len, max);
+                            for m in batch.drain(..) {
+                                if m != ToWrite::Heartbeat {
+                                    c.queue_send(&FromWrite::Error(literal!("batch too large")))?
+                                }
+                            }
+                            if ttl_reply {
+                                c.queue_send(&FromWrite::Ttl(ttl_left(last_tick)))?
+                            }
+                            c.flush().await?;
+                            continue 'main
+                        }
+                    }
                     trace!("{:?} checking batch of len {} for clear", connection_id, batch.len());
                     while let Some((i, _)) =
                         batch.iter().enumerate().find(|(_, m)| *m == &ToWrite::Clear)
@@ -326,17 +475,23 @@ async fn client_loop_write(
                         for m in batch.drain(..) {
                             match m {
                                 ToWrite::Heartbeat => (),
+                                ToWrite::Status => c.queue_send(&FromWrite::Error(
+                                    literal!("status must be sent alone")
+                                ))?,
                                 ToWrite::Publish(_)
                                     | ToWrite::PublishDefault(_)
                                     | ToWrite::PublishWithFlags(_, _)
-                                    | ToWrite::PublishDefaultWithFlags(_, _) =>
+                                    | ToWrite::PublishDefaultWithFlags(_, _)
+                                    | ToWrite::Republish { .. } =>
                                     c.queue_send(&FromWrite::Published)?,
                                 ToWrite::Unpublish(_) =>
                                     c.queue_send(&FromWrite::Unpublished)?,
                                 ToWrite::UnpublishDefault(_) =>
                                     c.queue_send(&FromWrite::Unpublished)?,
+                                ToWrite::UnpublishSubtree(_) =>
+                                    c.queue_send(&FromWrite::Unpublished)?,
                                 ToWrite::Clear => {
-                                    trace!("{:?} handling clear", connection_id);
+                                    debug!("client={client} publisher={:?} op=clear", publisher.id);
                                     ctx.store.handle_clear(
                                         uifo.clone(),
                                         publisher.clone()
@@ -349,17 +504,25 @@ async fn client_loop_write(
                         batch = GPooled::orphan(rest);
                     }
                     trace!("{:?} handling write batch of size {}", connection_id, batch.len());
+                    log_write_batch(client, &publisher, &batch);
                     if let Err(e) = ctx.store.handle_batch_write(
                         Some(c),
                         uifo.clone(),
                         publisher.clone(),
                         mem::replace(&mut batch, WRITE_BATCHES.take())
                     ).await {
-                        warn!("handle_write_batch failed {}", e);
+                        warn!("client={client} op=write handle_write_batch failed {}", e);
                         con = None;
                         ctx.ctracker.close(connection_id);
                         continue 'main;
                     }
+                    if ttl_reply {
+                        let c = match con.as_mut() {
+                            Some(c) => c,
+                            None => unreachable!("bug, con is none and we received a batch"),
+                        };
+                        c.send_one(&FromWrite::Ttl(ttl_left(last_tick))).await?
+                    }
                     trace!("{:?} write success", connection_id);
                 }
             },
@@ -369,6 +532,13 @@ async fn client_loop_write(
 
 const TOKEN_MAX: usize = 64 * 1024;
 
+// the most clients that may be waiting to be told the server is at
+// capacity, past this they are just disconnected
+const MAX_REJECTING: usize = 128;
+
+// sent to clients in the hello
+const VERSION: ArcStr = literal!(env!("CARGO_PKG_VERSION"));
+
 async fn recv<T: Pack + Debug>(timeout: Duration, con: &mut TcpStream) -> Result<T> {
     Ok(time::timeout(timeout, channel::read_raw::<_, _, TOKEN_MAX>(con)).await??)
 }
@@ -452,7 +622,17 @@ async fn ownership_check(
     Ok(secret)
 }
 
-type AuthResult = Result<(Channel, Arc<UserInfo>, Arc<Publisher>, oneshot::Receiver<()>)>;
+// the bool is the ttl_expired flag sent to the client in the hello
+type AuthResult =
+    Result<(Channel, Arc<UserInfo>, Arc<Publisher>, bool, oneshot::Receiver<()>)>;
+
+// Clients that asked for large frames may send one as soon as they
+// have our hello, before the rest of the handshake is done, so this
+// must be called before anything is received on the channel.
+fn set_large_frames(ctx: &Ctx, con: &mut Channel, large: bool) {
+    con.set_max_large_batch(ctx.cfg.max_large_frame);
+    con.set_large_frames(large)
+}
 
 async fn write_client_anonymous_auth(
     ctx: &Arc<Ctx>,
@@ -467,6 +647,12 @@ async fn write_client_anonymous_auth(
         ttl_expired,
         resolver_id: ctx.id,
         auth: AuthWrite::Anonymous,
+        resync: hello.resync,
+        large_frames: hello.large_frames,
+        ttl_countdown: hello.ttl_countdown,
+        version: VERSION,
+        capabilities: CAP_ALL,
+        instance: ctx.instance,
     };
     info!("hello_write accepting Anonymous authentication");
     debug!("hello_write sending hello {:?}", h);
@@ -474,12 +660,9 @@ async fn write_client_anonymous_auth(
         ctx.clinfos.lock().await.remove(&ctx, &publisher, uifo).await?;
         Err(e)?;
     }
-    Ok((
-        Channel::new::<ServerCtx, TcpStream>(None, con),
-        ANONYMOUS.clone(),
-        publisher,
-        rx_stop,
-    ))
+    let mut con = Channel::new::<ServerCtx, TcpStream>(None, con);
+    set_large_frames(ctx, &mut con, hello.large_frames);
+    Ok((con, ANONYMOUS.clone(), publisher, ttl_expired, rx_stop))
 }
 
 async fn write_client_local_auth(
@@ -497,16 +680,23 @@ async fn write_client_local_auth(
         ttl_expired: true, // re auth always clears
         resolver_id: ctx.id,
         auth: AuthWrite::Local,
+        resync: hello.resync,
+        large_frames: hello.large_frames,
+        ttl_countdown: hello.ttl_countdown,
+        version: VERSION,
+        capabilities: CAP_ALL,
+        instance: ctx.instance,
     };
     debug!("hello_write sending {:?}", h);
     send(ctx.cfg.hello_timeout, &mut con, &h).await?;
     let mut con = Channel::new::<ServerCtx, TcpStream>(None, con);
+    set_large_frames(ctx, &mut con, hello.large_frames);
     let secret = ownership_check(&ctx, &mut con, hello.write_addr).await?;
     let (publisher, _, rx_stop) =
         ctx.clinfos.lock().await.insert(&ctx, &uifo, &hello).await?;
     let d = LocalSecData { user: cred.user, secret };
     a.1.write().await.insert(publisher.id, d);
-    Ok((con, uifo, publisher, rx_stop))
+    Ok((con, uifo, publisher, true, rx_stop))
 }
 
 async fn write_client_reuse_local(
@@ -520,6 +710,7 @@ async fn write_client_reuse_local(
     let d = a.1.read().await.get(&id).ok_or_else(|| anyhow!("missing"))?.clone();
     let uifo = a.1.write().await.users.ifo(ctx.id, Some(&*d.user)).await?;
     let mut con = Channel::new::<ServerCtx, TcpStream>(None, con);
+    set_large_frames(ctx, &mut con, hello.large_frames);
     challenge_auth(&ctx.cfg, &mut con, d.secret).await?;
     let (publisher, ttl_expired, rx_stop) =
         ctx.clinfos.lock().await.insert(&ctx, &uifo, &hello).await?;
@@ -528,6 +719,12 @@ async fn write_client_reuse_local(
         ttl_expired,
         resolver_id: ctx.id,
         auth: AuthWrite::Reuse,
+        resync: hello.resync,
+        large_frames: hello.large_frames,
+        ttl_countdown: hello.ttl_countdown,
+        version: VERSION,
+        capabilities: CAP_ALL,
+        instance: ctx.instance,
     };
     match time::timeout(ctx.cfg.hello_timeout, con.send_one(&h)).await {
         Ok(Ok(())) => (),
@@ -540,7 +737,7 @@ async fn write_client_reuse_local(
             Err(e)?
         }
     }
-    Ok((con, uifo, publisher, rx_stop))
+    Ok((con, uifo, publisher, ttl_expired, rx_stop))
 }
 
 async fn write_client_krb5_auth(
@@ -553,12 +750,19 @@ async fn write_client_krb5_auth(
     let k5ctx = krb5_authentication(ctx.cfg.hello_timeout, Some(&*a.0), &mut con).await?;
     let k5ctx = K5CtxWrap::new(k5ctx);
     let mut con = Channel::new(Some(k5ctx.clone()), con);
+    set_large_frames(ctx, &mut con, hello.large_frames);
     info!("hello_write all traffic now encrypted");
     let h = ServerHelloWrite {
         ttl: ctx.cfg.writer_ttl.as_secs(),
         ttl_expired: true, // re auth always clears
         resolver_id: ctx.id,
         auth: AuthWrite::Krb5 { spn: literal!("") },
+        resync: hello.resync,
+        large_frames: hello.large_frames,
+        ttl_countdown: hello.ttl_countdown,
+        version: VERSION,
+        capabilities: CAP_ALL,
+        instance: ctx.instance,
     };
     debug!("hello_write sending {:?}", h);
     time::timeout(ctx.cfg.hello_timeout, con.send_one(&h)).await??;
@@ -570,7 +774,7 @@ async fn write_client_krb5_auth(
         ctx.clinfos.lock().await.insert(&ctx, &uifo, &hello).await?;
     let d = K5SecData { ctx: k5ctx, secret };
     a.1.write().await.insert(publisher.id, d);
-    Ok((con, uifo, publisher, rx_stop))
+    Ok((con, uifo, publisher, true, rx_stop))
 }
 
 async fn write_client_reuse_krb5(
@@ -585,6 +789,7 @@ async fn write_client_reuse_krb5(
     let client = d.ctx.lock().client()?;
     let uifo = a.1.write().await.users.ifo(ctx.id, Some(&client)).await?;
     let mut con = Channel::new(Some(d.ctx), con);
+    set_large_frames(ctx, &mut con, hello.large_frames);
     info!("hello_write all traffic now encrypted");
     challenge_auth(&ctx.cfg, &mut con, d.secret).await?;
     let (publisher, ttl_expired, rx_stop) =
@@ -594,6 +799,12 @@ async fn write_client_reuse_krb5(
         ttl_expired,
         resolver_id: ctx.id,
         auth: AuthWrite::Reuse,
+        resync: hello.resync,
+        large_frames: hello.large_frames,
+        ttl_countdown: hello.ttl_countdown,
+        version: VERSION,
+        capabilities: CAP_ALL,
+        instance: ctx.instance,
     };
     info!("hello_write reusing krb5 context");
     debug!("hello_write sending {:?}", h);
@@ -608,7 +819,7 @@ async fn write_client_reuse_krb5(
             Err(e)?
         }
     }
-    Ok((con, uifo, publisher, rx_stop))
+    Ok((con, uifo, publisher, ttl_expired, rx_stop))
 }
 
 async fn get_tls_uifo(
@@ -642,12 +853,19 @@ async fn write_client_tls_auth(
     let uifo = get_tls_uifo(ctx.id, &tls, a).await?;
     let mut con =
         Channel::new::<ServerCtx, tokio_rustls::server::TlsStream<TcpStream>>(None, tls);
+    set_large_frames(ctx, &mut con, hello.large_frames);
     info!("hello_write all traffic now encrypted");
     let h = ServerHelloWrite {
         ttl: ctx.cfg.writer_ttl.as_secs(),
         ttl_expired: true,
         resolver_id: ctx.id,
         auth: AuthWrite::Tls { name: literal!("") },
+        resync: hello.resync,
+        large_frames: hello.large_frames,
+        ttl_countdown: hello.ttl_countdown,
+        version: VERSION,
+        capabilities: CAP_ALL,
+        instance: ctx.instance,
     };
     debug!("hello_write sending {:?}", h);
     time::timeout(ctx.cfg.hello_timeout, con.send_one(&h)).await??;
@@ -657,7 +875,7 @@ async fn write_client_tls_auth(
         ctx.clinfos.lock().await.insert(&ctx, &uifo, hello).await?;
     let d = TlsSecData(secret);
     a.1.write().await.insert(publisher.id, d);
-    Ok((con, uifo, publisher, rx_stop))
+    Ok((con, uifo, publisher, true, rx_stop))
 }
 
 async fn write_client_reuse_tls(
@@ -673,6 +891,7 @@ async fn write_client_reuse_tls(
     let uifo = get_tls_uifo(ctx.id, &tls, a).await?;
     let mut con =
         Channel::new::<ServerCtx, tokio_rustls::server::TlsStream<TcpStream>>(None, tls);
+    set_large_frames(ctx, &mut con, hello.large_frames);
     info!("hello_write all traffic now encrypted");
     challenge_auth(&ctx.cfg, &mut con, d.0).await?;
     let (publisher, ttl_expired, rx_stop) =
@@ -682,6 +901,12 @@ async fn write_client_reuse_tls(
         ttl_expired,
         resolver_id: ctx.id,
         auth: AuthWrite::Reuse,
+        resync: hello.resync,
+        large_frames: hello.large_frames,
+        ttl_countdown: hello.ttl_countdown,
+        version: VERSION,
+        capabilities: CAP_ALL,
+        instance: ctx.instance,
     };
     info!("hello_write reusing tls context");
     debug!("hello_write sending {:?}", h);
@@ -696,12 +921,13 @@ async fn write_client_reuse_tls(
             Err(e)?
         }
     }
-    Ok((con, uifo, publisher, rx_stop))
+    Ok((con, uifo, publisher, ttl_expired, rx_stop))
 }
 
 async fn hello_client_write(
     ctx: Arc<Ctx>,
     connection_id: CId,
+    client: SocketAddr,
     con: TcpStream,
     server_stop: oneshot::Receiver<()>,
     hello: ClientHelloWrite,
@@ -710,7 +936,7 @@ async fn hello_client_write(
     info!("hello_write starting negotiation");
     debug!("hello_write client_hello: {:?}", hello);
     utils::check_addr(hello.write_addr.ip(), &[(ctx.id, ())])?;
-    let (con, uifo, publisher, rx_stop) = match hello.auth {
+    let (con, uifo, publisher, ttl_expired, rx_stop) = match hello.auth {
         AuthWrite::Anonymous => write_client_anonymous_auth(&ctx, con, &hello).await?,
         AuthWrite::Local => match &ctx.secctx {
             SecCtx::Local(a) => write_client_local_auth(&ctx, con, a, &hello).await?,
@@ -731,12 +957,24 @@ async fn hello_client_write(
             SecCtx::Anonymous => bail!(NO),
         },
     };
-    Ok(client_loop_write(ctx, connection_id, con, server_stop, rx_stop, uifo, publisher)
-        .await?)
+    Ok(client_loop_write(
+        ctx,
+        connection_id,
+        client,
+        con,
+        server_stop,
+        rx_stop,
+        uifo,
+        publisher,
+        ttl_expired,
+        &hello,
+    )
+    .await?)
 }
 
 async fn client_loop_read(
     ctx: Arc<Ctx>,
+    client: SocketAddr,
     mut con: Channel,
     server_stop: oneshot::Receiver<()>,
     uifo: Arc<UserInfo>,
@@ -753,12 +991,17 @@ async fn client_loop_read(
                 if act {
                     act = false;
                 } else {
+                    info!("client={client} op=read timed out");
                     bail!("client timed out");
                 }
             }
             m = con.receive_batch(&mut batch).fuse() => {
-                m?;
+                if let Err(e) = m {
+                    info!("client={client} op=read error reading message: {}", e);
+                    return Err(e)
+                }
                 act = true;
+                log_read_batch(client, &batch);
                 ctx.store.handle_batch_read(
                     &mut con,
                     uifo.clone(),
@@ -769,66 +1012,169 @@ async fn client_loop_read(
     }
 }
 
+// tell a read client that its credentials were refused before
+// closing the connection, so it doesn't retry with the same ones. A
+// kerberos client is waiting for a token, an empty one tells it the
+// refusal follows.
+async fn deny_read<S: tokio::io::AsyncWrite + Unpin>(
+    ctx: &Ctx,
+    con: &mut S,
+    auth: AuthRead,
+    reason: &'static str,
+) -> anyhow::Error {
+    let timeout = ctx.cfg.hello_timeout;
+    if let AuthRead::Krb5 = auth {
+        let empty = BoundedBytes::<TOKEN_MAX>(utils::bytes(&[]));
+        let _ = time::timeout(timeout, channel::write_raw(con, &empty)).await;
+    }
+    let denied = Some(ArcStr::from(reason));
+    let h = ServerHelloRead {
+        auth,
+        version: VERSION,
+        capabilities: 0,
+        denied,
+        large_frames: false,
+    };
+    let _ = time::timeout(timeout, channel::write_raw(con, &h)).await;
+    anyhow!(reason)
+}
+
+// a tls client is already in its handshake, all it can understand is
+// a tls alert. Read its client hello, so closing doesn't reset the
+// connection, and refuse it with a plaintext fatal access_denied.
+async fn deny_tls_handshake(
+    ctx: &Ctx,
+    con: &mut TcpStream,
+    reason: &'static str,
+) -> anyhow::Error {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    const ACCESS_DENIED: [u8; 7] = [0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 0x31];
+    let deny = async {
+        let mut hdr = [0u8; 5];
+        con.read_exact(&mut hdr).await?;
+        let len = u16::from_be_bytes([hdr[3], hdr[4]]) as u64;
+        tokio::io::copy(&mut (&mut *con).take(len), &mut tokio::io::sink()).await?;
+        con.write_all(&ACCESS_DENIED).await?;
+        con.flush().await
+    };
+    let _ = time::timeout(ctx.cfg.hello_timeout, deny).await;
+    anyhow!(reason)
+}
+
 async fn hello_client_read(
     ctx: Arc<Ctx>,
+    client: SocketAddr,
     mut con: TcpStream,
     server_stop: oneshot::Receiver<()>,
     hello: AuthRead,
+    large_frames: bool,
 ) -> Result<()> {
     static NO: &str = "authentication mechanism not supported";
-    let (con, uifo) = match hello {
+    let reply = |auth| ServerHelloRead {
+        auth,
+        version: VERSION,
+        capabilities: CAP_ALL,
+        denied: None,
+        large_frames,
+    };
+    let (mut con, uifo) = match hello {
         AuthRead::Anonymous => {
-            send(ctx.cfg.hello_timeout, &mut con, &AuthRead::Anonymous).await?;
+            send(ctx.cfg.hello_timeout, &mut con, &reply(AuthRead::Anonymous)).await?;
             (Channel::new::<ServerCtx, TcpStream>(None, con), ANONYMOUS.clone())
         }
         AuthRead::Local => match &ctx.secctx {
             SecCtx::Local(a) => {
                 let tok: BoundedBytes<TOKEN_MAX> =
                     recv(ctx.cfg.hello_timeout, &mut con).await?;
-                let cred = a.0.authenticate(&*tok)?;
+                let cred = match a.0.authenticate(&*tok) {
+                    Ok(cred) => cred,
+                    Err(e) => {
+                        warn!("local authentication of {client} failed {e:?}");
+                        let reason = "local authentication failed";
+                        return Err(
+                            deny_read(&ctx, &mut con, AuthRead::Local, reason).await
+                        );
+                    }
+                };
                 let uifo = a.1.write().await.users.ifo(ctx.id, Some(&cred.user)).await?;
-                send(ctx.cfg.hello_timeout, &mut con, &AuthRead::Local).await?;
+                send(ctx.cfg.hello_timeout, &mut con, &reply(AuthRead::Local)).await?;
                 (Channel::new::<ServerCtx, TcpStream>(None, con), uifo)
             }
-            SecCtx::Anonymous | SecCtx::Krb5(_) | SecCtx::Tls(_) => bail!(NO),
+            SecCtx::Anonymous | SecCtx::Krb5(_) | SecCtx::Tls(_) => {
+                // read the token, closing with it unread would reset
+                // the connection before the client sees the reply
+                let _: BoundedBytes<TOKEN_MAX> =
+                    recv(ctx.cfg.hello_timeout, &mut con).await?;
+                return Err(deny_read(&ctx, &mut con, AuthRead::Local, NO).await);
+            }
         },
         AuthRead::Krb5 => match &ctx.secctx {
             SecCtx::Krb5(a) => {
-                let k5ctx =
-                    krb5_authentication(ctx.cfg.hello_timeout, Some(&*a.0), &mut con)
-                        .await?;
-                send(ctx.cfg.hello_timeout, &mut con, &AuthRead::Krb5).await?;
+                let timeout = ctx.cfg.hello_timeout;
+                let k5ctx = match krb5_authentication(timeout, Some(&*a.0), &mut con)
+                    .await
+                {
+                    Ok(k5ctx) => k5ctx,
+                    Err(e) => {
+                        warn!("kerberos authentication of {client} failed {e:?}");
+                        let reason = "kerberos authentication failed";
+                        return Err(
+                            deny_read(&ctx, &mut con, AuthRead::Krb5, reason).await
+                        );
+                    }
+                };
+                send(ctx.cfg.hello_timeout, &mut con, &reply(AuthRead::Krb5)).await?;
                 let k5ctx = K5CtxWrap::new(k5ctx);
                 let con = Channel::new::<ServerCtx, TcpStream>(Some(k5ctx.clone()), con);
                 let client = k5ctx.lock().client()?;
                 let uifo = a.1.write().await.users.ifo(ctx.id, Some(&client)).await?;
                 (con, uifo)
             }
-            SecCtx::Anonymous | SecCtx::Local(_) | SecCtx::Tls(_) => bail!(NO),
+            SecCtx::Anonymous | SecCtx::Local(_) | SecCtx::Tls(_) => {
+                // read the client's first token, as for local auth
+                let _: BoundedBytes<TOKEN_MAX> =
+                    recv(ctx.cfg.hello_timeout, &mut con).await?;
+                return Err(deny_read(&ctx, &mut con, AuthRead::Krb5, NO).await);
+            }
         },
         AuthRead::Tls => match &ctx.secctx {
             SecCtx::Tls(a) => {
-                let tls = a.0.accept(con).await.context("accepting tls connection")?;
-                let uifo =
-                    get_tls_uifo(ctx.id, &tls, a).await.context("getting tls info")?;
+                // if the handshake fails rustls has already sent the
+                // client an alert saying why
+                let mut tls =
+                    a.0.accept(con).await.context("accepting tls connection")?;
+                let uifo = match get_tls_uifo(ctx.id, &tls, a).await {
+                    Ok(uifo) => uifo,
+                    Err(e) => {
+                        warn!("tls authentication of {client} failed {e:?}");
+                        let reason = "tls authentication failed";
+                        return Err(
+                            deny_read(&ctx, &mut tls, AuthRead::Tls, reason).await
+                        );
+                    }
+                };
                 let mut con = Channel::new::<
                     ServerCtx,
                     tokio_rustls::server::TlsStream<TcpStream>,
                 >(None, tls);
-                time::timeout(ctx.cfg.hello_timeout, con.send_one(&AuthRead::Tls))
+                time::timeout(ctx.cfg.hello_timeout, con.send_one(&reply(AuthRead::Tls)))
                     .await
                     .context("saying hello")??;
                 (con, uifo)
             }
-            SecCtx::Anonymous | SecCtx::Local(_) | SecCtx::Krb5(_) => bail!(NO),
+            SecCtx::Anonymous | SecCtx::Local(_) | SecCtx::Krb5(_) => {
+                return Err(deny_tls_handshake(&ctx, &mut con, NO).await);
+            }
         },
     };
-    Ok(client_loop_read(ctx, con, server_stop, uifo).await?)
+    set_large_frames(&ctx, &mut con, large_frames);
+    Ok(client_loop_read(ctx, client, con, server_stop, uifo).await?)
 }
 
 async fn hello_client(
     ctx: Arc<Ctx>,
     connection_id: CId,
+    client: SocketAddr,
     mut s: TcpStream,
     server_stop: oneshot::Receiver<()>,
 ) -> Result<()> {
@@ -839,26 +1185,65 @@ async fn hello_client(
         bail!("unsupported protocol version")
     }
     let hello: ClientHello = recv(ctx.cfg.hello_timeout, &mut s).await?;
+    let access = ctx.cfg.anonymous_access;
     match hello {
-        ClientHello::ReadOnly(hello) => {
+        ClientHello::ReadOnly(AuthRead::Anonymous, _)
+            if access == AnonymousAccess::Deny =>
+        {
+            let reason = "anonymous read not permitted";
+            Err(deny_read(&ctx, &mut s, AuthRead::Anonymous, reason).await)
+        }
+        ClientHello::WriteOnly(ClientHelloWrite {
+            auth: AuthWrite::Anonymous, ..
+        }) if access != AnonymousAccess::ReadWrite => {
+            bail!("anonymous write not permitted")
+        }
+        ClientHello::ReadOnly(hello, large_frames) => {
             if let Some(t) = ctx.delay_reads {
                 if Instant::now() < t {
                     bail!("no read clients allowed yet");
                 }
             }
-            Ok(hello_client_read(ctx, s, server_stop, hello).await?)
+            Ok(hello_client_read(ctx, client, s, server_stop, hello, large_frames)
+                .await?)
         }
         ClientHello::WriteOnly(hello) => {
-            Ok(hello_client_write(ctx, connection_id, s, server_stop, hello).await?)
+            Ok(hello_client_write(ctx, connection_id, client, s, server_stop, hello)
+                .await?)
         }
     }
 }
 
+async fn reject_client(timeout: Duration, mut s: TcpStream) -> Result<()> {
+    send(timeout, &mut s, &AT_CAPACITY).await?;
+    // closing with the client's version unread would reset the
+    // connection, and the client might never see ours
+    let _: u64 = recv(timeout, &mut s).await?;
+    Ok(())
+}
+
+async fn accept_any(
+    listeners: &[TcpListener],
+) -> std::io::Result<(TcpStream, SocketAddr)> {
+    future::select_all(listeners.iter().map(|l| Box::pin(l.accept()))).await.0
+}
+
+fn listen(addr: &SocketAddr, member: &MemberServer) -> Result<TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    #[cfg(not(windows))]
+    socket.set_reuseaddr(member.reuse_addr)?;
+    socket.bind(*addr)?;
+    Ok(socket.listen(member.listen_backlog)?)
+}
+
 async fn server_loop(
     cfg: Config,
     delay_reads: bool,
     stop: oneshot::Receiver<()>,
-    ready: oneshot::Sender<SocketAddr>,
+    ready: oneshot::Sender<(SocketAddr, Store, SecCtx)>,
     id: usize,
     listener: Option<TcpListener>,
 ) -> Result<()> {
@@ -870,35 +1255,51 @@ async fn server_loop(
     let id = member.addr;
     debug!("creating security context");
     let secctx = SecCtx::new(&cfg, &member).await?;
+    let audit = match &member.audit_log {
+        None => None,
+        Some(path) => Some(AuditLog::open(path.clone(), member.audit_log_max_size)?),
+    };
     debug!("creating resolver store");
     let store = Store::new(
         cfg.parent.clone().map(|s| s.into()),
         cfg.children.iter().map(|(p, s)| (p.clone(), s.clone().into())).collect(),
         secctx.clone(),
         id,
+        member.max_published,
+        member.evict_idle_anonymous,
+        audit,
     );
-    let listen_addr = SocketAddr::new(member.bind_addr, id.port());
-    debug!("creating tcp listener on {:?}", listen_addr);
-    let listener = match listener {
-        None => TcpListener::bind(listen_addr).await?,
-        Some(listener) => listener,
+    // the first bind address is the main one, if the caller already
+    // bound it we still need to listen on the additional ones
+    let mut listeners = match listener {
+        Some(listener) => vec![listener],
+        None => {
+            debug!("creating tcp listener on {:?}", member.bind_addrs[0]);
+            vec![listen(&member.bind_addrs[0], &member)?]
+        }
     };
+    for listen_addr in member.bind_addrs[1..].iter() {
+        debug!("creating tcp listener on {:?}", listen_addr);
+        listeners.push(listen(listen_addr, &member)?);
+    }
     let ctx = Arc::new(Ctx {
         cfg: member,
-        secctx,
+        secctx: secctx.clone(),
         clinfos: Clinfos::new(),
         ctracker: CTracker::new(),
         id,
+        instance: rng().random(),
         delay_reads,
-        store,
+        store: store.clone(),
     });
     let mut stop = stop.fuse();
     let mut client_stops: Vec<oneshot::Sender<()>> = Vec::new();
     let max_connections = ctx.cfg.max_connections;
+    let rejecting = Arc::new(Semaphore::new(MAX_REJECTING));
     debug!("signaling ready");
-    let mut listen_addr = listener.local_addr()?;
+    let mut listen_addr = listeners[0].local_addr()?;
     listen_addr.set_ip(id.ip());
-    let _ = ready.send(listen_addr);
+    let _ = ready.send((listen_addr, store, secctx));
     loop {
         select_biased! {
             _ = stop => {
@@ -908,9 +1309,22 @@ async fn server_loop(
                 }
                 return Ok(())
             },
-            cl = listener.accept().fuse() => match cl {
+            cl = accept_any(&listeners).fuse() => match cl {
                 Err(e) => warn!("accept failed: {}", e),
-                Ok((client, _)) => {
+                Ok((client, client_addr))
+                    if ctx.cfg.at_capacity == AtCapacity::Reject
+                        && ctx.ctracker.num_open() >= max_connections =>
+                {
+                    warn!("client={client_addr} rejected, at capacity");
+                    if let Ok(permit) = Arc::clone(&rejecting).try_acquire_owned() {
+                        let timeout = ctx.cfg.hello_timeout;
+                        task::spawn(async move {
+                            let _ = reject_client(timeout, client).await;
+                            drop(permit)
+                        });
+                    }
+                }
+                Ok((client, client_addr)) => {
                     let (tx, rx) = oneshot::channel();
                     client_stops.push(tx);
                     let connection_id = ctx.ctracker.open();
@@ -920,11 +1334,12 @@ async fn server_loop(
                             let r = hello_client(
                                 Arc::clone(&ctx),
                                 connection_id,
+                                client_addr,
                                 client,
                                 rx
                             ).await;
                             ctx.ctracker.close(connection_id);
-                            info!("server_loop client shutting down {:?}", r);
+                            info!("client={client_addr} server_loop client shutting down {:?}", r);
                         }
                     });
                     while ctx.ctracker.num_open() > max_connections {
@@ -938,10 +1353,20 @@ async fn server_loop(
 }
 
 /// Run a resolver server
+///
+/// This is a handle to the running server, which is enough to embed
+/// the resolver in a larger program. It can report the size of the
+/// store, publish statistics, change the permissions, and stop the
+/// server.
 #[derive(Debug)]
 pub struct Server {
     stop: Option<oneshot::Sender<()>>,
+    task: Option<task::JoinHandle<Result<()>>>,
     local_addr: SocketAddr,
+    cfg: Config,
+    store: Store,
+    secctx: SecCtx,
+    stop_stats: Option<oneshot::Sender<()>>,
 }
 
 impl Drop for Server {
@@ -967,7 +1392,8 @@ impl Server {
     pub async fn new(cfg: Config, delay_reads: bool, id: usize) -> Result<Server> {
         let (send_stop, recv_stop) = oneshot::channel();
         let (send_ready, recv_ready) = oneshot::channel();
-        task::spawn(async move {
+        let server_cfg = cfg.clone();
+        let task = task::spawn(async move {
             let res =
                 server_loop(cfg, delay_reads, recv_stop, send_ready, id, None).await;
             match &res {
@@ -976,11 +1402,19 @@ impl Server {
             }
             res
         });
-        let local_addr = match recv_ready.await {
+        let (local_addr, store, secctx) = match recv_ready.await {
             Err(_) => bail!("resolver server shutdown"),
-            Ok(addr) => addr,
+            Ok(r) => r,
         };
-        Ok(Server { stop: Some(send_stop), local_addr })
+        Ok(Server {
+            stop: Some(send_stop),
+            task: Some(task),
+            local_addr,
+            cfg: server_cfg,
+            store,
+            secctx,
+            stop_stats: None,
+        })
     }
 
     /// Start a new local only resolver server
@@ -1005,7 +1439,8 @@ impl Server {
         }
         let (send_stop, recv_stop) = oneshot::channel();
         let (send_ready, recv_ready) = oneshot::channel();
-        task::spawn(async move {
+        let server_cfg = cfg.clone();
+        let task = task::spawn(async move {
             let res =
                 server_loop(cfg, false, recv_stop, send_ready, 0, Some(listener)).await;
             match &res {
@@ -1014,15 +1449,77 @@ impl Server {
             }
             res
         });
-        let local_addr = match recv_ready.await {
+        let (local_addr, store, secctx) = match recv_ready.await {
             Err(_) => bail!("resolver server shutdown"),
-            Ok(addr) => addr,
+            Ok(r) => r,
         };
-        Ok(Server { stop: Some(send_stop), local_addr })
+        Ok(Server {
+            stop: Some(send_stop),
+            task: Some(task),
+            local_addr,
+            cfg: server_cfg,
+            store,
+            secctx,
+            stop_stats: None,
+        })
     }
 
     /// Get the local address this resolver server is bound to
     pub fn local_addr(&self) -> &SocketAddr {
         &self.local_addr
     }
+
+    /// The number of (path, publisher) pairs in the store, not
+    /// counting default publishers.
+    pub fn published(&self) -> usize {
+        self.store.published()
+    }
+
+    /// Replace the permissions with `perms`. They are checked in the
+    /// same way as the perms in the config file, and if they are
+    /// invalid an error is returned and the old permissions stay in
+    /// effect. The new permissions apply to every request processed
+    /// after this returns, including from clients that are already
+    /// connected. It is an error to set permissions on a server with
+    /// anonymous auth.
+    pub async fn set_perms(&mut self, perms: PMap) -> Result<()> {
+        self.secctx.set_perms(&self.cfg, &perms).await?;
+        self.cfg.perms = perms;
+        Ok(())
+    }
+
+    /// Stop the server and wait for it to shut down. When this
+    /// returns the listening sockets are closed and every client
+    /// connection has been told to stop. Dropping the server stops
+    /// it too, but without waiting.
+    pub async fn shutdown(mut self) -> Result<()> {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        match self.task.take() {
+            None => Ok(()),
+            Some(task) => task.await?,
+        }
+    }
+
+    /// Publish aggregate statistics about this server's store under
+    /// `base` with `publisher`. The values are recomputed at most
+    /// once a second, after paths are published or unpublished.
+    ///
+    /// - `base/published`: the number of (path, publisher) pairs
+    /// - `base/paths`: the number of distinct paths
+    /// - `base/publishers`: the number of distinct publishers
+    /// - `base/subtrees/<name>/{paths, publishers}`: the same, for
+    ///   each immediate child of the root
+    ///
+    /// Default publishers are not counted. Each member server only
+    /// knows about the publishers that wrote to it, so in a cluster
+    /// each member should publish under its own base. Calling this
+    /// again replaces the previous stats publisher, and the stats
+    /// stop when the server is dropped.
+    pub fn publish_stats(&mut self, publisher: crate::publisher::Publisher, base: Path) {
+        let (tx, rx) = oneshot::channel();
+        self.stop_stats = Some(tx);
+        task::spawn(stats::run(self.store.clone(), publisher, base, rx));
+    }
 }
@@ -1,6 +1,6 @@
 use super::{
-    auth::{PMap, UserDb},
-    config::{Auth, Config, MemberServer},
+    auth::{Namespaces, PMap, UserDb},
+    config::{self, Auth, Config, MemberServer},
 };
 use crate::{
     channel::K5CtxWrap,
@@ -17,7 +17,7 @@ use cross_krb5::{K5Ctx, ServerCtx};
 use log::debug;
 use netidx_core::pack::Pack;
 use nohash::IntMap;
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, fmt, sync::Arc};
 use tokio::sync::{RwLock, RwLockReadGuard};
 
 pub(super) struct LocalAuth(AuthServer);
@@ -50,6 +50,7 @@ pub(super) trait SecDataCommon {
 pub(super) struct SecCtxData<S: 'static> {
     pub(super) users: UserDb,
     pub(super) pmap: PMap,
+    pub(super) namespaces: Namespaces,
     data: IntMap<PublisherId, S>,
 }
 
@@ -58,7 +59,8 @@ impl<S: 'static + SecDataCommon> SecCtxData<S> {
         let mut users =
             UserDb::new(member.id_map_timeout, Mapper::new(cfg, member).await?);
         let pmap = PMap::from_file(&cfg.perms, &mut users, cfg.root(), &cfg.children)?;
-        Ok(Self { users, pmap, data: HashMap::default() })
+        let namespaces = Namespaces::from_file(&cfg.namespaces)?;
+        Ok(Self { users, pmap, namespaces, data: HashMap::default() })
     }
 
     pub(super) fn remove(&mut self, id: &PublisherId) {
@@ -145,6 +147,15 @@ impl<'a> SecCtxDataReadGuard<'a> {
             SecCtxDataReadGuard::Tls(r) => Some(&r.pmap),
         }
     }
+
+    pub(super) fn namespaces(&'a self) -> Option<&'a Namespaces> {
+        match self {
+            SecCtxDataReadGuard::Anonymous => None,
+            SecCtxDataReadGuard::Krb5(r) => Some(&r.namespaces),
+            SecCtxDataReadGuard::Local(r) => Some(&r.namespaces),
+            SecCtxDataReadGuard::Tls(r) => Some(&r.namespaces),
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -155,6 +166,17 @@ pub(super) enum SecCtx {
     Tls(Arc<(tokio_rustls::TlsAcceptor, RwLock<SecCtxData<TlsSecData>>)>),
 }
 
+impl fmt::Debug for SecCtx {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SecCtx::Anonymous => write!(f, "Anonymous"),
+            SecCtx::Krb5(a) => write!(f, "Krb5({})", a.0),
+            SecCtx::Local(_) => write!(f, "Local"),
+            SecCtx::Tls(_) => write!(f, "Tls"),
+        }
+    }
+}
+
 impl SecCtx {
     pub(super) async fn new(cfg: &Config, member: &MemberServer) -> Result<Self> {
         let t = match &member.auth {
@@ -190,6 +212,30 @@ impl SecCtx {
         }
     }
 
+    /// Replace the permissions with `perms`, checked against `cfg`
+    /// in the same way as the permissions in the config file. If
+    /// they are invalid the old permissions are kept.
+    pub(super) async fn set_perms(
+        &self,
+        cfg: &Config,
+        perms: &config::PMap,
+    ) -> Result<()> {
+        macro_rules! set {
+            ($a:expr) => {{
+                let mut t = $a.1.write().await;
+                let t = &mut *t;
+                t.pmap = PMap::from_file(perms, &mut t.users, cfg.root(), &cfg.children)?;
+            }};
+        }
+        match self {
+            SecCtx::Anonymous => bail!("anonymous servers have no permissions"),
+            SecCtx::Krb5(a) => set!(a),
+            SecCtx::Local(a) => set!(a),
+            SecCtx::Tls(a) => set!(a),
+        }
+        Ok(())
+    }
+
     pub(super) async fn remove(&self, id: &PublisherId) {
         match self {
             SecCtx::Krb5(a) => a.1.write().await.remove(id),
@@ -1,5 +1,6 @@
 use super::{
-    auth::{Permissions, UserInfo},
+    audit::AuditLog,
+    auth::{Permissions, UserInfo, ANONYMOUS},
     secctx::{SecCtx, SecCtxDataReadGuard},
     store::{self, COLS_POOL, MAX_READ_BATCH, MAX_WRITE_BATCH, PATH_POOL, REF_POOL},
 };
@@ -10,13 +11,14 @@ use crate::{
     protocol::{
         glob::Scope,
         resolver::{
-            FromRead, FromWrite, GetChangeNr, ListMatching, Publisher, PublisherId,
-            Referral, Resolved, Table, ToRead, ToWrite,
+            FromRead, FromWrite, GetChangeNr, ListMatching, PathStatus, Publisher,
+            PublisherId, Referral, Resolved, Table, ToRead, ToWrite,
         },
     },
 };
 use ahash::{AHashMap, AHashSet, AHasher};
 use anyhow::Result;
+use arcstr::literal;
 use chrono::prelude::*;
 use futures::{
     channel::{
@@ -27,8 +29,9 @@ use futures::{
     prelude::*,
     select,
 };
-use log::{info, trace};
-use nohash::IntMap;
+use log::{error, info, trace, warn};
+use nohash::{IntMap, IntSet};
+use parking_lot::Mutex;
 use poolshark::global::{GPooled, Pool};
 use std::{
     collections::{BTreeMap, VecDeque},
@@ -37,10 +40,13 @@ use std::{
     net::SocketAddr,
     ops::Deref,
     result,
-    sync::{Arc, LazyLock},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, LazyLock,
+    },
     time::SystemTime,
 };
-use tokio::task;
+use tokio::{sync::Notify, task, time::Instant};
 
 type ReadB = Vec<(u64, ToRead)>;
 type ReadR = VecDeque<(u64, FromRead)>;
@@ -87,6 +93,8 @@ struct Shard {
     read: UnboundedSender<(ReadRequest, oneshot::Sender<ReadResponse>)>,
     write: UnboundedSender<(WriteRequest, oneshot::Sender<GPooled<WriteR>>)>,
     internal: UnboundedSender<(PublisherId, oneshot::Sender<AHashSet<Path>>)>,
+    count: UnboundedSender<(PublisherId, oneshot::Sender<(usize, usize)>)>,
+    stats: UnboundedSender<oneshot::Sender<AHashMap<Path, store::SubtreeStats>>>,
 }
 
 impl Shard {
@@ -96,15 +104,21 @@ impl Shard {
         children: BTreeMap<Path, Referral>,
         secctx: SecCtx,
         resolver: SocketAddr,
+        published: Arc<AtomicUsize>,
+        max_published: Option<usize>,
+        changed: Arc<Notify>,
     ) -> Self {
         let (read, read_rx) = unbounded();
         let (write, write_rx) = unbounded();
         let (internal, mut internal_rx) = unbounded();
+        let (count, mut count_rx) = unbounded();
+        let (stats, mut stats_rx) = unbounded();
         let mut read_rx = read_rx.fuse();
         let mut write_rx = write_rx.fuse();
-        let t = Shard { read, write, internal };
+        let t = Shard { read, write, internal, count, stats };
         task::spawn(async move {
             let mut last_shrink = Utc::now();
+            let mut last_prune = Utc::now();
             let mut store = store::Store::new(parent, children);
             loop {
                 select! {
@@ -129,9 +143,12 @@ impl Shard {
                             let r = Shard::process_write_batch(
                                 &mut store,
                                 &secctx,
+                                &published,
+                                max_published,
                                 req
                             ).await;
                             let _ = reply.send(r);
+                            changed.notify_one();
                         }
                     },
                     id = internal_rx.next() => match id {
@@ -139,9 +156,25 @@ impl Shard {
                         Some((id, reply)) => {
                             let _ = reply.send(store.published_for_id(&id));
                         }
+                    },
+                    id = count_rx.next() => match id {
+                        None => break,
+                        Some((id, reply)) => {
+                            let _ = reply.send(store.published_count_for_id(&id));
+                        }
+                    },
+                    reply = stats_rx.next() => match reply {
+                        None => break,
+                        Some(reply) => {
+                            let _ = reply.send(store.subtree_stats());
+                        }
                     }
                 }
                 let now = Utc::now();
+                if now - last_prune > chrono::Duration::minutes(1) {
+                    last_prune = now;
+                    store.prune_expired()
+                }
                 if now - last_shrink > chrono::Duration::hours(1) {
                     last_shrink = now;
                     store.shrink_to_fit()
@@ -184,12 +217,18 @@ impl Shard {
                             None => {
                                 let (flags, publishers) =
                                     store.resolve(&mut resp.publishers, &path);
+                                let status = if publishers.is_empty() {
+                                    store.path_status(&path)
+                                } else {
+                                    PathStatus::Published
+                                };
                                 let a = Resolved {
                                     resolver,
                                     publishers,
                                     timestamp: now,
                                     permissions: Permissions::all().bits(),
                                     flags,
+                                    status,
                                 };
                                 (id, FromRead::Resolved(a))
                             }
@@ -206,12 +245,18 @@ impl Shard {
                                         perm,
                                         &path,
                                     );
+                                    let status = if publishers.is_empty() {
+                                        store.path_status(&path)
+                                    } else {
+                                        PathStatus::Published
+                                    };
                                     let a = Resolved {
                                         resolver,
                                         publishers,
                                         timestamp: now,
                                         permissions: perm.bits(),
                                         flags,
+                                        status,
                                     };
                                     (id, FromRead::Resolved(a))
                                 }
@@ -268,6 +313,22 @@ impl Shard {
                         (id, FromRead::ListMatching(lm))
                     }
                 }
+                ToRead::ListByAddr(addr) => {
+                    n += 100;
+                    let allowed = pmap
+                        .map(|pmap| pmap.allowed(store.root(), Permissions::LIST, &*uifo))
+                        .unwrap_or(true);
+                    if !allowed {
+                        (id, FromRead::Denied)
+                    } else {
+                        let mut paths = store.published_by_addr(&addr);
+                        if let Some(pmap) = pmap {
+                            paths
+                                .retain(|p| pmap.allowed(&**p, Permissions::LIST, &*uifo))
+                        }
+                        (id, FromRead::List(paths))
+                    }
+                }
                 ToRead::GetChangeNr(path) => {
                     n += 1;
                     let mut referrals = REF_POOL.take();
@@ -312,11 +373,14 @@ impl Shard {
     async fn process_write_batch<'a>(
         store: &mut store::Store,
         secctx: &SecCtxDataReadGuard<'a>,
+        published: &AtomicUsize,
+        max_published: Option<usize>,
         mut req: WriteRequest,
     ) -> GPooled<WriteR> {
         let uifo = &*req.uifo;
         let publisher = req.publisher;
         let pmap = secctx.pmap();
+        let namespaces = secctx.namespaces();
         let publish = |s: &mut store::Store,
                        path: Path,
                        default: bool,
@@ -332,23 +396,32 @@ impl Shard {
                 } else {
                     Permissions::PUBLISH
                 };
-                if pmap.map(|p| p.allowed(&*path, perm, uifo)).unwrap_or(true) {
+                let full = max_published
+                    .map(|max| published.load(Ordering::Relaxed) >= max)
+                    .unwrap_or(false);
+                if !pmap.map(|p| p.allowed(&*path, perm, uifo)).unwrap_or(true) {
+                    FromWrite::Denied
+                } else if !namespaces.map(|n| n.allowed(&*path, uifo)).unwrap_or(true) {
+                    FromWrite::Denied
+                } else if full && !default && !s.is_published(&path, &publisher.id) {
+                    FromWrite::Error(literal!("store full"))
+                } else {
                     s.publish(path, &publisher, default, flags);
+                    s.set_owner(publisher.id, uifo);
                     FromWrite::Published
-                } else {
-                    FromWrite::Denied
                 }
             }
         };
         let mut resp = FROM_WRITE_POOL.take();
         let mut n = 0;
+        let mut len = store.published_len();
         for (id, m) in req.batch.drain(..) {
             if n > 5_000 {
                 n = 0;
                 task::yield_now().await;
             }
             resp.push(match m {
-                ToWrite::Heartbeat => unreachable!(),
+                ToWrite::Heartbeat | ToWrite::Status => unreachable!(),
                 ToWrite::Clear => {
                     n += 1000;
                     store.clear(&publisher);
@@ -392,7 +465,68 @@ impl Shard {
                         (id, FromWrite::Unpublished)
                     }
                 }
-            })
+                ToWrite::Republish { path, from, to } => {
+                    n += 5;
+                    if to != publisher.addr {
+                        let e = "republish target must be the sender";
+                        (id, FromWrite::Error(e.into()))
+                    } else {
+                        match store.publisher_by_addr(&from) {
+                            Some(old)
+                                if !store.is_owner(&old.id, &publisher.id, uifo) =>
+                            {
+                                (id, FromWrite::Denied)
+                            }
+                            old => {
+                                // keep the flags the path was published with
+                                let flags = store.flags(&path);
+                                match publish(store, path.clone(), false, flags) {
+                                    FromWrite::Published => {
+                                        // the new publisher is already in the
+                                        // set, so the path is never missing
+                                        if let Some(old) = old {
+                                            if old.id != publisher.id {
+                                                store.unpublish(&old, false, path);
+                                            }
+                                        }
+                                        (id, FromWrite::Published)
+                                    }
+                                    r => (id, r),
+                                }
+                            }
+                        }
+                    }
+                }
+                ToWrite::UnpublishSubtree(path) => {
+                    n += 100;
+                    if !Path::is_absolute(&*path) {
+                        (id, FromWrite::Error("absolute paths required".into()))
+                    } else if let Some(r) = store.check_referral(&path) {
+                        (id, FromWrite::Referral(r))
+                    } else {
+                        store.unpublish_subtree(&publisher, &path);
+                        // paths under a child referral were published
+                        // there, so they can't be unpublished from here
+                        match store.referral_under(&path) {
+                            None => (id, FromWrite::Unpublished),
+                            Some(r) => {
+                                let e = format!(
+                                    "{} is referred to another resolver, unpublish it there",
+                                    r.path
+                                );
+                                (id, FromWrite::Error(e.into()))
+                            }
+                        }
+                    }
+                }
+            });
+            let new_len = store.published_len();
+            if new_len > len {
+                published.fetch_add(new_len - len, Ordering::Relaxed);
+            } else if new_len < len {
+                published.fetch_sub(len - new_len, Ordering::Relaxed);
+            }
+            len = new_len;
         }
         resp
     }
@@ -421,11 +555,32 @@ pub(super) struct StoreInner {
     shards: Vec<Shard>,
     shard_mask: usize,
     tx_write: UnboundedSender<QueuedWrite>,
+    published: Arc<AtomicUsize>,
+    max_published: Option<usize>,
+    // notified after every write batch
+    pub(super) changed: Arc<Notify>,
+    evict_idle_anonymous: bool,
+    // the last time each anonymous writer was active, only
+    // maintained if evict_idle_anonymous is set
+    anonymous_writers: Mutex<IntMap<PublisherId, (Instant, Arc<Publisher>)>>,
+    // writers that were evicted while still connected, they will be
+    // asked to resync on their next heartbeat
+    evicted: Mutex<IntSet<PublisherId>>,
+    // only used by the write task
+    audit: Option<Mutex<AuditLog>>,
 }
 
 #[derive(Clone)]
 pub(super) struct Store(Arc<StoreInner>);
 
+impl std::fmt::Debug for Store {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Store")
+            .field("shards", &self.shards.len())
+            .finish_non_exhaustive()
+    }
+}
+
 impl Deref for Store {
     type Target = StoreInner;
 
@@ -440,17 +595,42 @@ impl Store {
         children: BTreeMap<Path, Referral>,
         secctx: SecCtx,
         resolver: SocketAddr,
+        max_published: Option<usize>,
+        evict_idle_anonymous: bool,
+        audit: Option<AuditLog>,
     ) -> Self {
         let shards = std::cmp::max(1, num_cpus::get().next_power_of_two());
         let shard_mask = shards - 1;
+        let published = Arc::new(AtomicUsize::new(0));
+        let changed = Arc::new(Notify::new());
         let shards = (0..shards)
             .into_iter()
             .map(|i| {
-                Shard::new(i, parent.clone(), children.clone(), secctx.clone(), resolver)
+                Shard::new(
+                    i,
+                    parent.clone(),
+                    children.clone(),
+                    secctx.clone(),
+                    resolver,
+                    published.clone(),
+                    max_published,
+                    changed.clone(),
+                )
             })
             .collect();
         let (tx_write, rx_write) = unbounded();
-        let t = Store(Arc::new(StoreInner { shards, shard_mask, tx_write }));
+        let t = Store(Arc::new(StoreInner {
+            shards,
+            shard_mask,
+            tx_write,
+            published,
+            max_published,
+            changed,
+            evict_idle_anonymous: evict_idle_anonymous && max_published.is_some(),
+            anonymous_writers: Mutex::new(IntMap::default()),
+            evicted: Mutex::new(IntSet::default()),
+            audit: audit.map(Mutex::new),
+        }));
         task::spawn({
             let t = t.clone();
             async { t.write_task(rx_write).await }
@@ -469,6 +649,10 @@ impl Store {
         let mut n = 0;
         let mut replies = FROM_WRITE_POOL.take();
         loop {
+            if self.is_full() && self.evict_idle_anonymous {
+                self.evict_idle_anonymous(&publisher).await?;
+            }
+            let full = self.is_full();
             let mut by_shard = self.write_shard_batch();
             for _ in 0..MAX_WRITE_BATCH {
                 match msgs.next() {
@@ -477,6 +661,12 @@ impl Store {
                         break;
                     }
                     Some(ToWrite::Heartbeat) => continue,
+                    // status is answered by the write loop when it is
+                    // sent alone
+                    Some(ToWrite::Status) => {
+                        let e = "status must be sent alone".into();
+                        replies.push((n, FromWrite::Error(e)));
+                    }
                     Some(ToWrite::Clear) => {
                         for b in by_shard.iter_mut() {
                             b.push((n, ToWrite::Clear));
@@ -495,11 +685,28 @@ impl Store {
                             b.push((n, ToWrite::UnpublishDefault(path.clone())));
                         }
                     }
+                    Some(ToWrite::UnpublishSubtree(path)) => {
+                        for b in by_shard.iter_mut() {
+                            b.push((n, ToWrite::UnpublishSubtree(path.clone())));
+                        }
+                    }
+                    // default publishes go to every shard, so they must be
+                    // rejected here to avoid the shards disagreeing
+                    Some(ToWrite::PublishDefault(_))
+                    | Some(ToWrite::PublishDefaultWithFlags(_, _))
+                        if full =>
+                    {
+                        replies.push((n, FromWrite::Error(literal!("store full"))));
+                    }
                     Some(ToWrite::PublishDefault(path)) => {
                         for b in by_shard.iter_mut() {
                             b.push((n, ToWrite::PublishDefault(path.clone())));
                         }
                     }
+                    Some(ToWrite::Republish { path, from, to }) => {
+                        let s = self.shard(&path);
+                        by_shard[s].push((n, ToWrite::Republish { path, from, to }));
+                    }
                     Some(ToWrite::PublishWithFlags(path, flags)) => {
                         let s = self.shard(&path);
                         by_shard[s].push((n, ToWrite::PublishWithFlags(path, flags)));
@@ -517,8 +724,10 @@ impl Store {
             }
             trace!("handle_write_batch dispatching {} messages to shards", n);
             if by_shard.iter().all(|v| v.is_empty()) {
-                assert!(finished);
-                break;
+                if finished {
+                    break;
+                }
+                continue;
             }
             let mut r = join_all(by_shard.drain(..).enumerate().map(|(i, batch)| {
                 let (tx, rx) = oneshot::channel();
@@ -537,10 +746,118 @@ impl Store {
         Ok(replies)
     }
 
+    fn is_full(&self) -> bool {
+        match self.max_published {
+            None => false,
+            Some(max) => self.published.load(Ordering::Relaxed) >= max,
+        }
+    }
+
+    /// Record that the specified writer was active. This is used to
+    /// pick a victim when the store is full and idle anonymous
+    /// writers may be evicted.
+    pub(super) fn writer_active(&self, publisher: &Arc<Publisher>) {
+        if self.evict_idle_anonymous && publisher.target_auth.is_anonymous() {
+            self.anonymous_writers
+                .lock()
+                .insert(publisher.id, (Instant::now(), publisher.clone()));
+        }
+    }
+
+    /// Return true, and forget it, if the specified writer was
+    /// evicted and there is now room for it to publish again. While
+    /// the store is still full evicted writers are not asked to
+    /// resync, otherwise they would just evict someone else.
+    pub(super) fn take_evicted(&self, publisher: &Arc<Publisher>) -> bool {
+        self.evict_idle_anonymous
+            && !self.is_full()
+            && self.evicted.lock().remove(&publisher.id)
+    }
+
+    /// True if the specified writer was evicted and has not yet been
+    /// asked to resync
+    pub(super) fn is_evicted(&self, publisher: &Arc<Publisher>) -> bool {
+        self.evict_idle_anonymous && self.evicted.lock().contains(&publisher.id)
+    }
+
+    // Clear everything published by the anonymous writer that has
+    // been idle the longest, except for `current`. This must only be
+    // called from the write task.
+    async fn evict_idle_anonymous(&self, current: &Arc<Publisher>) -> Result<()> {
+        let victim = {
+            let mut writers = self.anonymous_writers.lock();
+            let victim = writers
+                .values()
+                .filter(|(_, p)| p.id != current.id)
+                .min_by_key(|(last, _)| *last)
+                .map(|(_, p)| p.clone());
+            if let Some(victim) = &victim {
+                writers.remove(&victim.id);
+                self.evicted.lock().insert(victim.id);
+            }
+            victim
+        };
+        if let Some(victim) = victim {
+            warn!("store full, evicting idle anonymous writer {}", victim.addr);
+            if let Some(audit) = &self.audit {
+                if let Err(e) = audit.lock().evicted(&victim) {
+                    error!("failed to write the audit log {e:?}")
+                }
+            }
+            join_all(self.shards.iter().map(|shard| {
+                let (tx, rx) = oneshot::channel();
+                let mut batch = TO_WRITE_POOL.take();
+                batch.push((0, ToWrite::Clear));
+                let req = WriteRequest {
+                    uifo: ANONYMOUS.clone(),
+                    publisher: victim.clone(),
+                    batch,
+                };
+                let _ = shard.write.unbounded_send((req, tx));
+                rx
+            }))
+            .await
+            .into_iter()
+            .collect::<result::Result<Vec<GPooled<WriteR>>, Canceled>>()?;
+        }
+        Ok(())
+    }
+
     async fn write_task(self, mut rx: UnboundedReceiver<QueuedWrite>) {
         while let Some(mut w) = rx.next().await {
-            let msgs = w.msgs.drain(..);
-            let res = self.handle_queued_write(w.uifo, w.publisher, msgs).await;
+            let res = match &self.audit {
+                None => {
+                    let msgs = w.msgs.drain(..);
+                    self.handle_queued_write(w.uifo, w.publisher, msgs).await
+                }
+                // writes that can't be recorded are refused
+                Some(audit) if audit.lock().failed() => {
+                    let e = literal!("the audit log can't be written");
+                    let mut replies = FROM_WRITE_POOL.take();
+                    let msgs = w.msgs.iter().filter(|m| !matches!(m, ToWrite::Heartbeat));
+                    for (n, _) in (0..).zip(msgs) {
+                        replies.push((n, FromWrite::Error(e.clone())));
+                    }
+                    Ok(replies)
+                }
+                Some(audit) => {
+                    let (uifo, publisher) = (w.uifo.clone(), w.publisher.clone());
+                    let msgs = w.msgs.iter().cloned();
+                    let res = self.handle_queued_write(uifo, publisher, msgs).await;
+                    // a failed batch may have been partly applied,
+                    // so record every write in it as an error
+                    let replies = match &res {
+                        Ok(replies) => &replies[..],
+                        Err(_) => &[],
+                    };
+                    let mut audit = audit.lock();
+                    if let Err(e) = audit.record(&w.uifo, &w.publisher, &w.msgs, replies)
+                    {
+                        error!("failed to write the audit log {e:?}")
+                    }
+                    res
+                }
+            };
             let _ = w.result.send(res);
         }
         info!("write task shutting down")
@@ -610,6 +927,12 @@ impl Store {
                         }
                         c += 100000;
                     }
+                    Some(ToRead::ListByAddr(addr)) => {
+                        for b in by_shard.iter_mut() {
+                            b.push((n, ToRead::ListByAddr(addr)));
+                        }
+                        c += 10000;
+                    }
                 }
                 n += 1;
             }
@@ -798,6 +1121,55 @@ impl Store {
         Ok(())
     }
 
+    /// The total number of (path, publisher) pairs in the store, not
+    /// including default publishers
+    pub(super) fn published(&self) -> usize {
+        self.published.load(Ordering::Relaxed)
+    }
+
+    /// The number of paths, and the number of defaults, published by
+    /// the specified publisher across every shard
+    pub(super) async fn published_by(
+        &self,
+        publisher: &Arc<Publisher>,
+    ) -> Result<(usize, usize)> {
+        let (mut published, mut defaults) = (0, 0);
+        for r in join_all(self.shards.iter().map(|shard| {
+            let (tx, rx) = oneshot::channel();
+            let _ = shard.count.unbounded_send((publisher.id, tx));
+            rx
+        }))
+        .await
+        {
+            let (p, d) = r?;
+            published += p;
+            // defaults are stored in every shard
+            defaults = std::cmp::max(defaults, d);
+        }
+        Ok((published, defaults))
+    }
+
+    /// Merge the subtree stats of every shard
+    pub(super) async fn subtree_stats(
+        &self,
+    ) -> Result<AHashMap<Path, store::SubtreeStats>> {
+        let mut res: AHashMap<Path, store::SubtreeStats> = AHashMap::default();
+        for r in join_all(self.shards.iter().map(|shard| {
+            let (tx, rx) = oneshot::channel();
+            let _ = shard.stats.unbounded_send(tx);
+            rx
+        }))
+        .await
+        {
+            for (path, st) in r? {
+                let cur = res.entry(path).or_default();
+                cur.paths += st.paths;
+                cur.publishers.extend(st.publishers);
+            }
+        }
+        Ok(res)
+    }
+
     pub(super) async fn handle_clear(
         &self,
         uifo: Arc<UserInfo>,
@@ -805,6 +1177,10 @@ impl Store {
     ) -> Result<()> {
         use rand::{rng, seq::SliceRandom};
         trace!("clearing publisher {:?}", &publisher);
+        if self.evict_idle_anonymous {
+            self.anonymous_writers.lock().remove(&publisher.id);
+            self.evicted.lock().remove(&publisher.id);
+        }
         let mut published_paths = join_all(self.shards.iter().map(|shard| {
             let (tx, rx) = oneshot::channel();
             let _ = shard.internal.unbounded_send((publisher.id, tx));
@@ -0,0 +1,123 @@
+//! Publish aggregate statistics about the store as netidx values, so
+//! the resolver can be monitored with the normal subscriber tools.
+use super::shard_store::Store;
+use crate::{
+    path::Path,
+    publisher::{Publisher, Val, Value},
+};
+use ahash::AHashMap;
+use anyhow::Result;
+use futures::{channel::oneshot, prelude::*, select_biased};
+use log::warn;
+use nohash::IntSet;
+use std::time::Duration;
+use tokio::time;
+
+// the stats are recomputed at most this often
+const INTERVAL: Duration = Duration::from_secs(1);
+
+struct Subtree {
+    paths: Val,
+    publishers: Val,
+}
+
+struct Stats {
+    publisher: Publisher,
+    base: Path,
+    published: Val,
+    paths: Val,
+    publishers: Val,
+    subtrees: AHashMap<Path, Subtree>,
+}
+
+impl Stats {
+    fn new(publisher: Publisher, base: Path) -> Result<Self> {
+        let zero = || Value::from(0u64);
+        let published = publisher.publish(base.append("published"), zero())?;
+        let paths = publisher.publish(base.append("paths"), zero())?;
+        let publishers = publisher.publish(base.append("publishers"), zero())?;
+        Ok(Self {
+            publisher,
+            base,
+            published,
+            paths,
+            publishers,
+            subtrees: AHashMap::default(),
+        })
+    }
+
+    async fn update(&mut self, store: &Store) -> Result<()> {
+        let stats = store.subtree_stats().await?;
+        let mut batch = self.publisher.start_batch();
+        let mut paths = 0;
+        let mut publishers = IntSet::default();
+        for (path, st) in stats.iter() {
+            paths += st.paths;
+            publishers.extend(st.publishers.iter().copied());
+            let (npaths, npubs) =
+                (Value::from(st.paths as u64), Value::from(st.publishers.len() as u64));
+            match self.subtrees.get(path) {
+                Some(sub) => {
+                    sub.paths.update_changed(&mut batch, npaths);
+                    sub.publishers.update_changed(&mut batch, npubs);
+                }
+                None => {
+                    let name = Path::basename(path).unwrap_or("root");
+                    let base = self.base.append("subtrees").append(name);
+                    let sub = Subtree {
+                        paths: self.publisher.publish(base.append("paths"), npaths)?,
+                        publishers: self
+                            .publisher
+                            .publish(base.append("publishers"), npubs)?,
+                    };
+                    self.subtrees.insert(path.clone(), sub);
+                }
+            }
+        }
+        self.subtrees.retain(|path, _| stats.contains_key(path));
+        self.published.update_changed(&mut batch, Value::from(store.published() as u64));
+        self.paths.update_changed(&mut batch, Value::from(paths as u64));
+        self.publishers.update_changed(&mut batch, Value::from(publishers.len() as u64));
+        batch.commit(None).await;
+        Ok(())
+    }
+}
+
+/// Publish stats about `store` under `base` until `stop` is sent or
+/// dropped. The layout is,
+///
+/// - `base/published`: the number of (path, publisher) pairs
+/// - `base/paths`: the number of distinct paths
+/// - `base/publishers`: the number of distinct publishers
+/// - `base/subtrees/<name>/{paths, publishers}`: the same, for each
+///   immediate child of the root
+///
+/// Default publishers are not counted.
+pub(super) async fn run(
+    store: Store,
+    publisher: Publisher,
+    base: Path,
+    stop: oneshot::Receiver<()>,
+) {
+    let mut stats = match Stats::new(publisher, base) {
+        Ok(stats) => stats,
+        Err(e) => {
+            warn!("failed to publish resolver stats {e:?}");
+            return;
+        }
+    };
+    let mut stop = stop.fuse();
+    loop {
+        if let Err(e) = stats.update(&store).await {
+            warn!("failed to update resolver stats {e:?}")
+        }
+        select_biased! {
+            _ = stop => break,
+            () = store.changed.notified().fuse() => (),
+        }
+        select_biased! {
+            _ = stop => break,
+            () = time::sleep(INTERVAL).fuse() => (),
+        }
+    }
+}
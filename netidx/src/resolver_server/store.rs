@@ -7,15 +7,16 @@ use crate::{
     path::Path,
     protocol::{
         glob::{GlobSet, Scope},
-        resolver::{Publisher, PublisherId, PublisherRef, Referral},
+        resolver::{PathStatus, Publisher, PublisherId, PublisherRef, Referral},
     },
     utils,
 };
 use ahash::{AHashMap, AHashSet};
+use arcstr::ArcStr;
 use bytes::Bytes;
 use immutable_chunkmap::set::Set as ISet;
 use log::debug;
-use nohash::IntMap;
+use nohash::{IntMap, IntSet};
 use poolshark::global::{GPooled, Pool};
 use std::{
     clone::Clone,
@@ -23,12 +24,14 @@ use std::{
         hash_map::Entry,
         BTreeMap,
         Bound::{self, Excluded, Included, Unbounded},
+        VecDeque,
     },
     convert::AsRef,
     hash::Hash,
     iter::{self, FromIterator},
     net::SocketAddr,
     sync::{Arc, LazyLock},
+    time::{Duration, SystemTime},
 };
 
 static SIGNED_PUBS_POOL: LazyLock<Pool<Vec<PublisherRef>>> =
@@ -43,9 +46,35 @@ pub(super) static REF_POOL: LazyLock<Pool<Vec<Referral>>> =
     LazyLock::new(|| Pool::new(100, 100));
 
 type Set<T> = ISet<T, 8>;
+
+/// Aggregate statistics about one subtree of the store
+#[derive(Debug, Default)]
+pub(super) struct SubtreeStats {
+    pub(super) paths: usize,
+    pub(super) publishers: IntSet<PublisherId>,
+}
+
+// The number of paths, and the number of paths of each publisher,
+// under one immediate child of the root
+#[derive(Debug, Default)]
+struct SubtreeCounts {
+    paths: usize,
+    publishers: IntMap<PublisherId, usize>,
+}
 pub(super) const MAX_WRITE_BATCH: usize = 100_000;
 pub(super) const MAX_READ_BATCH: usize = 1_000_000;
 pub(super) const GC_THRESHOLD: usize = 100_000;
+// how long the store remembers that a path used to be published
+pub(super) const EXPIRED_RETENTION: Duration = Duration::from_secs(600);
+// the most recently unpublished paths the store remembers
+pub(super) const MAX_EXPIRED: usize = 100_000;
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
 
 fn with_trailing<R, F: FnOnce(&str) -> R>(p: &str, f: F) -> R {
     use std::{cell::RefCell, fmt::Write};
@@ -127,7 +156,19 @@ fn column_path_parts<S: AsRef<str>>(path: &S) -> Option<(&str, &str)> {
 pub(super) struct Store {
     publishers_by_id: IntMap<PublisherId, Arc<Publisher>>,
     publishers_by_addr: AHashMap<SocketAddr, PublisherId>,
+    // the authenticated user behind each publisher, if any
+    owners: IntMap<PublisherId, ArcStr>,
     published_by_path: AHashMap<Path, Set<PublisherId>>,
+    // kept up to date by publish and unpublish, keyed by the name of
+    // the child of the root
+    subtrees: AHashMap<ArcStr, SubtreeCounts>,
+    // when each recently unpublished path lost its last publisher,
+    // and the sequence number of that expiry
+    expired: AHashMap<Path, (u64, u64)>,
+    // the same, in the order they expired, oldest first. Entries
+    // whose sequence number no longer matches are stale.
+    expired_order: VecDeque<(u64, u64, Path)>,
+    expired_seq: u64,
     flags_by_path: AHashMap<Path, u32>,
     published_by_id: IntMap<PublisherId, AHashSet<Path>>,
     published_by_level: IntMap<usize, BTreeMap<Path, Z64>>,
@@ -137,6 +178,7 @@ pub(super) struct Store {
     parent: Option<Referral>,
     children: BTreeMap<Path, Referral>,
     sets: HCSet<PublisherId>,
+    published: usize,
 }
 
 impl Store {
@@ -147,7 +189,12 @@ impl Store {
         let mut t = Store {
             publishers_by_id: IntMap::default(),
             publishers_by_addr: AHashMap::default(),
+            owners: IntMap::default(),
             published_by_path: AHashMap::default(),
+            subtrees: AHashMap::default(),
+            expired: AHashMap::default(),
+            expired_order: VecDeque::new(),
+            expired_seq: 0,
             flags_by_path: AHashMap::default(),
             published_by_id: IntMap::default(),
             published_by_level: IntMap::default(),
@@ -157,6 +204,7 @@ impl Store {
             parent,
             children,
             sets: HCSet::new(),
+            published: 0,
         };
         let children = t.children.keys().cloned().collect::<Vec<_>>();
         for child in children {
@@ -173,7 +221,11 @@ impl Store {
     pub(crate) fn shrink_to_fit(&mut self) {
         self.publishers_by_id.shrink_to_fit();
         self.publishers_by_addr.shrink_to_fit();
+        self.owners.shrink_to_fit();
         self.published_by_path.shrink_to_fit();
+        self.subtrees.shrink_to_fit();
+        self.expired.shrink_to_fit();
+        self.expired_order.shrink_to_fit();
         self.flags_by_path.shrink_to_fit();
         self.published_by_id.shrink_to_fit();
         for v in self.published_by_id.values_mut() {
@@ -254,6 +306,11 @@ impl Store {
         }
     }
 
+    /// The root of the namespace this store is responsible for
+    pub(super) fn root(&self) -> &str {
+        self.parent.as_ref().map(|r| r.path.as_ref()).unwrap_or("/")
+    }
+
     pub(super) fn check_referral(&self, path: &Path) -> Option<Referral> {
         if let Some(r) = self.parent.as_ref() {
             if !Path::is_parent(&r.path, path) {
@@ -278,6 +335,19 @@ impl Store {
         }
     }
 
+    /// The first referral to a child resolver strictly under `base`,
+    /// if there is one
+    pub(super) fn referral_under(&self, base: &Path) -> Option<&Referral> {
+        self.children
+            .range::<str, (Bound<&str>, Bound<&str>)>((
+                Excluded(base.as_ref()),
+                Unbounded,
+            ))
+            .take_while(|(p, _)| p.starts_with(base.as_ref()))
+            .find(|(p, _)| Path::is_parent(base, p))
+            .map(|(_, r)| r)
+    }
+
     pub(super) fn referrals_in_scope<T: AsRef<str> + ?Sized>(
         &self,
         refs: &mut Vec<Referral>,
@@ -363,6 +433,7 @@ impl Store {
             self.publishers_by_addr.insert(publisher.addr, publisher.id);
             p
         });
+        let id = publisher.id;
         let up = if default {
             let pubs = self.defaults.entry(path.clone()).or_insert_with(Set::new);
             let len = pubs.len();
@@ -383,7 +454,10 @@ impl Store {
                 .insert(path.clone());
             let up = pubs.len() > len;
             if up {
+                self.published += 1;
                 self.add_column(&path);
+                self.expired.remove(&path);
+                self.count_subtree(&path, id, len == 0);
             }
             up
         };
@@ -458,13 +532,18 @@ impl Store {
                             *pubs = new_pubs;
                             let up = pubs.len() < len;
                             if up {
+                                self.published -= 1;
                                 self.remove_column(&path);
+                                self.uncount_subtree(&path, &publisher.id, false);
                             }
                             up
                         }
                         None => {
+                            self.published -= 1;
                             self.published_by_path.remove(&path);
+                            self.add_expired(path.clone());
                             self.remove_column(&path);
+                            self.uncount_subtree(&path, &publisher.id, true);
                             true
                         }
                     }
@@ -494,14 +573,173 @@ impl Store {
             {
                 self.publishers_by_id.remove(&publisher.id);
                 self.publishers_by_addr.remove(&publisher.addr);
+                self.owners.remove(&publisher.id);
             }
         }
     }
 
+    /// The number of (path, publisher) pairs currently published,
+    /// not including default publishers.
+    pub(super) fn published_len(&self) -> usize {
+        self.published
+    }
+
+    /// Why `path` resolves to no publishers, `Expired` if it lost its
+    /// last publisher within `EXPIRED_RETENTION`, otherwise `Unknown`.
+    pub(super) fn path_status(&self, path: &Path) -> PathStatus {
+        match self.expired.get(path) {
+            Some((last_seen, _)) => PathStatus::Expired { last_seen: *last_seen },
+            None => PathStatus::Unknown,
+        }
+    }
+
+    fn add_expired(&mut self, path: Path) {
+        let now = unix_now();
+        self.expired_seq += 1;
+        self.expired.insert(path.clone(), (now, self.expired_seq));
+        self.expired_order.push_back((now, self.expired_seq, path));
+        while self.expired_order.len() > MAX_EXPIRED {
+            self.pop_expired();
+        }
+    }
+
+    fn pop_expired(&mut self) {
+        if let Some((_, seq, path)) = self.expired_order.pop_front() {
+            if let Entry::Occupied(e) = self.expired.entry(path) {
+                if e.get().1 == seq {
+                    e.remove();
+                }
+            }
+        }
+    }
+
+    /// Forget paths that lost their last publisher more than
+    /// `EXPIRED_RETENTION` ago.
+    pub(super) fn prune_expired(&mut self) {
+        let cutoff = unix_now().saturating_sub(EXPIRED_RETENTION.as_secs());
+        while self.expired_order.front().map(|(ts, _, _)| *ts <= cutoff).unwrap_or(false)
+        {
+            self.pop_expired();
+        }
+    }
+
+    /// Return true if `id` is currently publishing `path`, not
+    /// including default publishers.
+    pub(super) fn is_published(&self, path: &Path, id: &PublisherId) -> bool {
+        self.published_by_path.get(path).map(|pubs| pubs.contains(id)).unwrap_or(false)
+    }
+
+    /// The flags `path` was published with, if any were given
+    pub(super) fn flags(&self, path: &str) -> Option<u32> {
+        self.flags_by_path.get(path).copied()
+    }
+
     pub(super) fn published_for_id(&self, id: &PublisherId) -> AHashSet<Path> {
         self.published_by_id.get(id).map(|s| s.clone()).unwrap_or_else(AHashSet::new)
     }
 
+    /// The number of paths, and the number of defaults, published by
+    /// the specified publisher
+    pub(super) fn published_count_for_id(&self, id: &PublisherId) -> (usize, usize) {
+        let published = self.published_by_id.get(id).map(|s| s.len()).unwrap_or(0);
+        let defaults = self.defaults_by_id.get(id).map(|s| s.len()).unwrap_or(0);
+        (published, defaults)
+    }
+
+    /// All the paths, including defaults, published by the
+    /// publisher with the specified write address.
+    pub(super) fn published_by_addr(&self, addr: &SocketAddr) -> GPooled<Vec<Path>> {
+        let mut paths = PATH_POOL.take();
+        if let Some(id) = self.publishers_by_addr.get(addr) {
+            let published = self.published_by_id.get(id).into_iter().flatten();
+            let defaults = self.defaults_by_id.get(id).into_iter().flatten();
+            paths.extend(published.chain(defaults).cloned());
+        }
+        paths
+    }
+
+    /// The publisher with the specified write address, if it has
+    /// anything published in this store.
+    pub(super) fn publisher_by_addr(&self, addr: &SocketAddr) -> Option<Arc<Publisher>> {
+        let id = self.publishers_by_addr.get(addr)?;
+        self.publishers_by_id.get(id).cloned()
+    }
+
+    /// Remember the authenticated user behind a publisher that has
+    /// something published in this store. Anonymous publishers have
+    /// no owner.
+    pub(super) fn set_owner(&mut self, id: PublisherId, uifo: &UserInfo) {
+        if let Some(u) = &uifo.user_info {
+            if self.publishers_by_id.contains_key(&id) {
+                self.owners.entry(id).or_insert_with(|| u.name.clone());
+            }
+        }
+    }
+
+    /// Return true if the user `uifo`, writing as `id`, owns the
+    /// publisher `old`. Either they are the same publisher, or `old`
+    /// belongs to the same authenticated user.
+    pub(super) fn is_owner(
+        &self,
+        old: &PublisherId,
+        id: &PublisherId,
+        uifo: &UserInfo,
+    ) -> bool {
+        old == id
+            || match (self.owners.get(old), &uifo.user_info) {
+                (Some(owner), Some(u)) => owner == &u.name,
+                (None, _) | (_, None) => false,
+            }
+    }
+
+    fn count_subtree(&mut self, path: &Path, id: PublisherId, new_path: bool) {
+        let level = Path::levels(self.root());
+        if let Some(part) = Path::parts(path).nth(level) {
+            if !self.subtrees.contains_key(part) {
+                self.subtrees.insert(ArcStr::from(part), SubtreeCounts::default());
+            }
+            if let Some(st) = self.subtrees.get_mut(part) {
+                if new_path {
+                    st.paths += 1;
+                }
+                *st.publishers.entry(id).or_insert(0) += 1;
+            }
+        }
+    }
+
+    fn uncount_subtree(&mut self, path: &Path, id: &PublisherId, gone_path: bool) {
+        let level = Path::levels(self.root());
+        if let Some(part) = Path::parts(path).nth(level) {
+            if let Some(st) = self.subtrees.get_mut(part) {
+                if gone_path {
+                    st.paths -= 1;
+                }
+                if let Some(n) = st.publishers.get_mut(id) {
+                    *n -= 1;
+                    if *n == 0 {
+                        st.publishers.remove(id);
+                    }
+                }
+                if st.paths == 0 && st.publishers.is_empty() {
+                    self.subtrees.remove(part);
+                }
+            }
+        }
+    }
+
+    /// The number of paths and the set of publishers under each
+    /// immediate child of the root, not including default publishers
+    pub(super) fn subtree_stats(&self) -> AHashMap<Path, SubtreeStats> {
+        let root = Path::from(self.root());
+        self.subtrees
+            .iter()
+            .map(|(part, st)| {
+                let publishers = st.publishers.keys().copied().collect();
+                (root.append(part), SubtreeStats { paths: st.paths, publishers })
+            })
+            .collect()
+    }
+
     fn defaults_for_id(&self, id: &PublisherId) -> AHashSet<Path> {
         self.defaults_by_id.get(id).map(|s| s.clone()).unwrap_or_else(AHashSet::new)
     }
@@ -515,6 +753,23 @@ impl Store {
         }
     }
 
+    pub(super) fn unpublish_subtree(&mut self, publisher: &Arc<Publisher>, base: &Path) {
+        let under = |paths: Option<&AHashSet<Path>>| {
+            paths
+                .into_iter()
+                .flatten()
+                .filter(|p| Path::is_parent(base, p))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        for path in under(self.published_by_id.get(&publisher.id)) {
+            self.unpublish(publisher, false, path);
+        }
+        for path in under(self.defaults_by_id.get(&publisher.id)) {
+            self.unpublish(publisher, true, path)
+        }
+    }
+
     fn get_flags(&self, path: &str) -> u32 {
         self.flags_by_path.get(path).copied().unwrap_or(0)
     }
@@ -578,6 +833,15 @@ impl Store {
         }
     }
 
+    // sort the resolved publishers by address so that the order of
+    // Resolved::publishers is deterministic
+    fn sort_by_addr(
+        publishers: &IntMap<PublisherId, Publisher>,
+        pubs: &mut GPooled<Vec<PublisherRef>>,
+    ) {
+        pubs.sort_by_key(|r| (publishers.get(&r.id).map(|p| p.addr), r.id));
+    }
+
     pub(super) fn resolve(
         &self,
         publishers: &mut IntMap<PublisherId, Publisher>,
@@ -605,6 +869,7 @@ impl Store {
                 }
             }
         };
+        Self::sort_by_addr(publishers, &mut pubs);
         (flags, pubs)
     }
 
@@ -662,6 +927,7 @@ impl Store {
                 }
             }
         };
+        Self::sort_by_addr(publishers, &mut pubs);
         (flags, pubs)
     }
 
@@ -760,5 +1026,26 @@ impl Store {
                 }
             }
         }
+        let level = Path::levels(self.root());
+        let mut subtrees: AHashMap<&str, SubtreeCounts> = AHashMap::default();
+        for (path, pubs) in self.published_by_path.iter() {
+            if let Some(part) = Path::parts(path).nth(level) {
+                let st = subtrees.entry(part).or_default();
+                st.paths += 1;
+                for id in pubs {
+                    *st.publishers.entry(*id).or_insert(0) += 1;
+                }
+            }
+        }
+        assert_eq!(subtrees.len(), self.subtrees.len());
+        for (part, st) in subtrees {
+            match self.subtrees.get(part) {
+                None => panic!("subtree {} is not counted", part),
+                Some(cur) => {
+                    assert_eq!(cur.paths, st.paths, "paths in subtree {}", part);
+                    assert_eq!(cur.publishers, st.publishers, "publishers in {}", part)
+                }
+            }
+        }
     }
 }
@@ -1,12 +1,19 @@
-use super::store::Store;
+use super::{
+    auth::{Namespaces, UserInfo, ANONYMOUS},
+    store::Store,
+};
 use crate::{
     pack::Z64,
     path::Path,
-    protocol::resolver::{HashMethod, Publisher, PublisherId, PublisherRef, TargetAuth},
+    protocol::resolver::{
+        Auth, HashMethod, Publisher, PublisherId, PublisherRef, Referral, TargetAuth,
+    },
 };
 use ahash::AHashMap;
+use arcstr::{literal, ArcStr};
 use bytes::Bytes;
 use netidx_netproto::resolver::PublisherPriority;
+use poolshark::global::GPooled;
 use rand::{self, rng, RngExt};
 use std::{
     collections::{BTreeMap, HashMap},
@@ -183,3 +190,148 @@ fn test_resolver_store() {
     let cols = store.columns(&Path::from("/app/test"));
     assert_eq!(cols.len(), 0);
 }
+
+#[test]
+fn test_namespaces() {
+    let user = |name: &str| UserInfo {
+        user_info: Some(netidx_netproto::resolver::UserInfo {
+            name: ArcStr::from(name),
+            primary_group: ArcStr::from(name),
+            groups: vec![].into(),
+            resolver: "127.0.0.1:1".parse().unwrap(),
+            token: Bytes::new(),
+        }),
+        ..(**ANONYMOUS).clone()
+    };
+    let (alice, bob, carol) = (user("alice"), user("bob"), user("carol"));
+    let cfg = HashMap::from_iter([
+        (literal!("alice"), vec![literal!("/tenants/alice"), literal!("/shared")]),
+        (literal!("bob"), vec![literal!("/tenants/bob")]),
+    ]);
+    let ns = Namespaces::from_file(&cfg).unwrap();
+    assert!(ns.allowed("/tenants/alice", &alice));
+    assert!(ns.allowed("/tenants/alice/foo/bar", &alice));
+    assert!(ns.allowed("/shared/foo", &alice));
+    assert!(!ns.allowed("/tenants/bob/foo", &alice));
+    assert!(!ns.allowed("/tenants/alicex", &alice));
+    assert!(ns.allowed("/tenants/bob/foo", &bob));
+    assert!(!ns.allowed("/tenants/alice/foo", &bob));
+    assert!(!ns.allowed("/shared/foo", &bob));
+    // no namespace, and anonymous, are unrestricted
+    assert!(ns.allowed("/tenants/alice/foo", &carol));
+    assert!(ns.allowed("/tenants/alice/foo", &ANONYMOUS));
+    // $[user] confines everyone not named to their own subtree
+    let mut cfg = cfg;
+    cfg.insert(literal!("$[user]"), vec![literal!("/tenants/$[user]")]);
+    let ns = Namespaces::from_file(&cfg).unwrap();
+    assert!(ns.allowed("/tenants/carol/foo", &carol));
+    assert!(!ns.allowed("/tenants/alice/foo", &carol));
+    assert!(ns.allowed("/shared/foo", &alice));
+    let bad = HashMap::from_iter([(literal!("alice"), vec![literal!("tenants")])]);
+    assert!(Namespaces::from_file(&bad).is_err());
+}
+
+#[test]
+fn test_expired_bounded() {
+    use super::store::MAX_EXPIRED;
+    use crate::protocol::resolver::PathStatus;
+    let addr = "127.0.0.1:100".parse::<SocketAddr>().unwrap();
+    let publisher = Arc::new(Publisher {
+        id: PublisherId::new(),
+        addr,
+        hash_method: HashMethod::Sha3_512,
+        resolver: addr,
+        target_auth: TargetAuth::Anonymous,
+        user_info: None,
+        priority: PublisherPriority::Normal,
+    });
+    let mut store = Store::new(None, BTreeMap::new());
+    let path = |i: usize| Path::from(format!("/expired/{i}"));
+    for i in 0..MAX_EXPIRED + 10 {
+        store.publish(path(i), &publisher, false, None);
+        store.unpublish(&publisher, false, path(i));
+    }
+    // the oldest are forgotten once the store is full
+    for i in 0..10 {
+        assert_eq!(store.path_status(&path(i)), PathStatus::Unknown);
+    }
+    assert!(matches!(store.path_status(&path(10)), PathStatus::Expired { .. }));
+    let last = path(MAX_EXPIRED + 9);
+    assert!(matches!(store.path_status(&last), PathStatus::Expired { .. }));
+    // publishing again forgets the expiry, and the stale order entry
+    // doesn't remove a later one
+    store.publish(path(10), &publisher, false, None);
+    store.unpublish(&publisher, false, path(10));
+    store.publish(path(MAX_EXPIRED + 10), &publisher, false, None);
+    store.unpublish(&publisher, false, path(MAX_EXPIRED + 10));
+    assert!(matches!(store.path_status(&path(10)), PathStatus::Expired { .. }));
+}
+
+#[test]
+fn test_subtree_stats() {
+    let publisher = |port: u16| {
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        Arc::new(Publisher {
+            id: PublisherId::new(),
+            addr,
+            hash_method: HashMethod::Sha3_512,
+            resolver: addr,
+            target_auth: TargetAuth::Anonymous,
+            user_info: None,
+            priority: PublisherPriority::Normal,
+        })
+    };
+    let (p0, p1) = (publisher(100), publisher(101));
+    let mut store = Store::new(None, BTreeMap::new());
+    let stats = |store: &Store| {
+        store.invariant();
+        let mut stats = store
+            .subtree_stats()
+            .into_iter()
+            .map(|(path, st)| (path, st.paths, st.publishers.len()))
+            .collect::<Vec<_>>();
+        stats.sort();
+        stats
+    };
+    store.publish(Path::from("/a/x"), &p0, false, None);
+    store.publish(Path::from("/a/y/z"), &p0, false, None);
+    store.publish(Path::from("/a/x"), &p1, false, None);
+    store.publish(Path::from("/b/x"), &p1, false, None);
+    store.publish(Path::from("/c/x"), &p1, true, None);
+    assert_eq!(stats(&store), vec![(Path::from("/a"), 2, 2), (Path::from("/b"), 1, 1)]);
+    store.unpublish(&p0, false, Path::from("/a/x"));
+    assert_eq!(stats(&store), vec![(Path::from("/a"), 2, 2), (Path::from("/b"), 1, 1)]);
+    store.unpublish(&p1, false, Path::from("/a/x"));
+    assert_eq!(stats(&store), vec![(Path::from("/a"), 1, 1), (Path::from("/b"), 1, 1)]);
+    store.clear(&p1);
+    assert_eq!(stats(&store), vec![(Path::from("/a"), 1, 1)]);
+    store.unpublish(&p0, false, Path::from("/a/y/z"));
+    assert_eq!(stats(&store), vec![]);
+}
+
+#[test]
+fn test_referral_under() {
+    let referral = |path: &'static str| Referral {
+        path: Path::from(path),
+        ttl: None,
+        addrs: GPooled::orphan(vec![(
+            "127.0.0.1:4564".parse().unwrap(),
+            Auth::Anonymous,
+        )]),
+    };
+    let children = [referral("/app/a/b/c"), referral("/app-x")]
+        .into_iter()
+        .map(|r| (r.path.clone(), r))
+        .collect::<BTreeMap<_, _>>();
+    let store = Store::new(None, children);
+    let under = |base: &'static str| {
+        store.referral_under(&Path::from(base)).map(|r| r.path.clone())
+    };
+    assert_eq!(under("/"), Some(Path::from("/app-x")));
+    assert_eq!(under("/app"), Some(Path::from("/app/a/b/c")));
+    assert_eq!(under("/app/a"), Some(Path::from("/app/a/b/c")));
+    assert_eq!(under("/app/a/b/c"), None);
+    assert_eq!(under("/app/a/b/c/d"), None);
+    assert_eq!(under("/app/ab"), None);
+    assert_eq!(under("/other"), None);
+}
@@ -0,0 +1,69 @@
+//! A blocking facade over the subscriber.
+//!
+//! Enabled by the `blocking` feature. This lets code that isn't
+//! async, e.g. a synchronous data processing thread, read values
+//! without running its own runtime. The async machinery runs on the
+//! tokio runtime whose handle is passed to `Subscriber::blocking`,
+//! the calling thread just waits for the result.
+//!
+//! None of these methods may be called from within an async
+//! runtime, they will return an error if they are.
+use super::{Dval, Event, Subscriber, Value};
+use crate::path::Path;
+use anyhow::Result;
+use std::{future::Future, time::Duration};
+use tokio::{runtime::Handle, time};
+
+/// A subscriber that can be used from synchronous code. Create one
+/// with `Subscriber::blocking`.
+#[derive(Debug, Clone)]
+pub struct BlockingSubscriber {
+    subscriber: Subscriber,
+    rt: Handle,
+}
+
+impl BlockingSubscriber {
+    fn block_on<F: Future>(&self, f: F) -> Result<F::Output> {
+        if Handle::try_current().is_ok() {
+            bail!("blocking subscriber methods can't be called from an async runtime")
+        }
+        Ok(self.rt.block_on(f))
+    }
+
+    /// The async subscriber this wraps.
+    pub fn subscriber(&self) -> &Subscriber {
+        &self.subscriber
+    }
+
+    /// Get the current value of `path` and then unsubscribe, waiting
+    /// at most `timeout`. See `Subscriber::get_once`.
+    pub fn subscribe_val_blocking(
+        &self,
+        path: Path,
+        timeout: Option<Duration>,
+    ) -> Result<Value> {
+        self.block_on(self.subscriber.get_once(path, timeout))?
+    }
+
+    /// Wait at most `timeout` for `dv` to be subscribed and then
+    /// return its last value.
+    pub fn last_blocking(&self, dv: &Dval, timeout: Duration) -> Result<Value> {
+        self.block_on(async {
+            time::timeout(timeout, dv.wait_subscribed()).await??;
+            match dv.last() {
+                Event::Update(v) => Ok(v),
+                Event::Unsubscribed => bail!("unsubscribed"),
+            }
+        })?
+    }
+}
+
+impl Subscriber {
+    /// Wrap this subscriber for use from synchronous code. The
+    /// async work is driven by `rt`, which must be a multi threaded
+    /// runtime, or a runtime that is otherwise being driven while
+    /// the blocking methods wait.
+    pub fn blocking(&self, rt: Handle) -> BlockingSubscriber {
+        BlockingSubscriber { subscriber: self.clone(), rt }
+    }
+}
@@ -1,7 +1,8 @@
 use super::{
-    ConId, DvDead, DvState, Event, NoSuchValue, PermissionDenied, SubId, SubStatus,
-    SubscribeValRequest, Subscriber, SubscriberInner, SubscriberWeak, ToCon,
-    UpdatesFlags, Val, ValInner, ValWeak, WUpdateChan, BATCHES, DECODE_BATCHES,
+    metrics, throughput, ConId, DvDead, DvState, Event, Last, MissingSpn, NoSuchValue,
+    PermissionDenied, SubId, SubStatus, SubscribeValRequest, Subscriber, SubscriberInner,
+    SubscriberWeak, ToCon, UpdatesFlags, Val, ValInner, ValWeak, WRawUpdateChan,
+    WUpdateChan, WriteTimedOut, BATCHES, DECODE_BATCHES, SEQ_BATCHES,
 };
 pub use crate::protocol::value::{FromValue, Value};
 pub use crate::resolver_client::DesiredAuth;
@@ -30,14 +31,20 @@ use futures::{
     select_biased,
     stream::FuturesUnordered,
 };
-use log::{info, trace};
+use log::{info, trace, warn};
 use nohash::{IntMap, IntSet};
 use parking_lot::Mutex;
-use poolshark::global::GPooled;
+use poolshark::global::{GPooled, Pool};
 use protocol::resolver::UserInfo;
 use smallvec::SmallVec;
 use std::{
-    collections::hash_map::Entry, mem, net::SocketAddr, pin::Pin, sync::Arc,
+    cmp::{min, Reverse},
+    collections::{hash_map::Entry, BinaryHeap},
+    fmt::Debug,
+    mem,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, LazyLock},
     time::Duration,
 };
 use tokio::{
@@ -47,53 +54,92 @@ use tokio::{
 };
 use triomphe::Arc as TArc;
 
+static UNSUBSCRIBES: LazyLock<Pool<Vec<Id>>> = LazyLock::new(|| Pool::new(64, 16384));
+
 #[derive(Debug)]
 struct Sub {
     path: Path,
     sub_id: SubId,
-    streams: SmallVec<[(ChanId, ChanWrap<GPooled<Vec<(SubId, Event)>>>); 1]>,
-    last: Option<TArc<Mutex<Event>>>,
+    // the last field is the next sequence number of a sequenced
+    // stream, it is unused by plain streams
+    streams: SmallVec<[(ChanId, WUpdateChan, u64); 1]>,
+    last: Option<TArc<Mutex<Last>>>,
     val: ValWeak,
 }
 
-type ByChan = IntMap<
-    ChanId,
-    (ChanWrap<GPooled<Vec<(SubId, Event)>>>, GPooled<Vec<(SubId, Event)>>),
->;
+enum ChanBatch {
+    Plain(ChanWrap<GPooled<Vec<(SubId, Event)>>>, GPooled<Vec<(SubId, Event)>>),
+    Seq(ChanWrap<GPooled<Vec<(SubId, u64, Event)>>>, GPooled<Vec<(SubId, u64, Event)>>),
+}
+
+type ByChan = IntMap<ChanId, ChanBatch>;
+
+// queue an event for every stream of a subscription
+fn queue(by_chan: &mut ByChan, sub: &mut Sub, ev: &Event) {
+    for (chan_id, c, seq) in sub.streams.iter_mut() {
+        let batch = by_chan.entry(*chan_id).or_insert_with(|| match c {
+            WUpdateChan::Plain(c) => ChanBatch::Plain(c.clone(), BATCHES.take()),
+            WUpdateChan::Seq(c) => ChanBatch::Seq(c.clone(), SEQ_BATCHES.take()),
+        });
+        match batch {
+            ChanBatch::Plain(_, batch) => batch.push((sub.sub_id, ev.clone())),
+            ChanBatch::Seq(_, batch) => {
+                batch.push((sub.sub_id, *seq, ev.clone()));
+                *seq += 1;
+            }
+        }
+    }
+}
 
 fn unsubscribe(
     subscriber: &mut SubscriberInner,
     by_chan: &mut ByChan,
-    sub: Sub,
+    mut sub: Sub,
     id: Id,
     conid: ConId,
 ) {
-    for (chan_id, c) in sub.streams.iter() {
-        by_chan
-            .entry(*chan_id)
-            .or_insert_with(|| (c.clone(), BATCHES.take()))
-            .1
-            .push((sub.sub_id, Event::Unsubscribed))
-    }
     if let Some(last) = &sub.last {
-        *last.lock() = Event::Unsubscribed;
+        last.lock().event = Event::Unsubscribed;
     }
-    if let Some(dsw) = subscriber
-        .durable_alive
-        .remove(&sub.path)
-        .or_else(|| subscriber.durable_pending.remove(&sub.path))
-    {
-        trace!("unsubscribing {}", sub.path);
-        if let Some(ds) = dsw.upgrade() {
-            let mut inner = ds.0.lock();
-            inner.sub = DvState::Dead(Box::new(DvDead {
-                queued_writes: Vec::new(),
-                waiting: Vec::new(),
-                tries: 0,
-                next_try: Instant::now(),
-            }));
-            subscriber.durable_dead.insert(sub.path.clone(), dsw);
-            let _ = subscriber.trigger_resub.unbounded_send(());
+    // a durable subscription that was migrated to another publisher
+    // has moved on. Its streams are now fed by the new subscription,
+    // so they must not see Unsubscribed, but any other streams here,
+    // e.g. those of a Val the user still holds, must.
+    let dv = subscriber.durable_alive.get(&sub.path).and_then(|dsw| dsw.upgrade());
+    let moved = dv.and_then(|ds| {
+        let inner = ds.0.lock();
+        match &inner.sub {
+            DvState::Subscribed(v) if v.0.id != id || v.0.conid != conid => {
+                Some(inner.streams.iter().map(|(_, c)| c.clone()).collect::<Vec<_>>())
+            }
+            DvState::Subscribed(_) | DvState::Dead(_) => None,
+        }
+    });
+    if let Some(moved) = moved {
+        trace!("unsubscribed from {} after migrating", sub.path);
+        sub.streams.retain(|(_, c, _)| !moved.contains(c));
+        queue(by_chan, &mut sub, &Event::Unsubscribed);
+    } else {
+        queue(by_chan, &mut sub, &Event::Unsubscribed);
+        if let Some(dsw) = subscriber
+            .durable_alive
+            .remove(&sub.path)
+            .or_else(|| subscriber.durable_pending.remove(&sub.path))
+        {
+            trace!("unsubscribing {}", sub.path);
+            if let Some(ds) = dsw.upgrade() {
+                let mut inner = ds.0.lock();
+                inner.sub = DvState::Dead(Box::new(DvDead {
+                    queued_writes: Vec::new(),
+                    waiting: Vec::new(),
+                    ready: Vec::new(),
+                    tries: 0,
+                    next_try: Instant::now(),
+                    last_error: None,
+                }));
+                subscriber.durable_dead.insert(sub.path.clone(), dsw);
+                let _ = subscriber.trigger_resub.unbounded_send(());
+            }
         }
     }
     match subscriber.subscribed.entry(sub.path) {
@@ -120,20 +166,21 @@ async fn hello_publisher(
     uifo: Option<UserInfo>,
     desired_auth: &DesiredAuth,
     target_auth: &TargetAuth,
-) -> Result<Channel> {
-    use protocol::publisher::Hello;
-    channel::write_raw(&mut con, &3u64).await?;
-    if channel::read_raw::<u64, _, 1024>(&mut con).await? != 3 {
+) -> Result<(Channel, u64)> {
+    use protocol::publisher::{Hello, PROTOCOL_VERSION, WIRE_VERSION};
+    channel::write_raw(&mut con, &WIRE_VERSION).await?;
+    if channel::read_raw::<u64, _, 1024>(&mut con).await? != WIRE_VERSION {
         bail!("incompatible protocol version")
     }
+    let negotiate = |h: &Hello| min(h.version(), PROTOCOL_VERSION);
     match (desired_auth, target_auth) {
         (DesiredAuth::Anonymous, TargetAuth::Anonymous) => {
-            channel::write_raw(&mut con, &Hello::Anonymous).await?;
-            match channel::read_raw::<_, _, 8124>(&mut con).await? {
-                Hello::Anonymous => (),
+            channel::write_raw(&mut con, &Hello::Anonymous(PROTOCOL_VERSION)).await?;
+            let version = match channel::read_raw::<_, _, 8124>(&mut con).await? {
+                h @ Hello::Anonymous(_) => negotiate(&h),
                 _ => bail!("unexpected response from publisher"),
-            }
-            Ok(Channel::new::<ClientCtx, TcpStream>(None, con))
+            };
+            Ok((Channel::new::<ClientCtx, TcpStream>(None, con), version))
         }
         (
             DesiredAuth::Anonymous,
@@ -151,26 +198,31 @@ async fn hello_publisher(
             DesiredAuth::Local | DesiredAuth::Krb5 { .. } | DesiredAuth::Tls { .. },
             TargetAuth::Local,
         ) => {
-            channel::write_raw(&mut con, &Hello::Local(uifo)).await?;
-            match channel::read_raw::<_, _, 8124>(&mut con).await? {
-                Hello::Local(_) => (),
+            channel::write_raw(&mut con, &Hello::Local(uifo, PROTOCOL_VERSION)).await?;
+            let version = match channel::read_raw::<_, _, 8124>(&mut con).await? {
+                h @ Hello::Local(..) => negotiate(&h),
                 _ => bail!("unexpected response from publisher"),
-            }
-            Ok(Channel::new::<ClientCtx, TcpStream>(None, con))
+            };
+            Ok((Channel::new::<ClientCtx, TcpStream>(None, con), version))
         }
         (DesiredAuth::Local, TargetAuth::Krb5 { .. } | TargetAuth::Tls { .. }) => {
             bail!("local auth not supported")
         }
+        (DesiredAuth::Krb5 { .. }, TargetAuth::Krb5 { spn }) if spn.is_empty() => {
+            let addr = con.peer_addr()?;
+            warn!("publisher {addr} requires kerberos but has no spn");
+            return Err(MissingSpn { addr }.into());
+        }
         (DesiredAuth::Krb5 { upn, .. }, TargetAuth::Krb5 { spn }) => {
             let upn = upn.as_ref().map(|p| p.as_str());
-            channel::write_raw(&mut con, &Hello::Krb5(uifo)).await?;
+            channel::write_raw(&mut con, &Hello::Krb5(uifo, PROTOCOL_VERSION)).await?;
             let ctx = krb5_authentication(upn, spn, &mut con).await?;
             let mut con = Channel::new(Some(K5CtxWrap::new(ctx)), con);
-            match con.receive::<Hello>().await? {
-                Hello::Krb5(_) => (),
+            let version = match con.receive::<Hello>().await? {
+                h @ Hello::Krb5(..) => negotiate(&h),
                 _ => bail!("protocol error"),
-            }
-            Ok(con)
+            };
+            Ok((con, version))
         }
         (DesiredAuth::Krb5 { .. }, TargetAuth::Tls { .. }) => {
             bail!("desired authentication mechanism not supported")
@@ -183,17 +235,17 @@ async fn hello_publisher(
             })
             .await??;
             let name = rustls_pki_types::ServerName::try_from(&**name)?.to_owned();
-            channel::write_raw(&mut con, &Hello::Tls(uifo)).await?;
+            channel::write_raw(&mut con, &Hello::Tls(uifo, PROTOCOL_VERSION)).await?;
             let tls = ctx.connect(name, con).await?;
             let mut con = Channel::new::<
                 ClientCtx,
                 tokio_rustls::client::TlsStream<TcpStream>,
             >(None, tls);
-            match con.receive::<Hello>().await? {
-                Hello::Tls(_) => (),
+            let version = match con.receive::<Hello>().await? {
+                h @ Hello::Tls(..) => negotiate(&h),
                 _ => bail!("protocol error"),
-            }
-            Ok(con)
+            };
+            Ok((con, version))
         }
         (DesiredAuth::Tls { .. }, TargetAuth::Krb5 { .. }) => {
             bail!("desired authentication mechanism not supported")
@@ -202,22 +254,153 @@ async fn hello_publisher(
 }
 
 const PERIOD: Duration = Duration::from_secs(100);
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+type ConnectFut = Pin<Box<dyn Future<Output = Result<(Channel, u64)>> + Send + 'static>>;
+
+/// Establishes the transport to a publisher, including the hello
+/// handshake, and returns the resulting channel along with the
+/// negotiated protocol version. The subscriber uses
+/// `TcpConnectionFactory` unless told otherwise, substituting another
+/// implementation lets tests drive the subscriber without a real
+/// publisher.
+pub(crate) trait ConnectionFactory: Debug + Send + Sync + 'static {
+    fn connect(
+        &self,
+        addr: SocketAddr,
+        tls_ctx: Option<tls::CachedConnector>,
+        uifo: Option<UserInfo>,
+        desired_auth: DesiredAuth,
+        target_auth: TargetAuth,
+    ) -> ConnectFut;
+}
+
+/// Where the subscriber should open the TCP connection for a
+/// publisher.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectTarget {
+    /// Connect directly to this address
+    Direct(SocketAddr),
+    /// Connect to `proxy`, a SOCKS5 proxy that requires no
+    /// authentication, and ask it to connect to `target`
+    Socks5 { proxy: SocketAddr, target: SocketAddr },
+}
+
+/// Maps the publisher addresses returned by the resolver to where the
+/// subscriber actually connects, e.g. to rewrite addresses for a NAT,
+/// or to reach publishers in another network through a proxy. Auth
+/// is unaffected, the publisher is still authenticated as the
+/// resolver says it should be.
+pub trait AddressMapper: Debug + Send + Sync + 'static {
+    fn map(&self, addr: SocketAddr) -> ConnectTarget;
+}
+
+/// The default `AddressMapper`, connects directly to every publisher.
+#[derive(Debug, Clone, Copy)]
+pub struct IdentityMapper;
+
+impl AddressMapper for IdentityMapper {
+    fn map(&self, addr: SocketAddr) -> ConnectTarget {
+        ConnectTarget::Direct(addr)
+    }
+}
+
+async fn socks5_connect(proxy: SocketAddr, target: SocketAddr) -> Result<TcpStream> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let mut soc = TcpStream::connect(proxy).await?;
+    // version 5, one method, no authentication
+    soc.write_all(&[5, 1, 0]).await?;
+    let mut method = [0u8; 2];
+    soc.read_exact(&mut method).await?;
+    if method != [5, 0] {
+        bail!("socks proxy {proxy} won't accept no authentication")
+    }
+    // version 5, connect, reserved, then the address
+    let mut req: SmallVec<[u8; 22]> = SmallVec::from_slice(&[5, 1, 0]);
+    match target {
+        SocketAddr::V4(a) => {
+            req.push(1);
+            req.extend_from_slice(&a.ip().octets())
+        }
+        SocketAddr::V6(a) => {
+            req.push(4);
+            req.extend_from_slice(&a.ip().octets())
+        }
+    }
+    req.extend_from_slice(&target.port().to_be_bytes());
+    soc.write_all(&req).await?;
+    let mut reply = [0u8; 4];
+    soc.read_exact(&mut reply).await?;
+    if reply[0] != 5 {
+        bail!("socks proxy {proxy} sent an invalid reply")
+    }
+    if reply[1] != 0 {
+        bail!("socks proxy {proxy} failed to connect to {target}, error {}", reply[1])
+    }
+    // skip the bound address and port
+    let len = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => soc.read_u8().await? as usize,
+        t => bail!("socks proxy {proxy} sent an invalid address type {t}"),
+    };
+    let mut bound = [0u8; 257];
+    soc.read_exact(&mut bound[..len + 2]).await?;
+    Ok(soc)
+}
+
+#[derive(Debug)]
+pub(crate) struct TcpConnectionFactory {
+    pub(crate) mapper: Arc<dyn AddressMapper>,
+    pub(crate) nodelay: bool,
+}
+
+impl ConnectionFactory for TcpConnectionFactory {
+    fn connect(
+        &self,
+        addr: SocketAddr,
+        tls_ctx: Option<tls::CachedConnector>,
+        uifo: Option<UserInfo>,
+        desired_auth: DesiredAuth,
+        target_auth: TargetAuth,
+    ) -> ConnectFut {
+        let target = self.mapper.map(addr);
+        let nodelay = self.nodelay;
+        Box::pin(async move {
+            let soc = time::timeout(PERIOD, async move {
+                match target {
+                    ConnectTarget::Direct(addr) => Ok(TcpStream::connect(addr).await?),
+                    ConnectTarget::Socks5 { proxy, target } => {
+                        socks5_connect(proxy, target).await
+                    }
+                }
+            })
+            .await??;
+            soc.set_nodelay(nodelay)?;
+            let hello = hello_publisher(soc, tls_ctx, uifo, &desired_auth, &target_auth);
+            Ok(time::timeout(HELLO_TIMEOUT, hello).await??)
+        })
+    }
+}
 
 fn decode_task(
     mut con: ReadChannel,
+    throughput: Arc<throughput::Meter>,
     stop: oneshot::Receiver<()>,
 ) -> Receiver<Result<(GPooled<Vec<From>>, bool)>> {
     let (mut send, recv) = mpsc::channel(3);
     let mut stop = stop.fuse();
     task::spawn(async move {
         let mut buf = DECODE_BATCHES.take();
+        let mut received = 0;
         let r: Result<(), anyhow::Error> = loop {
             let mut only_updates = true;
+            let mut updates = 0;
             select_biased! {
                 _ = stop => { break Ok(()); },
                 r = con.receive_batch_fn(|up| {
                     match up {
-                        From::Update(_, _) => (),
+                        From::Update(_, _) => { updates += 1 },
                         _ => { only_updates = false }
                     }
                     buf.push(up);
@@ -227,6 +410,10 @@ fn decode_task(
                         try_cf!(send.send(Err(e)).await)
                     }
                     Ok(()) => {
+                        let total = con.bytes_received();
+                        metrics::bytes_received(total - received);
+                        throughput.record(updates, total - received);
+                        received = total;
                         let batch = mem::replace(&mut buf, DECODE_BATCHES.take());
                         try_cf!(send.send(Ok((batch, only_updates))).await)
                     }
@@ -240,6 +427,58 @@ fn decode_task(
 
 type BlockedChannelFut = Pin<Box<dyn Future<Output = ()> + Send + Sync + 'static>>;
 
+/// What to do about a consumer that doesn't take its updates, see
+/// `SubscriberBuilder::slow_consumer_timeout`.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct SlowConsumer {
+    pub(super) timeout: Duration,
+    pub(super) drop: bool,
+}
+
+// send a batch to a full channel. The connection doesn't read from
+// the publisher until every blocked send has finished, so one stuck
+// consumer stalls every subscription on the connection. If the send
+// takes longer than the slow consumer timeout, say who is stuck, and
+// if asked to, close the channel so the connection can go on.
+fn blocked<T: Send + Sync + 'static>(
+    addr: SocketAddr,
+    slow: Option<SlowConsumer>,
+    id: Option<ChanId>,
+    mut c: mpsc::Sender<T>,
+    batch: T,
+) -> BlockedChannelFut {
+    Box::pin(async move {
+        let slow = match slow {
+            Some(slow) => slow,
+            None => {
+                let _ = c.send(batch).await;
+                return;
+            }
+        };
+        let start = Instant::now();
+        let mut send = c.send(batch);
+        if time::timeout(slow.timeout, &mut send).await.is_ok() {
+            return;
+        }
+        let stream = match id {
+            Some(id) => format!("{id:?}"),
+            None => "raw stream".into(),
+        };
+        if slow.drop {
+            warn!(
+                "connection to {addr} blocked for {:?} on {stream}, dropping it",
+                slow.timeout
+            );
+            drop(send);
+            c.close_channel();
+        } else {
+            warn!("connection to {addr} blocked for {:?} on {stream}", slow.timeout);
+            let _ = send.await;
+            warn!("connection to {addr} unblocked after {:?}", start.elapsed());
+        }
+    })
+}
+
 pub(super) struct ConnectionCtx {
     addr: SocketAddr,
     subscriber: SubscriberWeak,
@@ -248,17 +487,35 @@ pub(super) struct ConnectionCtx {
     conid: ConId,
     tls_ctx: Option<tls::CachedConnector>,
     uifo: Option<UserInfo>,
+    version: u64,
+    factory: Arc<dyn ConnectionFactory>,
+    subscribe_timeout: Duration,
+    throughput: Arc<throughput::Meter>,
+    write_coalesce: Duration,
+    slow_consumer: Option<SlowConsumer>,
+    // when the oldest unflushed write was queued
+    queued_at: Option<Instant>,
     from_sub: BatchReceiver<ToCon>,
     pending: AHashMap<Path, SubscribeValRequest>,
+    // the deadline of every pending subscribe, entries whose request
+    // has already finished are skipped when they come due
+    deadlines: BinaryHeap<Reverse<(Instant, Path)>>,
+    write_timeout: Option<Duration>,
+    // the deadline of every write waiting for a reply, entries that
+    // have already been answered are skipped when they come due
+    write_deadlines: BinaryHeap<Reverse<(Instant, Id, WriteId)>>,
     subscriptions: IntMap<Id, Sub>,
     msg_recvd: bool,
+    closed: bool,
     pending_flushes: Vec<oneshot::Sender<()>>,
-    pending_writes: IntMap<Id, IntMap<WriteId, oneshot::Sender<Value>>>,
-    by_receiver: AHashMap<ChanWrap<GPooled<Vec<(SubId, Event)>>>, ChanId>,
+    pending_writes: IntMap<Id, IntMap<WriteId, oneshot::Sender<Result<Value>>>>,
+    by_receiver: AHashMap<WUpdateChan, ChanId>,
     by_chan: ByChan,
     gc_chan: IntSet<ChanId>,
+    raw_streams: IntMap<Id, SmallVec<[WRawUpdateChan; 1]>>,
+    raw_batches: AHashMap<WRawUpdateChan, GPooled<Vec<From>>>,
+    gc_raw: bool,
     blocked_channels: FuturesUnordered<BlockedChannelFut>,
-    timed_out: Vec<Path>,
 }
 
 impl ConnectionCtx {
@@ -270,6 +527,12 @@ impl ConnectionCtx {
         uifo: Option<UserInfo>,
         target_auth: TargetAuth,
         desired_auth: DesiredAuth,
+        factory: Arc<dyn ConnectionFactory>,
+        subscribe_timeout: Duration,
+        throughput: Arc<throughput::Meter>,
+        write_coalesce: Duration,
+        slow_consumer: Option<SlowConsumer>,
+        write_timeout: Option<Duration>,
         from_sub: BatchReceiver<ToCon>,
     ) -> Self {
         Self {
@@ -280,39 +543,71 @@ impl ConnectionCtx {
             conid,
             tls_ctx,
             uifo,
+            version: protocol::publisher::PROTOCOL_VERSION,
+            factory,
+            subscribe_timeout,
+            throughput,
+            write_coalesce,
+            slow_consumer,
+            queued_at: None,
             from_sub,
             pending: AHashMap::default(),
+            deadlines: BinaryHeap::new(),
+            write_timeout,
+            write_deadlines: BinaryHeap::new(),
             subscriptions: IntMap::default(),
             msg_recvd: false,
+            closed: false,
             pending_flushes: Vec::new(),
             pending_writes: IntMap::default(),
             by_receiver: AHashMap::default(),
             by_chan: IntMap::default(),
             gc_chan: IntSet::default(),
+            raw_streams: IntMap::default(),
+            raw_batches: AHashMap::default(),
+            gc_raw: false,
             blocked_channels: FuturesUnordered::<BlockedChannelFut>::new(),
-            timed_out: Vec::new(),
         }
     }
 
-    fn handle_heartbeat(&mut self, now: Instant) -> Result<()> {
+    fn handle_heartbeat(&mut self) -> Result<()> {
         if !self.msg_recvd {
             bail!("hung publisher");
         } else {
             self.msg_recvd = false;
         }
-        for (path, req) in self.pending.iter() {
-            if let Some(deadline) = req.deadline {
-                if deadline < now {
-                    self.timed_out.push(path.clone());
+        Ok(())
+    }
+
+    // fail every pending subscribe, and every write waiting for a
+    // reply, whose deadline has passed
+    fn handle_deadlines(&mut self, now: Instant) {
+        while let Some(Reverse((deadline, _))) = self.deadlines.peek() {
+            if *deadline > now {
+                break;
+            }
+            let Reverse((deadline, path)) = self.deadlines.pop().unwrap();
+            if let Entry::Occupied(e) = self.pending.entry(path) {
+                if e.get().deadline == Some(deadline) {
+                    let _ = e.remove().finished.send(Err(anyhow!("timed out")));
                 }
             }
         }
-        for path in self.timed_out.drain(..) {
-            if let Some(req) = self.pending.remove(&path) {
-                let _ = req.finished.send(Err(anyhow!("timed out")));
+        while let Some(Reverse((deadline, _, _))) = self.write_deadlines.peek() {
+            if *deadline > now {
+                break;
+            }
+            let Reverse((_, id, wid)) = self.write_deadlines.pop().unwrap();
+            if let Entry::Occupied(mut e) = self.pending_writes.entry(id) {
+                let tbl = e.get_mut();
+                if let Some(tx) = tbl.remove(&wid) {
+                    let _ = tx.send(Err(Error::from(WriteTimedOut)));
+                }
+                if tbl.is_empty() {
+                    e.remove();
+                }
             }
         }
-        Ok(())
     }
 
     fn handle_connect_stream(
@@ -324,12 +619,12 @@ impl ConnectionCtx {
     ) -> Result<()> {
         if let Some(sub) = self.subscriptions.get_mut(&id) {
             let mut already_have = false;
-            for (id, c) in sub.streams.iter() {
+            for (id, c, _) in sub.streams.iter() {
                 if &tx == c {
                     trace!("ignore already registered stream");
                     already_have = true;
                 }
-                if c.0.is_closed() {
+                if c.is_closed() {
                     trace!("scheduling closed stream for gc");
                     self.by_receiver.remove(&c);
                     self.gc_chan.insert(*id);
@@ -339,7 +634,7 @@ impl ConnectionCtx {
                 && !(already_have && flags.contains(UpdatesFlags::NO_SPURIOUS))
             {
                 if let Some(last) = &sub.last {
-                    if let Event::Update(v) = last.lock().clone() {
+                    if let Event::Update(v) = last.lock().event.clone() {
                         stream_batch.push(From::Update(id, v.clone()));
                     }
                 }
@@ -351,21 +646,78 @@ impl ConnectionCtx {
             if !already_have {
                 trace!("adding new channel to streams");
                 let id = self.by_receiver.entry(tx.clone()).or_insert_with(ChanId::new);
-                sub.streams.push((*id, tx));
+                sub.streams.push((*id, tx, 0));
             }
         }
         Ok(())
     }
 
+    fn handle_connect_raw_stream(&mut self, id: Id, tx: WRawUpdateChan) {
+        if self.subscriptions.contains_key(&id) {
+            let chans = self.raw_streams.entry(id).or_default();
+            chans.retain(|c| !c.0.is_closed());
+            if !chans.contains(&tx) {
+                trace!("adding new raw channel to streams");
+                chans.push(tx);
+            }
+        }
+    }
+
+    // the channels are dropped by the next send_updates, once
+    // anything already queued for them is sent
+    fn remove_raw_streams(&mut self, id: Id) {
+        if self.raw_streams.remove(&id).is_some() {
+            self.gc_raw = true;
+        }
+    }
+
+    fn queue_raw(&mut self, m: &From) {
+        fn raw_batch<'a>(
+            raw_batches: &'a mut AHashMap<WRawUpdateChan, GPooled<Vec<From>>>,
+            c: &WRawUpdateChan,
+        ) -> &'a mut GPooled<Vec<From>> {
+            raw_batches.entry(c.clone()).or_insert_with(|| DECODE_BATCHES.take())
+        }
+        let Self { raw_streams, raw_batches, .. } = self;
+        match m {
+            From::Heartbeat => {
+                for c in raw_streams.values().flatten() {
+                    let batch = raw_batch(raw_batches, c);
+                    // a channel shared by many subscriptions should
+                    // only see each heartbeat once
+                    if !matches!(batch.last(), Some(From::Heartbeat)) {
+                        batch.push(From::Heartbeat)
+                    }
+                }
+            }
+            From::Update(id, _)
+            | From::WriteResult(id, _, _)
+            | From::Unsubscribed(id) => {
+                if let Some(chans) = raw_streams.get(id) {
+                    for c in chans {
+                        raw_batch(raw_batches, c).push(m.clone())
+                    }
+                }
+            }
+            From::NoSuchValue(_) | From::Denied(_) | From::Subscribed(_, _, _, _) => (),
+        }
+    }
+
     fn handle_from_sub(
         &mut self,
         write_con: &mut WriteChannel,
         mut batch: GPooled<Vec<ToCon>>,
     ) -> Result<()> {
         let mut stream_batch = DECODE_BATCHES.take();
+        let mut unsubscribes = UNSUBSCRIBES.take();
         for msg in batch.drain(..) {
             match msg {
-                ToCon::Subscribe(req) => {
+                ToCon::Subscribe(mut req) => {
+                    let deadline = req
+                        .deadline
+                        .unwrap_or_else(|| Instant::now() + self.subscribe_timeout);
+                    req.deadline = Some(deadline);
+                    self.deadlines.push(Reverse((deadline, req.path.clone())));
                     let path = req.path.clone();
                     let resolver = req.resolver;
                     let token = req.token.clone();
@@ -380,14 +732,20 @@ impl ConnectionCtx {
                 }
                 ToCon::Unsubscribe(id) => {
                     info!("unsubscribe {:?}", id);
-                    write_con.queue_send(&To::Unsubscribe(id))?
+                    self.remove_raw_streams(id);
+                    unsubscribes.push(id)
                 }
                 ToCon::Stream { id, tx, flags } => {
                     self.handle_connect_stream(&mut stream_batch, id, tx, flags)?
                 }
+                ToCon::RawStream { id, tx } => self.handle_connect_raw_stream(id, tx),
                 ToCon::Write(id, v, wid, tx) => {
                     write_con.queue_send(&To::Write(id, tx.is_some(), v, wid))?;
                     if let Some(tx) = tx {
+                        if let Some(timeout) = self.write_timeout {
+                            let deadline = Instant::now() + timeout;
+                            self.write_deadlines.push(Reverse((deadline, id, wid)));
+                        }
                         self.pending_writes
                             .entry(id)
                             .or_insert_with(IntMap::default)
@@ -395,6 +753,22 @@ impl ConnectionCtx {
                     }
                 }
                 ToCon::Flush(tx) => self.pending_flushes.push(tx),
+                ToCon::Close => self.closed = true,
+            }
+        }
+        // dropping a group of subscriptions produces a run of
+        // unsubscribes, send them as one message if the publisher
+        // understands it
+        match unsubscribes.len() {
+            0 => (),
+            1 => write_con.queue_send(&To::Unsubscribe(unsubscribes[0]))?,
+            _ if self.version >= 4 => {
+                write_con.queue_send(&To::UnsubscribeMany(unsubscribes))?
+            }
+            _ => {
+                for id in unsubscribes.drain(..) {
+                    write_con.queue_send(&To::Unsubscribe(id))?
+                }
             }
         }
         if stream_batch.len() > 0 {
@@ -409,21 +783,20 @@ impl ConnectionCtx {
         con: &mut WriteChannel,
         subscriber: &Subscriber,
     ) -> Result<()> {
+        let now = Instant::now();
         let mut stream_batch = DECODE_BATCHES.take();
         for m in batch.drain(..) {
             trace!("processing from publisher {m:?}");
+            if !self.raw_streams.is_empty() {
+                self.queue_raw(&m)
+            }
             match m {
-                From::Update(i, m) => match self.subscriptions.get(&i) {
+                From::Update(i, m) => match self.subscriptions.get_mut(&i) {
                     Some(sub) => {
-                        for (chan_id, c) in sub.streams.iter() {
-                            self.by_chan
-                                .entry(*chan_id)
-                                .or_insert_with(|| (c.clone(), BATCHES.take()))
-                                .1
-                                .push((sub.sub_id, Event::Update(m.clone())));
-                        }
+                        let event = Event::Update(m);
+                        queue(&mut self.by_chan, sub, &event);
                         if let Some(last) = &sub.last {
-                            *last.lock() = Event::Update(m);
+                            *last.lock() = Last { event, received: now };
                         }
                     }
                     None => con.queue_send(&To::Unsubscribe(i))?,
@@ -433,7 +806,7 @@ impl ConnectionCtx {
                     if let Entry::Occupied(mut e) = self.pending_writes.entry(id) {
                         let tbl = e.get_mut();
                         if let Some(tx) = tbl.remove(&wid) {
-                            let _ = tx.send(v);
+                            let _ = tx.send(Ok(v));
                         }
                         if tbl.is_empty() {
                             e.remove();
@@ -451,13 +824,18 @@ impl ConnectionCtx {
                     }
                 }
                 From::Unsubscribed(id) => {
+                    self.remove_raw_streams(id);
                     if let Some(s) = self.subscriptions.remove(&id) {
                         let mut t = subscriber.0.lock();
                         unsubscribe(&mut *t, &mut self.by_chan, s, id, self.conid);
                     }
                 }
-                From::Subscribed(p, id, m) => {
+                From::Subscribed(p, id, m, metadata) => {
                     match self.pending.remove(&p) {
+                        // unsubscribing would kill the live subscription
+                        None if self.subscriptions.contains_key(&id) => {
+                            warn!("duplicate subscribed for live id {id:?}, ignoring")
+                        }
                         None => {
                             trace!("subscribed for id with no subscription");
                             con.queue_send(&To::Unsubscribe(id))?
@@ -487,13 +865,19 @@ impl ConnectionCtx {
                             },
                             None => {
                                 trace!("subscribe success");
-                                let last = TArc::new(Mutex::new(Event::Update(m)));
+                                let last = TArc::new(Mutex::new(Last {
+                                    event: Event::Update(m),
+                                    received: now,
+                                }));
                                 let s = Val(Arc::new(ValInner {
                                     sub_id: req.sub_id,
                                     id,
                                     conid: self.conid,
+                                    addr: self.addr,
                                     connection: req.con,
                                     last: last.clone(),
+                                    metadata,
+                                    protocol_version: self.version,
                                 }));
                                 match req.finished.send(Ok(s.clone())) {
                                     Err(e) => {
@@ -541,19 +925,18 @@ impl ConnectionCtx {
     // only updates. As of 2020-04-30, sending to an mpsc channel is
     // pretty slow, about 250ns, so we go to great lengths to avoid it.
     fn process_updates_batch(&mut self, mut batch: GPooled<Vec<From>>) {
+        let now = Instant::now();
         for m in batch.drain(..) {
+            if !self.raw_streams.is_empty() {
+                self.queue_raw(&m)
+            }
             match m {
                 From::Update(i, m) => {
-                    if let Some(sub) = self.subscriptions.get(&i) {
-                        for (chan_id, c) in sub.streams.iter() {
-                            self.by_chan
-                                .entry(*chan_id)
-                                .or_insert_with(|| (c.clone(), BATCHES.take()))
-                                .1
-                                .push((sub.sub_id, Event::Update(m.clone())))
-                        }
+                    if let Some(sub) = self.subscriptions.get_mut(&i) {
+                        let event = Event::Update(m);
+                        queue(&mut self.by_chan, sub, &event);
                         if let Some(last) = &sub.last {
-                            *last.lock() = Event::Update(m);
+                            *last.lock() = Last { event, received: now };
                         }
                     }
                 }
@@ -564,26 +947,76 @@ impl ConnectionCtx {
     }
 
     fn send_updates(&mut self) {
-        for (id, (c, batch)) in self.by_chan.iter_mut() {
+        macro_rules! send {
+            ($id:expr, $c:expr, $batch:expr, $pool:expr, $wrap:expr) => {{
+                if $batch.len() == 0 {
+                    continue;
+                }
+                let batch = mem::replace($batch, $pool.take());
+                if let Err(e) = $c.0.try_send(batch) {
+                    if e.is_full() {
+                        let batch = e.into_inner();
+                        let (addr, slow) = (self.addr, self.slow_consumer);
+                        let c = $c.0.clone();
+                        self.blocked_channels.push(blocked(
+                            addr,
+                            slow,
+                            Some(*$id),
+                            c,
+                            batch,
+                        ))
+                    } else if e.is_disconnected() {
+                        self.by_receiver.remove(&$wrap($c.clone()));
+                        self.gc_chan.insert(*$id);
+                    }
+                }
+            }};
+        }
+        for (id, batch) in self.by_chan.iter_mut() {
+            match batch {
+                ChanBatch::Plain(c, batch) => {
+                    send!(id, c, batch, BATCHES, WUpdateChan::Plain)
+                }
+                ChanBatch::Seq(c, batch) => {
+                    send!(id, c, batch, SEQ_BATCHES, WUpdateChan::Seq)
+                }
+            }
+        }
+        for id in self.gc_chan.drain() {
+            self.by_chan.remove(&id);
+        }
+        for (c, batch) in self.raw_batches.iter_mut() {
             if batch.len() == 0 {
                 continue;
             }
-            let batch = mem::replace(batch, BATCHES.take());
+            let batch = mem::replace(batch, DECODE_BATCHES.take());
             if let Err(e) = c.0.try_send(batch) {
                 if e.is_full() {
                     let batch = e.into_inner();
-                    let mut c = c.clone();
-                    self.blocked_channels.push(Box::pin(async move {
-                        let _ = c.0.send(batch).await;
-                    }))
+                    let (addr, slow) = (self.addr, self.slow_consumer);
+                    self.blocked_channels.push(blocked(
+                        addr,
+                        slow,
+                        None,
+                        c.0.clone(),
+                        batch,
+                    ))
                 } else if e.is_disconnected() {
-                    self.by_receiver.remove(c);
-                    self.gc_chan.insert(*id);
+                    self.gc_raw = true;
                 }
             }
         }
-        for id in self.gc_chan.drain() {
-            self.by_chan.remove(&id);
+        if self.gc_raw {
+            self.gc_raw = false;
+            self.raw_streams.retain(|_, chans| {
+                chans.retain(|c| !c.0.is_closed());
+                !chans.is_empty()
+            });
+            let raw_streams = &self.raw_streams;
+            self.raw_batches.retain(|c, batch| {
+                !c.0.is_closed()
+                    && (batch.len() > 0 || raw_streams.values().flatten().any(|s| s == c))
+            });
         }
     }
 
@@ -646,9 +1079,13 @@ impl ConnectionCtx {
                 }
             }
         }
+        // with write coalescing the first queued write waits up to
+        // `coalesce` for others to join it before they are flushed
         async fn flush(
             con: &mut WriteChannel,
             pending: &mut Vec<oneshot::Sender<()>>,
+            coalesce: Duration,
+            queued_at: &mut Option<Instant>,
         ) -> Result<()> {
             let mut flushed = || {
                 for s in pending.drain(..) {
@@ -656,25 +1093,70 @@ impl ConnectionCtx {
                 }
             };
             if con.bytes_queued() == 0 {
+                *queued_at = None;
                 flushed();
                 future::pending().await
             } else {
+                if !coalesce.is_zero() {
+                    let at = *queued_at.get_or_insert_with(Instant::now);
+                    time::sleep_until(at + coalesce).await;
+                }
                 con.flush().await?;
+                *queued_at = None;
                 flushed();
                 Ok(())
             }
         }
+        async fn next_deadline(
+            deadlines: &BinaryHeap<Reverse<(Instant, Path)>>,
+            write_deadlines: &BinaryHeap<Reverse<(Instant, Id, WriteId)>>,
+        ) -> Instant {
+            let sub = deadlines.peek().map(|Reverse((d, _))| *d);
+            let write = write_deadlines.peek().map(|Reverse((d, _, _))| *d);
+            match sub.into_iter().chain(write).min() {
+                None => future::pending().await,
+                Some(deadline) => {
+                    time::sleep_until(deadline).await;
+                    deadline
+                }
+            }
+        }
         let mut periodic = time::interval_at(Instant::now() + PERIOD, PERIOD);
+        let mut sample = time::interval(throughput::SAMPLE);
         loop {
             select_biased! {
                 // this has to come first because batch_channel isn't cancel safe
+                //
+                // requests are only coalesced if more of them arrive
+                // while we are handling the last batch, recv never
+                // waits to fill a batch, so a lone subscribe is
+                // written as soon as it's queued and there is no
+                // batch size to tune for latency.
                 batch = self.from_sub.recv().fuse() => match batch {
-                    Some(batch) => self.handle_from_sub(write_con, batch)?,
+                    Some(batch) => {
+                        self.handle_from_sub(write_con, batch)?;
+                        if self.closed {
+                            info!("connection to {} closed by request", self.addr);
+                            break Ok(())
+                        }
+                    },
                     None => break Ok(()),
                 },
-                r = flush(write_con, &mut self.pending_flushes).fuse() => r?,
-                now = periodic.tick().fuse() => {
-                    self.handle_heartbeat(now)?;
+                r = flush(
+                    write_con,
+                    &mut self.pending_flushes,
+                    self.write_coalesce,
+                    &mut self.queued_at
+                ).fuse() => r?,
+                _ = sample.tick().fuse() => self.throughput.sample(),
+                _ = periodic.tick().fuse() => {
+                    self.handle_heartbeat()?;
+                    if !self.maybe_disconnect_idle() {
+                        break Ok(())
+                    }
+                },
+                now = next_deadline(&self.deadlines, &self.write_deadlines).fuse() => {
+                    self.handle_deadlines(now);
                     if !self.maybe_disconnect_idle() {
                         break Ok(())
                     }
@@ -700,23 +1182,22 @@ impl ConnectionCtx {
     }
 
     pub(super) async fn start(mut self) -> Result<()> {
-        let soc = time::timeout(PERIOD, TcpStream::connect(self.addr)).await??;
-        soc.set_nodelay(true)?;
-        const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
-        let con = time::timeout(
-            HELLO_TIMEOUT,
-            hello_publisher(
-                soc,
+        let (mut con, version) = self
+            .factory
+            .connect(
+                self.addr,
                 self.tls_ctx.clone(),
                 self.uifo.take(),
-                &self.desired_auth,
-                &self.target_auth,
-            ),
-        )
-        .await??;
+                self.desired_auth.clone(),
+                self.target_auth.clone(),
+            )
+            .await?;
+        self.version = version;
+        con.set_large_frames(version >= 5);
         let (read_con, mut write_con) = con.split();
         let (tx_stop, rx_stop) = oneshot::channel();
-        let res = self.run(decode_task(read_con, rx_stop), &mut write_con).await;
+        let batches = decode_task(read_con, self.throughput.clone(), rx_stop);
+        let res = self.run(batches, &mut write_con).await;
         let _ = tx_stop.send(());
         if let Some(subscriber) = self.subscriber.upgrade() {
             let mut batch = DECODE_BATCHES.take();
@@ -0,0 +1,70 @@
+//! Optional subscriber metrics.
+//!
+//! When the `metrics` feature is enabled the subscriber reports the
+//! following through the [metrics](https://docs.rs/metrics) crate
+//! facade. Install any compatible recorder (e.g. a Prometheus or
+//! StatsD exporter) to collect them. When the feature is disabled
+//! every function in this module compiles to nothing.
+//!
+//! - `netidx_subscriber_subscriptions_created` (counter)
+//! - `netidx_subscriber_subscriptions_failed` (counter)
+//! - `netidx_subscriber_resubscriptions` (counter)
+//! - `netidx_subscriber_active_connections` (gauge)
+//! - `netidx_subscriber_bytes_received` (counter)
+//! - `netidx_subscriber_subscribe_latency_seconds` (histogram)
+
+#[cfg(feature = "metrics")]
+mod imp {
+    use std::time::Duration;
+
+    pub(crate) fn subscription_created(latency: Duration) {
+        metrics::counter!("netidx_subscriber_subscriptions_created").increment(1);
+        metrics::histogram!("netidx_subscriber_subscribe_latency_seconds")
+            .record(latency.as_secs_f64());
+    }
+
+    pub(crate) fn subscription_failed() {
+        metrics::counter!("netidx_subscriber_subscriptions_failed").increment(1);
+    }
+
+    pub(crate) fn resubscription() {
+        metrics::counter!("netidx_subscriber_resubscriptions").increment(1);
+    }
+
+    pub(crate) fn connection_opened() {
+        metrics::gauge!("netidx_subscriber_active_connections").increment(1.);
+    }
+
+    pub(crate) fn connection_closed() {
+        metrics::gauge!("netidx_subscriber_active_connections").decrement(1.);
+    }
+
+    pub(crate) fn bytes_received(n: u64) {
+        metrics::counter!("netidx_subscriber_bytes_received").increment(n);
+    }
+}
+
+#[cfg(not(feature = "metrics"))]
+mod imp {
+    use std::time::Duration;
+
+    #[inline(always)]
+    pub(crate) fn subscription_created(_latency: Duration) {}
+
+    #[inline(always)]
+    pub(crate) fn subscription_failed() {}
+
+    #[inline(always)]
+    pub(crate) fn resubscription() {}
+
+    #[inline(always)]
+    pub(crate) fn connection_opened() {}
+
+    #[inline(always)]
+    pub(crate) fn connection_closed() {}
+
+    #[inline(always)]
+    pub(crate) fn bytes_received(_n: u64) {}
+}
+
+pub(super) use imp::*;
@@ -0,0 +1,192 @@
+//! Move live durable subscriptions to better publishers, see
+//! `SubscriberBuilder::migrate_interval`.
+use super::{
+    locality, Chosen, ConId, DvState, Dval, Streams, SubStatus, SubscribeValRequest,
+    Subscriber, SubscriberInner, SubscriberWeak, ToCon, UpdatesFlags,
+};
+use crate::{
+    path::Path,
+    protocol::{
+        publisher::Id,
+        resolver::{Publisher, PublisherPriority},
+    },
+    publisher::PublishFlags,
+};
+use anyhow::Result;
+use futures::{channel::oneshot, prelude::*, stream::FuturesUnordered};
+use log::{info, trace, warn};
+use std::{mem, net::SocketAddr, time::Duration};
+use tokio::{
+    task,
+    time::{self, Instant, MissedTickBehavior},
+};
+
+// how long a migration round may take, including waiting for the new
+// publishers to accept
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+// lower is better
+fn rank(t: &SubscriberInner, pb: &Publisher) -> (u8, u8) {
+    let priority = match pb.priority {
+        PublisherPriority::High => 0,
+        PublisherPriority::Normal => 1,
+        PublisherPriority::Low => 2,
+    };
+    (priority, locality(&t.interfaces, pb.addr.ip()))
+}
+
+struct Migration {
+    path: Path,
+    dv: Dval,
+    // the subscription being replaced
+    old: (Id, ConId),
+}
+
+async fn migrate(subscriber: &Subscriber) -> Result<()> {
+    let (resolver, current) = {
+        let t = subscriber.0.lock();
+        let current = t
+            .durable_alive
+            .iter()
+            .filter_map(|(path, w)| {
+                let dv = w.upgrade()?;
+                let dv = dv.0.lock();
+                match &dv.sub {
+                    DvState::Subscribed(v) if dv.pin.is_none() => {
+                        Some((path.clone(), v.0.addr))
+                    }
+                    DvState::Subscribed(_) | DvState::Dead(_) => None,
+                }
+            })
+            .collect::<Vec<_>>();
+        (t.resolver.clone(), current)
+    };
+    if current.is_empty() {
+        return Ok(());
+    }
+    let (publishers, resolved) =
+        resolver.resolve(current.iter().map(|(p, _)| p.clone())).await?;
+    let deadline = Instant::now() + TIMEOUT;
+    let mut migrations = FuturesUnordered::new();
+    {
+        let mut t = subscriber.0.lock();
+        t.gc_recently_failed();
+        for ((path, addr), r) in current.into_iter().zip(resolved.iter()) {
+            let flags = match PublishFlags::from_bits(r.flags) {
+                Some(flags) if !flags.contains(PublishFlags::ISOLATED) => flags,
+                Some(_) | None => continue,
+            };
+            let mut current_rank = (u8::MAX, u8::MAX);
+            let mut best = None;
+            for pref in r.publishers.iter() {
+                if let Some(pb) = publishers.get(&pref.id) {
+                    let rank = rank(&t, pb);
+                    if pb.addr == addr {
+                        current_rank = rank;
+                    } else if !t.recently_failed.contains_key(&pb.addr)
+                        && best.as_ref().map(|(b, _, _)| rank < *b).unwrap_or(true)
+                    {
+                        best = Some((rank, pref, pb));
+                    }
+                }
+            }
+            let (pref, pb) = match best {
+                Some((rank, pref, pb)) if rank < current_rank => (pref, pb),
+                Some(_) | None => continue,
+            };
+            let dv = match t.durable_alive.get(&path).and_then(|w| w.upgrade()) {
+                Some(dv) => dv,
+                None => continue,
+            };
+            let (sub_id, old) = {
+                let inner = dv.0.lock();
+                match &inner.sub {
+                    DvState::Subscribed(v) if v.0.addr == addr => {
+                        (inner.sub_id, (v.0.id, v.0.conid))
+                    }
+                    DvState::Subscribed(_) | DvState::Dead(_) => continue,
+                }
+            };
+            let chosen = Chosen {
+                addr: pb.addr,
+                target_auth: pb.target_auth.clone(),
+                token: pref.token.clone(),
+                uifo: pb.user_info.clone(),
+                flags,
+            };
+            trace!("migrating {path} from {addr} to {}", pb.addr);
+            let con = subscriber.connection_for(&mut t, &chosen);
+            let (tx, rx) = oneshot::channel();
+            let sent = con.send(ToCon::Subscribe(SubscribeValRequest {
+                path: path.clone(),
+                sub_id,
+                timestamp: r.timestamp,
+                permissions: r.permissions as u32,
+                token: chosen.token,
+                resolver: r.resolver,
+                finished: tx,
+                con: con.clone(),
+                deadline: Some(deadline),
+                streams: Streams::new(),
+            }));
+            if sent {
+                let m = Migration { path, dv, old };
+                migrations.push(rx.map(move |res| (m, res)));
+            }
+        }
+    }
+    while let Some((m, res)) = migrations.next().await {
+        let val = match res {
+            Ok(Ok(val)) => val,
+            Ok(Err(e)) => {
+                warn!("failed to migrate {}: {e:?}", m.path);
+                continue;
+            }
+            Err(_) => continue,
+        };
+        let mut t = subscriber.0.lock();
+        let mut dv = m.dv.0.lock();
+        match &dv.sub {
+            DvState::Subscribed(v) if (v.0.id, v.0.conid) == m.old => (),
+            // it died, or moved, while we were subscribing, the new
+            // subscription is dropped
+            DvState::Subscribed(_) | DvState::Dead(_) => continue,
+        }
+        for (f, tx) in &dv.streams {
+            val.0.connection.send(ToCon::Stream {
+                tx: tx.clone(),
+                id: val.0.id,
+                flags: *f | UpdatesFlags::BEGIN_WITH_LAST | UpdatesFlags::NO_SPURIOUS,
+            });
+        }
+        info!("migrated {} to {}", m.path, val.0.addr);
+        t.subscribed.insert(m.path.clone(), SubStatus::Subscribed(val.downgrade()));
+        let old = mem::replace(&mut dv.sub, DvState::Subscribed(val));
+        drop(dv);
+        drop(t);
+        // unsubscribes from the old publisher
+        drop(old)
+    }
+    Ok(())
+}
+
+pub(super) fn start(subscriber: SubscriberWeak, interval: Duration) {
+    task::spawn(async move {
+        let mut tick = time::interval(interval);
+        tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // the first tick completes immediately
+        tick.tick().await;
+        loop {
+            tick.tick().await;
+            let subscriber = match subscriber.upgrade() {
+                Some(subscriber) => subscriber,
+                None => break,
+            };
+            match time::timeout(TIMEOUT, migrate(&subscriber)).await {
+                Ok(Ok(())) => (),
+                Ok(Err(e)) => warn!("migration failed {e:?}"),
+                Err(_) => warn!("migration timed out"),
+            }
+        }
+    });
+}
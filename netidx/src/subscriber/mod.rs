@@ -1,6 +1,14 @@
 //! Subscribe to published values.
+#[cfg(feature = "blocking")]
+pub mod blocking;
 mod connection;
-pub use crate::protocol::value::{FromValue, Typ, Value};
+mod metrics;
+mod migrate;
+mod throughput;
+pub use crate::protocol::{
+    publisher::Metadata,
+    value::{FromValue, Typ, Value},
+};
 pub use crate::resolver_client::DesiredAuth;
 use crate::{
     batch_channel::{self, BatchSender},
@@ -8,8 +16,9 @@ use crate::{
     pack::{Pack, PackError},
     path::Path,
     protocol::{
+        glob::{Glob, GlobSet},
         publisher::{From, Id, WriteId},
-        resolver::{Publisher, PublisherId, Resolved, TargetAuth},
+        resolver::{PathStatus, Publisher, PublisherId, Resolved, TargetAuth},
     },
     publisher::PublishFlags,
     resolver_client::ResolverRead,
@@ -17,8 +26,11 @@ use crate::{
     utils::{BatchItem, Batched, ChanWrap},
 };
 use ahash::AHashMap;
-use anyhow::{anyhow, Error, Result};
+use anyhow::{anyhow, Context, Error, Result};
+use arcstr::ArcStr;
 use bytes::{Buf, BufMut, Bytes};
+pub(crate) use connection::ConnectionFactory;
+pub use connection::{AddressMapper, ConnectTarget, IdentityMapper};
 use futures::{
     channel::{
         mpsc::{self, Sender, UnboundedReceiver, UnboundedSender},
@@ -26,7 +38,7 @@ use futures::{
     },
     prelude::*,
     select_biased,
-    stream::FuturesUnordered,
+    stream::{self, FuturesUnordered},
 };
 use if_addrs::{get_if_addrs, IfAddr, Interface as NetworkInterface};
 use log::{info, trace, warn};
@@ -35,21 +47,25 @@ use nohash::IntMap;
 use parking_lot::Mutex;
 use poolshark::global::{GPooled, Pool};
 use poolshark::local::LPooled;
-use rand::RngExt;
+use rand::{rngs::StdRng, RngExt, SeedableRng};
+use serde_derive::Serialize;
 use smallvec::SmallVec;
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::sync::LazyLock;
 use std::{
-    cmp::{max, Eq, PartialEq},
-    collections::{hash_map::Entry, VecDeque},
+    cmp::{max, min, Eq, PartialEq},
+    collections::{hash_map::Entry, HashMap, VecDeque},
     error, fmt,
     hash::Hash,
     iter, mem,
     net::SocketAddr,
+    pin::pin,
     result,
     sync::{Arc, Weak},
+    task::Poll,
     time::Duration,
 };
+pub use throughput::{Rate, Throughput};
 use tokio::{
     task,
     time::{self, Instant},
@@ -58,6 +74,8 @@ use triomphe::Arc as TArc;
 
 static BATCHES: LazyLock<Pool<Vec<(SubId, Event)>>> =
     LazyLock::new(|| Pool::new(64, 16384));
+static SEQ_BATCHES: LazyLock<Pool<Vec<(SubId, u64, Event)>>> =
+    LazyLock::new(|| Pool::new(64, 16384));
 static DECODE_BATCHES: LazyLock<Pool<Vec<From>>> = LazyLock::new(|| Pool::new(64, 16384));
 
 /// Subscription was denied due to insufficient permissions.
@@ -84,6 +102,87 @@ impl fmt::Display for NoSuchValue {
 
 impl error::Error for NoSuchValue {}
 
+/// The requested path has no publishers, but it did until recently,
+/// so it will likely be published again soon.
+#[derive(Debug)]
+pub struct PathExpired {
+    /// When the last publisher went away, in seconds since the unix
+    /// epoch
+    pub last_seen: u64,
+}
+
+impl fmt::Display for PathExpired {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "path not found, last published at {}", self.last_seen)
+    }
+}
+
+impl error::Error for PathExpired {}
+
+/// The operation was cancelled by the caller.
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "cancelled")
+    }
+}
+
+impl error::Error for Cancelled {}
+
+/// The publisher requires kerberos, but the resolver did not give
+/// its service principal, so there is nothing to authenticate to.
+#[derive(Debug)]
+pub struct MissingSpn {
+    pub addr: SocketAddr,
+}
+
+impl fmt::Display for MissingSpn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "publisher {} requires kerberos but has no spn", self.addr)
+    }
+}
+
+impl error::Error for MissingSpn {}
+
+/// The publisher didn't reply to a write within
+/// `SubscriberBuilder::write_timeout`.
+#[derive(Debug)]
+pub struct WriteTimedOut;
+
+impl fmt::Display for WriteTimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "write timed out")
+    }
+}
+
+impl error::Error for WriteTimedOut {}
+
+/// The publisher's reply to `Val::write_with_recipt` or
+/// `Dval::write_with_recipt`. It resolves to the reply, to
+/// `WriteTimedOut` if the publisher didn't reply in time, or to some
+/// other error if the write was dropped, e.g. because the connection
+/// died.
+#[derive(Debug)]
+pub struct WriteReceipt(oneshot::Receiver<Result<Value>>);
+
+impl Future for WriteReceipt {
+    type Output = Result<Value>;
+
+    fn poll(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context,
+    ) -> Poll<Self::Output> {
+        self.0.poll_unpin(cx).map(|r| match r {
+            Ok(r) => r,
+            Err(_) => Err(anyhow!("write dropped before the publisher replied")),
+        })
+    }
+}
+
+type Cancel = future::Shared<future::BoxFuture<'static, ()>>;
+
 atomic_id!(SubId);
 atomic_id!(SubscriberId);
 atomic_id!(ConId);
@@ -117,8 +216,28 @@ bitflags! {
 
 type Updates = GPooled<Vec<(SubId, Event)>>;
 pub type UpdateChan = Sender<Updates>;
-type WUpdateChan = ChanWrap<Updates>;
+type SeqUpdates = GPooled<Vec<(SubId, u64, Event)>>;
+pub type SeqUpdateChan = Sender<SeqUpdates>;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum WUpdateChan {
+    Plain(ChanWrap<Updates>),
+    Seq(ChanWrap<SeqUpdates>),
+}
+
+impl WUpdateChan {
+    fn is_closed(&self) -> bool {
+        match self {
+            WUpdateChan::Plain(c) => c.0.is_closed(),
+            WUpdateChan::Seq(c) => c.0.is_closed(),
+        }
+    }
+}
+
 type Streams = SmallVec<[(UpdatesFlags, WUpdateChan); 1]>;
+type RawUpdates = GPooled<Vec<From>>;
+pub type RawUpdateChan = Sender<RawUpdates>;
+type WRawUpdateChan = ChanWrap<RawUpdates>;
 
 #[derive(Debug)]
 struct SubscribeValRequest {
@@ -139,8 +258,11 @@ enum ToCon {
     Subscribe(SubscribeValRequest),
     Unsubscribe(Id),
     Stream { id: Id, tx: WUpdateChan, flags: UpdatesFlags },
-    Write(Id, Value, WriteId, Option<oneshot::Sender<Value>>),
+    RawStream { id: Id, tx: WRawUpdateChan },
+    Write(Id, Value, WriteId, Option<oneshot::Sender<Result<Value>>>),
     Flush(oneshot::Sender<()>),
+    // shut the connection down as if the publisher had gone away
+    Close,
 }
 
 /// A subscription event
@@ -178,13 +300,23 @@ impl Pack for Event {
     }
 }
 
+// the last event, and when the connection task received it
+#[derive(Debug)]
+struct Last {
+    event: Event,
+    received: Instant,
+}
+
 #[derive(Debug)]
 struct ValInner {
     sub_id: SubId,
     id: Id,
     conid: ConId,
+    addr: SocketAddr,
     connection: BatchSender<ToCon>,
-    last: TArc<Mutex<Event>>,
+    last: TArc<Mutex<Last>>,
+    metadata: Option<Metadata>,
+    protocol_version: u64,
 }
 
 impl Drop for ValInner {
@@ -214,8 +346,43 @@ impl Val {
     }
 
     /// Get the last event value.
+    ///
+    /// The last value is kept in memory shared with the connection
+    /// task, so this is just a brief lock and a clone, it does not
+    /// communicate with the connection or the publisher. It is fine
+    /// to call it in a loop over many subscriptions. If the
+    /// subscription is dead this returns `Event::Unsubscribed`.
     pub fn last(&self) -> Event {
-        self.0.last.lock().clone()
+        self.0.last.lock().event.clone()
+    }
+
+    /// Get the last value and the time since the connection task
+    /// received it, or `None` if the subscription is dead.
+    ///
+    /// A publisher that has stopped sending won't be detected as
+    /// dead until the heartbeat times out, until then this lets you
+    /// see how old the value is. If the subscription was made with
+    /// `UpdatesFlags::STOP_COLLECTING_LAST` the last value is no
+    /// longer updated, and the age will just keep growing.
+    pub fn last_with_age(&self) -> Option<(Value, Duration)> {
+        let last = self.0.last.lock();
+        match &last.event {
+            Event::Unsubscribed => None,
+            Event::Update(v) => Some((v.clone(), last.received.elapsed())),
+        }
+    }
+
+    /// Get the metadata the publisher attached to this value, if
+    /// any. Metadata is sent once when the subscription is made, it
+    /// is not updated for the life of the subscription.
+    pub fn metadata(&self) -> Option<Metadata> {
+        self.0.metadata.clone()
+    }
+
+    /// Get the publisher protocol version negotiated by the
+    /// connection this value was subscribed over.
+    pub fn protocol_version(&self) -> u64 {
+        self.0.protocol_version
     }
 
     /// Register a channel to receive updates to this subscription.
@@ -229,14 +396,61 @@ impl Val {
     /// register a duplicate channel and begin_with_last is true you
     /// will get an update with the current state, even though the
     /// channel registration will be ignored.
+    ///
+    /// Publishers always send an initial value with the subscription,
+    /// so with `BEGIN_WITH_LAST` the first update will be the last
+    /// value, unless the subscription is dead or `STOP_COLLECTING_LAST`
+    /// was set previously, in which case nothing is sent. To know
+    /// which happened, await `flush` after calling this method. Once
+    /// it returns the registration has been processed and the last
+    /// value, if any, has been sent to the channel, or is waiting for
+    /// room in it.
     pub fn updates(&self, flags: UpdatesFlags, tx: UpdateChan) {
-        let m = ToCon::Stream { tx: ChanWrap(tx), id: self.0.id, flags };
+        let tx = WUpdateChan::Plain(ChanWrap(tx));
+        let m = ToCon::Stream { tx, id: self.0.id, flags };
+        self.0.connection.send(m);
+    }
+
+    /// Register a channel to receive sequenced updates to this
+    /// subscription. This is the same as `updates` except that each
+    /// event carries a sequence number.
+    ///
+    /// Each channel gets its own sequence, starting at 0 with the
+    /// first event sent to it and increasing by 1 with every event
+    /// after that. Since no event is ever omitted during the life of
+    /// a subscription the numbers a channel receives are contiguous,
+    /// and the last one is for `Event::Unsubscribed`.
+    pub fn updates_sequenced(&self, flags: UpdatesFlags, tx: SeqUpdateChan) {
+        let tx = WUpdateChan::Seq(ChanWrap(tx));
+        let m = ToCon::Stream { tx, id: self.0.id, flags };
+        self.0.connection.send(m);
+    }
+
+    /// Register a channel to receive the raw protocol messages the
+    /// publisher sends about this subscription.
+    ///
+    /// This is an escape hatch for protocol level tools such as
+    /// conformance tests and debuggers, most applications should use
+    /// `updates` instead. The channel will receive the `Update`,
+    /// `WriteResult`, and `Unsubscribed` messages for this
+    /// subscription, as well as every `Heartbeat` sent on the
+    /// underlying connection. Since registration happens after the
+    /// subscription is established the channel will never see
+    /// `Subscribed`. Raw channels are independent of `last` and of
+    /// any channels registered with `updates`.
+    ///
+    /// Registering the same channel more than once has no effect.
+    pub fn updates_raw(&self, tx: RawUpdateChan) {
+        let m = ToCon::RawStream { tx: ChanWrap(tx), id: self.0.id };
         self.0.connection.send(m);
     }
 
-    /// Write a value back to the publisher. This will start going out
-    /// as soon as this method returns, and you can call `flush` on
-    /// the subscriber to get pushback in case of a slow publisher.
+    /// Write a value back to the publisher. This never blocks, the
+    /// write is queued on the connection along with everything else
+    /// going to the publisher, and it starts going out as soon as
+    /// this method returns, or after `SubscriberBuilder::write_coalesce`
+    /// if that is set. Call `flush` on the value, or on the
+    /// subscriber, to get pushback in case of a slow publisher.
     ///
     /// The publisher will receive multiple writes in the order you
     /// call `write`.
@@ -252,16 +466,19 @@ impl Val {
     ///
     /// This does the same thing as `write` except that it requires
     /// the publisher send a reply indicating the outcome of the
-    /// request. The reply can be read from the returned oneshot
-    /// channel.
+    /// request. The reply can be read from the returned receipt.
+    ///
+    /// If `SubscriberBuilder::write_timeout` is set and the publisher
+    /// doesn't reply in time the receipt is a `WriteTimedOut`
+    /// error. If the connection dies first it is some other error.
     ///
     /// Note that compared to `write` this function has higher
     /// overhead, avoid it in situations where high message volumes
     /// are required.
-    pub fn write_with_recipt(&self, v: Value) -> oneshot::Receiver<Value> {
+    pub fn write_with_recipt(&self, v: Value) -> WriteReceipt {
         let (tx, rx) = oneshot::channel();
         self.0.connection.send(ToCon::Write(self.0.id, v, WriteId::new(), Some(tx)));
-        rx
+        WriteReceipt(rx)
     }
 
     /// Get the unique id of this subscription.
@@ -269,6 +486,18 @@ impl Val {
         self.0.sub_id
     }
 
+    /// Wait until everything queued on this subscription's
+    /// connection, including writes, has been sent to the publisher.
+    ///
+    /// There is no flush interval unless
+    /// `SubscriberBuilder::write_coalesce` is set. The connection
+    /// starts writing as soon as it has anything queued, and
+    /// messages that are queued while a write is in progress are
+    /// sent together in the next one. So latency stays low when
+    /// traffic is light, and syscalls are amortized over larger
+    /// batches when it is heavy. This method doesn't make anything
+    /// go out sooner, it is for pushback when the publisher or the
+    /// network is slower than you are.
     pub async fn flush(&self) -> Result<()> {
         let (tx, rx) = oneshot::channel();
         self.0.connection.send(ToCon::Flush(tx));
@@ -278,10 +507,34 @@ impl Val {
 
 #[derive(Debug)]
 struct DvDead {
-    queued_writes: Vec<(Value, Option<oneshot::Sender<Value>>)>,
+    queued_writes: Vec<(Value, Option<oneshot::Sender<Result<Value>>>)>,
     waiting: Vec<oneshot::Sender<()>>,
+    ready: Vec<oneshot::Sender<Result<()>>>,
     tries: usize,
     next_try: Instant,
+    last_error: Option<ArcStr>,
+}
+
+impl DvDead {
+    // drop the oldest queued writes until there are at most `limit`
+    fn trim_queued_writes(&mut self, limit: Option<usize>) {
+        if let Some(limit) = limit {
+            let n = self.queued_writes.len();
+            if n > limit {
+                self.queued_writes.drain(..n - limit);
+            }
+        }
+    }
+
+    fn queue_write(
+        &mut self,
+        limit: Option<usize>,
+        v: Value,
+        tx: Option<oneshot::Sender<Result<Value>>>,
+    ) {
+        self.queued_writes.push((v, tx));
+        self.trim_queued_writes(limit)
+    }
 }
 
 #[derive(Debug)]
@@ -290,11 +543,24 @@ enum DvState {
     Dead(Box<DvDead>), // the box ensures that DvState is tag + 1 word
 }
 
+// the last resolution of a pinned dval, and how many more times we
+// may reuse it before going back to the resolver
+#[derive(Debug)]
+struct Pin {
+    attempts: usize,
+    remaining: usize,
+    last: Option<CachedResolve>,
+}
+
 #[derive(Debug)]
 struct DvalInner {
     sub_id: SubId,
     sub: DvState,
     streams: Streams,
+    pin: Option<Box<Pin>>,
+    write_queue_limit: Option<usize>,
+    // overrides the subscriber's durable config
+    durable_config: Option<Box<DurableConfig>>,
 }
 
 #[derive(Debug, Clone)]
@@ -321,8 +587,9 @@ impl DvalWeak {
 ///   `Dval` will transparently move to another one.
 ///
 /// - a publisher is restarted (possibly on a different
-///   machine). `Dval` will wait using linear backoff for the publisher
-///   to come back, and then it will resubscribe.
+///   machine). `Dval` will wait, as `SubscriberBuilder::durable_config`
+///   says, for the publisher to come back, and then it will
+///   resubscribe.
 ///
 /// - The resolver server cluster is restarted. In this case existing
 ///   subscriptions won't die, but new ones will fail while the
@@ -367,6 +634,38 @@ impl Dval {
         }
     }
 
+    /// Get the last value published by the publisher and the time
+    /// since it was received, or None if the subscription is
+    /// currently dead. see `Val::last_with_age`.
+    pub fn last_with_age(&self) -> Option<(Value, Duration)> {
+        match &self.0.lock().sub {
+            DvState::Dead(_) => None,
+            DvState::Subscribed(val) => val.last_with_age(),
+        }
+    }
+
+    /// Get the metadata of the current subscription, or None if the
+    /// subscription is currently dead or the publisher did not attach
+    /// any. Metadata may change when the durable subscription is
+    /// resubscribed.
+    pub fn metadata(&self) -> Option<Metadata> {
+        match &self.0.lock().sub {
+            DvState::Dead(_) => None,
+            DvState::Subscribed(val) => val.metadata(),
+        }
+    }
+
+    /// Get the publisher protocol version of the current
+    /// subscription, or None if the subscription is currently
+    /// dead. The version may change when the durable subscription is
+    /// resubscribed.
+    pub fn protocol_version(&self) -> Option<u64> {
+        match &self.0.lock().sub {
+            DvState::Dead(_) => None,
+            DvState::Subscribed(val) => Some(val.protocol_version()),
+        }
+    }
+
     /// Register a channel to receive updates to this durable subscription.
     ///
     /// You may register multiple different channels to receive
@@ -377,8 +676,22 @@ impl Dval {
         flags: UpdatesFlags,
         tx: mpsc::Sender<GPooled<Vec<(SubId, Event)>>>,
     ) {
+        self.stream(flags, WUpdateChan::Plain(ChanWrap(tx)))
+    }
+
+    /// Register a channel to receive sequenced updates to this
+    /// durable subscription, see `Val::updates_sequenced`.
+    ///
+    /// The sequence starts over at 0 every time the `Dval` is
+    /// resubscribed or migrated to another publisher, so a 0 on
+    /// anything but the first event marks a boundary across which
+    /// updates may have been missed.
+    pub fn updates_sequenced(&self, flags: UpdatesFlags, tx: SeqUpdateChan) {
+        self.stream(flags, WUpdateChan::Seq(ChanWrap(tx)))
+    }
+
+    fn stream(&self, flags: UpdatesFlags, tx: WUpdateChan) {
         let mut t = self.0.lock();
-        let tx = ChanWrap(tx);
         if !t.streams.iter().any(|(_, s)| &tx == s) {
             t.streams.push((flags, tx.clone()));
         }
@@ -388,6 +701,112 @@ impl Dval {
         }
     }
 
+    /// Turn the subscription into a stream of values.
+    ///
+    /// The stream never ends on its own. While the subscription is
+    /// dead it produces nothing, and when it is resubscribed it
+    /// picks up again starting with the current value. If
+    /// `begin_with_last` is true and the `Dval` is currently
+    /// subscribed then the current value is the first item,
+    /// otherwise the first item is the next update. A `Dval` that is
+    /// not yet subscribed always starts with the value it is
+    /// subscribed with. The stream holds the `Dval`, so the
+    /// subscription lives as long as the stream does.
+    pub fn into_stream(
+        self,
+        begin_with_last: bool,
+    ) -> impl Stream<Item = Value> + Send + 'static {
+        let (tx, rx) = mpsc::channel(3);
+        let flags = if begin_with_last {
+            UpdatesFlags::BEGIN_WITH_LAST
+        } else {
+            UpdatesFlags::empty()
+        };
+        self.updates(flags, tx);
+        rx.flat_map(move |mut batch| {
+            let _dv = &self;
+            let vals = batch
+                .drain(..)
+                .filter_map(|(_, ev)| match ev {
+                    Event::Update(v) => Some(v),
+                    Event::Unsubscribed => None,
+                })
+                .collect::<SmallVec<[Value; 8]>>();
+            stream::iter(vals)
+        })
+    }
+
+    /// Subscribe every `Dval` in `dvals` to one stream that keeps
+    /// updates grouped the way they arrived. Each item holds the
+    /// updates for these subscriptions from one batch received from
+    /// a publisher, so updates the publisher flushed together, e.g.
+    /// a set of related values, can be applied together. Updates
+    /// from different publishers are never in the same item.
+    ///
+    /// The grouping follows the publisher's flushes as they arrive,
+    /// it is not a guarantee of atomicity. A large flush may arrive
+    /// in more than one batch, and `Unsubscribed` events are
+    /// dropped, as with `into_stream`. The stream holds the `Dval`s,
+    /// so the subscriptions live as long as the stream does.
+    pub fn updates_batched<I: IntoIterator<Item = Dval>>(
+        dvals: I,
+        flags: UpdatesFlags,
+    ) -> impl Stream<Item = Vec<(SubId, Value)>> + Send + 'static {
+        let (tx, rx) = mpsc::channel(3);
+        let dvals = dvals.into_iter().collect::<Vec<_>>();
+        for dv in &dvals {
+            dv.updates(flags, tx.clone())
+        }
+        rx.filter_map(move |mut batch| {
+            let _dvals = &dvals;
+            let vals = batch
+                .drain(..)
+                .filter_map(|(id, ev)| match ev {
+                    Event::Update(v) => Some((id, v)),
+                    Event::Unsubscribed => None,
+                })
+                .collect::<Vec<_>>();
+            future::ready(if vals.is_empty() { None } else { Some(vals) })
+        })
+    }
+
+    /// Return the error from the last failed resubscription attempt,
+    /// e.g. connection refused, or None if the `Dval` is subscribed
+    /// or no attempt has failed since it was last subscribed.
+    pub fn last_error(&self) -> Option<ArcStr> {
+        match &self.0.lock().sub {
+            DvState::Subscribed(_) => None,
+            DvState::Dead(d) => d.last_error.clone(),
+        }
+    }
+
+    /// Use `config` instead of the subscriber's
+    /// `SubscriberBuilder::durable_config` to decide how long to wait
+    /// between resubscription attempts, or go back to the
+    /// subscriber's if `config` is None. Takes effect after the next
+    /// failed attempt.
+    pub fn set_durable_config(&self, config: Option<DurableConfig>) -> Result<()> {
+        if let Some(config) = &config {
+            config.check()?
+        }
+        self.0.lock().durable_config = config.map(Box::new);
+        Ok(())
+    }
+
+    /// Return when the next resubscription attempt will be made, or
+    /// None if the `Dval` is subscribed. While an attempt is in
+    /// progress this is the time it was scheduled for.
+    ///
+    /// The subscriber only reads the clock through `tokio::time`, so
+    /// in a runtime with the clock paused (`tokio::time::pause`) the
+    /// retries happen exactly as the clock is advanced.
+    pub fn next_try(&self) -> Option<Instant> {
+        match &self.0.lock().sub {
+            DvState::Subscribed(_) => None,
+            DvState::Dead(d) => Some(d.next_try),
+        }
+    }
+
     /// Wait until the `Dval` is subscribed.
     ///
     /// This is not a guarantee that the `Dval` will stay subscribed for any
@@ -412,16 +831,18 @@ impl Dval {
     /// when we are. The return value will be `true` if the write was
     /// sent immediatly, and false if it was queued. It is still
     /// possible that a write will be dropped e.g. if the connection
-    /// dies while we are writing it.
+    /// dies while we are writing it. See `set_write_queue_limit` to
+    /// bound the queue.
     pub fn write(&self, v: Value) -> bool {
         let mut t = self.0.lock();
+        let limit = t.write_queue_limit;
         match &mut t.sub {
             DvState::Subscribed(val) => {
                 val.write(v);
                 true
             }
             DvState::Dead(dead) => {
-                dead.queued_writes.push((v, None));
+                dead.queue_write(limit, v, None);
                 false
             }
         }
@@ -431,8 +852,7 @@ impl Dval {
     ///
     /// This does the same thing as `write` except that it requires
     /// the publisher send a reply indicating the outcome of the
-    /// request. The reply can be read from the returned oneshot
-    /// channel.
+    /// request. The reply can be read from the returned receipt.
     ///
     /// Note that compared to `write` this function has higher
     /// overhead, avoid it in situations where high message volumes
@@ -441,9 +861,10 @@ impl Dval {
     /// If we are not currently subscribed then the write will be
     /// queued until we are. It is still possible that a write will be
     /// dropped e.g. if the connection dies while we are writing it.
-    pub fn write_with_recipt(&self, v: Value) -> oneshot::Receiver<Value> {
+    pub fn write_with_recipt(&self, v: Value) -> WriteReceipt {
         let (tx, rx) = oneshot::channel();
         let mut t = self.0.lock();
+        let limit = t.write_queue_limit;
         match &mut t.sub {
             DvState::Subscribed(sub) => {
                 sub.0.connection.send(ToCon::Write(
@@ -454,10 +875,10 @@ impl Dval {
                 ));
             }
             DvState::Dead(dead) => {
-                dead.queued_writes.push((v, Some(tx)));
+                dead.queue_write(limit, v, Some(tx));
             }
         }
-        rx
+        WriteReceipt(rx)
     }
 
     /// Clear the write queue.
@@ -468,6 +889,22 @@ impl Dval {
         }
     }
 
+    /// Limit how many writes are queued while we are not
+    /// subscribed. When the queue is full the oldest write is
+    /// dropped to make room, and if it was made with
+    /// `write_with_recipt` its receipt channel is closed. So a limit
+    /// of 1 keeps only the last write, which suits setpoints, and 0
+    /// drops every write made while unsubscribed. If the queue is
+    /// already longer than `limit` it is trimmed now. Default None,
+    /// the queue is unbounded.
+    pub fn set_write_queue_limit(&self, limit: Option<usize>) {
+        let mut t = self.0.lock();
+        t.write_queue_limit = limit;
+        if let DvState::Dead(dead) = &mut t.sub {
+            dead.trim_queued_writes(limit)
+        }
+    }
+
     /// Return the number of queued writes.
     pub fn queued_writes(&self) -> usize {
         match &mut self.0.lock().sub {
@@ -489,12 +926,224 @@ enum SubStatus {
 }
 
 const REMEBER_FAILED: Duration = Duration::from_secs(60);
+const DEFAULT_RESUB_BATCH: usize = 100_000;
+// resolver tokens are only valid for 5 minutes
+const MAX_RESOLVE_CACHE_TTL: Duration = Duration::from_secs(240);
+// the longest a durable subscription may wait between attempts, it
+// keeps the next try time representable
+const MAX_DURABLE_WAIT: Duration = Duration::from_secs(365 * 24 * 3600);
+// the longest a durable subscription to a path that only just lost
+// its publisher waits, such paths usually come back soon
+const MAX_EXPIRED_WAIT: Duration = Duration::from_secs(5);
+// the most durable resubscriptions that may be in flight at once
+const MAX_RESUB_PENDING: usize = 100_000;
+const DEFAULT_SUBSCRIBE_TIMEOUT: Duration = Duration::from_secs(60);
 
 fn pick(n: usize) -> usize {
     let mut rng = rand::rng();
     rng.random_range(0..n)
 }
 
+// How close ip is to this machine, 0 if it is one of our addresses, 1
+// if it is on the same subnet as one of our interfaces, otherwise 2
+fn locality(interfaces: &[NetworkInterface], ip: IpAddr) -> u8 {
+    fn mv4(ip: Ipv4Addr, mask: Ipv4Addr) -> Ipv4Addr {
+        let mut masked = [0u8; 4];
+        let ip = ip.octets();
+        let mask = mask.octets();
+        for i in 0..4 {
+            masked[i] = ip[i] & mask[i];
+        }
+        masked.into()
+    }
+    fn mv6(ip: Ipv6Addr, mask: Ipv6Addr) -> Ipv6Addr {
+        let mut masked = [0u8; 16];
+        let ip = ip.octets();
+        let mask = mask.octets();
+        for i in 0..16 {
+            masked[i] = ip[i] & mask[i];
+        }
+        masked.into()
+    }
+    interfaces.iter().fold(2, |cur, i| match &i.addr {
+        IfAddr::V4(ifv4) => match ip {
+            IpAddr::V6(_) => cur,
+            IpAddr::V4(ipv4) => {
+                if ipv4 == ifv4.ip {
+                    0
+                } else if mv4(ifv4.ip, ifv4.netmask) == mv4(ipv4, ifv4.netmask) {
+                    min(cur, 1)
+                } else {
+                    min(cur, 2)
+                }
+            }
+        },
+        IfAddr::V6(ifv6) => match ip {
+            IpAddr::V4(_) => cur,
+            IpAddr::V6(ipv6) => {
+                if ipv6 == ifv6.ip {
+                    0
+                } else if mv6(ifv6.ip, ifv6.netmask) == mv6(ipv6, ifv6.netmask) {
+                    min(cur, 1)
+                } else {
+                    min(cur, 2)
+                }
+            }
+        },
+    })
+}
+
+/// How long durable subscriptions wait between resubscription
+/// attempts, see `SubscriberBuilder::durable_config`.
+///
+/// After `tries` failures the longest wait is `base * tries` if the
+/// multiplier is 1, and `base * multiplier^(tries - 1)` if it is
+/// larger, capped at `max`. So a multiplier of 1 backs off linearly
+/// and anything larger backs off exponentially. The actual wait is drawn uniformly from
+/// `[longest * (1 - jitter), longest)`, so that after a mass failure,
+/// e.g. a resolver cluster restart, the retries spread out instead
+/// of arriving together. A jitter of 0 always waits the longest, 1
+/// waits anywhere from 0 to the longest.
+///
+/// The default, base 50ms, max a year, multiplier 1, and jitter 1,
+/// waits up to 50ms after the first failure, up to 100ms after the
+/// second, and so on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DurableConfig {
+    pub base: Duration,
+    pub max: Duration,
+    pub multiplier: f64,
+    pub jitter: f64,
+}
+
+impl Default for DurableConfig {
+    fn default() -> Self {
+        Self {
+            base: Duration::from_millis(50),
+            max: MAX_DURABLE_WAIT,
+            multiplier: 1.,
+            jitter: 1.,
+        }
+    }
+}
+
+impl DurableConfig {
+    fn check(&self) -> Result<()> {
+        if self.base.is_zero() {
+            bail!("durable base must be positive")
+        }
+        if self.max < self.base {
+            bail!("durable max must be at least base")
+        }
+        if self.max > MAX_DURABLE_WAIT {
+            bail!("durable max may not exceed {:?}", MAX_DURABLE_WAIT)
+        }
+        if !(self.multiplier >= 1.) || self.multiplier.is_infinite() {
+            bail!("durable multiplier must be finite and at least 1")
+        }
+        if !(0. ..=1.).contains(&self.jitter) {
+            bail!("durable jitter must be between 0 and 1")
+        }
+        Ok(())
+    }
+
+    /// How long a durable subscription that has failed `tries` times
+    /// waits before trying again.
+    pub(crate) fn retry_wait(&self, tries: usize) -> Duration {
+        let tries = min(max(1, tries), i32::MAX as usize);
+        let base = self.base.as_secs_f64();
+        let longest = if self.multiplier > 1. {
+            base * self.multiplier.powi(tries as i32 - 1)
+        } else {
+            base * tries as f64
+        };
+        let longest = longest.min(self.max.as_secs_f64());
+        let u = rand::rng().random::<f64>();
+        let wait = longest * ((1. - self.jitter) + self.jitter * u);
+        Duration::try_from_secs_f64(wait).unwrap_or(self.max).min(self.max)
+    }
+}
+
+/// How the subscriber chooses between multiple publishers of the same
+/// path, when the publish flags don't otherwise dictate the choice.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PublisherSelection {
+    /// Choose uniformly at random. This is the default.
+    #[default]
+    Random,
+    /// Choose uniformly at random using a random number generator
+    /// seeded with the specified value. Given the same publishers,
+    /// the sequence of choices will be the same every time.
+    Seeded(u64),
+    /// Cycle through the available publishers in order of address,
+    /// this spreads subscriptions evenly across publishers.
+    RoundRobin,
+}
+
+/// A scheduling hint for a batch of nondurable subscriptions, see
+/// `Subscriber::subscribe_nondurable_prio`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SubscribePriority {
+    /// Interactive subscriptions. This is the default, and what every
+    /// other subscribe method uses.
+    #[default]
+    Foreground,
+    /// Bulk subscriptions that can wait. A background batch does not
+    /// start resolving while a foreground batch is resolving or
+    /// connecting, but it never waits more than
+    /// `MAX_BACKGROUND_DELAY`.
+    Background,
+}
+
+/// The longest a background batch will wait for foreground batches
+pub const MAX_BACKGROUND_DELAY: Duration = Duration::from_secs(1);
+
+// held by a foreground batch until its subscribe requests are sent
+pub(crate) struct Foreground(SubscriberWeak);
+
+impl Drop for Foreground {
+    fn drop(&mut self) {
+        if let Some(subscriber) = self.0.upgrade() {
+            let mut t = subscriber.0.lock();
+            t.foreground -= 1;
+            if t.foreground == 0 {
+                for tx in t.background.drain(..) {
+                    let _ = tx.send(());
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum Selector {
+    Random,
+    Seeded(StdRng),
+    RoundRobin(usize),
+}
+
+impl Selector {
+    pub(crate) fn new(selection: PublisherSelection) -> Self {
+        match selection {
+            PublisherSelection::Random => Self::Random,
+            PublisherSelection::Seeded(seed) => Self::Seeded(StdRng::seed_from_u64(seed)),
+            PublisherSelection::RoundRobin => Self::RoundRobin(0),
+        }
+    }
+
+    pub(crate) fn pick(&mut self, n: usize) -> usize {
+        match self {
+            Self::Random => pick(n),
+            Self::Seeded(rng) => rng.random_range(0..n),
+            Self::RoundRobin(i) => {
+                let r = *i % n;
+                *i = i.wrapping_add(1);
+                r
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 struct Connection {
     primary: Option<(ConId, BatchSender<ToCon>)>,
@@ -523,7 +1172,7 @@ impl Connection {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 struct Chosen {
     addr: SocketAddr,
     target_auth: TargetAuth,
@@ -532,6 +1181,16 @@ struct Chosen {
     flags: PublishFlags,
 }
 
+#[derive(Debug, Clone)]
+struct CachedResolve {
+    chosen: Chosen,
+    timestamp: u64,
+    permissions: u32,
+    resolver: SocketAddr,
+    expires: Instant,
+    pinned: bool,
+}
+
 #[derive(Debug)]
 struct SubscriberInner {
     id: SubscriberId,
@@ -546,16 +1205,50 @@ struct SubscriberInner {
     desired_auth: DesiredAuth,
     tls_ctx: Option<tls::CachedConnector>,
     interfaces: Vec<NetworkInterface>,
+    selector: Selector,
+    factory: Arc<dyn ConnectionFactory>,
+    resolve_cache: AHashMap<Path, CachedResolve>,
+    resolve_cache_ttl: Duration,
+    max_subscriptions: Option<usize>,
+    subscribe_timeout: Duration,
+    durable_config: DurableConfig,
+    write_coalesce: Duration,
+    slow_consumer: Option<connection::SlowConsumer>,
+    write_timeout: Option<Duration>,
+    throughput: Arc<throughput::Meter>,
+    // the kerberos spn of each publisher in the last resolve
+    krb5_spns: AHashMap<SocketAddr, ArcStr>,
+    // the size of each resubscription batch processed so far
+    #[cfg(test)]
+    resub_batches: Vec<usize>,
+    foreground: usize,
+    background: Vec<oneshot::Sender<()>>,
 }
 
 impl SubscriberInner {
-    fn durable_id(&self, path: &Path) -> Option<SubId> {
+    fn durable(&self, path: &Path) -> Option<Dval> {
         self.durable_dead
             .get(path)
             .or_else(|| self.durable_pending.get(path))
             .or_else(|| self.durable_alive.get(path))
             .and_then(|w| w.upgrade())
-            .map(|d| d.id())
+    }
+
+    fn durable_id(&self, path: &Path) -> Option<SubId> {
+        self.durable(path).map(|d| d.id())
+    }
+
+    /// Return the copy of `path` already held by the subscription
+    /// tables, if any, so that every table shares one allocation of
+    /// each path no matter how many times the caller builds it.
+    fn intern(&self, path: Path) -> Path {
+        if let Some((p, _)) = self.subscribed.get_key_value(&path) {
+            return p.clone();
+        }
+        [&self.durable_dead, &self.durable_pending, &self.durable_alive]
+            .into_iter()
+            .find_map(|m| m.get_key_value(&path).map(|(p, _)| p.clone()))
+            .unwrap_or(path)
     }
 
     fn choose_random_addr(
@@ -564,7 +1257,6 @@ impl SubscriberInner {
         resolved: &Resolved,
         flags: PublishFlags,
     ) -> Option<Chosen> {
-        use rand::seq::IteratorRandom;
         trace!("publishers {:?}", publishers);
         trace!("resolved {:?}", resolved);
         let mk = |(pref, pb): (&PublisherRef, &Publisher)| Chosen {
@@ -574,17 +1266,21 @@ impl SubscriberInner {
             uifo: pb.user_info.clone(),
             flags,
         };
+        let mut buf = SmallVec::<[(&PublisherRef, &Publisher); 16]>::new();
         macro_rules! with_pred {
-            ($f:expr) => {
-                resolved
-                    .publishers
-                    .iter()
-                    .filter_map(|pref| {
-                        publishers.get(&pref.id).filter($f).map(|pb| (pref, pb))
-                    })
-                    .choose(&mut rand::rng())
-                    .map(mk)
-            };
+            ($f:expr) => {{
+                buf.clear();
+                buf.extend(resolved.publishers.iter().filter_map(|pref| {
+                    publishers.get(&pref.id).filter($f).map(|pb| (pref, pb))
+                }));
+                // the resolver's order isn't stable, the selector's is
+                buf.sort_by_key(|(_, pb)| pb.addr);
+                if buf.is_empty() {
+                    None
+                } else {
+                    Some(mk(buf[self.selector.pick(buf.len())]))
+                }
+            }};
         }
         let high = with_pred!(|pb| {
             pb.priority == PublisherPriority::High
@@ -607,12 +1303,7 @@ impl SubscriberInner {
         if let Some(chosen) = low {
             return Some(chosen);
         }
-        let chosen = resolved
-            .publishers
-            .iter()
-            .filter_map(|pref| publishers.get(&pref.id).map(|pb| (pref, pb)))
-            .choose(&mut rand::rng())
-            .map(mk);
+        let chosen = with_pred!(|_| true);
         trace!("chosen {chosen:?}");
         chosen
     }
@@ -651,25 +1342,6 @@ impl SubscriberInner {
         resolved: &Resolved,
         flags: PublishFlags,
     ) -> Option<Chosen> {
-        use std::{cmp::min, net::IpAddr};
-        fn mv4(ip: Ipv4Addr, mask: Ipv4Addr) -> Ipv4Addr {
-            let mut masked = [0u8; 4];
-            let ip = ip.octets();
-            let mask = mask.octets();
-            for i in 0..4 {
-                masked[i] = ip[i] & mask[i];
-            }
-            masked.into()
-        }
-        fn mv6(ip: Ipv6Addr, mask: Ipv6Addr) -> Ipv6Addr {
-            let mut masked = [0u8; 16];
-            let ip = ip.octets();
-            let mask = mask.octets();
-            for i in 0..16 {
-                masked[i] = ip[i] & mask[i];
-            }
-            masked.into()
-        }
         let mut buf = SmallVec::<[(&PublisherRef, &Publisher); 16]>::new();
         buf.extend(
             resolved
@@ -680,33 +1352,7 @@ impl SubscriberInner {
         );
         let mut all_far = true;
         buf.sort_by_key(|(_, pb): &(&PublisherRef, &Publisher)| {
-            let ip = pb.addr.ip();
-            let pri = self.interfaces.iter().fold(2, |cur, i| match &i.addr {
-                IfAddr::V4(ifv4) => match ip {
-                    IpAddr::V6(_) => cur,
-                    IpAddr::V4(ipv4) => {
-                        if ipv4 == ifv4.ip {
-                            0
-                        } else if mv4(ifv4.ip, ifv4.netmask) == mv4(ipv4, ifv4.netmask) {
-                            min(cur, 1)
-                        } else {
-                            min(cur, 2)
-                        }
-                    }
-                },
-                IfAddr::V6(ifv6) => match ip {
-                    IpAddr::V4(_) => cur,
-                    IpAddr::V6(ipv6) => {
-                        if ipv6 == ifv6.ip {
-                            0
-                        } else if mv6(ifv6.ip, ifv6.netmask) == mv6(ipv6, ifv6.netmask) {
-                            min(cur, 1)
-                        } else {
-                            min(cur, 2)
-                        }
-                    }
-                },
-            });
+            let pri = locality(&self.interfaces, pb.addr.ip());
             if pri < 2 {
                 all_far = false;
             }
@@ -755,6 +1401,50 @@ impl SubscriberInner {
         let now = Instant::now();
         self.recently_failed.retain(|_, v| (now - *v) < REMEBER_FAILED)
     }
+
+    fn subscription_count(&self) -> usize {
+        self.subscribed.len() + self.durable_dead.len()
+    }
+
+    /// Return true if subscribing to a new path would exceed the quota
+    fn over_quota(&self) -> bool {
+        match self.max_subscriptions {
+            None => false,
+            Some(max) => self.subscription_count() >= max,
+        }
+    }
+
+    fn gc_resolve_cache(&mut self, now: Instant) {
+        if !self.resolve_cache.is_empty() {
+            self.resolve_cache.retain(|_, r| r.expires > now)
+        }
+    }
+
+    /// Return the cached resolution of path, if it is still fresh
+    /// and we still have a connection to the chosen publisher. Pinned
+    /// resolutions are used once, with or without a connection.
+    fn cached_resolve(
+        &mut self,
+        path: &Path,
+        now: Instant,
+    ) -> Option<(CachedResolve, Option<BatchSender<ToCon>>)> {
+        let r = self.resolve_cache.get(path)?;
+        let con = match self.connections.get(&r.chosen.addr) {
+            Some(Connection { primary: Some((_, con)), .. }) => Some(con.clone()),
+            Some(_) | None => None,
+        };
+        if r.pinned {
+            let r = self.resolve_cache.remove(path)?;
+            return Some((r, con));
+        }
+        match con {
+            Some(con) if r.expires > now => Some((r.clone(), Some(con))),
+            Some(_) | None => {
+                self.resolve_cache.remove(path);
+                None
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -767,22 +1457,69 @@ impl SubscriberWeak {
 }
 
 /// Statistics about durable subscriptions.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Serialize)]
 pub struct DurableStats {
     pub alive: usize,
     pub pending: usize,
     pub dead: usize,
 }
 
+/// A summary of the state of a subscriber, see `Subscriber::health`.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Health {
+    /// how long ago the resolver last answered a query, `None` if it
+    /// never has
+    pub resolver_last_success: Option<Duration>,
+    /// the number of publishers with an open connection
+    pub connections_up: usize,
+    /// the number of publishers whose connection failed within the
+    /// last minute and hasn't been reestablished
+    pub connections_down: usize,
+    /// durable subscriptions, dead ones failed and are waiting to
+    /// retry
+    pub durable: DurableStats,
+}
+
 /// Builder for configuring and creating a Subscriber.
 pub struct SubscriberBuilder {
     cfg: Option<Config>,
     desired_auth: Option<DesiredAuth>,
+    selection: PublisherSelection,
+    factory: Option<Arc<dyn ConnectionFactory>>,
+    address_mapper: Option<Arc<dyn AddressMapper>>,
+    resub_batch_size: usize,
+    resolve_cache_ttl: Duration,
+    max_subscriptions: Option<usize>,
+    subscribe_timeout: Duration,
+    migrate_interval: Option<Duration>,
+    durable_config: DurableConfig,
+    nodelay: bool,
+    write_coalesce: Duration,
+    slow_consumer_timeout: Option<Duration>,
+    drop_slow_consumers: bool,
+    write_timeout: Option<Duration>,
 }
 
 impl SubscriberBuilder {
     pub fn new(cfg: Config) -> Self {
-        Self { cfg: Some(cfg), desired_auth: None }
+        Self {
+            cfg: Some(cfg),
+            desired_auth: None,
+            selection: PublisherSelection::Random,
+            factory: None,
+            address_mapper: None,
+            resub_batch_size: DEFAULT_RESUB_BATCH,
+            resolve_cache_ttl: Duration::ZERO,
+            max_subscriptions: None,
+            subscribe_timeout: DEFAULT_SUBSCRIBE_TIMEOUT,
+            migrate_interval: None,
+            durable_config: DurableConfig::default(),
+            nodelay: true,
+            write_coalesce: Duration::ZERO,
+            slow_consumer_timeout: None,
+            drop_slow_consumers: false,
+            write_timeout: None,
+        }
     }
 
     pub fn build(&mut self) -> Result<Subscriber> {
@@ -790,23 +1527,293 @@ impl SubscriberBuilder {
             .cfg
             .take()
             .ok_or_else(|| anyhow!("config is required, did you reuse the builder?"))?;
+        if self.resub_batch_size == 0 {
+            bail!("resub_batch_size must be at least 1")
+        }
+        if self.max_subscriptions == Some(0) {
+            bail!("max_subscriptions must be at least 1")
+        }
+        if self.resolve_cache_ttl > MAX_RESOLVE_CACHE_TTL {
+            bail!("resolve_cache_ttl may not exceed {:?}", MAX_RESOLVE_CACHE_TTL)
+        }
+        if self.subscribe_timeout.is_zero() {
+            bail!("subscribe_timeout must be positive")
+        }
+        if self.migrate_interval.map(|i| i.is_zero()).unwrap_or(false) {
+            bail!("migrate_interval must be positive")
+        }
+        self.durable_config.check()?;
+        if self.write_timeout.map(|t| t.is_zero()).unwrap_or(false) {
+            bail!("write_timeout must be positive")
+        }
         let desired_auth = self.desired_auth.take().unwrap_or_else(|| cfg.default_auth());
-        Subscriber::new(cfg, desired_auth)
+        let mapper =
+            self.address_mapper.take().unwrap_or_else(|| Arc::new(IdentityMapper));
+        let nodelay = self.nodelay;
+        let slow_consumer = self.slow_consumer_timeout.map(|timeout| {
+            connection::SlowConsumer { timeout, drop: self.drop_slow_consumers }
+        });
+        let factory = self.factory.take().unwrap_or_else(|| {
+            Arc::new(connection::TcpConnectionFactory { mapper, nodelay })
+        });
+        Subscriber::new_with(
+            cfg,
+            desired_auth,
+            self.selection,
+            factory,
+            self.resub_batch_size,
+            self.resolve_cache_ttl,
+            self.max_subscriptions,
+            self.subscribe_timeout,
+            self.migrate_interval,
+            self.durable_config,
+            self.write_coalesce,
+            slow_consumer,
+            self.write_timeout,
+        )
     }
 
     pub fn desired_auth(&mut self, auth: DesiredAuth) -> &mut Self {
         self.desired_auth = Some(auth);
         self
     }
-}
 
-/// Subscribe to published values.
-#[derive(Clone, Debug)]
-pub struct Subscriber(Arc<Mutex<SubscriberInner>>);
+    /// Set the strategy used to choose between multiple publishers of
+    /// the same path. Default `PublisherSelection::Random`.
+    pub fn publisher_selection(&mut self, selection: PublisherSelection) -> &mut Self {
+        self.selection = selection;
+        self
+    }
 
-impl Subscriber {
-    /// Create a new subscriber with the specified config and desired auth.
-    pub fn new(resolver: Config, desired_auth: DesiredAuth) -> Result<Subscriber> {
+    /// Set the maximum number of completed durable (re)subscriptions
+    /// that are buffered and then processed together by the
+    /// resubscription task. Default 100,000. This has no effect on
+    /// non durable subscriptions.
+    ///
+    /// Each batch is processed while holding the subscriber lock. A
+    /// large batch amortizes that cost when many subscriptions must
+    /// be reestablished at once, e.g. after a publisher restarts, but
+    /// it buffers more results in memory and holds the lock longer,
+    /// which delays other subscriber operations. A small batch bounds
+    /// both memory and latency at the cost of throughput. Small
+    /// embedded subscribers may want a small batch, subscribers with
+    /// a very large number of durable subscriptions will recover
+    /// faster with a large one.
+    pub fn resub_batch_size(&mut self, n: usize) -> &mut Self {
+        self.resub_batch_size = n;
+        self
+    }
+
+    /// Remember where each path was resolved to for `ttl`. If a path
+    /// is subscribed again within `ttl`, and the subscriber is still
+    /// connected to the publisher it was resolved to, then the
+    /// resolver is not consulted and the subscription goes directly
+    /// to the publisher. A failed subscription forgets the cached
+    /// resolution. Default 0, which disables the cache. The maximum
+    /// is 4 minutes, because the resolver's authorization expires.
+    ///
+    /// The cache only helps programs that frequently drop and
+    /// recreate subscriptions to the same paths, and while it is
+    /// enabled changes in the resolver, e.g. a new publisher of a
+    /// path, will not be noticed until the entry expires.
+    pub fn resolve_cache_ttl(&mut self, ttl: Duration) -> &mut Self {
+        self.resolve_cache_ttl = ttl;
+        self
+    }
+
+    /// Limit the number of distinct paths this subscriber may be
+    /// subscribed to at once. Once the limit is reached
+    /// `subscribe_nondurable` fails for paths that aren't already
+    /// subscribed, and so does `try_subscribe`. Durable subscriptions
+    /// count against the limit whether or not they are currently
+    /// alive, and their resubscriptions are never refused. Default
+    /// unlimited.
+    ///
+    /// `subscribe` and `subscribe_updates` can't fail, so they don't
+    /// check the limit, however their subscriptions still count
+    /// toward it. See `Subscriber::subscription_count`.
+    pub fn max_subscriptions(&mut self, n: usize) -> &mut Self {
+        self.max_subscriptions = Some(n);
+        self
+    }
+
+    /// How long to wait for a publisher to answer a subscribe
+    /// request when the caller didn't specify a timeout. A publisher
+    /// that accepted the connection but never answers would otherwise
+    /// hold the request forever, since it may still be sending
+    /// heartbeats. Requests with a timeout fail at their own
+    /// deadline. Default 60 seconds.
+    pub fn subscribe_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.subscribe_timeout = timeout;
+        self
+    }
+
+    /// How long to wait for the publisher to reply to a
+    /// `write_with_recipt` before the receipt is a `WriteTimedOut`
+    /// error. Default None, wait as long as the connection is up.
+    pub fn write_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.write_timeout = timeout;
+        self
+    }
+
+    /// Every `interval` re-resolve the live durable subscriptions and
+    /// move any that are subscribed to a worse publisher than the
+    /// best one available. One publisher is better than another if
+    /// it has a higher priority, or the same priority and is closer
+    /// to this machine (the same host, then the same subnet). A
+    /// publisher that is no longer in the resolver is worse than any
+    /// other. The new subscription is established before the old one
+    /// is dropped, and update streams carry on without an
+    /// `Unsubscribed` event, though they may see an update twice.
+    /// Pinned and isolated subscriptions are never moved.
+    ///
+    /// This costs a resolve of every live durable subscription each
+    /// interval, so it is off by default.
+    pub fn migrate_interval(&mut self, interval: Duration) -> &mut Self {
+        self.migrate_interval = Some(interval);
+        self
+    }
+
+    /// Set how long durable subscriptions wait between
+    /// resubscription attempts. Individual `Dval`s can override it
+    /// with `Dval::set_durable_config`. Default
+    /// `DurableConfig::default()`.
+    ///
+    /// With many durable subscriptions, e.g. tens of thousands, a
+    /// larger base, a multiplier above 1, and a max keep the retries
+    /// from overwhelming the resolvers and publishers during a long
+    /// outage.
+    pub fn durable_config(&mut self, config: DurableConfig) -> &mut Self {
+        self.durable_config = config;
+        self
+    }
+
+    /// Set TCP_NODELAY on connections to publishers. Default true.
+    ///
+    /// With nodelay a small write, e.g. a subscribe, is sent
+    /// immediately, which suits interactive use. Without it the
+    /// kernel may hold a small write for up to about 40ms waiting
+    /// for more data (Nagle's algorithm). Ignored if a connection
+    /// factory is set.
+    pub fn nodelay(&mut self, nodelay: bool) -> &mut Self {
+        self.nodelay = nodelay;
+        self
+    }
+
+    /// Hold writes to a publisher for up to `delay` after the first
+    /// one is queued so that others can join it in one flush.
+    /// Default zero, every batch of requests is flushed as soon as
+    /// it is queued.
+    ///
+    /// The default suits interactive workloads. A bulk workload that
+    /// issues many tiny writes, e.g. a write to each of thousands of
+    /// values one at a time, sends fewer, larger packets with a
+    /// delay of a millisecond or so, at the cost of that much
+    /// latency on every write and subscribe.
+    pub fn write_coalesce(&mut self, delay: Duration) -> &mut Self {
+        self.write_coalesce = delay;
+        self
+    }
+
+    /// Warn when a connection has been blocked for longer than
+    /// `timeout` sending updates to a stream that is full. Default
+    /// None, never warn.
+    ///
+    /// Every subscription on a connection shares it, and when a
+    /// consumer doesn't read from the channel it passed to e.g.
+    /// `Val::updates` the connection waits for it, so one slow
+    /// consumer stalls all the others. The warning names the stuck
+    /// channel so the stall can be tracked down, see also
+    /// `drop_slow_consumers`.
+    pub fn slow_consumer_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.slow_consumer_timeout = timeout;
+        self
+    }
+
+    /// When a send to a stream has been blocked for longer than the
+    /// `slow_consumer_timeout`, close the stream instead of waiting
+    /// for it. The consumer sees the end of the stream after it
+    /// reads what was already queued, and the other subscriptions on
+    /// the connection go on. Default false.
+    pub fn drop_slow_consumers(&mut self, drop: bool) -> &mut Self {
+        self.drop_slow_consumers = drop;
+        self
+    }
+
+    /// Set the mapper from publisher addresses, as returned by the
+    /// resolver, to where the subscriber actually connects. Use this
+    /// to reach publishers through a SOCKS5 proxy or an address
+    /// rewriting NAT. Default `IdentityMapper`.
+    pub fn address_mapper(&mut self, mapper: Arc<dyn AddressMapper>) -> &mut Self {
+        self.address_mapper = Some(mapper);
+        self
+    }
+
+    /// Set the factory used to connect to publishers. Default
+    /// `TcpConnectionFactory`. The factory takes over connecting, so
+    /// the address mapper is not used.
+    #[cfg(test)]
+    pub(crate) fn connection_factory(
+        &mut self,
+        factory: Arc<dyn ConnectionFactory>,
+    ) -> &mut Self {
+        self.factory = Some(factory);
+        self
+    }
+}
+
+/// Subscribe to published values.
+///
+/// A `Path` is a reference counted string, and the subscriber keeps
+/// exactly one copy of each subscribed path. Its subscription
+/// tables and the connection holding the subscription all share
+/// that copy, even if the caller builds a new `Path` on every call.
+/// Each subscription costs one path allocation, not one per table.
+/// Run `netidx stress subscriber` to see the resident memory per
+/// subscription.
+#[derive(Clone, Debug)]
+pub struct Subscriber(Arc<Mutex<SubscriberInner>>);
+
+impl Subscriber {
+    /// Create a new subscriber with the specified config and desired auth.
+    pub fn new(resolver: Config, desired_auth: DesiredAuth) -> Result<Subscriber> {
+        let factory = Arc::new(connection::TcpConnectionFactory {
+            mapper: Arc::new(IdentityMapper),
+            nodelay: true,
+        });
+        let selection = PublisherSelection::Random;
+        Self::new_with(
+            resolver,
+            desired_auth,
+            selection,
+            factory,
+            DEFAULT_RESUB_BATCH,
+            Duration::ZERO,
+            None,
+            DEFAULT_SUBSCRIBE_TIMEOUT,
+            None,
+            DurableConfig::default(),
+            Duration::ZERO,
+            None,
+            None,
+        )
+    }
+
+    fn new_with(
+        resolver: Config,
+        desired_auth: DesiredAuth,
+        selection: PublisherSelection,
+        factory: Arc<dyn ConnectionFactory>,
+        resub_batch_size: usize,
+        resolve_cache_ttl: Duration,
+        max_subscriptions: Option<usize>,
+        subscribe_timeout: Duration,
+        migrate_interval: Option<Duration>,
+        durable_config: DurableConfig,
+        write_coalesce: Duration,
+        slow_consumer: Option<connection::SlowConsumer>,
+        write_timeout: Option<Duration>,
+    ) -> Result<Subscriber> {
         let (tx, rx) = mpsc::unbounded();
         let tls_ctx = resolver.tls.clone().map(tls::CachedConnector::new);
         let resolver = ResolverRead::new(resolver, desired_auth.clone());
@@ -823,8 +1830,27 @@ impl Subscriber {
             trigger_resub: tx,
             tls_ctx,
             interfaces: get_if_addrs()?,
+            selector: Selector::new(selection),
+            factory,
+            resolve_cache: AHashMap::default(),
+            resolve_cache_ttl,
+            max_subscriptions,
+            subscribe_timeout,
+            durable_config,
+            write_coalesce,
+            slow_consumer,
+            write_timeout,
+            throughput: Arc::new(throughput::Meter::new()),
+            krb5_spns: AHashMap::default(),
+            #[cfg(test)]
+            resub_batches: Vec::new(),
+            foreground: 0,
+            background: Vec::new(),
         })));
-        t.start_resub_task(rx);
+        t.start_resub_task(rx, resub_batch_size);
+        if let Some(interval) = migrate_interval {
+            migrate::start(t.downgrade(), interval);
+        }
         Ok(t)
     }
 
@@ -836,6 +1862,14 @@ impl Subscriber {
         self.0.lock().id
     }
 
+    /// Return the number of subscriptions counted against the
+    /// `max_subscriptions` quota. This is the number of paths that
+    /// are subscribed, or in the process of subscribing, plus the
+    /// number of durable subscriptions waiting to be retried.
+    pub fn subscription_count(&self) -> usize {
+        self.0.lock().subscription_count()
+    }
+
     /// Return stats about durable subscriptions.
     pub fn durable_stats(&self) -> DurableStats {
         let t = self.0.lock();
@@ -846,6 +1880,102 @@ impl Subscriber {
         }
     }
 
+    /// Return the size of each resubscription batch processed so
+    /// far.
+    #[cfg(test)]
+    pub(crate) fn resub_batches(&self) -> Vec<usize> {
+        self.0.lock().resub_batches.clone()
+    }
+
+    /// Return the number of distinct path allocations held by the
+    /// subscription tables, and the number of paths they hold.
+    #[cfg(test)]
+    pub(crate) fn path_allocations(&self) -> (usize, usize) {
+        let t = self.0.lock();
+        let paths = t
+            .subscribed
+            .keys()
+            .chain(t.durable_dead.keys())
+            .chain(t.durable_pending.keys())
+            .chain(t.durable_alive.keys());
+        let mut allocs = std::collections::HashSet::new();
+        let mut n = 0;
+        for p in paths {
+            allocs.insert(p.as_ptr());
+            n += 1;
+        }
+        (allocs.len(), n)
+    }
+
+    /// Act as if a foreground batch were resolving until the
+    /// returned guard is dropped.
+    #[cfg(test)]
+    pub(crate) fn hold_foreground(&self) -> Foreground {
+        self.0.lock().foreground += 1;
+        Foreground(self.downgrade())
+    }
+
+    /// Return a summary of the state of the subscriber suitable for a
+    /// health check. This only reads state the subscriber already
+    /// has, it never contacts the resolver or any publisher.
+    pub fn health(&self) -> Health {
+        let mut t = self.0.lock();
+        t.gc_recently_failed();
+        let now = Instant::now();
+        let connections_down =
+            t.recently_failed.keys().filter(|a| !t.connections.contains_key(*a)).count();
+        Health {
+            resolver_last_success: t.resolver.last_success().map(|i| now - i),
+            connections_up: t.connections.len(),
+            connections_down,
+            durable: DurableStats {
+                alive: t.durable_alive.len(),
+                pending: t.durable_pending.len(),
+                dead: t.durable_dead.len(),
+            },
+        }
+    }
+
+    /// Close every connection to the publisher at `addr`. All the
+    /// subscriptions they carry are unsubscribed, durable ones are
+    /// resubscribed as usual, which makes a new connection. Use this
+    /// to force a reconnect, e.g. after a network change, without
+    /// restarting the whole subscriber. Returns false if there was
+    /// no connection to `addr`.
+    ///
+    /// Unlike a failed connection, `addr` is not avoided when durable
+    /// subscriptions choose a publisher to resubscribe to.
+    pub fn drop_connection(&self, addr: SocketAddr) -> bool {
+        match self.0.lock().connections.remove(&addr) {
+            None => false,
+            Some(con) => {
+                info!("dropping connection to {addr}");
+                for c in con.iter() {
+                    c.send(ToCon::Close);
+                }
+                true
+            }
+        }
+    }
+
+    /// Return the rate of updates and bytes received across all of
+    /// this subscriber's connections, averaged over 1, 10, and 60
+    /// seconds. The averages are sampled once a second while the
+    /// subscriber has a connection, and when they are read.
+    pub fn throughput(&self) -> Throughput {
+        let meter = self.0.lock().throughput.clone();
+        meter.get()
+    }
+
+    /// Return the kerberos service principal of each publisher in
+    /// the last resolve, by address. A publisher that requires
+    /// kerberos but has no spn maps to an empty string. This is
+    /// meant for debugging authentication failures.
+    pub fn krb5_spns(&self) -> HashMap<SocketAddr, ArcStr> {
+        let t = self.0.lock();
+        t.krb5_spns.iter().map(|(addr, spn)| (*addr, spn.clone())).collect()
+    }
+
     pub fn is_subscribed_or_pending(&self, path: &Path) -> bool {
         let t = self.0.lock();
         t.subscribed.contains_key(path)
@@ -858,11 +1988,39 @@ impl Subscriber {
         self.0.lock().resolver.clone()
     }
 
+    /// List the immediate children of `path` using this subscriber's
+    /// resolver. The result is sorted.
+    pub async fn list(&self, path: Path) -> Result<Vec<Path>> {
+        let mut paths = self.resolver().list(path).await?;
+        Ok(paths.drain(..).collect())
+    }
+
+    /// List every path under `path`, not including `path` itself,
+    /// using a single `path/**` glob query. Glob meta chars in `path`
+    /// are escaped. Structural paths (those with children but no
+    /// publisher) are included. The paths are yielded in sorted
+    /// order.
+    pub async fn list_recursive(
+        &self,
+        path: Path,
+    ) -> Result<impl Stream<Item = Path> + Unpin + use<>> {
+        let base = Path::from(ArcStr::from(Glob::escape(&*path)));
+        let glob = Glob::new(base.append("**").into())?;
+        let globs = GlobSet::new(false, [glob])?;
+        let mut paths = Vec::new();
+        for mut batch in self.resolver().list_matching(&globs).await?.drain(..) {
+            paths.extend(batch.drain(..).filter(|p| p != &path));
+        }
+        paths.sort();
+        paths.dedup();
+        Ok(stream::iter(paths))
+    }
+
     fn downgrade(&self) -> SubscriberWeak {
         SubscriberWeak(Arc::downgrade(&self.0))
     }
 
-    fn start_resub_task(&self, incoming: UnboundedReceiver<()>) {
+    fn start_resub_task(&self, incoming: UnboundedReceiver<()>, resub_batch_size: usize) {
         async fn wait_retry(retry: Option<Instant>) {
             match retry {
                 None => future::pending().await,
@@ -872,6 +2030,10 @@ impl Subscriber {
         fn update_retry(subscriber: &mut SubscriberInner, retry: &mut Option<Instant>) {
             let now = Instant::now();
             *retry = None;
+            // we will check again when an in flight batch finishes
+            if subscriber.durable_pending.len() >= MAX_RESUB_PENDING {
+                return;
+            }
             for w in subscriber.durable_dead.values() {
                 if let Some(dv) = w.upgrade() {
                     let next_try = match &dv.0.lock().sub {
@@ -905,13 +2067,16 @@ impl Subscriber {
             let (batch, timeout) = {
                 let mut dead = Vec::new();
                 let mut batch: Vec<(Path, Streams)> = Vec::new();
+                let mut pinned: Vec<(Path, CachedResolve)> = Vec::new();
                 let mut subscriber = subscriber.0.lock();
                 let subscriber = &mut *subscriber;
                 let durable_dead = &mut subscriber.durable_dead;
                 let durable_pending = &mut subscriber.durable_pending;
                 let mut max_tries = 1;
-                let mut total_retries = 0;
                 for (p, w) in durable_dead.iter() {
+                    if durable_pending.len() >= MAX_RESUB_PENDING {
+                        break;
+                    }
                     match w.upgrade() {
                         None => {
                             dead.push(p.clone());
@@ -926,14 +2091,19 @@ impl Subscriber {
                             };
                             if next_try <= now {
                                 let streams = dv.streams.clone();
+                                if let Some(pin) = &mut dv.pin {
+                                    match &pin.last {
+                                        Some(r) if pin.remaining > 0 => {
+                                            pin.remaining -= 1;
+                                            pinned.push((p.clone(), r.clone()));
+                                        }
+                                        Some(_) | None => (),
+                                    }
+                                }
                                 drop(dv);
                                 batch.push((p.clone(), streams));
                                 durable_pending.insert(p.clone(), w.clone());
                                 max_tries = max(max_tries, tries);
-                                total_retries += 1;
-                                if total_retries >= 100_000 {
-                                    break;
-                                }
                             }
                         }
                     }
@@ -942,7 +2112,15 @@ impl Subscriber {
                     durable_dead.remove(p);
                 }
                 let timeout = 30 + max(10, batch.len() / 10000) * max_tries;
-                (batch, Duration::from_secs(timeout as u64))
+                let timeout = Duration::from_secs(timeout as u64);
+                // pinned dvals skip the resolver and go straight to the
+                // last publisher they were subscribed to
+                for (p, mut r) in pinned {
+                    r.pinned = true;
+                    r.expires = now + timeout;
+                    subscriber.resolve_cache.insert(p, r);
+                }
+                (batch, timeout)
             };
             if batch.len() == 0 {
                 let mut subscriber = subscriber.0.lock();
@@ -950,7 +2128,16 @@ impl Subscriber {
                 None
             } else {
                 update_retry(&mut *subscriber.0.lock(), retry);
-                Some(subscriber.subscribe_nondurable_internal(batch, Some(timeout)).await)
+                Some(
+                    subscriber
+                        .subscribe_nondurable_internal(
+                            batch,
+                            Some(timeout),
+                            SubscribePriority::Foreground,
+                            None,
+                        )
+                        .await,
+                )
             }
         }
         fn finish_resubscription_batch(
@@ -961,6 +2148,10 @@ impl Subscriber {
             if let Some(subscriber) = subscriber.upgrade() {
                 let mut subscriber = subscriber.0.lock();
                 let now = Instant::now();
+                #[cfg(test)]
+                if !batch.is_empty() {
+                    subscriber.resub_batches.push(batch.len());
+                }
                 for (p, r) in batch.drain(..) {
                     if let Some(ds) =
                         subscriber.durable_pending.remove(&p).and_then(|ds| ds.upgrade())
@@ -968,16 +2159,30 @@ impl Subscriber {
                         trace!("processing pending subscrition to {p}");
                         let dsw = ds.downgrade();
                         let mut dv = ds.0.lock();
+                        // a path that only just lost its publisher will
+                        // probably be back soon, so cap the back off lower
                         macro_rules! failed {
-                            ($e:expr) => {
+                            ($e:expr, $expired:expr) => {
+                                let cfg = match &dv.durable_config {
+                                    Some(cfg) => **cfg,
+                                    None => subscriber.durable_config,
+                                };
                                 match &mut dv.sub {
                                     DvState::Subscribed(_) => unreachable!(),
                                     DvState::Dead(d) => {
                                         d.tries += 1;
-                                        let wait = Duration::from_millis(
-                                            pick(d.tries) as u64 * 50,
-                                        );
+                                        let wait = cfg.retry_wait(d.tries);
+                                        let wait = if $expired {
+                                            wait.min(MAX_EXPIRED_WAIT)
+                                        } else {
+                                            wait
+                                        };
                                         d.next_try = now + wait;
+                                        for tx in d.ready.drain(..) {
+                                            let _ = tx.send(Err(anyhow!("{}", $e)));
+                                        }
+                                        d.last_error =
+                                            Some(ArcStr::from(format!("{:#}", $e)));
                                         let s = wait.as_secs_f32();
                                         warn!(
                                             "resubscription error {}: {}, next try: {}s",
@@ -989,12 +2194,13 @@ impl Subscriber {
                             };
                         }
                         match r {
-                            Err(e) => failed!(e),
-                            Ok(sub) if *sub.0.last.lock() == Event::Unsubscribed => {
-                                failed!(anyhow!("unsubscribed"))
+                            Err(e) => failed!(e, e.is::<PathExpired>()),
+                            Ok(sub) if sub.0.last.lock().event == Event::Unsubscribed => {
+                                failed!(anyhow!("unsubscribed"), false)
                             }
                             Ok(sub) => {
                                 info!("resubscription success {}", p);
+                                metrics::resubscription();
                                 for (f, tx) in &dv.streams {
                                     sub.0.connection.send(ToCon::Stream {
                                         tx: tx.clone(),
@@ -1004,7 +2210,13 @@ impl Subscriber {
                                             | UpdatesFlags::NO_SPURIOUS,
                                     });
                                 }
+                                if let Some(pin) = &mut dv.pin {
+                                    pin.remaining = pin.attempts;
+                                }
                                 if let DvState::Dead(d) = &mut dv.sub {
+                                    for tx in d.ready.drain(..) {
+                                        let _ = tx.send(Ok(()));
+                                    }
                                     for (v, resp) in d.queued_writes.drain(..) {
                                         sub.0.connection.send(ToCon::Write(
                                             sub.0.id,
@@ -1070,7 +2282,7 @@ impl Subscriber {
                         Some(BatchItem::EndBatch) => {
                             trace!("incoming end batch");
                             if let Some(set) = do_resub(&subscriber, &mut retry).await {
-                                subscriptions.push_back(Batched::new(set, 100_000));
+                                subscriptions.push_back(Batched::new(set, resub_batch_size));
                             }
                         }
                     },
@@ -1089,7 +2301,7 @@ impl Subscriber {
                             if let Some(t) = retry {
                                 if Instant::now() >= t {
                                     if let Some(set) = do_resub(&subscriber, &mut retry).await {
-                                        subscriptions.push_back(Batched::new(set, 100_000));
+                                        subscriptions.push_back(Batched::new(set, resub_batch_size));
                                     }
                                 }
                             }
@@ -1098,7 +2310,7 @@ impl Subscriber {
                     _ = wait_retry(retry).fuse() => {
                         trace!("time to retry");
                         if let Some(set) = do_resub(&subscriber, &mut retry).await {
-                            subscriptions.push_back(Batched::new(set, 100_000));
+                            subscriptions.push_back(Batched::new(set, resub_batch_size));
                         }
                     },
                 }
@@ -1107,6 +2319,61 @@ impl Subscriber {
         });
     }
 
+    /// Return the connection to the publisher in `ch`, starting it if
+    /// necessary. Isolated subscriptions always get a new connection.
+    fn connection_for(&self, t: &mut SubscriberInner, ch: &Chosen) -> BatchSender<ToCon> {
+        let tls_ctx = t.tls_ctx.clone();
+        let desired_auth = t.desired_auth.clone();
+        let factory = t.factory.clone();
+        let subscribe_timeout = t.subscribe_timeout;
+        let write_coalesce = t.write_coalesce;
+        let slow_consumer = t.slow_consumer;
+        let write_timeout = t.write_timeout;
+        let throughput = t.throughput.clone();
+        let con = t
+            .connections
+            .entry(ch.addr)
+            .or_insert_with(|| Connection { primary: None, isolated: IntMap::default() });
+        if ch.flags.contains(PublishFlags::ISOLATED) {
+            let (id, c) = self.start_connection(
+                tls_ctx,
+                ch.uifo.clone(),
+                ch.addr,
+                &ch.target_auth,
+                &desired_auth,
+                &factory,
+                subscribe_timeout,
+                write_coalesce,
+                slow_consumer,
+                write_timeout,
+                throughput,
+            );
+            con.isolated.insert(id, c.clone());
+            c
+        } else {
+            match &con.primary {
+                Some((_, c)) => c.clone(),
+                None => {
+                    let (id, c) = self.start_connection(
+                        tls_ctx,
+                        ch.uifo.clone(),
+                        ch.addr,
+                        &ch.target_auth,
+                        &desired_auth,
+                        &factory,
+                        subscribe_timeout,
+                        write_coalesce,
+                        slow_consumer,
+                        write_timeout,
+                        throughput,
+                    );
+                    con.primary = Some((id, c.clone()));
+                    c
+                }
+            }
+        }
+    }
+
     fn start_connection(
         &self,
         tls_ctx: Option<tls::CachedConnector>,
@@ -1114,13 +2381,21 @@ impl Subscriber {
         addr: SocketAddr,
         target_auth: &TargetAuth,
         desired_auth: &DesiredAuth,
+        factory: &Arc<dyn ConnectionFactory>,
+        subscribe_timeout: Duration,
+        write_coalesce: Duration,
+        slow_consumer: Option<connection::SlowConsumer>,
+        write_timeout: Option<Duration>,
+        throughput: Arc<throughput::Meter>,
     ) -> (ConId, BatchSender<ToCon>) {
         let (tx, rx) = batch_channel::channel();
         let subscriber = self.downgrade();
         let desired_auth = desired_auth.clone();
         let conid = ConId::new();
         let target_auth = target_auth.clone();
+        let factory = factory.clone();
         task::spawn(async move {
+            metrics::connection_opened();
             let res = connection::ConnectionCtx::new(
                 addr,
                 subscriber.clone(),
@@ -1129,10 +2404,17 @@ impl Subscriber {
                 uifo,
                 target_auth,
                 desired_auth,
+                factory,
+                subscribe_timeout,
+                throughput,
+                write_coalesce,
+                slow_consumer,
+                write_timeout,
                 rx,
             )
             .start()
             .await;
+            metrics::connection_closed();
             if let Some(subscriber) = subscriber.upgrade() {
                 if let Entry::Occupied(mut e) =
                     subscriber.0.lock().connections.entry(addr)
@@ -1181,12 +2463,56 @@ impl Subscriber {
     /// the batch, which may complete successfully. If you need all or
     /// nothing behavior, specify None for timeout and wrap the
     /// `subscribe` future in a `tokio::time::timeout`.
+    ///
+    /// The timeout is a single budget that covers the entire
+    /// subscription process, resolution, connection, and
+    /// subscription. Whatever phase is in progress when it expires
+    /// will fail promptly.
+    ///
+    /// Paths are resolved as a batch, but the returned
+    /// `FuturesUnordered` is a `Stream` that yields each result as
+    /// soon as it is ready, so a slow publisher does not hold up the
+    /// rest of the batch. Poll it with `StreamExt::next` to consume
+    /// results progressively, or collect it to wait for all of them.
+    ///
+    /// A path that appears more than once in the batch is only
+    /// subscribed once, but there is a result for every occurrence.
     pub async fn subscribe_nondurable(
         &self,
         batch: impl Iterator<Item = Path>,
         timeout: Option<Duration>,
     ) -> FuturesUnordered<impl Future<Output = (Path, Result<Val>)>> {
-        self.subscribe_nondurable_internal(batch.map(|p| (p, [])), timeout).await
+        self.subscribe_nondurable_internal(
+            batch.map(|p| (p, [])),
+            timeout,
+            SubscribePriority::Foreground,
+            None,
+        )
+        .await
+    }
+
+    /// Same as `subscribe_nondurable`, but with a scheduling hint.
+    ///
+    /// Background batches hold off resolving and connecting while
+    /// any foreground batch is doing so, which lets interactive
+    /// subscriptions go ahead of bulk ones. This is best effort. It
+    /// only orders batches made through this subscriber, a background
+    /// batch that has already started is not interrupted, and no
+    /// background batch waits more than `MAX_BACKGROUND_DELAY`. The
+    /// wait counts against `timeout`.
+    pub async fn subscribe_nondurable_prio(
+        &self,
+        batch: impl Iterator<Item = Path>,
+        timeout: Option<Duration>,
+        priority: SubscribePriority,
+    ) -> FuturesUnordered<impl Future<Output = (Path, Result<Val>)>> {
+        self.subscribe_nondurable_internal(
+            batch.map(|p| (p, [])),
+            timeout,
+            priority,
+            None,
+        )
+        .await
     }
 
     /// Subscribe to a batch of values with updates channels.
@@ -1205,67 +2531,237 @@ impl Subscriber {
         I: IntoIterator<Item = (Path, CI)>,
         CI: IntoIterator<Item = (UpdatesFlags, UpdateChan)>,
     {
-        let batch = batch
-            .into_iter()
-            .map(|(p, i)| (p, i.into_iter().map(|(f, c)| (f, ChanWrap(c)))));
-        self.subscribe_nondurable_internal(batch, timeout).await
+        let batch = batch.into_iter().map(|(p, i)| {
+            (p, i.into_iter().map(|(f, c)| (f, WUpdateChan::Plain(ChanWrap(c)))))
+        });
+        self.subscribe_nondurable_internal(
+            batch,
+            timeout,
+            SubscribePriority::Foreground,
+            None,
+        )
+        .await
+    }
+
+    /// Same as `subscribe_nondurable`, but the batch can be
+    /// cancelled.
+    ///
+    /// When `cancel` completes every subscription in the batch that
+    /// hasn't finished fails with `Cancelled`, the ones that did
+    /// finish are dropped (and so unsubscribed unless they are also
+    /// held elsewhere), and this returns `Cancelled`. Unlike dropping
+    /// the future, this always leaves the subscriber as if the batch
+    /// had never been made, and any subscription the publisher
+    /// completes afterward is immediately unsubscribed.
+    ///
+    /// Other callers concurrently subscribing to the same paths are
+    /// not affected, the subscriptions they are waiting for carry on
+    /// without this batch. If this batch is still resolving such a
+    /// path when it is cancelled, it returns once the resolution
+    /// finishes.
+    ///
+    /// Any future will do for `cancel`, e.g. a
+    /// `tokio_util::sync::CancellationToken::cancelled_owned`, or a
+    /// `oneshot::Receiver` mapped to `()`.
+    pub async fn subscribe_nondurable_cancel(
+        &self,
+        batch: impl Iterator<Item = Path>,
+        timeout: Option<Duration>,
+        cancel: impl Future<Output = ()> + Send + 'static,
+    ) -> Result<Vec<(Path, Result<Val>)>> {
+        let cancel = cancel.boxed().shared();
+        let res = self
+            .subscribe_nondurable_internal(
+                batch.map(|p| (p, [])),
+                timeout,
+                SubscribePriority::Foreground,
+                Some(cancel.clone()),
+            )
+            .await
+            .collect::<Vec<_>>()
+            .await;
+        match cancel.peek() {
+            None => Ok(res),
+            Some(()) => Err(anyhow!(Cancelled)),
+        }
+    }
+
+    // wait, up to MAX_BACKGROUND_DELAY or the deadline, until no
+    // foreground batch is resolving or connecting
+    async fn schedule(
+        &self,
+        priority: SubscribePriority,
+        deadline: Option<Instant>,
+    ) -> Option<Foreground> {
+        match priority {
+            SubscribePriority::Foreground => {
+                self.0.lock().foreground += 1;
+                Some(Foreground(self.downgrade()))
+            }
+            SubscribePriority::Background => {
+                let limit = Instant::now() + MAX_BACKGROUND_DELAY;
+                let until = deadline.map(|d| d.min(limit)).unwrap_or(limit);
+                loop {
+                    let rx = {
+                        let mut t = self.0.lock();
+                        if t.foreground == 0 {
+                            break None;
+                        }
+                        let (tx, rx) = oneshot::channel();
+                        t.background.push(tx);
+                        rx
+                    };
+                    if time::timeout_at(until, rx).await.is_err() {
+                        break None;
+                    }
+                }
+            }
+        }
     }
 
     async fn subscribe_nondurable_internal<I, CI>(
         &self,
         batch: I,
         timeout: Option<Duration>,
+        priority: SubscribePriority,
+        cancel: Option<Cancel>,
     ) -> FuturesUnordered<impl Future<Output = (Path, Result<Val>)> + use<I, CI>>
     where
         I: IntoIterator<Item = (Path, CI)>,
         CI: IntoIterator<Item = (UpdatesFlags, WUpdateChan)>,
     {
+        // the other publishers of a path, to try in order if the
+        // chosen publisher denies the subscription
+        #[derive(Debug)]
+        struct Fallback {
+            alternates: SmallVec<[Chosen; 4]>,
+            resolve: CachedResolve,
+            sub_id: SubId,
+            streams: Streams,
+        }
         #[derive(Debug)]
         enum St {
             Resolve(Streams),
-            Subscribing(oneshot::Receiver<Result<Val>>),
+            Subscribing(oneshot::Receiver<Result<Val>>, Option<Box<Fallback>>),
             WaitingOther(oneshot::Receiver<Result<Val>>, Streams),
             Subscribed(Val, Streams),
             Error(Error),
+            // cancelled before it was subscribed, nothing left to clean up
+            Cancelled,
+        }
+        enum Until<T> {
+            Done(T),
+            TimedOut,
+            Cancelled,
+        }
+        async fn until<F: Future>(
+            deadline: Option<Instant>,
+            cancel: &Option<Cancel>,
+            f: F,
+        ) -> Until<F::Output> {
+            let f = async {
+                match deadline {
+                    None => Until::Done(f.await),
+                    Some(d) => match time::timeout_at(d, f).await {
+                        Ok(r) => Until::Done(r),
+                        Err(_) => Until::TimedOut,
+                    },
+                }
+            };
+            match cancel {
+                None => f.await,
+                Some(cancel) => {
+                    let cancel = cancel.clone();
+                    select_biased! {
+                        () = cancel.fuse() => Until::Cancelled,
+                        r = f.fuse() => r,
+                    }
+                }
+            }
         }
+        fn send_subscribe(
+            path: Path,
+            sub_id: SubId,
+            con: BatchSender<ToCon>,
+            r: &CachedResolve,
+            streams: Streams,
+            deadline: Option<Instant>,
+        ) -> Result<oneshot::Receiver<Result<Val>>> {
+            let (tx, rx) = oneshot::channel();
+            let sent = con.send(ToCon::Subscribe(SubscribeValRequest {
+                path,
+                sub_id,
+                timestamp: r.timestamp,
+                permissions: r.permissions,
+                token: r.chosen.token.clone(),
+                resolver: r.resolver,
+                finished: tx,
+                con: con.clone(),
+                deadline,
+                streams,
+            }));
+            if sent {
+                Ok(rx)
+            } else {
+                Err(anyhow!("connection closed"))
+            }
+        }
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let foreground = self.schedule(priority, deadline).await;
         let now = Instant::now();
         let mut pending: LPooled<AHashMap<Path, St>> = LPooled::take();
+        // later occurrences of a path that is in the batch more than
+        // once, they wait for the first one like a concurrent caller
+        let mut duplicates: Vec<(Path, St)> = Vec::new();
         // Init
         let r = {
             let mut t = self.0.lock();
             t.gc_recently_failed();
+            t.gc_resolve_cache(now);
             for (p, chans) in batch {
+                let p = t.intern(p);
                 let streams: Streams = chans.into_iter().collect();
                 trace!("subscribing to {} streams {}", p, streams.len());
-                match t.subscribed.entry(p.clone()) {
+                let durable =
+                    t.durable_pending.contains_key(&p) || t.durable_dead.contains_key(&p);
+                let over_quota = !durable && t.over_quota();
+                let st = match t.subscribed.entry(p.clone()) {
+                    Entry::Vacant(_) if over_quota => {
+                        St::Error(anyhow!("subscription quota exceeded"))
+                    }
                     Entry::Vacant(e) => {
                         e.insert(SubStatus::Pending(Box::new(SmallVec::new())));
-                        pending.insert(p, St::Resolve(streams));
+                        St::Resolve(streams)
                     }
                     Entry::Occupied(mut e) => match e.get_mut() {
                         SubStatus::Pending(v) => {
                             let (tx, rx) = oneshot::channel();
                             v.push(tx);
-                            pending.insert(p, St::WaitingOther(rx, streams));
+                            St::WaitingOther(rx, streams)
                         }
                         SubStatus::Subscribed(r) => match r.upgrade() {
                             Some(r) => {
                                 trace!("already subscribed to {}", p);
-                                pending.insert(p, St::Subscribed(r, streams));
+                                St::Subscribed(r, streams)
                             }
                             None => {
                                 e.insert(SubStatus::Pending(Box::new(SmallVec::new())));
-                                pending.insert(p, St::Resolve(streams));
+                                St::Resolve(streams)
                             }
                         },
                     },
+                };
+                if pending.contains_key(&p) {
+                    duplicates.push((p, st));
+                } else {
+                    pending.insert(p, st);
                 }
             }
             t.resolver.clone()
         };
         // Resolve, Connect, Subscribe
         {
-            let to_resolve = pending
+            let mut to_resolve = pending
                 .iter()
                 .filter(|(_, s)| match s {
                     St::Resolve(_) => true,
@@ -1273,88 +2769,194 @@ impl Subscriber {
                 })
                 .map(|(p, _)| p.clone())
                 .collect::<SmallVec<[_; 100]>>();
-            let r = match timeout {
-                None => Ok(r.resolve(to_resolve.iter().cloned()).await),
-                Some(d) => time::timeout(d, r.resolve(to_resolve.iter().cloned())).await,
+            {
+                let mut t = self.0.lock();
+                if !t.resolve_cache.is_empty() {
+                    to_resolve.retain(|p| match t.cached_resolve(p, now) {
+                        None => true,
+                        Some((r, con)) => {
+                            trace!("using cached resolution of {}", p);
+                            let sub_id = t.durable_id(p).unwrap_or_else(SubId::new);
+                            let con = match con {
+                                Some(con) => con,
+                                None => self.connection_for(&mut t, &r.chosen),
+                            };
+                            let streams = match pending.remove(p) {
+                                Some(St::Resolve(streams)) => streams,
+                                _ => unreachable!(),
+                            };
+                            let st = match send_subscribe(
+                                p.clone(),
+                                sub_id,
+                                con,
+                                &r,
+                                streams,
+                                deadline,
+                            ) {
+                                Ok(rx) => St::Subscribing(rx, None),
+                                Err(e) => St::Error(e),
+                            };
+                            pending.insert(p.clone(), st);
+                            false
+                        }
+                    })
+                }
+            }
+            let r = {
+                let mut resolve = pin!(r.resolve(to_resolve.iter().cloned()));
+                match until(deadline, &cancel, resolve.as_mut()).await {
+                    Until::Cancelled => {
+                        // drop the paths nobody else is waiting for, the
+                        // others must still be resolved for them
+                        let mut t = self.0.lock();
+                        let mut shared = false;
+                        for p in &to_resolve {
+                            match t.subscribed.get(p) {
+                                Some(SubStatus::Pending(w))
+                                    if w.iter().any(|w| !w.is_canceled()) =>
+                                {
+                                    shared = true
+                                }
+                                _ => {
+                                    t.subscribed.remove(p);
+                                    t.resolve_cache.remove(p);
+                                    pending.insert(p.clone(), St::Cancelled);
+                                }
+                            }
+                        }
+                        drop(t);
+                        if shared {
+                            until(deadline, &None, resolve).await
+                        } else {
+                            Until::Cancelled
+                        }
+                    }
+                    r => r,
+                }
+            };
+            let cancelled = |pending: &AHashMap<Path, St>, p: &Path| {
+                matches!(pending.get(p), Some(St::Cancelled))
             };
             match r {
-                Err(_) => {
+                Until::Cancelled => (),
+                Until::TimedOut => {
                     for p in to_resolve {
+                        if cancelled(&pending, &p) {
+                            continue;
+                        }
                         let e = anyhow!("resolving {} timed out", p);
                         pending.insert(p, St::Error(e));
                     }
                 }
-                Ok(Err(e)) => {
+                Until::Done(Err(e)) => {
                     for p in to_resolve {
+                        if cancelled(&pending, &p) {
+                            continue;
+                        }
                         let s = St::Error(anyhow!("resolving {} failed {}", p, e));
                         pending.insert(p, s);
                     }
                 }
-                Ok(Ok((publishers, mut res))) => {
+                Until::Done(Ok((publishers, mut res))) => {
                     let mut t = self.0.lock();
-                    let deadline = timeout.map(|t| now + t);
-                    let desired_auth = t.desired_auth.clone();
+                    let ttl = t.resolve_cache_ttl;
+                    t.krb5_spns.clear();
+                    for pb in publishers.values() {
+                        if let TargetAuth::Krb5 { spn } = &pb.target_auth {
+                            t.krb5_spns.insert(pb.addr, spn.clone());
+                        }
+                    }
                     for (p, resolved) in to_resolve.into_iter().zip(res.drain(..)) {
-                        if resolved.publishers.len() == 0 {
-                            pending.insert(p, St::Error(anyhow!("path not found")));
+                        if cancelled(&pending, &p) {
+                            continue;
+                        } else if resolved.publishers.len() == 0 {
+                            let e = match resolved.status {
+                                PathStatus::Expired { last_seen } => {
+                                    anyhow!(PathExpired { last_seen })
+                                }
+                                PathStatus::Published | PathStatus::Unknown => {
+                                    anyhow!("path not found")
+                                }
+                            };
+                            pending.insert(p, St::Error(e));
                         } else if let Some(ch) = t.choose_addr(&publishers, &resolved) {
-                            let tls_ctx = t.tls_ctx.clone();
-                            let sub_id = t.durable_id(&p).unwrap_or_else(SubId::new);
-                            let con = t.connections.entry(ch.addr).or_insert_with(|| {
-                                Connection { primary: None, isolated: IntMap::default() }
-                            });
-                            let con = if ch.flags.contains(PublishFlags::ISOLATED) {
-                                let (id, c) = self.start_connection(
-                                    tls_ctx,
-                                    ch.uifo,
-                                    ch.addr,
-                                    &ch.target_auth,
-                                    &desired_auth,
-                                );
-                                con.isolated.insert(id, c.clone());
-                                c
-                            } else {
-                                match &con.primary {
-                                    Some((_, c)) => c.clone(),
-                                    None => {
-                                        let (id, c) = self.start_connection(
-                                            tls_ctx,
-                                            ch.uifo,
-                                            ch.addr,
-                                            &ch.target_auth,
-                                            &desired_auth,
-                                        );
-                                        con.primary = Some((id, c.clone()));
-                                        c
+                            if let (DesiredAuth::Krb5 { .. }, TargetAuth::Krb5 { spn }) =
+                                (&t.desired_auth, &ch.target_auth)
+                            {
+                                if spn.is_empty() {
+                                    let addr = ch.addr;
+                                    warn!("publisher {addr} requires kerberos but has no spn");
+                                    pending.insert(
+                                        p,
+                                        St::Error(anyhow!(MissingSpn { addr })),
+                                    );
+                                    continue;
+                                }
+                            }
+                            let r = CachedResolve {
+                                chosen: ch.clone(),
+                                timestamp: resolved.timestamp,
+                                permissions: resolved.permissions as u32,
+                                resolver: resolved.resolver,
+                                expires: now + ttl,
+                                pinned: false,
+                            };
+                            let sub_id = match t.durable(&p) {
+                                None => SubId::new(),
+                                Some(dv) => {
+                                    let mut dv = dv.0.lock();
+                                    if let Some(pin) = &mut dv.pin {
+                                        pin.last = Some(r.clone());
                                     }
+                                    dv.sub_id
                                 }
                             };
-                            let (tx, rx) = oneshot::channel();
-                            let con_ = con.clone();
+                            let con = self.connection_for(&mut t, &ch);
                             let streams = match pending.remove(&p) {
                                 Some(St::Resolve(streams)) => streams,
                                 _ => unreachable!(),
                             };
-                            let r = con.send(ToCon::Subscribe(SubscribeValRequest {
-                                path: p.clone(),
+                            let alternates = resolved
+                                .publishers
+                                .iter()
+                                .filter_map(|pref| {
+                                    let pb = publishers.get(&pref.id)?;
+                                    (pb.addr != ch.addr).then(|| Chosen {
+                                        addr: pb.addr,
+                                        target_auth: pb.target_auth.clone(),
+                                        token: pref.token.clone(),
+                                        uifo: pb.user_info.clone(),
+                                        flags: ch.flags,
+                                    })
+                                })
+                                .collect::<SmallVec<[Chosen; 4]>>();
+                            let fallback = if alternates.is_empty() {
+                                None
+                            } else {
+                                Some(Box::new(Fallback {
+                                    alternates,
+                                    resolve: r.clone(),
+                                    sub_id,
+                                    streams: streams.clone(),
+                                }))
+                            };
+                            let st = match send_subscribe(
+                                p.clone(),
                                 sub_id,
-                                timestamp: resolved.timestamp,
-                                permissions: resolved.permissions as u32,
-                                token: ch.token,
-                                resolver: resolved.resolver,
-                                finished: tx,
-                                con: con_,
-                                deadline,
+                                con,
+                                &r,
                                 streams,
-                            }));
-                            if r {
-                                pending.insert(p, St::Subscribing(rx));
-                            } else {
-                                pending.insert(
-                                    p,
-                                    St::Error(Error::from(anyhow!("connection closed"))),
-                                );
+                                deadline,
+                            ) {
+                                Ok(rx) => St::Subscribing(rx, fallback),
+                                Err(e) => St::Error(e),
+                            };
+                            if ttl > Duration::ZERO
+                                && !r.chosen.flags.contains(PublishFlags::ISOLATED)
+                            {
+                                t.resolve_cache.insert(p.clone(), r);
                             }
+                            pending.insert(p, st);
                         } else {
                             let e = anyhow!("missing publisher record");
                             pending.insert(p, St::Error(e));
@@ -1363,10 +2965,123 @@ impl Subscriber {
                 }
             }
         }
+        drop(foreground);
+        // wait for a subscription, asking the path's other publishers
+        // if it's denied, and then tell everyone waiting for the path.
+        // If it is cancelled while others are waiting the wait is
+        // handed back, so it can be finished for them.
+        async fn subscribing(
+            sub: &Subscriber,
+            started: Instant,
+            deadline: Option<Instant>,
+            cancel: &Option<Cancel>,
+            path: &Path,
+            mut w: oneshot::Receiver<Result<Val>>,
+            mut fallback: Option<Box<Fallback>>,
+        ) -> Result<Result<Val>, (oneshot::Receiver<Result<Val>>, Option<Box<Fallback>>)>
+        {
+            let res = loop {
+                let res = match until(deadline, cancel, &mut w).await {
+                    Until::TimedOut => Err(anyhow!("subscribing {} timed out", path)),
+                    Until::Cancelled => {
+                        let mut t = sub.0.lock();
+                        match t.subscribed.get(path) {
+                            Some(SubStatus::Pending(waiters))
+                                if waiters.iter().any(|w| !w.is_canceled()) =>
+                            {
+                                return Err((w, fallback));
+                            }
+                            _ => {
+                                t.subscribed.remove(path);
+                                t.resolve_cache.remove(path);
+                                return Ok(Err(anyhow!(Cancelled)));
+                            }
+                        }
+                    }
+                    Until::Done(Err(e)) => Err(anyhow!("connection died {}", e)),
+                    Until::Done(Ok(Err(e))) => Err(e),
+                    Until::Done(Ok(Ok(raw))) => Ok(raw),
+                };
+                // only give up on a denial once every publisher
+                // of the path has denied us
+                let fb = match (&res, &mut fallback) {
+                    (Err(e), Some(fb))
+                        if e.is::<PermissionDenied>() && !fb.alternates.is_empty() =>
+                    {
+                        fb
+                    }
+                    _ => break res,
+                };
+                fb.resolve.chosen = fb.alternates.remove(0);
+                trace!("{} denied, trying {}", path, fb.resolve.chosen.addr);
+                let con = {
+                    let mut t = sub.0.lock();
+                    t.resolve_cache.remove(path);
+                    sub.connection_for(&mut t, &fb.resolve.chosen)
+                };
+                let sent = send_subscribe(
+                    path.clone(),
+                    fb.sub_id,
+                    con,
+                    &fb.resolve,
+                    fb.streams.clone(),
+                    deadline,
+                );
+                match sent {
+                    Ok(rx) => w = rx,
+                    Err(e) => break Err(e),
+                }
+            };
+            match &res {
+                Ok(_) => metrics::subscription_created(started.elapsed()),
+                Err(_) => metrics::subscription_failed(),
+            }
+            let mut t = sub.0.lock();
+            if res.is_err() {
+                t.resolve_cache.remove(path);
+            }
+            match t.subscribed.entry(path.clone()) {
+                Entry::Vacant(_) => unreachable!(),
+                Entry::Occupied(mut e) => match res {
+                    Err(err) => match e.remove() {
+                        SubStatus::Subscribed(_) => unreachable!(),
+                        SubStatus::Pending(waiters) => {
+                            for w in waiters.into_iter() {
+                                let _ = w.send(Err(anyhow!("{}", err)));
+                            }
+                            Ok(Err(err))
+                        }
+                    },
+                    Ok(raw) => {
+                        let s = mem::replace(
+                            e.get_mut(),
+                            SubStatus::Subscribed(raw.downgrade()),
+                        );
+                        match s {
+                            SubStatus::Subscribed(_) => unreachable!(),
+                            SubStatus::Pending(waiters) => {
+                                for w in waiters.into_iter() {
+                                    let _ = w.send(Ok(raw.clone()));
+                                }
+                                Ok(Ok(raw))
+                            }
+                        }
+                    }
+                },
+            }
+        }
         // Wait
-        async fn wait_result(sub: Subscriber, path: Path, st: St) -> (Path, Result<Val>) {
+        async fn wait_result(
+            sub: Subscriber,
+            started: Instant,
+            deadline: Option<Instant>,
+            cancel: Option<Cancel>,
+            path: Path,
+            st: St,
+        ) -> (Path, Result<Val>) {
             match st {
                 St::Resolve(_) => unreachable!(),
+                St::Cancelled => (path, Err(anyhow!(Cancelled))),
                 St::Subscribed(raw, streams) => {
                     for (f, tx) in streams {
                         let m = ToCon::Stream {
@@ -1379,7 +3094,9 @@ impl Subscriber {
                     (path, Ok(raw))
                 }
                 St::Error(e) => {
+                    metrics::subscription_failed();
                     let mut t = sub.0.lock();
+                    t.resolve_cache.remove(&path);
                     if let Some(sub) = t.subscribed.remove(path.as_ref()) {
                         match sub {
                             SubStatus::Subscribed(_) => unreachable!(),
@@ -1393,10 +3110,14 @@ impl Subscriber {
                     }
                     (path, Err(e))
                 }
-                St::WaitingOther(w, streams) => match w.await {
-                    Err(e) => (path, Err(anyhow!("other side died {}", e))),
-                    Ok(Err(e)) => (path, Err(e)),
-                    Ok(Ok(raw)) => {
+                St::WaitingOther(w, streams) => match until(deadline, &cancel, w).await {
+                    Until::TimedOut => {
+                        (path, Err(anyhow!("subscribing {} timed out", path)))
+                    }
+                    Until::Cancelled => (path, Err(anyhow!(Cancelled))),
+                    Until::Done(Err(e)) => (path, Err(anyhow!("other side died {}", e))),
+                    Until::Done(Ok(Err(e))) => (path, Err(e)),
+                    Until::Done(Ok(Ok(raw))) => {
                         for (f, tx) in streams {
                             let m = ToCon::Stream { tx, flags: f, id: raw.0.id };
                             raw.0.connection.send(m);
@@ -1404,47 +3125,34 @@ impl Subscriber {
                         (path, Ok(raw))
                     }
                 },
-                St::Subscribing(w) => {
-                    let res = match w.await {
-                        Err(e) => Err(anyhow!("connection died {}", e)),
-                        Ok(Err(e)) => Err(e),
-                        Ok(Ok(raw)) => Ok(raw),
-                    };
-                    let mut t = sub.0.lock();
-                    match t.subscribed.entry(path.clone()) {
-                        Entry::Vacant(_) => unreachable!(),
-                        Entry::Occupied(mut e) => match res {
-                            Err(err) => match e.remove() {
-                                SubStatus::Subscribed(_) => unreachable!(),
-                                SubStatus::Pending(waiters) => {
-                                    for w in waiters.into_iter() {
-                                        let err = Err(anyhow!("{}", err));
-                                        let _ = w.send(err);
-                                    }
-                                    (path, Err(err))
-                                }
-                            },
-                            Ok(raw) => {
-                                let s = mem::replace(
-                                    e.get_mut(),
-                                    SubStatus::Subscribed(raw.downgrade()),
+                St::Subscribing(w, fallback) => {
+                    let r =
+                        subscribing(&sub, started, deadline, &cancel, &path, w, fallback)
+                            .await;
+                    match r {
+                        Ok(res) => (path, res),
+                        Err((w, fallback)) => {
+                            // others are waiting for the path, finish it for them
+                            let p = path.clone();
+                            task::spawn(async move {
+                                let fin = subscribing(
+                                    &sub, started, deadline, &None, &p, w, fallback,
                                 );
-                                match s {
-                                    SubStatus::Subscribed(_) => unreachable!(),
-                                    SubStatus::Pending(waiters) => {
-                                        for w in waiters.into_iter() {
-                                            let _ = w.send(Ok(raw.clone()));
-                                        }
-                                        (path, Ok(raw))
-                                    }
-                                }
-                            }
-                        },
+                                let _ = fin.await;
+                            });
+                            (path, Err(anyhow!(Cancelled)))
+                        }
                     }
                 }
             }
         }
-        pending.drain().map(|(path, st)| wait_result(self.clone(), path, st)).collect()
+        pending
+            .drain()
+            .chain(duplicates)
+            .map(|(path, st)| {
+                wait_result(self.clone(), now, deadline, cancel.clone(), path, st)
+            })
+            .collect()
     }
 
     /// Subscribe to a single value.
@@ -1473,16 +3181,95 @@ impl Subscriber {
         updates: impl IntoIterator<Item = (UpdatesFlags, UpdateChan)>,
         timeout: Option<Duration>,
     ) -> Result<Val> {
-        let updates = updates.into_iter().map(|(f, c)| (f, ChanWrap(c)));
-        self.subscribe_nondurable_internal(iter::once((path, updates)), timeout)
-            .await
-            .next()
+        let updates =
+            updates.into_iter().map(|(f, c)| (f, WUpdateChan::Plain(ChanWrap(c))));
+        self.subscribe_nondurable_internal(
+            iter::once((path, updates)),
+            timeout,
+            SubscribePriority::Foreground,
+            None,
+        )
+        .await
+        .next()
+        .await
+        .unwrap()
+        .1
+    }
+
+    /// Get the current value of `path` and then unsubscribe.
+    ///
+    /// This is for scripts and tools that just want to read a value
+    /// once. Once the publisher acknowledges the unsubscribe the
+    /// connection to it is closed, unless something else is
+    /// subscribed through it. If `path` is already subscribed by
+    /// this subscriber the existing subscription's last value is
+    /// returned and the subscription is left alone.
+    pub async fn get_once(&self, path: Path, timeout: Option<Duration>) -> Result<Value> {
+        let v = self.subscribe_nondurable_one(path, timeout).await?;
+        match v.last() {
+            Event::Update(v) => Ok(v),
+            Event::Unsubscribed => bail!("unsubscribed"),
+        }
+    }
+
+    /// Same as `get_once`, but it can be cancelled. When `cancel`
+    /// completes first this returns `Cancelled`, and the subscriber
+    /// is cleaned up as described in `subscribe_nondurable_cancel`.
+    pub async fn get_once_cancel(
+        &self,
+        path: Path,
+        timeout: Option<Duration>,
+        cancel: impl Future<Output = ()> + Send + 'static,
+    ) -> Result<Value> {
+        let mut res =
+            self.subscribe_nondurable_cancel(iter::once(path), timeout, cancel).await?;
+        let v = res.pop().unwrap().1?;
+        match v.last() {
+            Event::Update(v) => Ok(v),
+            Event::Unsubscribed => bail!("unsubscribed"),
+        }
+    }
+
+    /// Get the current values of a group of paths and then
+    /// unsubscribe from all of them.
+    ///
+    /// The paths are resolved and subscribed as one batch, and no
+    /// value is read until every subscription has completed, then
+    /// all of them are read at once. This is not a transaction,
+    /// each value is read on its own, so an update that arrives while
+    /// they are being read, even one in the same batch as another
+    /// value of the group, may or may not be seen.
+    ///
+    /// If any path fails to subscribe, or is unsubscribed before it
+    /// is read, the whole snapshot fails. Paths that are already
+    /// subscribed by this subscriber are read from the existing
+    /// subscription, as with `get_once`.
+    pub async fn snapshot(
+        &self,
+        paths: impl IntoIterator<Item = Path>,
+        timeout: Option<Duration>,
+    ) -> Result<HashMap<Path, Value>> {
+        let vals = self
+            .subscribe_nondurable(paths.into_iter(), timeout)
             .await
-            .unwrap()
-            .1
+            .map(|(path, r)| r.with_context(|| path.clone()).map(|v| (path, v)))
+            .try_collect::<Vec<_>>()
+            .await?;
+        vals.iter()
+            .map(|(path, v)| match v.last() {
+                Event::Update(v) => Ok((path.clone(), v)),
+                Event::Unsubscribed => bail!("{path} unsubscribed"),
+            })
+            .collect()
     }
 
-    fn subscribe_internal<I>(&self, path: Path, updates: I) -> Dval
+    fn subscribe_internal<I>(
+        &self,
+        path: Path,
+        updates: I,
+        quota: bool,
+        pin: Option<usize>,
+    ) -> Result<Dval>
     where
         I: IntoIterator<Item = (UpdatesFlags, Sender<GPooled<Vec<(SubId, Event)>>>)>,
     {
@@ -1497,24 +3284,45 @@ impl Subscriber {
                 for (f, c) in updates {
                     s.updates(f, c)
                 }
-                return s;
+                if let Some(attempts) = pin {
+                    let mut dv = s.0.lock();
+                    if dv.pin.is_none() {
+                        dv.pin = Some(Box::new(Pin {
+                            attempts,
+                            remaining: attempts,
+                            last: None,
+                        }));
+                    }
+                }
+                return Ok(s);
             }
         }
+        if quota && !t.subscribed.contains_key(&path) && t.over_quota() {
+            bail!("subscription quota exceeded")
+        }
+        let path = t.intern(path);
         let s = Dval(Arc::new(Mutex::new(DvalInner {
             sub_id: SubId::new(),
             sub: DvState::Dead(Box::new(DvDead {
                 queued_writes: Vec::new(),
                 waiting: Vec::new(),
+                ready: Vec::new(),
                 tries: 0,
                 next_try: Instant::now(),
+                last_error: None,
             })),
             streams: SmallVec::from_iter(
-                updates.into_iter().map(|(f, c)| (f, ChanWrap(c))),
+                updates.into_iter().map(|(f, c)| (f, WUpdateChan::Plain(ChanWrap(c)))),
             ),
+            pin: pin.map(|attempts| {
+                Box::new(Pin { attempts, remaining: attempts, last: None })
+            }),
+            write_queue_limit: None,
+            durable_config: None,
         })));
         t.durable_dead.insert(path, s.downgrade());
         let _ = t.trigger_resub.unbounded_send(());
-        s
+        Ok(s)
     }
 
     /// Create a durable subscription with updates channels.
@@ -1533,7 +3341,16 @@ impl Subscriber {
     where
         I: IntoIterator<Item = (UpdatesFlags, Sender<GPooled<Vec<(SubId, Event)>>>)>,
     {
-        self.subscribe_internal(path, updates)
+        self.subscribe_internal(path, updates, false, None).unwrap()
+    }
+
+    /// Same as `subscribe_updates`, but fail if creating the
+    /// subscription would exceed the `max_subscriptions` quota.
+    pub fn try_subscribe_updates<I>(&self, path: Path, updates: I) -> Result<Dval>
+    where
+        I: IntoIterator<Item = (UpdatesFlags, Sender<GPooled<Vec<(SubId, Event)>>>)>,
+    {
+        self.subscribe_internal(path, updates, true, None)
     }
 
     /// Create a durable subscription.
@@ -1545,7 +3362,81 @@ impl Subscriber {
     /// subscribe_nondurable, except that certain errors are caught,
     /// and resubscriptions are attempted. see `Dval`.
     pub fn subscribe(&self, path: Path) -> Dval {
-        self.subscribe_internal(path, [])
+        self.subscribe_internal(path, [], false, None).unwrap()
+    }
+
+    /// Same as `subscribe`, but fail if creating the subscription
+    /// would exceed the `max_subscriptions` quota.
+    pub fn try_subscribe(&self, path: Path) -> Result<Dval> {
+        self.subscribe_internal(path, [], true, None)
+    }
+
+    /// Create a durable subscription that is pinned to the publisher
+    /// it last subscribed to.
+    ///
+    /// When the subscription dies it will first try to resubscribe
+    /// directly to the address the resolver last gave it, using the
+    /// last resolution, for up to `attempts` tries. Only after those
+    /// fail does it go back to the resolver. This lets subscriptions
+    /// recover from a publisher connection loss while the resolver
+    /// servers are unreachable. A successful resubscription resets
+    /// the count. If the path is already durably subscribed the
+    /// existing `Dval` is returned, and pinned if it isn't already.
+    ///
+    /// The last resolution carries the permissions and token the
+    /// resolver issued, so a publisher that was restarted with new
+    /// credentials may deny the pinned attempts.
+    pub fn subscribe_pinned(&self, path: Path, attempts: usize) -> Dval {
+        self.subscribe_internal(path, [], false, Some(attempts)).unwrap()
+    }
+
+    /// Create a durable subscription, and a future that will resolve
+    /// when it is ready.
+    ///
+    /// The `Dval` is returned immediately, exactly as `subscribe`
+    /// would return it. The future will resolve to `Ok(())` once the
+    /// `Dval` is subscribed, or to the error that caused the first
+    /// subscription attempt to fail. In the latter case the `Dval`
+    /// will keep trying to subscribe as usual. If the path is already
+    /// subscribed the future will be ready immediately. Awaiting the
+    /// future is optional, dropping it has no effect on the `Dval`.
+    pub fn durable_subscribe_ready(
+        &self,
+        path: Path,
+    ) -> (Dval, impl Future<Output = Result<()>> + Send + 'static) {
+        let dv = self.subscribe(path);
+        let (tx, rx) = oneshot::channel();
+        match &mut dv.0.lock().sub {
+            DvState::Subscribed(_) => {
+                let _ = tx.send(Ok(()));
+            }
+            DvState::Dead(d) => d.ready.push(tx),
+        }
+        let ready = async move {
+            match rx.await {
+                Ok(r) => r,
+                Err(_) => Err(anyhow!("subscription dropped")),
+            }
+        };
+        (dv, ready)
+    }
+
+    /// Return a stream that yields `()` whenever any of the specified
+    /// `Dval`s receives an update.
+    ///
+    /// This is meant for cases where you only need to know that
+    /// something changed, e.g. to mark a view dirty and repaint it,
+    /// and you will read the current values with `last`. Updates
+    /// that arrive while the stream is not being polled are
+    /// coalesced into a single notification. As with any updates
+    /// channel, the stream must be polled, or dropped, or it will
+    /// eventually apply backpressure to the publishers.
+    pub fn dirty_notify(&self, vals: &[Dval]) -> impl Stream<Item = ()> + Send + 'static {
+        let (tx, rx) = mpsc::channel(3);
+        for dv in vals {
+            dv.updates(UpdatesFlags::empty(), tx.clone());
+        }
+        rx.ready_chunks(16).map(|_| ())
     }
 
     /// Wait for all pending operations to flush to publishers.
@@ -0,0 +1,140 @@
+//! The aggregate rate of data flowing into a subscriber, see
+//! `Subscriber::throughput`.
+use parking_lot::Mutex;
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use tokio::time::Instant;
+
+/// The minimum time between samples
+pub(super) const SAMPLE: Duration = Duration::from_secs(1);
+
+/// A rate, in units per second, averaged over three windows. Each
+/// window is an exponentially weighted moving average with a time
+/// constant of the window length, so a rate change is 63% reflected
+/// after one window length.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Rate {
+    pub avg_1s: f64,
+    pub avg_10s: f64,
+    pub avg_60s: f64,
+}
+
+impl Rate {
+    fn update(&mut self, rate: f64, elapsed: f64) {
+        for (avg, window) in
+            [(&mut self.avg_1s, 1.), (&mut self.avg_10s, 10.), (&mut self.avg_60s, 60.)]
+        {
+            let alpha = 1. - (-elapsed / window).exp();
+            *avg += alpha * (rate - *avg);
+        }
+    }
+}
+
+/// The rate of data received by a subscriber across all its
+/// connections.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Throughput {
+    /// updates received per second
+    pub values: Rate,
+    /// bytes received per second
+    pub bytes: Rate,
+}
+
+struct Windows {
+    last: Instant,
+    values: u64,
+    bytes: u64,
+    current: Throughput,
+}
+
+pub(super) struct Meter {
+    values: AtomicU64,
+    bytes: AtomicU64,
+    windows: Mutex<Windows>,
+}
+
+impl Meter {
+    pub(super) fn new() -> Self {
+        Self {
+            values: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            windows: Mutex::new(Windows {
+                last: Instant::now(),
+                values: 0,
+                bytes: 0,
+                current: Throughput::default(),
+            }),
+        }
+    }
+
+    /// Count `values` updates taking `bytes` bytes on the wire
+    pub(super) fn record(&self, values: u64, bytes: u64) {
+        self.values.fetch_add(values, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Fold everything recorded since the last sample into the
+    /// averages. Does nothing if the last sample was less than
+    /// `SAMPLE` ago, so every connection may call this every
+    /// `SAMPLE`, and readers may call it too.
+    pub(super) fn sample(&self) {
+        let now = Instant::now();
+        let mut w = self.windows.lock();
+        let elapsed = now - w.last;
+        if elapsed < SAMPLE {
+            return;
+        }
+        let secs = elapsed.as_secs_f64();
+        let values = self.values.load(Ordering::Relaxed);
+        let bytes = self.bytes.load(Ordering::Relaxed);
+        let values_rate = (values - w.values) as f64 / secs;
+        let bytes_rate = (bytes - w.bytes) as f64 / secs;
+        w.current.values.update(values_rate, secs);
+        w.current.bytes.update(bytes_rate, secs);
+        w.last = now;
+        w.values = values;
+        w.bytes = bytes;
+    }
+
+    pub(super) fn get(&self) -> Throughput {
+        self.sample();
+        self.windows.lock().current
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::time;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn averages() {
+        let m = Meter::new();
+        assert_eq!(m.get(), Throughput::default());
+        m.record(100, 1000);
+        time::advance(Duration::from_secs(2)).await;
+        let t = m.get();
+        // 50 values and 500 bytes a second for 2 seconds
+        for (rate, expected) in [(t.values, 50.), (t.bytes, 500.)] {
+            assert!(close(rate.avg_1s, expected * (1. - (-2f64).exp())));
+            assert!(close(rate.avg_10s, expected * (1. - (-0.2f64).exp())));
+            assert!(close(rate.avg_60s, expected * (1. - (-2f64 / 60.).exp())));
+        }
+        // less than SAMPLE after the last sample nothing changes
+        m.record(100, 1000);
+        time::advance(Duration::from_millis(500)).await;
+        assert_eq!(m.get(), t);
+        // a second with no new data counts the last 1.5 seconds
+        time::advance(Duration::from_millis(1000)).await;
+        let u = m.get();
+        let alpha = 1. - (-1.5f64).exp();
+        let expected = t.values.avg_1s + alpha * (100. / 1.5 - t.values.avg_1s);
+        assert!(close(u.values.avg_1s, expected));
+    }
+}
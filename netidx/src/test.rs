@@ -1,17 +1,127 @@
+use crate::{
+    config::Config as ClientConfig,
+    resolver_client::{DesiredAuth, ResolverWrite},
+    resolver_server::{
+        config::{file::AnonymousAccess, Config as ServerConfig},
+        Server,
+    },
+};
+use anyhow::{anyhow, Result};
+use arcstr::{literal, ArcStr};
+use netidx_netproto::resolver::{Auth, PublisherPriority};
+use std::net::SocketAddr;
+
+// an anonymous resolver listening on a random port on localhost
+async fn local_resolver() -> Result<(Server, ClientConfig)> {
+    let resolver = {
+        use crate::resolver_server::config::{self, file};
+        let cfg = file::ConfigBuilder::default()
+            .member_servers(vec![file::MemberServerBuilder::default()
+                .auth(file::Auth::Anonymous)
+                .addr("127.0.0.1:0".parse()?)
+                .bind_addr("127.0.0.1".parse()?)
+                .build()?])
+            .build()?;
+        let cfg = config::Config::from_file(cfg)?;
+        crate::resolver_server::Server::new(cfg, false, 0).await?
+    };
+    let addr = *resolver.local_addr();
+    let cfg = {
+        use crate::config::{self, file, DefaultAuthMech};
+        let cfg = file::ConfigBuilder::default()
+            .addrs(vec![(addr, file::Auth::Anonymous)])
+            .default_auth(DefaultAuthMech::Anonymous)
+            .default_bind_config("local")
+            .build()?;
+        config::Config::from_file(cfg)?
+    };
+    Ok((resolver, cfg))
+}
+
+// a resolver started from cfg/simple-server.json, and the
+// cfg/simple-client.json config pointed at it
+async fn simple_resolver() -> Result<(Server, ClientConfig)> {
+    let server_cfg = ServerConfig::load("../cfg/simple-server.json")?;
+    let mut client_cfg = ClientConfig::load("../cfg/simple-client.json")?;
+    let server = Server::new(server_cfg, false, 0).await?;
+    client_cfg.addrs[0].0 = *server.local_addr();
+    Ok((server, client_cfg))
+}
+
+// a resolver with local auth whose socket is in `dir`, and the
+// cfg/simple-client.json config pointed at it. Servers with auth deny
+// everything by default, so the current user and anonymous clients
+// are given every permission, and `access` decides whether anonymous
+// clients get in at all.
+async fn local_auth_resolver(
+    dir: &std::path::Path,
+    access: AnonymousAccess,
+) -> Result<(Server, ClientConfig)> {
+    use crate::resolver_server::config::{file, PMap};
+    use std::collections::HashMap;
+    let sock = dir.join("auth.sock");
+    let sock = ArcStr::from(sock.to_str().ok_or_else(|| anyhow!("non utf8 path"))?);
+    let user = local_user(dir)?;
+    let all = literal!("swlpd");
+    let perms = HashMap::from([(user, all.clone()), (literal!(""), all)]);
+    let cfg = file::ConfigBuilder::default()
+        .member_servers(vec![file::MemberServerBuilder::default()
+            .auth(file::Auth::Local(sock.clone()))
+            .addr("127.0.0.1:0".parse()?)
+            .bind_addr("127.0.0.1".parse()?)
+            .anonymous_access(access)
+            .id_map_type(file::IdMapType::DoNotMap)
+            .build()?])
+        .perms(PMap(HashMap::from([(literal!("/"), perms)])))
+        .build()?;
+    let server = Server::new(ServerConfig::from_file(cfg)?, false, 0).await?;
+    let mut client_cfg = ClientConfig::load("../cfg/simple-client.json")?;
+    client_cfg.addrs[0] = (*server.local_addr(), Auth::Local { path: sock });
+    Ok((server, client_cfg))
+}
+
+// the name local auth gives the current user, without id mapping
+// that is their uid, which owns `dir` since the test created it
+fn local_user(dir: &std::path::Path) -> Result<ArcStr> {
+    use std::os::unix::fs::MetadataExt;
+    Ok(ArcStr::from(dir.metadata()?.uid().to_string()))
+}
+
+// an anonymous writer for a publisher at `paddr`
+fn anonymous_writer(cfg: &ClientConfig, paddr: SocketAddr) -> Result<ResolverWrite> {
+    ResolverWrite::new(
+        cfg.clone(),
+        DesiredAuth::Anonymous,
+        paddr,
+        PublisherPriority::Normal,
+    )
+}
+
 mod resolver {
+    use super::{
+        anonymous_writer, local_auth_resolver, local_user, simple_resolver,
+        AnonymousAccess,
+    };
     use crate::{
+        channel::Channel,
         config::Config as ClientConfig,
         path::Path,
-        protocol::glob::{Glob, GlobSet},
+        protocol::{
+            glob::{Glob, GlobSet},
+            resolver::{ClientHelloWrite, ServerHelloWrite},
+        },
         publisher::PublishFlags,
         resolver_client::{ChangeTracker, DesiredAuth, ResolverRead, ResolverWrite},
         resolver_server::{config::Config as ServerConfig, Server},
     };
-    use arcstr::literal;
+    use arcstr::{literal, ArcStr};
     use netidx_netproto::resolver::{PublisherPriority, TargetAuth};
     use rand::{rng, RngExt};
     use std::{iter, net::SocketAddr, time::Duration};
-    use tokio::time;
+    use tokio::{
+        net::{TcpListener, TcpStream},
+        task, time,
+    };
 
     fn p(p: &'static str) -> Path {
         Path::from(p)
@@ -20,20 +130,9 @@ mod resolver {
     #[tokio::test(flavor = "multi_thread")]
     async fn publish_resolve_simple() {
         let _ = env_logger::try_init();
-        let server_cfg = ServerConfig::load("../cfg/simple-server.json")
-            .expect("load simple server config");
-        let mut client_cfg = ClientConfig::load("../cfg/simple-client.json")
-            .expect("load simple client config");
-        let server = Server::new(server_cfg, false, 0).await.expect("start server");
-        client_cfg.addrs[0].0 = *server.local_addr();
+        let (server, client_cfg) = simple_resolver().await.expect("start resolver");
         let paddr: SocketAddr = "127.0.0.1:1".parse().unwrap();
-        let w = ResolverWrite::new(
-            client_cfg.clone(),
-            DesiredAuth::Anonymous,
-            paddr,
-            PublisherPriority::Normal,
-        )
-        .unwrap();
+        let w = anonymous_writer(&client_cfg, paddr).unwrap();
         let r = ResolverRead::new(client_cfg, DesiredAuth::Anonymous);
         let paths = vec![p("/foo/bar"), p("/foo/baz"), p("/app/v0"), p("/app/v1")];
         let flags = Some(PublishFlags::USE_EXISTING.bits());
@@ -57,22 +156,522 @@ mod resolver {
     }
 
     #[tokio::test(flavor = "multi_thread")]
-    async fn publish_default() {
+    async fn config_verify() {
         let _ = env_logger::try_init();
         let server_cfg = ServerConfig::load("../cfg/simple-server.json")
             .expect("load simple server config");
         let mut client_cfg = ClientConfig::load("../cfg/simple-client.json")
             .expect("load simple client config");
         let server = Server::new(server_cfg, false, 0).await.expect("start server");
+        let dead = {
+            let l = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            l.local_addr().unwrap()
+        };
+        let timeout = Duration::from_secs(1);
+        client_cfg.addrs[0].0 = dead;
+        assert!(client_cfg.verify(timeout).await.is_err());
         client_cfg.addrs[0].0 = *server.local_addr();
+        client_cfg.verify(timeout).await.unwrap();
+        drop(server)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn server_handle() {
+        let _ = env_logger::try_init();
+        let (mut server, client_cfg) = simple_resolver().await.expect("start resolver");
+        let addr = *server.local_addr();
         let paddr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let w = anonymous_writer(&client_cfg, paddr).unwrap();
+        assert_eq!(server.published(), 0);
+        w.publish([p("/foo/bar"), p("/foo/baz")]).await.unwrap();
+        assert_eq!(server.published(), 2);
+        assert!(server.set_perms(Default::default()).await.is_err());
+        server.shutdown().await.unwrap();
+        assert!(tokio::net::TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn server_handle_set_perms() {
+        use crate::resolver_server::config::PMap;
+        use std::collections::HashMap;
+        let _ = env_logger::try_init();
+        let dir = tempdir::TempDir::new("netidx-perms").unwrap();
+        let (mut server, client_cfg) =
+            local_auth_resolver(dir.path(), AnonymousAccess::Deny).await.unwrap();
+        let user = local_user(dir.path()).unwrap();
+        let perms = |perms: &str| {
+            let entry = HashMap::from([(user.clone(), ArcStr::from(perms))]);
+            PMap(HashMap::from([(literal!("/"), entry)]))
+        };
         let w = ResolverWrite::new(
             client_cfg.clone(),
-            DesiredAuth::Anonymous,
-            paddr,
+            DesiredAuth::Local,
+            "127.0.0.1:1".parse().unwrap(),
             PublisherPriority::Normal,
         )
         .unwrap();
+        let r = ResolverRead::new(client_cfg, DesiredAuth::Local);
+        w.publish([p("/foo/a")]).await.unwrap();
+        // the connected writer loses the right to publish, but may
+        // still resolve
+        server.set_perms(perms("sl")).await.unwrap();
+        assert!(w.publish([p("/foo/b")]).await.is_err());
+        let (_, resolved) = r.resolve([p("/foo/a")]).await.unwrap();
+        assert_eq!(resolved[0].publishers.len(), 1);
+        // invalid perms are refused and the old ones stay in effect
+        assert!(server.set_perms(perms("x")).await.is_err());
+        assert!(w.publish([p("/foo/b")]).await.is_err());
+        // and then it gets the right back
+        server.set_perms(perms("swlpd")).await.unwrap();
+        w.publish([p("/foo/b")]).await.unwrap();
+        // without subscribe it can't resolve
+        server.set_perms(perms("p")).await.unwrap();
+        assert!(r.resolve([p("/foo/a")]).await.is_err());
+        drop(server)
+    }
+
+    #[test]
+    fn anonymous_access_config() {
+        use crate::resolver_server::config::file::{self, AnonymousAccess};
+        let cfg = |auth, access| {
+            let member = file::MemberServerBuilder::default()
+                .auth(auth)
+                .addr("127.0.0.1:4564".parse().unwrap())
+                .anonymous_access(access)
+                .build()
+                .unwrap();
+            let cfg = file::ConfigBuilder::default()
+                .member_servers(vec![member])
+                .build()
+                .unwrap();
+            ServerConfig::from_file(cfg)
+        };
+        let krb5 = || file::Auth::Krb5(literal!("host/localhost@LOCAL"));
+        assert!(cfg(file::Auth::Anonymous, AnonymousAccess::ReadWrite).is_ok());
+        assert!(cfg(file::Auth::Anonymous, AnonymousAccess::ReadOnly).is_err());
+        assert!(cfg(file::Auth::Anonymous, AnonymousAccess::Deny).is_err());
+        assert!(cfg(krb5(), AnonymousAccess::ReadOnly).is_ok());
+        assert!(cfg(krb5(), AnonymousAccess::Deny).is_ok());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn additional_bind_addrs() {
+        let _ = env_logger::try_init();
+        // the additional addresses are also bound when the main
+        // listener is passed in, as it is for a local only resolver
+        for prebound in [false, true] {
+            let extra = {
+                let l = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+                l.local_addr().unwrap()
+            };
+            let listener = if prebound {
+                Some(tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap())
+            } else {
+                None
+            };
+            let addr = match &listener {
+                Some(l) => l.local_addr().unwrap(),
+                None => "127.0.0.1:0".parse().unwrap(),
+            };
+            let server_cfg = {
+                use crate::resolver_server::config::file;
+                let cfg = file::ConfigBuilder::default()
+                    .member_servers(vec![file::MemberServerBuilder::default()
+                        .auth(file::Auth::Anonymous)
+                        .addr(addr)
+                        .bind_addr("127.0.0.1".parse().unwrap())
+                        .additional_bind_addrs(vec![extra])
+                        .build()
+                        .unwrap()])
+                    .build()
+                    .unwrap();
+                ServerConfig::from_file(cfg).unwrap()
+            };
+            let mut client_cfg = ClientConfig::load("../cfg/simple-client.json")
+                .expect("load simple client config");
+            let server = match listener {
+                Some(l) => Server::new_local_only(server_cfg, l).await,
+                None => Server::new(server_cfg, false, 0).await,
+            }
+            .expect("start server");
+            let paddr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+            client_cfg.addrs[0].0 = *server.local_addr();
+            let w = anonymous_writer(&client_cfg, paddr).unwrap();
+            w.publish([p("/foo/bar")]).await.unwrap();
+            // read back through the additional address
+            client_cfg.addrs[0].0 = extra;
+            let r = ResolverRead::new(client_cfg, DesiredAuth::Anonymous);
+            let (publishers, resolved) = r.resolve([p("/foo/bar")]).await.unwrap();
+            assert_eq!(resolved[0].publishers.len(), 1);
+            let pb = publishers.get(&resolved[0].publishers[0].id).unwrap();
+            assert_eq!(pb.addr, paddr);
+            drop(server)
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reject_at_capacity() {
+        use crate::{channel, resolver_server::config::file};
+        use netidx_netproto::resolver::AT_CAPACITY;
+        use tokio::net::TcpStream;
+        let _ = env_logger::try_init();
+        let server_cfg = {
+            let cfg = file::ConfigBuilder::default()
+                .member_servers(vec![file::MemberServerBuilder::default()
+                    .auth(file::Auth::Anonymous)
+                    .addr("127.0.0.1:0".parse().unwrap())
+                    .bind_addr("127.0.0.1".parse().unwrap())
+                    .max_connections(1)
+                    .at_capacity(file::AtCapacity::Reject)
+                    .build()
+                    .unwrap()])
+                .build()
+                .unwrap();
+            ServerConfig::from_file(cfg).unwrap()
+        };
+        let server = Server::new(server_cfg, false, 0).await.expect("start server");
+        let addr = *server.local_addr();
+        async fn version(addr: SocketAddr) -> (TcpStream, u64) {
+            let mut con = TcpStream::connect(addr).await.unwrap();
+            let v = channel::read_raw::<u64, _, 1024>(&mut con).await.unwrap();
+            (con, v)
+        }
+        let (first, v) = version(addr).await;
+        assert_eq!(v, 3);
+        let (mut second, v) = version(addr).await;
+        assert_eq!(v, AT_CAPACITY);
+        channel::write_raw(&mut second, &3u64).await.unwrap();
+        drop(second);
+        // once the first client goes away there is room again
+        drop(first);
+        let start = time::Instant::now();
+        loop {
+            let (_con, v) = version(addr).await;
+            if v == 3 {
+                break;
+            }
+            assert!(start.elapsed() < Duration::from_secs(10));
+            time::sleep(Duration::from_millis(10)).await
+        }
+        drop(server)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn resolver_auth_error() {
+        use crate::resolver_client::ResolverAuthError;
+        let _ = env_logger::try_init();
+        let dir = tempdir::TempDir::new("netidx-auth").unwrap();
+        let (server, client_cfg) =
+            local_auth_resolver(dir.path(), AnonymousAccess::Deny).await.unwrap();
+        let addr = *server.local_addr();
+        let check = |client_cfg: ClientConfig, auth: DesiredAuth| async move {
+            let r = ResolverRead::new(client_cfg, auth);
+            let e = time::timeout(Duration::from_secs(10), r.resolve([p("/foo")]))
+                .await
+                .expect("auth failure should not be retried")
+                .unwrap_err();
+            let e = e.downcast_ref::<ResolverAuthError>().expect("an auth error");
+            assert_eq!(e.resolver, addr);
+        };
+        // the server refuses anonymous clients
+        check(client_cfg.clone(), DesiredAuth::Anonymous).await;
+        // and tls clients, which it can't authenticate
+        let mut tls_cfg = ClientConfig::load("../cfg/tls/client/client.json").unwrap();
+        tls_cfg.addrs[0].0 = addr;
+        check(tls_cfg, DesiredAuth::Tls { identity: None }).await;
+        // but local clients are fine
+        let r = ResolverRead::new(client_cfg, DesiredAuth::Local);
+        r.resolve([p("/foo")]).await.unwrap();
+        drop(server)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn anonymous_write_denied() {
+        let _ = env_logger::try_init();
+        let dir = tempdir::TempDir::new("netidx-auth").unwrap();
+        let (server, client_cfg) =
+            local_auth_resolver(dir.path(), AnonymousAccess::ReadOnly).await.unwrap();
+        let writer = |auth: DesiredAuth, paddr: &str| {
+            ResolverWrite::new(
+                client_cfg.clone(),
+                auth,
+                paddr.parse().unwrap(),
+                PublisherPriority::Normal,
+            )
+            .unwrap()
+        };
+        // the anonymous WriteOnly hello is rejected, so the publish
+        // never goes through
+        let w = writer(DesiredAuth::Anonymous, "127.0.0.1:1");
+        let r = time::timeout(Duration::from_secs(3), w.publish([p("/foo/anon")])).await;
+        assert!(!matches!(r, Ok(Ok(()))));
+        drop(w);
+        // a local writer may publish
+        let w = writer(DesiredAuth::Local, "127.0.0.1:2");
+        w.publish([p("/foo/local")]).await.unwrap();
+        // and anonymous readers are still allowed, they see only the
+        // local publisher
+        let r = ResolverRead::new(client_cfg.clone(), DesiredAuth::Anonymous);
+        let (_, resolved) = r.resolve([p("/foo/anon"), p("/foo/local")]).await.unwrap();
+        assert_eq!(resolved[0].publishers.len(), 0);
+        assert_eq!(resolved[1].publishers.len(), 1);
+        drop(w);
+        drop(server)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn audit_log() {
+        use crate::resolver_server::config::file;
+        let _ = env_logger::try_init();
+        let dir = tempdir::TempDir::new("netidx-audit").unwrap();
+        let log = dir.path().join("audit.log");
+        let start = || async {
+            let cfg = file::ConfigBuilder::default()
+                .member_servers(vec![file::MemberServerBuilder::default()
+                    .auth(file::Auth::Anonymous)
+                    .addr("127.0.0.1:0".parse().unwrap())
+                    .bind_addr("127.0.0.1".parse().unwrap())
+                    .audit_log(log.clone())
+                    .build()
+                    .unwrap()])
+                .build()
+                .unwrap();
+            let server_cfg = ServerConfig::from_file(cfg).unwrap();
+            let server = Server::new(server_cfg, false, 0).await.expect("start server");
+            let mut client_cfg = ClientConfig::load("../cfg/simple-client.json")
+                .expect("load simple client config");
+            client_cfg.addrs[0].0 = *server.local_addr();
+            let w =
+                anonymous_writer(&client_cfg, "127.0.0.1:1".parse().unwrap()).unwrap();
+            (server, w)
+        };
+        // the log is written in the background
+        let wait_lines = |n: usize| {
+            let log = log.clone();
+            async move {
+                for _ in 0..100 {
+                    let lines = std::fs::read_to_string(&log).unwrap();
+                    if lines.lines().count() >= n {
+                        return lines;
+                    }
+                    time::sleep(Duration::from_millis(50)).await
+                }
+                panic!("audit log not written")
+            }
+        };
+        let (server, w) = start().await;
+        w.publish([p("/audit/a"), p("/audit/b")]).await.unwrap();
+        w.unpublish([p("/audit/a")]).await.unwrap();
+        wait_lines(3).await;
+        // stop the server first so it doesn't see the writer leave
+        drop(server);
+        drop(w);
+        // a restarted server continues the chain
+        let (server, w) = start().await;
+        w.publish([p("/audit/c")]).await.unwrap();
+        let lines = wait_lines(4).await;
+        let recs = lines
+            .lines()
+            .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+            .collect::<Vec<_>>();
+        let ops = recs
+            .iter()
+            .map(|r| (r["op"].as_str().unwrap(), r["path"].as_str().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ops,
+            vec![
+                ("publish", "/audit/a"),
+                ("publish", "/audit/b"),
+                ("unpublish", "/audit/a"),
+                ("publish", "/audit/c")
+            ]
+        );
+        let mut prev = String::new();
+        for (line, rec) in lines.lines().zip(recs.iter()) {
+            assert_eq!(rec["prev"].as_str().unwrap(), prev);
+            assert!(rec["principal"].is_null());
+            assert_eq!(rec["result"], "ok");
+            assert_eq!(rec["publisher"], "127.0.0.1:1");
+            prev = netidx_core::utils::make_sha3_token([line.as_bytes()])
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect();
+        }
+        drop(w);
+        drop(server)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn audit_log_fails_closed() {
+        use crate::resolver_server::config::file;
+        let _ = env_logger::try_init();
+        let dir = tempdir::TempDir::new("netidx-audit-fail").unwrap();
+        let log = dir.path().join("audit.log");
+        let cfg = file::ConfigBuilder::default()
+            .member_servers(vec![file::MemberServerBuilder::default()
+                .auth(file::Auth::Anonymous)
+                .addr("127.0.0.1:0".parse().unwrap())
+                .bind_addr("127.0.0.1".parse().unwrap())
+                .audit_log(log.clone())
+                .audit_log_max_size(1)
+                .build()
+                .unwrap()])
+            .build()
+            .unwrap();
+        let server_cfg = ServerConfig::from_file(cfg).unwrap();
+        let server = Server::new(server_cfg, false, 0).await.expect("start server");
+        let mut client_cfg = ClientConfig::load("../cfg/simple-client.json")
+            .expect("load simple client config");
+        client_cfg.addrs[0].0 = *server.local_addr();
+        let w = anonymous_writer(&client_cfg, "127.0.0.1:1".parse().unwrap()).unwrap();
+        w.publish([p("/audit/a")]).await.unwrap();
+        // every line after the first rotates the file, which fails
+        // once it is gone
+        for _ in 0..100 {
+            if std::fs::metadata(&log).map(|m| m.len() > 0).unwrap_or(false) {
+                break;
+            }
+            time::sleep(Duration::from_millis(50)).await
+        }
+        std::fs::remove_file(&log).unwrap();
+        w.publish([p("/audit/b")]).await.unwrap();
+        let mut refused = false;
+        for _ in 0..100 {
+            if w.publish([p("/audit/c")]).await.is_err() {
+                refused = true;
+                break;
+            }
+            time::sleep(Duration::from_millis(50)).await
+        }
+        assert!(refused, "writes were not refused");
+        drop(w);
+        drop(server)
+    }
+
+    // answer the listener ownership checks the resolver makes for
+    // `w`, the way a publisher listening on `listener` would
+    fn answer_ownership_checks(listener: TcpListener, w: &ResolverWrite) {
+        use crate::{
+            channel::{self, Channel},
+            pack::BoundedBytes,
+            protocol::{publisher::Hello, resolver::AuthChallenge},
+            utils,
+        };
+        use cross_krb5::ServerCtx;
+        let secrets = w.secrets();
+        task::spawn(async move {
+            while let Ok((mut s, _)) = listener.accept().await {
+                // a failed check just fails that writer's hello
+                let _: anyhow::Result<()> = async {
+                    channel::write_raw(&mut s, &3u64).await?;
+                    let _: u64 = channel::read_raw::<_, _, 1024>(&mut s).await?;
+                    let id = match channel::read_raw::<_, _, 8124>(&mut s).await? {
+                        Hello::ResolverAuthenticate(id) => id,
+                        h => bail!("unexpected {h:?}"),
+                    };
+                    let mut con = Channel::new::<ServerCtx, TcpStream>(None, s);
+                    let c: AuthChallenge = con.receive().await?;
+                    let secret = secrets.read().get(&id).copied();
+                    let secret = secret.ok_or_else(|| anyhow!("no secret"))?;
+                    let reply = utils::make_sha3_token([
+                        &c.challenge.to_be_bytes()[..],
+                        &secret.to_be_bytes()[..],
+                    ]);
+                    con.send_one(&BoundedBytes::<4096>(reply)).await
+                }
+                .await;
+            }
+        });
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn republish() {
+        let _ = env_logger::try_init();
+        // only publishers of the same authenticated user may take
+        // over each other's paths, so use local auth
+        let dir = tempdir::TempDir::new("netidx-republish").unwrap();
+        let (server, client_cfg) =
+            local_auth_resolver(dir.path(), AnonymousAccess::ReadWrite).await.unwrap();
+        let writer = || {
+            let client_cfg = client_cfg.clone();
+            async move {
+                let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let addr = listener.local_addr().unwrap();
+                let w = ResolverWrite::new(
+                    client_cfg,
+                    DesiredAuth::Local,
+                    addr,
+                    PublisherPriority::Normal,
+                )
+                .unwrap();
+                answer_ownership_checks(listener, &w);
+                (w, addr)
+            }
+        };
+        let (a, aaddr) = writer().await;
+        let (b, baddr) = writer().await;
+        let path = p("/migrate/v");
+        let flags = PublishFlags::USE_EXISTING.bits();
+        a.publish_with_flags([(path.clone(), Some(flags))]).await.unwrap();
+        let r = ResolverRead::new(client_cfg.clone(), DesiredAuth::Local);
+        let (stop_tx, mut stop_rx) = tokio::sync::oneshot::channel::<()>();
+        let reader = {
+            let path = path.clone();
+            tokio::task::spawn(async move {
+                let mut n = 0;
+                while stop_rx.try_recv().is_err() {
+                    let (_, resolved) = r.resolve([path.clone()]).await.unwrap();
+                    assert!(resolved[0].publishers.len() > 0, "path went missing");
+                    n += 1;
+                }
+                n
+            })
+        };
+        let mut last = aaddr;
+        for i in 0..50 {
+            let (w, from) = if i % 2 == 0 { (&b, aaddr) } else { (&a, baddr) };
+            w.republish([(path.clone(), from)]).await.unwrap();
+            last = if i % 2 == 0 { baddr } else { aaddr };
+        }
+        let _ = stop_tx.send(());
+        assert!(reader.await.unwrap() > 0);
+        let r = ResolverRead::new(client_cfg, DesiredAuth::Local);
+        let (publishers, resolved) = r.resolve([path]).await.unwrap();
+        assert_eq!(resolved[0].publishers.len(), 1);
+        let pb = publishers.get(&resolved[0].publishers[0].id).unwrap();
+        assert_eq!(pb.addr, last);
+        assert_eq!(resolved[0].flags, flags);
+        drop(server)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn republish_anonymous() {
+        let _ = env_logger::try_init();
+        let (server, client_cfg) = simple_resolver().await.expect("start resolver");
+        let aaddr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let baddr: SocketAddr = "127.0.0.1:2".parse().unwrap();
+        let writer = |addr| anonymous_writer(&client_cfg, addr).unwrap();
+        let (a, b) = (writer(aaddr), writer(baddr));
+        let path = p("/migrate/v");
+        a.publish([path.clone()]).await.unwrap();
+        // nothing ties two anonymous writers together, so the second
+        // one can't take the path from the first
+        assert!(b.republish([(path.clone(), aaddr)]).await.is_err());
+        let r = ResolverRead::new(client_cfg, DesiredAuth::Anonymous);
+        let (publishers, resolved) = r.resolve([path]).await.unwrap();
+        assert_eq!(resolved[0].publishers.len(), 1);
+        let pb = publishers.get(&resolved[0].publishers[0].id).unwrap();
+        assert_eq!(pb.addr, aaddr);
+        drop(server)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn publish_default() {
+        let _ = env_logger::try_init();
+        let (server, client_cfg) = simple_resolver().await.expect("start resolver");
+        let paddr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let w = anonymous_writer(&client_cfg, paddr).unwrap();
         let r = ResolverRead::new(client_cfg, DesiredAuth::Anonymous);
         let defaults = [
             p("/default"),
@@ -106,104 +705,487 @@ mod resolver {
         drop(server)
     }
 
-    struct Ctx {
-        _local: Server,
-        _root: (Server, Server),
-        _huge0: (Server, Server),
-        _huge1: (Server, Server),
-        _huge1_sub: (Server, Server),
-        cfg_local: ClientConfig,
-        cfg_root: ClientConfig,
-        cfg_huge0: ClientConfig,
-        cfg_huge1: ClientConfig,
-        cfg_huge1_sub: ClientConfig,
-    }
-
-    impl Ctx {
-        fn random_server(&self) -> ClientConfig {
-            match rng().random_range(0. ..=1.) {
-                n if n >= 0. && n <= 0.20 => self.cfg_root.clone(),
-                n if n > 0.20 && n <= 0.40 => self.cfg_huge0.clone(),
-                n if n > 0.40 && n <= 0.60 => self.cfg_huge1.clone(),
-                n if n > 0.60 && n <= 0.80 => self.cfg_huge1_sub.clone(),
-                _ => self.cfg_local.clone(),
+    #[tokio::test(flavor = "multi_thread")]
+    async fn unpublish_subtree() {
+        let _ = env_logger::try_init();
+        let (server, client_cfg) = simple_resolver().await.expect("start resolver");
+        let paddr0: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let paddr1: SocketAddr = "127.0.0.1:2".parse().unwrap();
+        let w0 = anonymous_writer(&client_cfg, paddr0).unwrap();
+        let w1 = anonymous_writer(&client_cfg, paddr1).unwrap();
+        let r = ResolverRead::new(client_cfg, DesiredAuth::Anonymous);
+        let gone =
+            (0..100).map(|i| Path::from(format!("/app/a/{i}"))).collect::<Vec<_>>();
+        let kept = vec![p("/app/ab"), p("/app/b/0")];
+        w0.publish(gone.iter().cloned().chain(kept.iter().cloned())).await.unwrap();
+        w0.publish_default([p("/app/a/default")]).await.unwrap();
+        w1.publish([p("/app/a/0")]).await.unwrap();
+        w0.unpublish_subtree([p("/app/a")]).await.unwrap();
+        let (publishers, mut resolved) = r.resolve(gone.clone()).await.unwrap();
+        for (i, r) in resolved.drain(..).enumerate() {
+            if i == 0 {
+                assert_eq!(r.publishers.len(), 1);
+                let pb = publishers.get(&r.publishers[0].id).unwrap();
+                assert_eq!(pb.addr, paddr1);
+            } else {
+                assert_eq!(r.publishers.len(), 0);
             }
         }
+        let (_, mut resolved) = r.resolve([p("/app/a/default/foo")]).await.unwrap();
+        assert_eq!(resolved.pop().unwrap().publishers.len(), 0);
+        let (publishers, mut resolved) = r.resolve(kept.clone()).await.unwrap();
+        for r in resolved.drain(..) {
+            assert_eq!(r.publishers.len(), 1);
+            let pb = publishers.get(&r.publishers[0].id).unwrap();
+            assert_eq!(pb.addr, paddr0);
+        }
+        drop(server)
+    }
 
-        async fn new() -> Ctx {
-            let server_cfg_local = ServerConfig::load("../cfg/complex-local-server.json")
-                .expect("local server config");
-            let cfg_local = ClientConfig::load("../cfg/complex-local-client.json")
-                .expect("local client config");
-            let server_cfg_root = ServerConfig::load("../cfg/complex-root-server.json")
-                .expect("root server config");
-            let cfg_root = ClientConfig::load("../cfg/complex-root-client.json")
-                .expect("root client config");
-            let server_cfg_huge0 = ServerConfig::load("../cfg/complex-huge0-server.json")
-                .expect("huge0 server config");
-            let cfg_huge0 = ClientConfig::load("../cfg/complex-huge0-client.json")
-                .expect("huge0 client config");
-            let server_cfg_huge1 = ServerConfig::load("../cfg/complex-huge1-server.json")
-                .expect("huge1 server config");
-            let cfg_huge1 = ClientConfig::load("../cfg/complex-huge1-client.json")
-                .expect("huge1 client config");
-            let server_cfg_huge1_sub =
-                ServerConfig::load("../cfg/complex-huge1-sub-server.json")
-                    .expect("huge1 sub server config");
-            let cfg_huge1_sub =
-                ClientConfig::load("../cfg/complex-huge1-sub-client.json")
-                    .expect("huge1 sub client config");
-            let server_local =
-                Server::new(server_cfg_local, false, 0).await.expect("local server");
-            let server0_root = Server::new(server_cfg_root.clone(), false, 0)
-                .await
-                .expect("root server 0");
-            let server1_root =
-                Server::new(server_cfg_root, false, 1).await.expect("root server 1");
-            let server0_huge0 = Server::new(server_cfg_huge0.clone(), false, 0)
-                .await
-                .expect("huge0 server0");
-            let server1_huge0 =
-                Server::new(server_cfg_huge0, false, 1).await.expect("huge0 server1");
-            let server0_huge1 = Server::new(server_cfg_huge1.clone(), false, 0)
-                .await
-                .expect("huge1 server0");
-            let server1_huge1 =
-                Server::new(server_cfg_huge1, false, 1).await.expect("huge1 server0");
-            let server0_huge1_sub = Server::new(server_cfg_huge1_sub.clone(), false, 0)
-                .await
-                .expect("huge1 sub server0");
-            let server1_huge1_sub = Server::new(server_cfg_huge1_sub, false, 1)
-                .await
-                .expect("huge1 sub server0");
-            Ctx {
-                _local: server_local,
-                _root: (server0_root, server1_root),
-                _huge0: (server0_huge0, server1_huge0),
-                _huge1: (server0_huge1, server1_huge1),
-                _huge1_sub: (server0_huge1_sub, server1_huge1_sub),
-                cfg_local,
-                cfg_root,
-                cfg_huge0,
-                cfg_huge1,
-                cfg_huge1_sub,
+    #[tokio::test(flavor = "multi_thread")]
+    async fn resolve_status() {
+        use netidx_netproto::resolver::PathStatus;
+        let _ = env_logger::try_init();
+        let (server, client_cfg) = simple_resolver().await.expect("start resolver");
+        let paddr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let w = anonymous_writer(&client_cfg, paddr).unwrap();
+        let r = ResolverRead::new(client_cfg, DesiredAuth::Anonymous);
+        w.publish([p("/app/v0"), p("/app/v1")]).await.unwrap();
+        w.unpublish([p("/app/v0")]).await.unwrap();
+        let (_, resolved) =
+            r.resolve([p("/app/v0"), p("/app/v1"), p("/app/never")]).await.unwrap();
+        assert_eq!(resolved[0].publishers.len(), 0);
+        match resolved[0].status {
+            PathStatus::Expired { last_seen } => {
+                assert!(last_seen <= resolved[0].timestamp)
             }
+            s => panic!("expected expired, got {s:?}"),
         }
+        assert_eq!(resolved[1].publishers.len(), 1);
+        assert_eq!(resolved[1].status, PathStatus::Published);
+        assert_eq!(resolved[2].publishers.len(), 0);
+        assert_eq!(resolved[2].status, PathStatus::Unknown);
+        // publishing again forgets the expiry
+        w.publish([p("/app/v0")]).await.unwrap();
+        w.unpublish([p("/app/v1")]).await.unwrap();
+        let (_, resolved) = r.resolve([p("/app/v0"), p("/app/v1")]).await.unwrap();
+        assert_eq!(resolved[0].status, PathStatus::Published);
+        assert!(matches!(resolved[1].status, PathStatus::Expired { .. }));
+        drop(server)
     }
 
-    async fn check_list(local: bool, r: &ResolverRead) {
-        let mut l = r.list(p("/")).await.unwrap();
+    #[tokio::test(flavor = "multi_thread")]
+    async fn list_by_addr() {
+        let _ = env_logger::try_init();
+        let (server, client_cfg) = simple_resolver().await.expect("start resolver");
+        let paddr0: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let paddr1: SocketAddr = "127.0.0.1:2".parse().unwrap();
+        let w0 = anonymous_writer(&client_cfg, paddr0).unwrap();
+        let w1 = anonymous_writer(&client_cfg, paddr1).unwrap();
+        let r = ResolverRead::new(client_cfg, DesiredAuth::Anonymous);
+        let mut paths =
+            (0..100).map(|i| Path::from(format!("/app/{i}"))).collect::<Vec<_>>();
+        w0.publish(paths.iter().cloned()).await.unwrap();
+        w0.publish_default([p("/default")]).await.unwrap();
+        w1.publish([p("/app/0"), p("/other")]).await.unwrap();
+        paths.push(p("/default"));
+        paths.sort();
+        let mut l = r.list_by_addr(paddr0).await.unwrap();
         l.sort();
-        if local {
-            assert_eq!(&*l, &[p("/app"), p("/local"), p("/tmp")]);
-        } else {
-            assert_eq!(&*l, &[p("/app"), p("/tmp")]);
-        }
-        if local {
-            let mut l = r.list(p("/local")).await.unwrap();
-            l.sort();
-            assert_eq!(&*l, &[p("/local/bar"), p("/local/foo")])
-        }
+        assert_eq!(&**l, &*paths);
+        let mut l = r.list_by_addr(paddr1).await.unwrap();
+        l.sort();
+        assert_eq!(&**l, &[p("/app/0"), p("/other")]);
+        let l = r.list_by_addr("127.0.0.1:3".parse().unwrap()).await.unwrap();
+        assert_eq!(&**l, &[]);
+        drop(server)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn publish_store_full() {
+        use crate::resolver_server::config::file;
+        let _ = env_logger::try_init();
+        let mut server_cfg: file::Config = serde_json::from_str(
+            &std::fs::read_to_string("../cfg/simple-server.json")
+                .expect("read simple server config"),
+        )
+        .expect("parse simple server config");
+        server_cfg.member_servers[0].max_published = Some(2);
+        server_cfg.member_servers[0].evict_idle_anonymous = true;
+        let server_cfg = ServerConfig::from_file(server_cfg).expect("server config");
+        let mut client_cfg = ClientConfig::load("../cfg/simple-client.json")
+            .expect("load simple client config");
+        let server = Server::new(server_cfg, false, 0).await.expect("start server");
+        client_cfg.addrs[0].0 = *server.local_addr();
+        let w0 = anonymous_writer(&client_cfg, "127.0.0.1:1".parse().unwrap()).unwrap();
+        let w1 = anonymous_writer(&client_cfg, "127.0.0.1:2".parse().unwrap()).unwrap();
+        let r = ResolverRead::new(client_cfg, DesiredAuth::Anonymous);
+        w0.publish([p("/a"), p("/b")]).await.unwrap();
+        assert!(w0.publish([p("/c")]).await.is_err());
+        // republishing an existing path is still allowed
+        w0.publish([p("/a")]).await.unwrap();
+        // w0 is the only other anonymous writer, so it gets evicted
+        w1.publish([p("/c")]).await.unwrap();
+        let (_, resolved) = r.resolve([p("/a"), p("/b"), p("/c")]).await.unwrap();
+        assert_eq!(resolved[0].publishers.len(), 0);
+        assert_eq!(resolved[1].publishers.len(), 0);
+        assert_eq!(resolved[2].publishers.len(), 1);
+        drop(server)
+    }
+
+    // do the anonymous write hello with the resolver at `addr` by hand
+    async fn write_hello(
+        addr: SocketAddr,
+        hello: ClientHelloWrite,
+    ) -> (Channel, ServerHelloWrite) {
+        use crate::{channel, protocol::resolver::ClientHello};
+        use cross_krb5::ClientCtx;
+        let mut con = TcpStream::connect(addr).await.unwrap();
+        channel::write_raw(&mut con, &3u64).await.unwrap();
+        let version: u64 = channel::read_raw::<_, _, 1024>(&mut con).await.unwrap();
+        assert_eq!(version, 3);
+        let hello = ClientHello::WriteOnly(hello);
+        channel::write_raw(&mut con, &hello).await.unwrap();
+        let r: ServerHelloWrite =
+            channel::read_raw::<_, _, 1024>(&mut con).await.unwrap();
+        (Channel::new::<ClientCtx, TcpStream>(None, con), r)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn heartbeat_ttl_countdown() {
+        use crate::{
+            protocol::resolver::{AuthWrite, FromWrite, ToWrite, CAP_ALL},
+            resolver_server::config::file,
+        };
+        let _ = env_logger::try_init();
+        let mut server_cfg: file::Config = serde_json::from_str(
+            &std::fs::read_to_string("../cfg/simple-server.json")
+                .expect("read simple server config"),
+        )
+        .expect("parse simple server config");
+        server_cfg.member_servers[0].writer_ttl = 10;
+        let server_cfg = ServerConfig::from_file(server_cfg).expect("server config");
+        let server = Server::new(server_cfg, false, 0).await.expect("start server");
+        let hello = ClientHelloWrite {
+            write_addr: "127.0.0.1:1".parse().unwrap(),
+            auth: AuthWrite::Anonymous,
+            priority: PublisherPriority::Normal,
+            resync: false,
+            large_frames: false,
+            ttl_countdown: true,
+        };
+        let (mut con, r) = write_hello(*server.local_addr(), hello).await;
+        assert!(r.ttl_countdown);
+        assert_eq!(r.capabilities, CAP_ALL);
+        assert_eq!(&*r.version, env!("CARGO_PKG_VERSION"));
+        con.send_one(&ToWrite::Heartbeat).await.unwrap();
+        match time::timeout(Duration::from_secs(10), con.receive()).await.unwrap() {
+            Ok(FromWrite::Ttl(secs)) => assert!(secs >= 9 && secs <= 20),
+            m => panic!("unexpected heartbeat reply {m:?}"),
+        }
+        // a heartbeat at the end of a publish batch is answered with
+        // the ttl after the publish replies
+        con.queue_send(&ToWrite::Publish(p("/a"))).unwrap();
+        con.queue_send(&ToWrite::Heartbeat).unwrap();
+        con.flush().await.unwrap();
+        let mut replies = vec![];
+        while replies.len() < 2 {
+            let m = time::timeout(Duration::from_secs(10), con.receive()).await;
+            replies.push(m.unwrap().unwrap());
+        }
+        assert_eq!(replies[0], FromWrite::Published);
+        match replies[1] {
+            FromWrite::Ttl(secs) => assert!(secs >= 9 && secs <= 20),
+            ref m => panic!("unexpected heartbeat reply {m:?}"),
+        }
+        drop(server)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn writer_status() {
+        let _ = env_logger::try_init();
+        let (server, client_cfg) = simple_resolver().await.expect("start resolver");
+        let w = anonymous_writer(&client_cfg, "127.0.0.1:1".parse().unwrap()).unwrap();
+        w.publish([p("/app/a"), p("/app/b"), p("/app/c")]).await.unwrap();
+        w.publish_default([p("/app/d")]).await.unwrap();
+        let st = w.status().await.unwrap();
+        assert_eq!(st.published, 3);
+        assert_eq!(st.defaults, 1);
+        assert!(st.ttl >= 119 && st.ttl <= 240);
+        assert!(st.ttl_expired);
+        assert!(!st.evicted);
+        w.unpublish([p("/app/a")]).await.unwrap();
+        assert_eq!(w.status().await.unwrap().published, 2);
+        drop(server)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn resync_after_eviction() {
+        use crate::resolver_server::config::file;
+        let _ = env_logger::try_init();
+        let mut server_cfg: file::Config = serde_json::from_str(
+            &std::fs::read_to_string("../cfg/simple-server.json")
+                .expect("read simple server config"),
+        )
+        .expect("parse simple server config");
+        server_cfg.member_servers[0].max_published = Some(2);
+        server_cfg.member_servers[0].evict_idle_anonymous = true;
+        server_cfg.member_servers[0].writer_ttl = 2;
+        let server_cfg = ServerConfig::from_file(server_cfg).expect("server config");
+        let mut client_cfg = ClientConfig::load("../cfg/simple-client.json")
+            .expect("load simple client config");
+        let server = Server::new(server_cfg, false, 0).await.expect("start server");
+        client_cfg.addrs[0].0 = *server.local_addr();
+        let paddr0: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let w0 = anonymous_writer(&client_cfg, paddr0).unwrap();
+        let w1 = anonymous_writer(&client_cfg, "127.0.0.1:2".parse().unwrap()).unwrap();
+        let r = ResolverRead::new(client_cfg, DesiredAuth::Anonymous);
+        w0.publish([p("/a"), p("/b")]).await.unwrap();
+        // evicts w0
+        w1.publish([p("/c")]).await.unwrap();
+        let (_, resolved) = r.resolve([p("/a"), p("/b")]).await.unwrap();
+        assert_eq!(resolved[0].publishers.len(), 0);
+        assert_eq!(resolved[1].publishers.len(), 0);
+        // once there is room w0 is asked to resync on its next heartbeat
+        w1.unpublish([p("/c")]).await.unwrap();
+        let start = time::Instant::now();
+        loop {
+            let (publishers, resolved) = r.resolve([p("/a"), p("/b")]).await.unwrap();
+            if resolved.iter().all(|r| {
+                r.publishers.len() == 1 && publishers[&r.publishers[0].id].addr == paddr0
+            }) {
+                break;
+            }
+            assert!(start.elapsed() < Duration::from_secs(10));
+            time::sleep(Duration::from_millis(100)).await
+        }
+        drop(server)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn resync_after_resolver_restart() {
+        use crate::{protocol::resolver::AuthWrite, resolver_server::config::file};
+        let _ = env_logger::try_init();
+        let mut server_cfg: file::Config = serde_json::from_str(
+            &std::fs::read_to_string("../cfg/simple-server.json")
+                .expect("read simple server config"),
+        )
+        .expect("parse simple server config");
+        server_cfg.member_servers[0].writer_ttl = 2;
+        let server =
+            Server::new(ServerConfig::from_file(server_cfg.clone()).unwrap(), false, 0)
+                .await
+                .expect("start server");
+        let addr = *server.local_addr();
+        let mut client_cfg = ClientConfig::load("../cfg/simple-client.json")
+            .expect("load simple client config");
+        client_cfg.addrs[0].0 = addr;
+        let paddr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let w = anonymous_writer(&client_cfg, paddr).unwrap();
+        w.publish([p("/restart/a"), p("/restart/b")]).await.unwrap();
+        let hello = ClientHelloWrite {
+            write_addr: "127.0.0.1:2".parse().unwrap(),
+            auth: AuthWrite::Anonymous,
+            priority: PublisherPriority::Normal,
+            resync: false,
+            large_frames: false,
+            ttl_countdown: false,
+        };
+        let (_, before) = write_hello(addr, hello.clone()).await;
+        drop(server);
+        // the new server listens at the same address, and knows
+        // nothing about the writer
+        let server_cfg = ServerConfig::from_file(server_cfg).unwrap();
+        let start = time::Instant::now();
+        let server = loop {
+            match Server::new(server_cfg.clone(), false, 0).await {
+                Ok(server) => break server,
+                Err(e) => {
+                    assert!(start.elapsed() < Duration::from_secs(10), "{e:?}");
+                    time::sleep(Duration::from_millis(100)).await
+                }
+            }
+        };
+        // it has the same id, but a different instance
+        let (_, after) = write_hello(addr, hello).await;
+        assert_eq!(before.resolver_id, after.resolver_id);
+        assert_ne!(before.instance, after.instance);
+        let r = ResolverRead::new(client_cfg, DesiredAuth::Anonymous);
+        let start = time::Instant::now();
+        loop {
+            let (publishers, resolved) =
+                r.resolve([p("/restart/a"), p("/restart/b")]).await.unwrap();
+            if resolved.iter().all(|r| {
+                r.publishers.len() == 1 && publishers[&r.publishers[0].id].addr == paddr
+            }) {
+                break;
+            }
+            assert!(start.elapsed() < Duration::from_secs(10));
+            time::sleep(Duration::from_millis(100)).await
+        }
+        drop(w);
+        drop(server)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn publish_batch_too_large() {
+        use crate::resolver_server::config::file;
+        let _ = env_logger::try_init();
+        let mut server_cfg: file::Config = serde_json::from_str(
+            &std::fs::read_to_string("../cfg/simple-server.json")
+                .expect("read simple server config"),
+        )
+        .expect("parse simple server config");
+        server_cfg.member_servers[0].max_write_batch = Some(2);
+        let server_cfg = ServerConfig::from_file(server_cfg).expect("server config");
+        let mut client_cfg = ClientConfig::load("../cfg/simple-client.json")
+            .expect("load simple client config");
+        let server = Server::new(server_cfg, false, 0).await.expect("start server");
+        client_cfg.addrs[0].0 = *server.local_addr();
+        let w = anonymous_writer(&client_cfg, "127.0.0.1:1".parse().unwrap()).unwrap();
+        let r = ResolverRead::new(client_cfg, DesiredAuth::Anonymous);
+        assert!(w.publish([p("/a"), p("/b"), p("/c")]).await.is_err());
+        let (_, resolved) = r.resolve([p("/a"), p("/b"), p("/c")]).await.unwrap();
+        assert!(resolved.iter().all(|r| r.publishers.len() == 0));
+        w.publish([p("/a"), p("/b")]).await.unwrap();
+        let (_, resolved) = r.resolve([p("/a"), p("/b")]).await.unwrap();
+        assert!(resolved.iter().all(|r| r.publishers.len() == 1));
+        drop(server)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn restart_same_port() {
+        use crate::resolver_server::config::file;
+        let _ = env_logger::try_init();
+        let mut server_cfg: file::Config = serde_json::from_str(
+            &std::fs::read_to_string("../cfg/simple-server.json")
+                .expect("read simple server config"),
+        )
+        .expect("parse simple server config");
+        server_cfg.member_servers[0].listen_backlog = 0;
+        assert!(ServerConfig::from_file(server_cfg.clone()).is_err());
+        server_cfg.member_servers[0].listen_backlog = 16;
+        server_cfg.member_servers[0].addr = "127.0.0.1:0".parse().unwrap();
+        let server =
+            Server::new(ServerConfig::from_file(server_cfg.clone()).unwrap(), false, 0)
+                .await
+                .expect("start server");
+        let addr = *server.local_addr();
+        let mut client_cfg = ClientConfig::load("../cfg/simple-client.json")
+            .expect("load simple client config");
+        client_cfg.addrs[0].0 = addr;
+        let r = ResolverRead::new(client_cfg.clone(), DesiredAuth::Anonymous);
+        r.resolve([p("/a")]).await.unwrap();
+        // the server closes the connection first, leaving it in TIME_WAIT
+        drop(server);
+        time::sleep(Duration::from_millis(100)).await;
+        server_cfg.member_servers[0].addr = addr;
+        let server = Server::new(ServerConfig::from_file(server_cfg).unwrap(), false, 0)
+            .await
+            .expect("restart server");
+        let r = ResolverRead::new(client_cfg, DesiredAuth::Anonymous);
+        r.resolve([p("/a")]).await.unwrap();
+        drop(server)
+    }
+
+    struct Ctx {
+        _local: Server,
+        _root: (Server, Server),
+        _huge0: (Server, Server),
+        _huge1: (Server, Server),
+        _huge1_sub: (Server, Server),
+        cfg_local: ClientConfig,
+        cfg_root: ClientConfig,
+        cfg_huge0: ClientConfig,
+        cfg_huge1: ClientConfig,
+        cfg_huge1_sub: ClientConfig,
+    }
+
+    impl Ctx {
+        fn random_server(&self) -> ClientConfig {
+            match rng().random_range(0. ..=1.) {
+                n if n >= 0. && n <= 0.20 => self.cfg_root.clone(),
+                n if n > 0.20 && n <= 0.40 => self.cfg_huge0.clone(),
+                n if n > 0.40 && n <= 0.60 => self.cfg_huge1.clone(),
+                n if n > 0.60 && n <= 0.80 => self.cfg_huge1_sub.clone(),
+                _ => self.cfg_local.clone(),
+            }
+        }
+
+        async fn new() -> Ctx {
+            let server_cfg_local = ServerConfig::load("../cfg/complex-local-server.json")
+                .expect("local server config");
+            let cfg_local = ClientConfig::load("../cfg/complex-local-client.json")
+                .expect("local client config");
+            let server_cfg_root = ServerConfig::load("../cfg/complex-root-server.json")
+                .expect("root server config");
+            let cfg_root = ClientConfig::load("../cfg/complex-root-client.json")
+                .expect("root client config");
+            let server_cfg_huge0 = ServerConfig::load("../cfg/complex-huge0-server.json")
+                .expect("huge0 server config");
+            let cfg_huge0 = ClientConfig::load("../cfg/complex-huge0-client.json")
+                .expect("huge0 client config");
+            let server_cfg_huge1 = ServerConfig::load("../cfg/complex-huge1-server.json")
+                .expect("huge1 server config");
+            let cfg_huge1 = ClientConfig::load("../cfg/complex-huge1-client.json")
+                .expect("huge1 client config");
+            let server_cfg_huge1_sub =
+                ServerConfig::load("../cfg/complex-huge1-sub-server.json")
+                    .expect("huge1 sub server config");
+            let cfg_huge1_sub =
+                ClientConfig::load("../cfg/complex-huge1-sub-client.json")
+                    .expect("huge1 sub client config");
+            let server_local =
+                Server::new(server_cfg_local, false, 0).await.expect("local server");
+            let server0_root = Server::new(server_cfg_root.clone(), false, 0)
+                .await
+                .expect("root server 0");
+            let server1_root =
+                Server::new(server_cfg_root, false, 1).await.expect("root server 1");
+            let server0_huge0 = Server::new(server_cfg_huge0.clone(), false, 0)
+                .await
+                .expect("huge0 server0");
+            let server1_huge0 =
+                Server::new(server_cfg_huge0, false, 1).await.expect("huge0 server1");
+            let server0_huge1 = Server::new(server_cfg_huge1.clone(), false, 0)
+                .await
+                .expect("huge1 server0");
+            let server1_huge1 =
+                Server::new(server_cfg_huge1, false, 1).await.expect("huge1 server0");
+            let server0_huge1_sub = Server::new(server_cfg_huge1_sub.clone(), false, 0)
+                .await
+                .expect("huge1 sub server0");
+            let server1_huge1_sub = Server::new(server_cfg_huge1_sub, false, 1)
+                .await
+                .expect("huge1 sub server0");
+            Ctx {
+                _local: server_local,
+                _root: (server0_root, server1_root),
+                _huge0: (server0_huge0, server1_huge0),
+                _huge1: (server0_huge1, server1_huge1),
+                _huge1_sub: (server0_huge1_sub, server1_huge1_sub),
+                cfg_local,
+                cfg_root,
+                cfg_huge0,
+                cfg_huge1,
+                cfg_huge1_sub,
+            }
+        }
+    }
+
+    async fn check_list(local: bool, r: &ResolverRead) {
+        let mut l = r.list(p("/")).await.unwrap();
+        l.sort();
+        if local {
+            assert_eq!(&*l, &[p("/app"), p("/local"), p("/tmp")]);
+        } else {
+            assert_eq!(&*l, &[p("/app"), p("/tmp")]);
+        }
+        if local {
+            let mut l = r.list(p("/local")).await.unwrap();
+            l.sort();
+            assert_eq!(&*l, &[p("/local/bar"), p("/local/foo")])
+        }
         let mut l = r.list(p("/tmp")).await.unwrap();
         l.sort();
         assert_eq!(&*l, &[p("/tmp/x"), p("/tmp/y"), p("/tmp/z")]);
@@ -259,9 +1241,9 @@ mod resolver {
         let (publishers, mut answer) = r.resolve(paths.iter().cloned()).await.unwrap();
         let mut i = 0;
         for (p, r) in paths.iter().zip(answer.drain(..)) {
-            let mut r_addrs =
+            // the resolver returns publishers sorted by address
+            let r_addrs =
                 r.publishers.iter().map(|pr| publishers[&pr.id].addr).collect::<Vec<_>>();
-            r_addrs.sort();
             assert_eq!(r_addrs.len(), addrs.len());
             assert_eq!(r_addrs, addrs);
             assert!(publishers.values().all(|p| p.target_auth == TargetAuth::Anonymous));
@@ -395,19 +1377,40 @@ mod resolver {
         check_resolve(&ctx, &r_huge1, &paths, &[waddrs[1]][..]).await;
         check_list(false, &r_huge0).await;
         check_resolve(&ctx, &r_huge0, &paths, &[waddrs[1]][..]).await;
+        // a new client has to follow every referral
+        let r = ResolverRead::new(ctx.cfg_root.clone(), DesiredAuth::Anonymous);
+        let (_, resolved, trace) =
+            r.resolve_traced([p("/tmp/x"), p("/app/huge1/sub/x")]).await.unwrap();
+        assert_eq!(resolved.len(), 2);
+        let hops = trace
+            .iter()
+            .map(|t| t.iter().map(|r| r.path.clone()).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(hops[0], [p("/")]);
+        assert_eq!(hops[1], [p("/"), p("/app/huge1"), p("/app/huge1/sub")]);
+        // the referrals are cached now
+        let (_, _, trace) = r.resolve_traced([p("/app/huge1/sub/y")]).await.unwrap();
+        assert_eq!(trace[0].len(), 1);
+        assert_eq!(trace[0][0].path, p("/app/huge1/sub"));
     }
 }
 
 mod publisher {
+    use super::{anonymous_writer, local_resolver, simple_resolver};
     use crate::{
         config::Config as ClientConfig,
+        protocol::publisher::From as PFrom,
         publisher::{
             BindCfg, DesiredAuth, Event as PEvent, PublishFlags, Publisher,
             PublisherBuilder, Val,
         },
         resolver_client::ResolverRead,
         resolver_server::{config::Config as ServerConfig, Server},
-        subscriber::{Event, SubId, Subscriber, SubscriberBuilder, UpdatesFlags, Value},
+        subscriber::{
+            Cancelled, Dval, Event, PermissionDenied, PublisherSelection, Selector,
+            SubId, SubscribePriority, Subscriber, SubscriberBuilder, Throughput,
+            UpdatesFlags, Value, WriteTimedOut, MAX_BACKGROUND_DELAY,
+        },
     };
     use anyhow::Result;
     use arcstr::literal;
@@ -418,12 +1421,14 @@ mod publisher {
     use parking_lot::Mutex;
     use poolshark::global::GPooled;
     use std::{
+        collections::{HashMap, HashSet},
         iter,
         net::{IpAddr, SocketAddr},
         sync::Arc,
         time::Duration,
     };
     use tokio::{
+        net::{TcpListener, TcpStream},
         task::{self, JoinHandle},
         time::{self, Instant},
     };
@@ -564,143 +1569,1841 @@ mod publisher {
                     }
                 }
             }
-            if c == 100 {
-                break;
+            if c == 100 {
+                break;
+            }
+        }
+        if !*default_destroyed.lock() {
+            panic!("default publisher value was not destroyed on idle")
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn publish_subscribe() {
+        let _ = env_logger::try_init();
+        let (server, client_cfg) = simple_resolver().await.expect("start resolver");
+        let default_destroyed = Arc::new(Mutex::new(false));
+        let (tx, ready) = oneshot::channel();
+        task::spawn(run_publisher(
+            client_cfg.clone(),
+            default_destroyed.clone(),
+            tx,
+            DesiredAuth::Anonymous,
+        ));
+        time::timeout(Duration::from_secs(1), ready).await.unwrap().unwrap();
+        run_subscriber(client_cfg, default_destroyed, DesiredAuth::Anonymous).await;
+        drop(server);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn tls_publish_subscribe() {
+        let _ = env_logger::try_init();
+        #[cfg(unix)]
+        let server_cfg = ServerConfig::load("../cfg/tls/resolver/resolver.json")
+            .expect("load tls server config");
+        #[cfg(windows)]
+        let server_cfg = ServerConfig::load("../cfg/tls/resolver/resolver-win.json")
+            .expect("load tls server config");
+        let mut pub_cfg = ClientConfig::load("../cfg/tls/publisher/client.json")
+            .expect("failed to load tls publisher config");
+        let mut sub_cfg = ClientConfig::load("../cfg/tls/client/client.json")
+            .expect("failed to load subscriber cfg");
+        let default_destroyed = Arc::new(Mutex::new(false));
+        let (tx, ready) = oneshot::channel();
+        let server = Server::new(server_cfg, false, 0).await.expect("start server");
+        pub_cfg.addrs[0].0 = *server.local_addr();
+        sub_cfg.addrs[0].0 = *server.local_addr();
+        task::spawn(run_publisher(
+            pub_cfg.clone(),
+            default_destroyed.clone(),
+            tx,
+            DesiredAuth::Tls { identity: None },
+        ));
+        time::timeout(Duration::from_secs(1), ready).await.unwrap().unwrap();
+        run_subscriber(pub_cfg, default_destroyed, DesiredAuth::Tls { identity: None })
+            .await;
+        drop(server)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn slow_consumer() -> Result<()> {
+        let _ = env_logger::try_init();
+        let resolver = {
+            use crate::resolver_server::config::{self, file};
+            let cfg = file::ConfigBuilder::default()
+                .member_servers(vec![file::MemberServerBuilder::default()
+                    .auth(file::Auth::Anonymous)
+                    .addr("127.0.0.1:0".parse()?)
+                    .bind_addr("127.0.0.1".parse()?)
+                    .build()?])
+                .build()?;
+            let cfg = config::Config::from_file(cfg)?;
+            crate::resolver_server::Server::new(cfg, false, 0).await?
+        };
+        let addr = *resolver.local_addr();
+        let cfg = {
+            use crate::config::{self, file, DefaultAuthMech};
+            let cfg = file::ConfigBuilder::default()
+                .addrs(vec![(addr, file::Auth::Anonymous)])
+                .default_auth(DefaultAuthMech::Anonymous)
+                .default_bind_config("local")
+                .build()?;
+            config::Config::from_file(cfg)?
+        };
+        let timeout = Duration::from_secs(10);
+        let _cfg = cfg.clone();
+        let pb: JoinHandle<Result<()>> = task::spawn(async move {
+            let publisher = PublisherBuilder::new(_cfg).slack(3).build().await?;
+            let v = publisher.publish(Path::from("/local/foo"), Value::from(42))?;
+            loop {
+                let mut batch = publisher.start_batch();
+                for i in 0..1000 {
+                    v.update(&mut batch, Value::from(i));
+                }
+                batch.commit(Some(timeout)).await;
+                time::sleep(Duration::from_millis(10)).await;
+                print!(".");
+            }
+        });
+        let _cfg = cfg.clone();
+        let slow_sub: JoinHandle<Result<()>> = task::spawn(async move {
+            use futures::channel::mpsc;
+            let subscriber = SubscriberBuilder::new(_cfg).build()?;
+            let s = subscriber.subscribe(Path::from("/local/foo"));
+            let (tx, rx) = mpsc::channel(3);
+            s.updates(UpdatesFlags::empty(), tx);
+            future::pending::<()>().await;
+            drop(rx);
+            Ok(())
+        });
+        let start = Instant::now();
+        let mut last_update = Instant::now();
+        let subscriber = SubscriberBuilder::new(cfg).build()?;
+        let s = subscriber.subscribe(Path::from("/local/foo"));
+        let mut hb = time::interval(Duration::from_secs(1));
+        let (tx, mut rx) = mpsc::channel(3);
+        s.updates(UpdatesFlags::empty(), tx);
+        loop {
+            tokio::select! {
+                _ = hb.tick() => {
+                    if dbg!(last_update.elapsed()) > timeout + Duration::from_secs(1) {
+                        bail!("updates stopped for longer than timeout!")
+                    }
+                    if start.elapsed() > timeout * 5 {
+                        break
+                    }
+                },
+                _ = rx.select_next_some() => {
+                    last_update = Instant::now();
+                    print!("-");
+                }
+            }
+        }
+        slow_sub.abort();
+        pb.abort();
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn durable_subscribe_ready() -> Result<()> {
+        let _ = env_logger::try_init();
+        let (_resolver, cfg) = local_resolver().await?;
+        let publisher = PublisherBuilder::new(cfg.clone()).build().await?;
+        let _v = publisher.publish(Path::from("/local/foo"), Value::from(42))?;
+        publisher.flushed().await;
+        let subscriber = SubscriberBuilder::new(cfg).build()?;
+        let (dv, ready) = subscriber.durable_subscribe_ready(Path::from("/local/foo"));
+        time::timeout(Duration::from_secs(10), ready).await??;
+        assert_eq!(dv.last(), Event::Update(Value::from(42)));
+        // already subscribed, so this should be ready immediately
+        let (_, ready) = subscriber.durable_subscribe_ready(Path::from("/local/foo"));
+        time::timeout(Duration::from_secs(10), ready).await??;
+        let (dv, ready) = subscriber.durable_subscribe_ready(Path::from("/local/bar"));
+        assert!(time::timeout(Duration::from_secs(10), ready).await?.is_err());
+        assert_eq!(dv.last(), Event::Unsubscribed);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn duplicate_in_batch() -> Result<()> {
+        let _ = env_logger::try_init();
+        let (_resolver, cfg) = local_resolver().await?;
+        let publisher = PublisherBuilder::new(cfg.clone()).build().await?;
+        let v = publisher.publish(Path::from("/local/dup"), Value::from(42))?;
+        publisher.flushed().await;
+        let subscriber = SubscriberBuilder::new(cfg).build()?;
+        let batch = [Path::from("/local/dup"), Path::from("/local/dup")];
+        let res = subscriber
+            .subscribe_nondurable(batch.into_iter(), Some(Duration::from_secs(10)))
+            .await
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(res.len(), 2);
+        let vals = res.into_iter().map(|(_, r)| r).collect::<Result<Vec<_>>>()?;
+        assert_eq!(vals[0].id(), vals[1].id());
+        assert_eq!(vals[1].last(), Event::Update(Value::from(42)));
+        assert_eq!(publisher.subscribed_len(&v.id()), 1);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn get_once() -> Result<()> {
+        let _ = env_logger::try_init();
+        let (_resolver, cfg) = local_resolver().await?;
+        let publisher = PublisherBuilder::new(cfg.clone()).build().await?;
+        let _v = publisher.publish(Path::from("/local/once"), Value::from(42))?;
+        publisher.flushed().await;
+        let subscriber = SubscriberBuilder::new(cfg).build()?;
+        let timeout = Some(Duration::from_secs(10));
+        let v = subscriber.get_once(Path::from("/local/once"), timeout).await?;
+        assert_eq!(v, Value::from(42));
+        // the connection is closed without waiting for the idle check
+        let start = Instant::now();
+        while publisher.clients() > 0 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            time::sleep(Duration::from_millis(10)).await
+        }
+        assert!(subscriber
+            .get_once(Path::from("/local/nothing"), timeout)
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn snapshot() -> Result<()> {
+        let _ = env_logger::try_init();
+        let (_resolver, cfg) = local_resolver().await?;
+        let publisher = PublisherBuilder::new(cfg.clone()).build().await?;
+        let _a = publisher.publish(Path::from("/local/snap/a"), Value::from(1))?;
+        let _b = publisher.publish(Path::from("/local/snap/b"), Value::from(2))?;
+        publisher.flushed().await;
+        let subscriber = SubscriberBuilder::new(cfg).build()?;
+        let timeout = Some(Duration::from_secs(10));
+        let paths = ["/local/snap/a", "/local/snap/b"].map(Path::from);
+        let snap = subscriber.snapshot(paths.clone(), timeout).await?;
+        let expected = HashMap::from([
+            (paths[0].clone(), Value::from(1)),
+            (paths[1].clone(), Value::from(2)),
+        ]);
+        assert_eq!(snap, expected);
+        let start = Instant::now();
+        while publisher.clients() > 0 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            time::sleep(Duration::from_millis(10)).await
+        }
+        let paths = ["/local/snap/a", "/local/snap/nothing"].map(Path::from);
+        assert!(subscriber.snapshot(paths, timeout).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn drop_connection() -> Result<()> {
+        let _ = env_logger::try_init();
+        let (_resolver, cfg) = local_resolver().await?;
+        let publisher = PublisherBuilder::new(cfg.clone()).build().await?;
+        let _v = publisher.publish(Path::from("/local/drop"), Value::from(42))?;
+        publisher.flushed().await;
+        let subscriber = SubscriberBuilder::new(cfg).build()?;
+        assert!(!subscriber.drop_connection(publisher.addr()));
+        let dv = subscriber.subscribe(Path::from("/local/drop"));
+        time::timeout(Duration::from_secs(10), dv.wait_subscribed()).await??;
+        let (tx, mut rx) = mpsc::channel(10);
+        dv.updates(UpdatesFlags::empty(), tx);
+        subscriber.flush().await;
+        assert!(subscriber.drop_connection(publisher.addr()));
+        let mut events = vec![];
+        while events.len() < 2 {
+            let mut batch =
+                time::timeout(Duration::from_secs(10), rx.next()).await?.unwrap();
+            events.extend(batch.drain(..).map(|(_, ev)| ev));
+        }
+        assert_eq!(events, vec![Event::Unsubscribed, Event::Update(Value::from(42))]);
+        assert_eq!(dv.last(), Event::Update(Value::from(42)));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn batched_unsubscribe() -> Result<()> {
+        use crate::protocol::publisher::PROTOCOL_VERSION;
+        let _ = env_logger::try_init();
+        let (_resolver, cfg) = local_resolver().await?;
+        let publisher = PublisherBuilder::new(cfg.clone()).build().await?;
+        let vals = (0..100)
+            .map(|i| publisher.publish(Path::from(format!("/local/many/{i}")), i))
+            .collect::<Result<Vec<_>>>()?;
+        publisher.flushed().await;
+        let subscriber = SubscriberBuilder::new(cfg).build()?;
+        let paths = (0..100).map(|i| Path::from(format!("/local/many/{i}")));
+        let timeout = Some(Duration::from_secs(10));
+        let mut subs = vec![];
+        for (_, r) in subscriber
+            .subscribe_nondurable(paths, timeout)
+            .await
+            .collect::<Vec<_>>()
+            .await
+        {
+            subs.push(r?)
+        }
+        // keep one subscription so the connection stays up
+        let keep = subs.pop().unwrap();
+        assert!(vals.iter().all(|v| publisher.subscribed_len(&v.id()) == 1));
+        drop(subs);
+        let start = Instant::now();
+        while vals[..99].iter().any(|v| publisher.subscribed_len(&v.id()) > 0) {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            time::sleep(Duration::from_millis(10)).await
+        }
+        assert_eq!(publisher.subscribed_len(&vals[99].id()), 1);
+        assert_eq!(keep.last(), Event::Update(Value::from(99)));
+        // on the wire, a publisher that advertises version 4 gets
+        // UnsubscribeMany, one that doesn't advertise a version only
+        // gets Unsubscribe
+        for version in [PROTOCOL_VERSION, 0] {
+            let (many, single) = unsubscribes_on_wire(&cfg, version).await?;
+            assert_eq!(many + single, 99);
+            if version == 0 {
+                assert_eq!(many, 0)
+            } else {
+                assert!(many > 0, "{many} {single}")
+            }
+        }
+        Ok(())
+    }
+
+    // subscribe to 100 paths on a fake publisher advertising
+    // `version`, drop 99 of them, and return how many were
+    // unsubscribed by UnsubscribeMany and by Unsubscribe messages
+    async fn unsubscribes_on_wire(
+        cfg: &ClientConfig,
+        version: u64,
+    ) -> Result<(usize, usize)> {
+        use crate::{
+            channel::{self, Channel},
+            protocol::publisher::{Hello, Id, To, WIRE_VERSION},
+        };
+        use cross_krb5::ServerCtx;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let paddr = listener.local_addr()?;
+        let (tx, mut rx) = mpsc::unbounded();
+        task::spawn(async move {
+            let (mut s, _) = listener.accept().await?;
+            let _: u64 = channel::read_raw::<_, _, 1024>(&mut s).await?;
+            channel::write_raw(&mut s, &WIRE_VERSION).await?;
+            let _: Hello = channel::read_raw::<_, _, 1024>(&mut s).await?;
+            channel::write_raw(&mut s, &Hello::Anonymous(version)).await?;
+            let mut con = Channel::new::<ServerCtx, TcpStream>(None, s);
+            while let Ok(m) = con.receive::<To>().await {
+                match m {
+                    To::Subscribe { path, .. } => {
+                        let m = PFrom::Subscribed(path, Id::new(), Value::Null, None);
+                        con.send_one(&m).await?
+                    }
+                    m => tx.unbounded_send(m)?,
+                }
+            }
+            Ok::<_, anyhow::Error>(())
+        });
+        let paths = (0..100).map(|i| Path::from(format!("/local/wire{version}/{i}")));
+        let w = anonymous_writer(&cfg, paddr)?;
+        w.publish(paths.clone()).await?;
+        let subscriber = SubscriberBuilder::new(cfg.clone()).build()?;
+        let mut subs = vec![];
+        for (_, r) in subscriber
+            .subscribe_nondurable(paths, Some(Duration::from_secs(10)))
+            .await
+            .collect::<Vec<_>>()
+            .await
+        {
+            subs.push(r?)
+        }
+        let _keep = subs.pop().unwrap();
+        drop(subs);
+        let (mut many, mut single) = (0, 0);
+        while many + single < 99 {
+            match time::timeout(Duration::from_secs(10), rx.next()).await? {
+                Some(To::UnsubscribeMany(ids)) => many += ids.len(),
+                Some(To::Unsubscribe(_)) => single += 1,
+                m => bail!("unexpected {m:?}"),
+            }
+        }
+        Ok((many, single))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn protocol_version() -> Result<()> {
+        use crate::{
+            channel::{self, Channel},
+            protocol::publisher::{Hello, To, PROTOCOL_VERSION, WIRE_VERSION},
+        };
+        use cross_krb5::ClientCtx;
+        let _ = env_logger::try_init();
+        let (_resolver, cfg) = local_resolver().await?;
+        let publisher = PublisherBuilder::new(cfg.clone()).build().await?;
+        let v = publisher.publish(Path::from("/local/version"), 42)?;
+        publisher.flushed().await;
+        let subscriber = SubscriberBuilder::new(cfg).build()?;
+        let val = subscriber
+            .subscribe_nondurable_one(Path::from("/local/version"), None)
+            .await?;
+        assert_eq!(val.protocol_version(), PROTOCOL_VERSION);
+        let clients = publisher.subscribed(&v.id());
+        assert_eq!(clients.len(), 1);
+        assert_eq!(publisher.protocol_version(&clients[0]), Some(PROTOCOL_VERSION));
+        // a subscriber that doesn't advertise a version speaks
+        // version 3, and may not use the messages added after it
+        let mut con = TcpStream::connect(publisher.addr()).await?;
+        channel::write_raw(&mut con, &WIRE_VERSION).await?;
+        assert_eq!(channel::read_raw::<u64, _, 1024>(&mut con).await?, WIRE_VERSION);
+        channel::write_raw(&mut con, &Hello::Anonymous(0)).await?;
+        let hello: Hello = channel::read_raw::<_, _, 1024>(&mut con).await?;
+        assert_eq!(hello.version(), PROTOCOL_VERSION);
+        let mut con = Channel::new::<ClientCtx, TcpStream>(None, con);
+        con.send_one(&To::Subscribe {
+            path: Path::from("/local/version"),
+            resolver: publisher.addr(),
+            timestamp: 0,
+            permissions: 0,
+            token: bytes::Bytes::new(),
+        })
+        .await?;
+        let id = match con.receive::<PFrom>().await? {
+            PFrom::Subscribed(_, id, v, _) => {
+                assert_eq!(v, Value::from(42));
+                id
+            }
+            m => bail!("unexpected {m:?}"),
+        };
+        let clients = publisher.subscribed(&v.id());
+        assert_eq!(clients.len(), 2);
+        let versions = clients
+            .iter()
+            .filter_map(|c| publisher.protocol_version(c))
+            .collect::<Vec<_>>();
+        assert!(versions.contains(&3) && versions.contains(&PROTOCOL_VERSION));
+        con.send_one(&To::UnsubscribeMany(GPooled::orphan(vec![id]))).await?;
+        let start = Instant::now();
+        while publisher.subscribed_len(&v.id()) > 1 || publisher.clients() > 1 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            time::sleep(Duration::from_millis(10)).await
+        }
+        let clients = publisher.subscribed(&v.id());
+        assert_eq!(publisher.protocol_version(&clients[0]), Some(PROTOCOL_VERSION));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn throughput() -> Result<()> {
+        let _ = env_logger::try_init();
+        let (_resolver, cfg) = local_resolver().await?;
+        let publisher = PublisherBuilder::new(cfg.clone()).build().await?;
+        let v = publisher.publish(Path::from("/local/rate"), 0u64)?;
+        publisher.flushed().await;
+        let subscriber = SubscriberBuilder::new(cfg).build()?;
+        assert_eq!(subscriber.throughput(), Throughput::default());
+        let dv = subscriber.subscribe(Path::from("/local/rate"));
+        let (tx, mut rx) = mpsc::channel(10);
+        dv.updates(UpdatesFlags::empty(), tx);
+        time::timeout(Duration::from_secs(10), dv.wait_subscribed()).await??;
+        for i in 1..=100u64 {
+            let mut batch = publisher.start_batch();
+            v.update(&mut batch, i);
+            batch.commit(None).await;
+        }
+        let mut n = 0;
+        while n < 100 {
+            let batch = time::timeout(Duration::from_secs(10), rx.next()).await?;
+            n += batch.unwrap().len();
+        }
+        // the connection only samples every 100s, so this read takes
+        // the one sample that contains every update
+        time::sleep(Duration::from_millis(1100)).await;
+        let t = subscriber.throughput();
+        assert!(t.values.avg_1s > 0.);
+        assert!(t.values.avg_60s > 0.);
+        assert!(t.values.avg_1s > t.values.avg_10s);
+        assert!(t.values.avg_10s > t.values.avg_60s);
+        assert!(t.bytes.avg_1s > t.values.avg_1s);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn updates_sequenced() -> Result<()> {
+        let _ = env_logger::try_init();
+        let (_resolver, cfg) = local_resolver().await?;
+        let path = Path::from("/local/seq");
+        let publisher = PublisherBuilder::new(cfg.clone()).build().await?;
+        let v = publisher.publish(path.clone(), 0u64)?;
+        publisher.flushed().await;
+        let subscriber = SubscriberBuilder::new(cfg.clone()).build()?;
+        let dv = subscriber.subscribe(path.clone());
+        time::timeout(Duration::from_secs(10), dv.wait_subscribed()).await??;
+        let (tx, mut rx) = mpsc::channel(10);
+        dv.updates_sequenced(UpdatesFlags::BEGIN_WITH_LAST, tx);
+        subscriber.flush().await;
+        for i in 1..=3u64 {
+            let mut batch = publisher.start_batch();
+            v.update(&mut batch, i);
+            batch.commit(None).await;
+        }
+        drop(v);
+        drop(publisher);
+        let publisher = PublisherBuilder::new(cfg).build().await?;
+        let _v = publisher.publish(path, 42u64)?;
+        publisher.flushed().await;
+        let mut events: Vec<(u64, Event)> = vec![];
+        while events.last().map(|(_, e)| e) != Some(&Event::Update(Value::from(42u64))) {
+            let mut batch =
+                time::timeout(Duration::from_secs(30), rx.next()).await?.unwrap();
+            events.extend(batch.drain(..).map(|(_, seq, e)| (seq, e)));
+        }
+        let expected = vec![
+            (0, Event::Update(Value::from(0u64))),
+            (1, Event::Update(Value::from(1u64))),
+            (2, Event::Update(Value::from(2u64))),
+            (3, Event::Update(Value::from(3u64))),
+            (4, Event::Unsubscribed),
+            // the sequence starts over with the new subscription
+            (0, Event::Update(Value::from(42u64))),
+        ];
+        assert_eq!(events, expected);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn updates_batched() -> Result<()> {
+        let _ = env_logger::try_init();
+        let (_resolver, cfg) = local_resolver().await?;
+        let publisher = PublisherBuilder::new(cfg.clone()).build().await?;
+        let va = publisher.publish(Path::from("/local/batched/a"), 0u64)?;
+        let vb = publisher.publish(Path::from("/local/batched/b"), 0u64)?;
+        publisher.flushed().await;
+        let subscriber = SubscriberBuilder::new(cfg).build()?;
+        let da = subscriber.subscribe(Path::from("/local/batched/a"));
+        let db = subscriber.subscribe(Path::from("/local/batched/b"));
+        for dv in [&da, &db] {
+            time::timeout(Duration::from_secs(10), dv.wait_subscribed()).await??;
+        }
+        let (ida, idb) = (da.id(), db.id());
+        let mut updates =
+            Box::pin(Dval::updates_batched([da, db], UpdatesFlags::empty()));
+        subscriber.flush().await;
+        for i in 1..=3u64 {
+            let mut batch = publisher.start_batch();
+            va.update(&mut batch, i);
+            vb.update(&mut batch, i * 10);
+            batch.commit(None).await;
+        }
+        for i in 1..=3u64 {
+            let mut batch =
+                time::timeout(Duration::from_secs(10), updates.next()).await?.unwrap();
+            batch.sort_by_key(|(id, _)| *id == idb);
+            let expected = vec![(ida, Value::from(i)), (idb, Value::from(i * 10))];
+            assert_eq!(batch, expected);
+        }
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn health() -> Result<()> {
+        let _ = env_logger::try_init();
+        let (_resolver, cfg) = local_resolver().await?;
+        let publisher = PublisherBuilder::new(cfg.clone()).build().await?;
+        let _v = publisher.publish(Path::from("/local/up"), Value::from(42))?;
+        publisher.flushed().await;
+        let subscriber = SubscriberBuilder::new(cfg).build()?;
+        let h = subscriber.health();
+        assert!(h.resolver_last_success.is_none());
+        assert_eq!(h.connections_up, 0);
+        assert_eq!(h.connections_down, 0);
+        let up = subscriber.subscribe(Path::from("/local/up"));
+        time::timeout(Duration::from_secs(10), up.wait_subscribed()).await??;
+        let (_down, ready) =
+            subscriber.durable_subscribe_ready(Path::from("/local/down"));
+        assert!(time::timeout(Duration::from_secs(10), ready).await?.is_err());
+        let h = subscriber.health();
+        assert!(h.resolver_last_success.unwrap() < Duration::from_secs(10));
+        assert_eq!(h.connections_up, 1);
+        assert_eq!(h.connections_down, 0);
+        assert_eq!(h.durable.alive, 1);
+        assert_eq!(h.durable.dead, 1);
+        let json = serde_json::to_value(&h)?;
+        assert_eq!(json["connections_up"], 1);
+        assert_eq!(json["durable"]["dead"], 1);
+        Ok(())
+    }
+
+    #[cfg(feature = "blocking")]
+    #[test]
+    fn blocking_subscriber() -> Result<()> {
+        let _ = env_logger::try_init();
+        let rt = tokio::runtime::Runtime::new()?;
+        let path = Path::from("/local/blocking");
+        let (resolver, publisher, v, subscriber) = rt.block_on(async {
+            let (resolver, cfg) = local_resolver().await?;
+            let publisher = PublisherBuilder::new(cfg.clone()).build().await?;
+            let v = publisher.publish(path.clone(), Value::from(42))?;
+            publisher.flushed().await;
+            let subscriber = SubscriberBuilder::new(cfg).build()?;
+            Ok::<_, anyhow::Error>((resolver, publisher, v, subscriber))
+        })?;
+        let bs = subscriber.blocking(rt.handle().clone());
+        let timeout = Duration::from_secs(10);
+        assert_eq!(
+            bs.subscribe_val_blocking(path.clone(), Some(timeout))?,
+            Value::from(42)
+        );
+        let dv = rt.block_on(async { subscriber.subscribe(path.clone()) });
+        assert_eq!(bs.last_blocking(&dv, timeout)?, Value::from(42));
+        // calling from inside the runtime is an error, not a panic
+        let r = rt.block_on(async { bs.subscribe_val_blocking(path, Some(timeout)) });
+        assert!(r.is_err());
+        // dropping these spawns tasks, so it must happen in the runtime
+        rt.block_on(async move { drop((dv, bs, subscriber, v, publisher, resolver)) });
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn metadata() -> Result<()> {
+        let _ = env_logger::try_init();
+        let (_resolver, cfg) = local_resolver().await?;
+        let publisher = PublisherBuilder::new(cfg.clone()).build().await?;
+        let v = publisher.publish(Path::from("/local/temp"), Value::from(21.5))?;
+        let _plain = publisher.publish(Path::from("/local/plain"), Value::from(1))?;
+        let meta = Arc::new(HashMap::from_iter([
+            (literal!("units"), Value::from("celsius")),
+            (literal!("description"), Value::from("room temperature")),
+        ]));
+        publisher.set_metadata(v.id(), Some(meta.clone()));
+        assert_eq!(publisher.metadata(&v.id()), Some(meta.clone()));
+        publisher.flushed().await;
+        let subscriber = SubscriberBuilder::new(cfg).build()?;
+        let (dv, ready) = subscriber.durable_subscribe_ready(Path::from("/local/temp"));
+        time::timeout(Duration::from_secs(10), ready).await??;
+        assert_eq!(dv.metadata(), Some(meta.clone()));
+        let val =
+            subscriber.subscribe_nondurable_one(Path::from("/local/temp"), None).await?;
+        assert_eq!(val.metadata(), Some(meta));
+        let val =
+            subscriber.subscribe_nondurable_one(Path::from("/local/plain"), None).await?;
+        assert_eq!(val.metadata(), None);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn subscriber_list() -> Result<()> {
+        let _ = env_logger::try_init();
+        let (_resolver, cfg) = local_resolver().await?;
+        let publisher = PublisherBuilder::new(cfg.clone()).build().await?;
+        let _vals = [
+            "/local/list/a",
+            "/local/list/b/c",
+            "/local/list/b/d/e",
+            "/local/list*/f",
+            "/local/listing/g",
+        ]
+        .into_iter()
+        .map(|p| publisher.publish(Path::from(p), Value::from(0)))
+        .collect::<Result<Vec<_>>>()?;
+        publisher.flushed().await;
+        let subscriber = SubscriberBuilder::new(cfg).build()?;
+        let children = subscriber.list(Path::from("/local/list")).await?;
+        assert_eq!(
+            children,
+            vec![Path::from("/local/list/a"), Path::from("/local/list/b")]
+        );
+        let all = subscriber
+            .list_recursive(Path::from("/local/list"))
+            .await?
+            .collect::<Vec<_>>()
+            .await;
+        let expected = ["a", "b", "b/c", "b/d", "b/d/e"]
+            .into_iter()
+            .map(|p| Path::from(format!("/local/list/{p}")))
+            .collect::<Vec<_>>();
+        assert_eq!(all, expected);
+        let star = subscriber
+            .list_recursive(Path::from("/local/list*"))
+            .await?
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(star, vec![Path::from("/local/list*/f")]);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn denied_fallback() -> Result<()> {
+        let _ = env_logger::try_init();
+        let (_resolver, cfg) = local_resolver().await?;
+        let path = Path::from("/local/replicated");
+        let mut publishers = vec![];
+        for i in 0..2 {
+            let publisher = PublisherBuilder::new(cfg.clone()).build().await?;
+            let v = publisher.publish(path.clone(), Value::from(i))?;
+            publisher.flushed().await;
+            publishers.push((publisher, v));
+        }
+        publishers[0].0.set_extended_authorization(Box::new(|_, _, _| false));
+        // whichever replica is chosen first, we should end up on the
+        // one that allows us
+        for _ in 0..8 {
+            let subscriber = SubscriberBuilder::new(cfg.clone()).build()?;
+            let v = subscriber.subscribe_nondurable_one(path.clone(), None).await?;
+            assert_eq!(v.last(), Event::Update(Value::from(1)));
+        }
+        publishers[1].0.set_extended_authorization(Box::new(|_, _, _| false));
+        let subscriber = SubscriberBuilder::new(cfg).build()?;
+        let e = subscriber.subscribe_nondurable_one(path, None).await.unwrap_err();
+        assert!(e.is::<PermissionDenied>());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn resolver_stats() -> Result<()> {
+        let _ = env_logger::try_init();
+        let (mut resolver, cfg) = local_resolver().await?;
+        let stats = PublisherBuilder::new(cfg.clone()).build().await?;
+        resolver.publish_stats(stats, Path::from("/stats"));
+        let publisher = PublisherBuilder::new(cfg.clone()).build().await?;
+        let vals = (0..10u64)
+            .map(|i| publisher.publish(Path::from(format!("/app/{i}")), Value::from(i)))
+            .collect::<Result<Vec<_>>>()?;
+        publisher.flushed().await;
+        let subscriber = SubscriberBuilder::new(cfg).build()?;
+        let app = subscriber.subscribe(Path::from("/stats/subtrees/app/paths"));
+        let pubs = subscriber.subscribe(Path::from("/stats/subtrees/app/publishers"));
+        time::timeout(Duration::from_secs(10), async {
+            while app.last() != Event::Update(Value::from(10u64))
+                || pubs.last() != Event::Update(Value::from(1u64))
+            {
+                time::sleep(Duration::from_millis(50)).await
+            }
+        })
+        .await?;
+        drop(vals);
+        publisher.flushed().await;
+        let paths = subscriber.subscribe(Path::from("/stats/paths"));
+        // only the stats themselves are left
+        time::timeout(Duration::from_secs(10), async {
+            while app.last() != Event::Unsubscribed
+                || paths.last() != Event::Update(Value::from(5u64))
+            {
+                time::sleep(Duration::from_millis(50)).await
+            }
+        })
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn subscribe_priority() -> Result<()> {
+        let _ = env_logger::try_init();
+        let (_resolver, cfg) = local_resolver().await?;
+        let publisher = PublisherBuilder::new(cfg.clone()).build().await?;
+        let vals = (0..100u64)
+            .map(|i| {
+                let path = Path::from(format!("/local/prio/{i}"));
+                Ok((path.clone(), publisher.publish(path, Value::from(i))?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        publisher.flushed().await;
+        let subscriber = SubscriberBuilder::new(cfg).build()?;
+        let batch = |prio| {
+            let paths = vals.iter().map(|(p, _)| p.clone()).collect::<Vec<_>>();
+            let subscriber = subscriber.clone();
+            async move {
+                let timeout = Some(Duration::from_secs(10));
+                let mut res = subscriber
+                    .subscribe_nondurable_prio(paths.into_iter(), timeout, prio)
+                    .await;
+                let mut n = 0;
+                while let Some((_, r)) = res.next().await {
+                    r?;
+                    n += 1;
+                }
+                Ok::<_, anyhow::Error>(n)
+            }
+        };
+        let (bg, fg) = future::join(
+            batch(SubscribePriority::Background),
+            batch(SubscribePriority::Foreground),
+        )
+        .await;
+        assert_eq!(bg?, 100);
+        assert_eq!(fg?, 100);
+        // a background batch waits until the foreground batch is done
+        let held = subscriber.hold_foreground();
+        let mut bg = Box::pin(batch(SubscribePriority::Background));
+        assert!(time::timeout(Duration::from_millis(300), &mut bg).await.is_err());
+        assert_eq!(batch(SubscribePriority::Foreground).await?, 100);
+        drop(held);
+        assert_eq!(time::timeout(Duration::from_millis(500), bg).await??, 100);
+        // but never for longer than MAX_BACKGROUND_DELAY
+        let held = subscriber.hold_foreground();
+        let start = Instant::now();
+        assert_eq!(batch(SubscribePriority::Background).await?, 100);
+        assert!(start.elapsed() >= MAX_BACKGROUND_DELAY);
+        drop(held);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn last_with_age() -> Result<()> {
+        let _ = env_logger::try_init();
+        let (_resolver, cfg) = local_resolver().await?;
+        let path = Path::from("/local/age");
+        let publisher = PublisherBuilder::new(cfg.clone()).build().await?;
+        let v = publisher.publish(path.clone(), Value::from(1u64))?;
+        publisher.flushed().await;
+        let subscriber = SubscriberBuilder::new(cfg).build()?;
+        let dv = subscriber.subscribe(path);
+        time::timeout(Duration::from_secs(10), dv.wait_subscribed()).await??;
+        time::sleep(Duration::from_millis(200)).await;
+        let (val, age) = dv.last_with_age().unwrap();
+        assert_eq!(val, Value::from(1u64));
+        assert!(age >= Duration::from_millis(200));
+        let mut batch = publisher.start_batch();
+        v.update(&mut batch, Value::from(2u64));
+        batch.commit(None).await;
+        time::timeout(Duration::from_secs(10), async {
+            while dv.last() != Event::Update(Value::from(2u64)) {
+                time::sleep(Duration::from_millis(10)).await
+            }
+        })
+        .await?;
+        let (_, new_age) = dv.last_with_age().unwrap();
+        assert!(new_age < age);
+        drop(publisher);
+        drop(v);
+        time::timeout(Duration::from_secs(10), async {
+            while dv.last_with_age().is_some() {
+                time::sleep(Duration::from_millis(10)).await
+            }
+        })
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn dval_into_stream() -> Result<()> {
+        let _ = env_logger::try_init();
+        let (_resolver, cfg) = local_resolver().await?;
+        let path = Path::from("/local/stream");
+        let publisher = PublisherBuilder::new(cfg.clone()).build().await?;
+        let v = publisher.publish(path.clone(), Value::from(1u64))?;
+        publisher.flushed().await;
+        let subscriber = SubscriberBuilder::new(cfg.clone()).build()?;
+        let dv = subscriber.subscribe(path.clone());
+        time::timeout(Duration::from_secs(10), dv.wait_subscribed()).await??;
+        async fn next(values: &mut (impl Stream<Item = Value> + Unpin)) -> Option<Value> {
+            time::timeout(Duration::from_secs(10), values.next()).await.ok().flatten()
+        }
+        let mut values = Box::pin(dv.into_stream(true));
+        assert_eq!(next(&mut values).await, Some(Value::from(1u64)));
+        let mut batch = publisher.start_batch();
+        v.update(&mut batch, Value::from(2u64));
+        batch.commit(None).await;
+        assert_eq!(next(&mut values).await, Some(Value::from(2u64)));
+        // the gap while there is no publisher is bridged, and the
+        // stream resumes with the value of the new publisher
+        drop(v);
+        drop(publisher);
+        let publisher = PublisherBuilder::new(cfg).build().await?;
+        let _v = publisher.publish(path, Value::from(3u64))?;
+        publisher.flushed().await;
+        assert_eq!(next(&mut values).await, Some(Value::from(3u64)));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn migrate_to_better_publisher() -> Result<()> {
+        let _ = env_logger::try_init();
+        let (_resolver, cfg) = local_resolver().await?;
+        let path = Path::from("/local/migrate");
+        let low = PublisherBuilder::new(cfg.clone())
+            .priority(PublisherPriority::Low)
+            .build()
+            .await?;
+        let vlow = low.publish(path.clone(), Value::from(1u64))?;
+        low.flushed().await;
+        let subscriber = SubscriberBuilder::new(cfg.clone())
+            .migrate_interval(Duration::from_millis(100))
+            .build()?;
+        let dv = subscriber.subscribe(path.clone());
+        time::timeout(Duration::from_secs(10), dv.wait_subscribed()).await??;
+        let (tx, mut rx) = mpsc::channel(10);
+        dv.updates(UpdatesFlags::BEGIN_WITH_LAST, tx);
+        let high =
+            PublisherBuilder::new(cfg).priority(PublisherPriority::High).build().await?;
+        let vhigh = high.publish(path, Value::from(2u64))?;
+        high.flushed().await;
+        let start = Instant::now();
+        while dv.last() != Event::Update(Value::from(2u64)) {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            time::sleep(Duration::from_millis(10)).await
+        }
+        // the old subscription is dropped only after the new one is up
+        while low.subscribed_len(&vlow.id()) > 0 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            time::sleep(Duration::from_millis(10)).await
+        }
+        assert_eq!(high.subscribed_len(&vhigh.id()), 1);
+        assert_eq!(subscriber.durable_stats().alive, 1);
+        let mut events = vec![];
+        while let Ok(Some(mut batch)) = rx.try_next() {
+            events.extend(batch.drain(..).map(|(_, e)| e));
+        }
+        assert!(!events.contains(&Event::Unsubscribed));
+        assert_eq!(events.last(), Some(&Event::Update(Value::from(2u64))));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn migrate_with_val_held() -> Result<()> {
+        let _ = env_logger::try_init();
+        let (_resolver, cfg) = local_resolver().await?;
+        let path = Path::from("/local/migrate_held");
+        let low = PublisherBuilder::new(cfg.clone())
+            .priority(PublisherPriority::Low)
+            .build()
+            .await?;
+        let vlow = low.publish(path.clone(), Value::from(1u64))?;
+        low.flushed().await;
+        let subscriber = SubscriberBuilder::new(cfg.clone())
+            .migrate_interval(Duration::from_millis(100))
+            .build()?;
+        let dv = subscriber.subscribe(path.clone());
+        time::timeout(Duration::from_secs(10), dv.wait_subscribed()).await??;
+        let (tx, mut rx) = mpsc::channel(10);
+        dv.updates(UpdatesFlags::empty(), tx);
+        // the user also holds the subscription the Dval starts with
+        let v = subscriber.subscribe_nondurable_one(path.clone(), None).await?;
+        let (tx_v, mut rx_v) = mpsc::channel(10);
+        v.updates(UpdatesFlags::empty(), tx_v);
+        let high =
+            PublisherBuilder::new(cfg).priority(PublisherPriority::High).build().await?;
+        let _vhigh = high.publish(path.clone(), Value::from(2u64))?;
+        high.flushed().await;
+        let start = Instant::now();
+        while dv.last() != Event::Update(Value::from(2u64)) {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            time::sleep(Duration::from_millis(10)).await
+        }
+        // the old publisher goes away, the held Val is unsubscribed,
+        // but the Dval has moved on
+        drop(vlow);
+        low.flushed().await;
+        let unsubscribed = async {
+            while let Some(mut batch) = rx_v.next().await {
+                if batch.drain(..).any(|(_, e)| e == Event::Unsubscribed) {
+                    return true;
+                }
+            }
+            false
+        };
+        assert!(time::timeout(Duration::from_secs(10), unsubscribed).await?);
+        assert_eq!(v.last(), Event::Unsubscribed);
+        let mut events = vec![];
+        while let Ok(Some(mut batch)) = rx.try_next() {
+            events.extend(batch.drain(..).map(|(_, e)| e));
+        }
+        assert!(!events.contains(&Event::Unsubscribed));
+        assert_eq!(dv.last(), Event::Update(Value::from(2u64)));
+        // and a new subscription gets the live one
+        let v = subscriber.subscribe_nondurable_one(path, None).await?;
+        assert_eq!(v.last(), Event::Update(Value::from(2u64)));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn pinned_resubscribe() -> Result<()> {
+        let _ = env_logger::try_init();
+        let (resolver, cfg) = local_resolver().await?;
+        let path = Path::from("/local/pinned");
+        let publisher = PublisherBuilder::new(cfg.clone()).build().await?;
+        let addr = publisher.addr();
+        let v = publisher.publish(path.clone(), Value::from(1u64))?;
+        publisher.flushed().await;
+        let subscriber = SubscriberBuilder::new(cfg.clone()).build()?;
+        let dv = subscriber.subscribe_pinned(path.clone(), 100);
+        time::timeout(Duration::from_secs(10), dv.wait_subscribed()).await??;
+        assert_eq!(dv.last(), Event::Update(Value::from(1u64)));
+        // with the resolver gone the only way back is the pinned address
+        drop(resolver);
+        drop(v);
+        drop(publisher);
+        let mut tries = 0;
+        let publisher = loop {
+            let bind = Some(BindCfg::Exact(addr));
+            match PublisherBuilder::new(cfg.clone()).bind_cfg(bind).build().await {
+                Ok(p) => break p,
+                Err(_) if tries < 50 => {
+                    tries += 1;
+                    time::sleep(Duration::from_millis(100)).await
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        let _v = publisher.publish(path, Value::from(2u64))?;
+        time::timeout(Duration::from_secs(10), async {
+            while dv.last() != Event::Update(Value::from(2u64)) {
+                time::sleep(Duration::from_millis(50)).await
+            }
+        })
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn publisher_selection() -> Result<()> {
+        let _ = env_logger::try_init();
+        let (_resolver, cfg) = local_resolver().await?;
+        let path = Path::from("/local/selection");
+        let mut publishers = vec![];
+        for i in 0..4 {
+            let publisher = PublisherBuilder::new(cfg.clone()).build().await?;
+            let v = publisher.publish(path.clone(), Value::from(i))?;
+            publisher.flushed().await;
+            publishers.push((publisher, v, Value::from(i)));
+        }
+        async fn chosen(subscriber: &Subscriber, path: &Path) -> Result<Value> {
+            let v = subscriber.subscribe_nondurable_one(path.clone(), None).await?;
+            match v.last() {
+                Event::Update(v) => Ok(v),
+                Event::Unsubscribed => bail!("unsubscribed"),
+            }
+        }
+        // round robin visits every candidate in turn
+        let mut selector = Selector::new(PublisherSelection::RoundRobin);
+        let picks = (0..8).map(|_| selector.pick(4)).collect::<Vec<_>>();
+        assert_eq!(picks, [0, 1, 2, 3, 0, 1, 2, 3]);
+        // and the candidates are in order of address, so each strategy
+        // chooses exactly the publisher its selector predicts
+        publishers.sort_by_key(|(p, _, _)| p.addr());
+        let strategies = [PublisherSelection::Seeded(42), PublisherSelection::RoundRobin];
+        for selection in strategies {
+            let mut selector = Selector::new(selection);
+            let subscriber = SubscriberBuilder::new(cfg.clone())
+                .publisher_selection(selection)
+                .build()?;
+            for _ in 0..8 {
+                let expected = &publishers[selector.pick(4)].2;
+                assert_eq!(&chosen(&subscriber, &path).await?, expected, "{selection:?}");
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn small_resub_batch_size() -> Result<()> {
+        let _ = env_logger::try_init();
+        let (_resolver, cfg) = local_resolver().await?;
+        let publisher = PublisherBuilder::new(cfg.clone()).build().await?;
+        let vals = (0..10)
+            .map(|i| publisher.publish(Path::from(format!("/local/batch/{i}")), i))
+            .collect::<Result<Vec<_>>>()?;
+        publisher.flushed().await;
+        assert!(SubscriberBuilder::new(cfg.clone()).resub_batch_size(0).build().is_err());
+        let subscriber = SubscriberBuilder::new(cfg).resub_batch_size(2).build()?;
+        let dvs = (0..10)
+            .map(|i| subscriber.subscribe(Path::from(format!("/local/batch/{i}"))))
+            .collect::<Vec<_>>();
+        for (i, dv) in dvs.iter().enumerate() {
+            time::timeout(Duration::from_secs(10), dv.wait_subscribed()).await??;
+            assert_eq!(dv.last(), Event::Update(Value::from(i as i32)));
+        }
+        let batches = subscriber.resub_batches();
+        assert!(batches.iter().all(|n| *n <= 2), "{batches:?}");
+        assert!(batches.iter().sum::<usize>() >= 10, "{batches:?}");
+        drop(vals);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn shared_paths() -> Result<()> {
+        const N: usize = 100;
+        let _ = env_logger::try_init();
+        let (_resolver, cfg) = local_resolver().await?;
+        let publisher = PublisherBuilder::new(cfg.clone()).build().await?;
+        // every call builds a new copy of the path
+        let path = |i: usize| Path::from(format!("/local/shared/{i}"));
+        let vals = (0..N)
+            .map(|i| publisher.publish(path(i), i as u64))
+            .collect::<Result<Vec<_>>>()?;
+        publisher.flushed().await;
+        let subscriber = SubscriberBuilder::new(cfg).build()?;
+        let mut subs = Vec::with_capacity(N);
+        let mut res = subscriber.subscribe_nondurable((0..N).map(path), None).await;
+        while let Some((_, r)) = res.next().await {
+            subs.push(r?);
+        }
+        let dvs = (0..N).map(|i| subscriber.subscribe(path(i))).collect::<Vec<_>>();
+        for dv in &dvs {
+            time::timeout(Duration::from_secs(10), dv.wait_subscribed()).await??;
+        }
+        // each path is in both the subscribed and the durable tables,
+        // but they share one copy of it
+        assert_eq!(subscriber.path_allocations(), (N, 2 * N));
+        drop(vals);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn write_coalesce() -> Result<()> {
+        let _ = env_logger::try_init();
+        let (_resolver, cfg) = local_resolver().await?;
+        let publisher = PublisherBuilder::new(cfg.clone()).build().await?;
+        let vp = publisher.publish(Path::from("/local/coalesce"), 0)?;
+        let (tx, mut rx) = mpsc::channel(10);
+        publisher.writes(vp.id(), tx);
+        publisher.flushed().await;
+        let delay = Duration::from_millis(50);
+        let subscriber =
+            SubscriberBuilder::new(cfg).nodelay(false).write_coalesce(delay).build()?;
+        let v = subscriber
+            .subscribe_nondurable_one(Path::from("/local/coalesce"), None)
+            .await?;
+        for i in 1..=10 {
+            v.write(Value::from(i));
+        }
+        let start = Instant::now();
+        v.flush().await?;
+        assert!(start.elapsed() >= delay - Duration::from_millis(1));
+        let mut written = vec![];
+        while written.len() < 10 {
+            let mut batch =
+                time::timeout(Duration::from_secs(10), rx.next()).await?.unwrap();
+            written.extend(batch.drain(..).map(|req| req.value));
+        }
+        assert_eq!(written, (1..=10).map(Value::from).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn write_timeout() -> Result<()> {
+        let _ = env_logger::try_init();
+        let (_resolver, cfg) = local_resolver().await?;
+        let publisher = PublisherBuilder::new(cfg.clone()).build().await?;
+        let vp = publisher.publish(Path::from("/local/write_timeout"), 0)?;
+        let (tx, mut rx) = mpsc::channel(10);
+        publisher.writes(vp.id(), tx);
+        publisher.flushed().await;
+        let subscriber = SubscriberBuilder::new(cfg)
+            .write_timeout(Some(Duration::from_millis(100)))
+            .build()?;
+        let v = subscriber
+            .subscribe_nondurable_one(Path::from("/local/write_timeout"), None)
+            .await?;
+        let receipt = v.write_with_recipt(Value::from(42));
+        // hold the request so the publisher never replies
+        let _req = time::timeout(Duration::from_secs(10), rx.next()).await?.unwrap();
+        let reply = time::timeout(Duration::from_secs(10), receipt).await?;
+        assert!(reply.unwrap_err().is::<WriteTimedOut>());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn drop_slow_consumers() -> Result<()> {
+        let _ = env_logger::try_init();
+        let (_resolver, cfg) = local_resolver().await?;
+        let publisher = PublisherBuilder::new(cfg.clone()).build().await?;
+        let va = publisher.publish(Path::from("/local/slow/a"), 0u64)?;
+        let vb = publisher.publish(Path::from("/local/slow/b"), 0u64)?;
+        publisher.flushed().await;
+        let subscriber = SubscriberBuilder::new(cfg)
+            .slow_consumer_timeout(Some(Duration::from_millis(100)))
+            .drop_slow_consumers(true)
+            .build()?;
+        let sa = subscriber.subscribe_nondurable_one(Path::from("/local/slow/a"), None);
+        let sb = subscriber.subscribe_nondurable_one(Path::from("/local/slow/b"), None);
+        let (sa, sb) = (sa.await?, sb.await?);
+        // never read, so it fills up and blocks the connection
+        let (tx_slow, mut rx_slow) = mpsc::channel(1);
+        sa.updates(UpdatesFlags::empty(), tx_slow);
+        let (tx, mut rx) = mpsc::channel(100);
+        sb.updates(UpdatesFlags::empty(), tx);
+        for i in 1..=20u64 {
+            let mut batch = publisher.start_batch();
+            va.update(&mut batch, i);
+            vb.update(&mut batch, i);
+            batch.commit(None).await;
+        }
+        let mut n = 0;
+        while n < 20 {
+            let batch = time::timeout(Duration::from_secs(10), rx.next()).await?;
+            n += batch.unwrap().len();
+        }
+        // the slow stream was closed, it ends after what was queued
+        time::timeout(Duration::from_secs(10), async {
+            while rx_slow.next().await.is_some() {}
+        })
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn resolve_cache() -> Result<()> {
+        let _ = env_logger::try_init();
+        let (resolver, cfg) = local_resolver().await?;
+        let publisher = PublisherBuilder::new(cfg.clone()).build().await?;
+        let _a = publisher.publish(Path::from("/local/cache/a"), Value::from(1))?;
+        let _b = publisher.publish(Path::from("/local/cache/b"), Value::from(2))?;
+        publisher.flushed().await;
+        let ttl = Duration::from_secs(3600);
+        assert!(SubscriberBuilder::new(cfg.clone())
+            .resolve_cache_ttl(ttl)
+            .build()
+            .is_err());
+        let subscriber = SubscriberBuilder::new(cfg)
+            .resolve_cache_ttl(Duration::from_secs(60))
+            .build()?;
+        let to = Some(Duration::from_secs(10));
+        let a =
+            subscriber.subscribe_nondurable_one(Path::from("/local/cache/a"), to).await?;
+        // b keeps the connection to the publisher open
+        let b =
+            subscriber.subscribe_nondurable_one(Path::from("/local/cache/b"), to).await?;
+        drop(a);
+        // without the resolver, only the cache can find the publisher
+        drop(resolver);
+        time::sleep(Duration::from_millis(100)).await;
+        let a =
+            subscriber.subscribe_nondurable_one(Path::from("/local/cache/a"), to).await?;
+        assert_eq!(a.last(), Event::Update(Value::from(1)));
+        drop(b);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn max_subscriptions() -> Result<()> {
+        let _ = env_logger::try_init();
+        let (_resolver, cfg) = local_resolver().await?;
+        let publisher = PublisherBuilder::new(cfg.clone()).build().await?;
+        let vals = (0..4)
+            .map(|i| publisher.publish(Path::from(format!("/local/quota/{i}")), i))
+            .collect::<Result<Vec<_>>>()?;
+        publisher.flushed().await;
+        assert!(SubscriberBuilder::new(cfg.clone())
+            .max_subscriptions(0)
+            .build()
+            .is_err());
+        let subscriber = SubscriberBuilder::new(cfg).max_subscriptions(2).build()?;
+        let p = |i: usize| Path::from(format!("/local/quota/{i}"));
+        let to = Some(Duration::from_secs(10));
+        let v0 = subscriber.subscribe_nondurable_one(p(0), to).await?;
+        let dv1 = subscriber.try_subscribe(p(1))?;
+        time::timeout(Duration::from_secs(10), dv1.wait_subscribed()).await??;
+        assert_eq!(subscriber.subscription_count(), 2);
+        assert!(subscriber.subscribe_nondurable_one(p(2), to).await.is_err());
+        assert!(subscriber.try_subscribe(p(3)).is_err());
+        // already subscribed paths don't count again
+        let v0b = subscriber.subscribe_nondurable_one(p(0), to).await?;
+        let _dv1 = subscriber.try_subscribe(p(1))?;
+        drop(v0);
+        drop(v0b);
+        time::timeout(Duration::from_secs(10), async {
+            while subscriber.subscription_count() > 1 {
+                time::sleep(Duration::from_millis(10)).await
+            }
+        })
+        .await?;
+        let v2 = subscriber.subscribe_nondurable_one(p(2), to).await?;
+        assert_eq!(v2.last(), Event::Update(Value::from(2)));
+        drop(vals);
+        Ok(())
+    }
+
+    #[test]
+    fn retry_jitter() {
+        use crate::subscriber::DurableConfig;
+        let cfg = DurableConfig::default();
+        // after a mass failure every durable subscription has failed
+        // the same number of times, their retries should not line up
+        for tries in [1, 2, 10] {
+            let waits =
+                (0..1000).map(|_| cfg.retry_wait(tries)).collect::<HashSet<Duration>>();
+            assert!(waits.len() > 900);
+            assert!(waits.iter().all(|w| *w < Duration::from_millis(50 * tries as u64)));
+        }
+    }
+
+    #[test]
+    fn retry_exponential() {
+        use crate::subscriber::DurableConfig;
+        let cfg = DurableConfig {
+            base: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+            multiplier: 2.,
+            jitter: 0.,
+        };
+        let waits =
+            (1..=8).map(|tries| cfg.retry_wait(tries).as_secs()).collect::<Vec<_>>();
+        assert_eq!(waits, vec![1, 2, 4, 8, 16, 32, 60, 60]);
+        assert_eq!(cfg.retry_wait(usize::MAX), Duration::from_secs(60));
+        // the default without jitter backs off linearly
+        let cfg = DurableConfig { jitter: 0., ..DurableConfig::default() };
+        let waits =
+            (1..=4).map(|tries| cfg.retry_wait(tries).as_millis()).collect::<Vec<_>>();
+        assert_eq!(waits, vec![50, 100, 150, 200]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn resubscribe_at_next_try() -> Result<()> {
+        let _ = env_logger::try_init();
+        let (_resolver, cfg) = local_resolver().await?;
+        let subscriber = SubscriberBuilder::new(cfg).build()?;
+        let dv = subscriber.subscribe(Path::from("/local/never"));
+        for _ in 0..3 {
+            // wait for the last attempt to fail
+            let next_try = loop {
+                let dead = subscriber.durable_stats().dead == 1;
+                match dv.next_try() {
+                    Some(t) if dead && t > Instant::now() => break t,
+                    Some(_) | None => time::sleep(Duration::from_micros(1)).await,
+                }
+            };
+            time::sleep_until(next_try - Duration::from_millis(1)).await;
+            assert_eq!(subscriber.durable_stats().dead, 1);
+            assert_eq!(dv.next_try(), Some(next_try));
+            time::sleep_until(next_try).await;
+            // yielding lets the resubscription task run without
+            // advancing the clock
+            let waiting = || {
+                subscriber.durable_stats().dead == 1 && dv.next_try() == Some(next_try)
+            };
+            while waiting() {
+                task::yield_now().await
+            }
+            // timers have millisecond resolution
+            let now = Instant::now();
+            assert!(now >= next_try && now - next_try < Duration::from_millis(1));
+        }
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn dval_last_error() -> Result<()> {
+        let _ = env_logger::try_init();
+        let (_resolver, cfg) = local_resolver().await?;
+        let publisher = PublisherBuilder::new(cfg.clone()).build().await?;
+        let subscriber = SubscriberBuilder::new(cfg).build()?;
+        let path = Path::from("/local/last_error");
+        let dv = subscriber.subscribe(path.clone());
+        let e = time::timeout(Duration::from_secs(10), async {
+            loop {
+                match dv.last_error() {
+                    Some(e) => break e,
+                    None => time::sleep(Duration::from_millis(10)).await,
+                }
+            }
+        })
+        .await?;
+        assert!(!e.is_empty());
+        let _v = publisher.publish(path, Value::from(42))?;
+        publisher.flushed().await;
+        time::timeout(Duration::from_secs(10), dv.wait_subscribed()).await??;
+        assert_eq!(dv.last_error(), None);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn dval_write_queue_limit() -> Result<()> {
+        let _ = env_logger::try_init();
+        let (_resolver, cfg) = local_resolver().await?;
+        let publisher = PublisherBuilder::new(cfg.clone()).build().await?;
+        let subscriber = SubscriberBuilder::new(cfg).build()?;
+        let path = Path::from("/local/write_queue");
+        let dv = subscriber.subscribe(path.clone());
+        for i in 1..=3 {
+            assert!(!dv.write(Value::from(i)));
+        }
+        assert_eq!(dv.queued_writes(), 3);
+        dv.set_write_queue_limit(Some(1));
+        assert_eq!(dv.queued_writes(), 1);
+        let dropped = dv.write_with_recipt(Value::from(4));
+        assert!(!dv.write(Value::from(5)));
+        assert_eq!(dv.queued_writes(), 1);
+        assert!(dropped.await.is_err());
+        let vp = publisher.publish(path, Value::from(0))?;
+        let (tx, mut rx) = mpsc::channel(10);
+        publisher.writes(vp.id(), tx);
+        publisher.flushed().await;
+        time::timeout(Duration::from_secs(30), dv.wait_subscribed()).await??;
+        let mut batch = time::timeout(Duration::from_secs(10), rx.next()).await?.unwrap();
+        let written = batch.drain(..).map(|req| req.value).collect::<Vec<_>>();
+        assert_eq!(written, vec![Value::from(5)]);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn dirty_notify() -> Result<()> {
+        let _ = env_logger::try_init();
+        let (_resolver, cfg) = local_resolver().await?;
+        let publisher = PublisherBuilder::new(cfg.clone()).build().await?;
+        let va = publisher.publish(Path::from("/local/dirty/a"), Value::from(0))?;
+        let vb = publisher.publish(Path::from("/local/dirty/b"), Value::from(0))?;
+        publisher.flushed().await;
+        let subscriber = SubscriberBuilder::new(cfg).build()?;
+        let (da, ready_a) =
+            subscriber.durable_subscribe_ready(Path::from("/local/dirty/a"));
+        let (db, ready_b) =
+            subscriber.durable_subscribe_ready(Path::from("/local/dirty/b"));
+        time::timeout(Duration::from_secs(10), ready_a).await??;
+        time::timeout(Duration::from_secs(10), ready_b).await??;
+        let dirty = subscriber.dirty_notify(&[da.clone(), db.clone()]);
+        futures::pin_mut!(dirty);
+        subscriber.flush().await;
+        for (i, v) in [&va, &vb].into_iter().enumerate() {
+            let mut batch = publisher.start_batch();
+            v.update(&mut batch, Value::from((i + 1) as u64));
+            batch.commit(None).await;
+            time::timeout(Duration::from_secs(10), dirty.next()).await?.unwrap();
+        }
+        assert_eq!(da.last(), Event::Update(Value::from(1u64)));
+        assert_eq!(db.last(), Event::Update(Value::from(2u64)));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn updates_raw() -> Result<()> {
+        let _ = env_logger::try_init();
+        let (_resolver, cfg) = local_resolver().await?;
+        let publisher = PublisherBuilder::new(cfg.clone()).build().await?;
+        let vp = publisher.publish(Path::from("/local/raw"), Value::from(0))?;
+        publisher.flushed().await;
+        let subscriber = SubscriberBuilder::new(cfg).build()?;
+        let v =
+            subscriber.subscribe_nondurable_one(Path::from("/local/raw"), None).await?;
+        let (tx, mut rx) = mpsc::channel(10);
+        v.updates_raw(tx);
+        v.flush().await?;
+        let mut batch = publisher.start_batch();
+        vp.update(&mut batch, Value::from(1));
+        batch.commit(None).await;
+        let mut next = async || loop {
+            let mut batch = rx.next().await.unwrap();
+            if let Some(m) = batch.drain(..).find(|m| m != &PFrom::Heartbeat) {
+                break m;
+            }
+        };
+        match time::timeout(Duration::from_secs(10), next()).await? {
+            PFrom::Update(_, v) => assert_eq!(v, Value::from(1)),
+            m => panic!("unexpected message {m:?}"),
+        }
+        drop(vp);
+        publisher.flushed().await;
+        match time::timeout(Duration::from_secs(10), next()).await? {
+            PFrom::Unsubscribed(_) => (),
+            m => panic!("unexpected message {m:?}"),
+        }
+        // dropping the subscription drops its raw channels
+        let _vp = publisher.publish(Path::from("/local/raw1"), Value::from(0))?;
+        publisher.flushed().await;
+        let v =
+            subscriber.subscribe_nondurable_one(Path::from("/local/raw1"), None).await?;
+        let (tx, mut rx) = mpsc::channel(10);
+        v.updates_raw(tx);
+        v.flush().await?;
+        drop(v);
+        let closed = async { while rx.next().await.is_some() {} };
+        time::timeout(Duration::from_secs(10), closed).await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn duplicate_subscribed() -> Result<()> {
+        use crate::{
+            channel::{self, Channel},
+            protocol::publisher::{Hello, Id, To},
+        };
+        use cross_krb5::ServerCtx;
+        let _ = env_logger::try_init();
+        let (_resolver, cfg) = local_resolver().await?;
+        // a publisher that answers the subscription, and then sends
+        // Subscribed again for the same id
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let paddr = listener.local_addr()?;
+        let (tx_unsub, mut rx_unsub) = mpsc::unbounded();
+        let (tx_go, rx_go) = oneshot::channel::<()>();
+        task::spawn(async move {
+            let (mut s, _) = listener.accept().await?;
+            let _: u64 = channel::read_raw::<_, _, 1024>(&mut s).await?;
+            channel::write_raw(&mut s, &3u64).await?;
+            let _: Hello = channel::read_raw::<_, _, 1024>(&mut s).await?;
+            channel::write_raw(&mut s, &Hello::Anonymous(0)).await?;
+            let mut con = Channel::new::<ServerCtx, TcpStream>(None, s);
+            let path = match con.receive::<To>().await? {
+                To::Subscribe { path, .. } => path,
+                m => bail!("unexpected {m:?}"),
+            };
+            let id = Id::new();
+            con.send_one(&PFrom::Subscribed(path.clone(), id, Value::from(1), None))
+                .await?;
+            let _ = rx_go.await;
+            con.send_one(&PFrom::Subscribed(path, id, Value::from(2), None)).await?;
+            con.send_one(&PFrom::Update(id, Value::from(3))).await?;
+            while let Ok(m) = con.receive::<To>().await {
+                if let To::Unsubscribe(_) = m {
+                    tx_unsub.unbounded_send(())?
+                }
+            }
+            Ok::<_, anyhow::Error>(())
+        });
+        let w = anonymous_writer(&cfg, paddr)?;
+        w.publish([Path::from("/local/dup")]).await?;
+        let subscriber = SubscriberBuilder::new(cfg).build()?;
+        let v =
+            subscriber.subscribe_nondurable_one(Path::from("/local/dup"), None).await?;
+        assert_eq!(v.last(), Event::Update(Value::from(1)));
+        let (tx, mut rx) = mpsc::channel(10);
+        v.updates(UpdatesFlags::empty(), tx);
+        v.flush().await?;
+        let _ = tx_go.send(());
+        // the duplicate is ignored, the subscription stays alive and
+        // keeps its streams
+        assert_eq!(
+            time::timeout(Duration::from_secs(10), wait_val(&mut rx)).await?,
+            Value::from(3)
+        );
+        assert_eq!(v.last(), Event::Update(Value::from(3)));
+        assert!(time::timeout(Duration::from_millis(500), rx_unsub.next())
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn connection_factory() -> Result<()> {
+        use crate::{
+            channel::Channel,
+            protocol::{
+                publisher::{Id, To, PROTOCOL_VERSION},
+                resolver::{TargetAuth, UserInfo},
+            },
+            subscriber::ConnectionFactory,
+            tls,
+        };
+        use cross_krb5::{ClientCtx, ServerCtx};
+        use std::pin::Pin;
+        use tokio::io::{self, DuplexStream};
+        // hands the publisher side of each in memory connection to
+        // the test
+        #[derive(Debug)]
+        struct Duplex(mpsc::UnboundedSender<DuplexStream>);
+        impl ConnectionFactory for Duplex {
+            fn connect(
+                &self,
+                _addr: SocketAddr,
+                _tls_ctx: Option<tls::CachedConnector>,
+                _uifo: Option<UserInfo>,
+                _desired_auth: DesiredAuth,
+                _target_auth: TargetAuth,
+            ) -> Pin<Box<dyn Future<Output = Result<(Channel, u64)>> + Send + 'static>>
+            {
+                let (client, server) = io::duplex(1 << 16);
+                let r = self.0.unbounded_send(server);
+                Box::pin(async move {
+                    r?;
+                    let con = Channel::new::<ClientCtx, DuplexStream>(None, client);
+                    Ok((con, PROTOCOL_VERSION))
+                })
             }
         }
-        if !*default_destroyed.lock() {
-            panic!("default publisher value was not destroyed on idle")
+        async fn accept(
+            rx: &mut mpsc::UnboundedReceiver<DuplexStream>,
+            v: i32,
+        ) -> Result<Channel> {
+            let s = time::timeout(Duration::from_secs(10), rx.next())
+                .await?
+                .ok_or_else(|| anyhow!("factory dropped"))?;
+            let mut con = Channel::new::<ServerCtx, DuplexStream>(None, s);
+            match con.receive::<To>().await? {
+                To::Subscribe { path, .. } => {
+                    let m = PFrom::Subscribed(path, Id::new(), Value::from(v), None);
+                    con.send_one(&m).await?
+                }
+                m => bail!("unexpected {m:?}"),
+            }
+            Ok(con)
+        }
+        let _ = env_logger::try_init();
+        let (_resolver, cfg) = local_resolver().await?;
+        // nothing listens here, every connection goes through the factory
+        let paddr: SocketAddr = "127.0.0.1:1".parse()?;
+        let w = anonymous_writer(&cfg, paddr)?;
+        w.publish([Path::from("/local/fake")]).await?;
+        let (tx, mut rx) = mpsc::unbounded();
+        let subscriber = SubscriberBuilder::new(cfg)
+            .connection_factory(Arc::new(Duplex(tx)))
+            .build()?;
+        let dv = subscriber.subscribe(Path::from("/local/fake"));
+        let con = accept(&mut rx, 1).await?;
+        time::timeout(Duration::from_secs(10), dv.wait_subscribed()).await??;
+        assert_eq!(dv.last(), Event::Update(Value::from(1)));
+        assert_eq!(dv.protocol_version(), Some(PROTOCOL_VERSION));
+        // killing the connection causes the durable subscription to
+        // come back through the factory
+        drop(con);
+        let _con = accept(&mut rx, 2).await?;
+        let start = Instant::now();
+        while dv.last() != Event::Update(Value::from(2)) {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            time::sleep(Duration::from_millis(10)).await
         }
+        Ok(())
     }
 
     #[tokio::test(flavor = "multi_thread")]
-    async fn publish_subscribe() {
+    async fn socks5_proxy() -> Result<()> {
+        use crate::subscriber::{AddressMapper, ConnectTarget};
+        use std::net::Ipv4Addr;
+        use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+        #[derive(Debug)]
+        struct ViaProxy(SocketAddr);
+        impl AddressMapper for ViaProxy {
+            fn map(&self, addr: SocketAddr) -> ConnectTarget {
+                ConnectTarget::Socks5 { proxy: self.0, target: addr }
+            }
+        }
+        async fn proxy(
+            mut s: TcpStream,
+            tx: mpsc::UnboundedSender<SocketAddr>,
+        ) -> Result<()> {
+            let mut buf = [0u8; 3];
+            s.read_exact(&mut buf).await?;
+            assert_eq!(buf, [5, 1, 0]);
+            s.write_all(&[5, 0]).await?;
+            let mut req = [0u8; 10];
+            s.read_exact(&mut req).await?;
+            assert_eq!(req[..4], [5, 1, 0, 1]);
+            let ip = Ipv4Addr::new(req[4], req[5], req[6], req[7]);
+            let target = SocketAddr::from((ip, u16::from_be_bytes([req[8], req[9]])));
+            tx.unbounded_send(target)?;
+            let mut t = TcpStream::connect(target).await?;
+            s.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+            io::copy_bidirectional(&mut s, &mut t).await?;
+            Ok(())
+        }
         let _ = env_logger::try_init();
-        let server_cfg = ServerConfig::load("../cfg/simple-server.json")
-            .expect("load simple server config");
-        let mut client_cfg = ClientConfig::load("../cfg/simple-client.json")
-            .expect("load simple client config");
-        let server = Server::new(server_cfg, false, 0).await.expect("start server");
-        client_cfg.addrs[0].0 = *server.local_addr();
-        let default_destroyed = Arc::new(Mutex::new(false));
-        let (tx, ready) = oneshot::channel();
-        task::spawn(run_publisher(
-            client_cfg.clone(),
-            default_destroyed.clone(),
-            tx,
-            DesiredAuth::Anonymous,
-        ));
-        time::timeout(Duration::from_secs(1), ready).await.unwrap().unwrap();
-        run_subscriber(client_cfg, default_destroyed, DesiredAuth::Anonymous).await;
-        drop(server);
+        let (_resolver, cfg) = local_resolver().await?;
+        let publisher = PublisherBuilder::new(cfg.clone()).build().await?;
+        let _v = publisher.publish(Path::from("/local/proxied"), Value::from(42))?;
+        publisher.flushed().await;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_addr = listener.local_addr()?;
+        let (tx, mut rx) = mpsc::unbounded();
+        task::spawn(async move {
+            while let Ok((s, _)) = listener.accept().await {
+                task::spawn(proxy(s, tx.clone()));
+            }
+        });
+        let subscriber = SubscriberBuilder::new(cfg)
+            .address_mapper(Arc::new(ViaProxy(proxy_addr)))
+            .build()?;
+        let v = time::timeout(
+            Duration::from_secs(10),
+            subscriber.subscribe_nondurable_one(Path::from("/local/proxied"), None),
+        )
+        .await??;
+        assert_eq!(v.last(), Event::Update(Value::from(42)));
+        assert_eq!(rx.next().await, Some(publisher.addr()));
+        Ok(())
     }
 
     #[tokio::test(flavor = "multi_thread")]
-    async fn tls_publish_subscribe() {
+    async fn subscribe_deadline() -> Result<()> {
         let _ = env_logger::try_init();
-        #[cfg(unix)]
-        let server_cfg = ServerConfig::load("../cfg/tls/resolver/resolver.json")
-            .expect("load tls server config");
-        #[cfg(windows)]
-        let server_cfg = ServerConfig::load("../cfg/tls/resolver/resolver-win.json")
-            .expect("load tls server config");
-        let mut pub_cfg = ClientConfig::load("../cfg/tls/publisher/client.json")
-            .expect("failed to load tls publisher config");
-        let mut sub_cfg = ClientConfig::load("../cfg/tls/client/client.json")
-            .expect("failed to load subscriber cfg");
-        let default_destroyed = Arc::new(Mutex::new(false));
-        let (tx, ready) = oneshot::channel();
-        let server = Server::new(server_cfg, false, 0).await.expect("start server");
-        pub_cfg.addrs[0].0 = *server.local_addr();
-        sub_cfg.addrs[0].0 = *server.local_addr();
-        task::spawn(run_publisher(
-            pub_cfg.clone(),
-            default_destroyed.clone(),
-            tx,
-            DesiredAuth::Tls { identity: None },
-        ));
-        time::timeout(Duration::from_secs(1), ready).await.unwrap().unwrap();
-        run_subscriber(pub_cfg, default_destroyed, DesiredAuth::Tls { identity: None })
-            .await;
-        drop(server)
+        let (_resolver, cfg) = local_resolver().await?;
+        // a "publisher" that accepts connections but never says hello
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let paddr = listener.local_addr()?;
+        task::spawn(async move {
+            let mut held = vec![];
+            while let Ok((s, _)) = listener.accept().await {
+                held.push(s)
+            }
+        });
+        let w = anonymous_writer(&cfg, paddr)?;
+        w.publish([Path::from("/local/stuck")]).await?;
+        let subscriber = SubscriberBuilder::new(cfg).build()?;
+        let budget = Duration::from_secs(1);
+        for _ in 0..3 {
+            let start = Instant::now();
+            let res = subscriber
+                .subscribe_nondurable_one(Path::from("/local/stuck"), Some(budget))
+                .await;
+            let elapsed = start.elapsed();
+            assert!(res.is_err());
+            assert!(elapsed < budget + Duration::from_millis(250), "took {elapsed:?}");
+        }
+        Ok(())
     }
 
     #[tokio::test(flavor = "multi_thread")]
-    async fn slow_consumer() -> Result<()> {
+    async fn subscribe_cancel() -> Result<()> {
         let _ = env_logger::try_init();
-        let resolver = {
-            use crate::resolver_server::config::{self, file};
-            let cfg = file::ConfigBuilder::default()
-                .member_servers(vec![file::MemberServerBuilder::default()
-                    .auth(file::Auth::Anonymous)
-                    .addr("127.0.0.1:0".parse()?)
-                    .bind_addr("127.0.0.1".parse()?)
-                    .build()?])
-                .build()?;
-            let cfg = config::Config::from_file(cfg)?;
-            crate::resolver_server::Server::new(cfg, false, 0).await?
+        let (_resolver, cfg) = local_resolver().await?;
+        let publisher = PublisherBuilder::new(cfg.clone()).build().await?;
+        let _v = publisher.publish(Path::from("/local/ok"), Value::from(42))?;
+        publisher.flushed().await;
+        // a "publisher" that accepts connections but never says hello
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let paddr = listener.local_addr()?;
+        task::spawn(async move {
+            let mut held = vec![];
+            while let Ok((s, _)) = listener.accept().await {
+                held.push(s)
+            }
+        });
+        let w = anonymous_writer(&cfg, paddr)?;
+        w.publish([Path::from("/local/stuck")]).await?;
+        let subscriber = SubscriberBuilder::new(cfg).build()?;
+        let (tx, rx) = oneshot::channel::<()>();
+        let batch = [Path::from("/local/ok"), Path::from("/local/stuck")];
+        let res = subscriber.subscribe_nondurable_cancel(
+            batch.into_iter(),
+            None,
+            rx.map(|_| ()),
+        );
+        let start = Instant::now();
+        task::spawn(async move {
+            time::sleep(Duration::from_millis(200)).await;
+            let _ = tx.send(());
+        });
+        let res = time::timeout(Duration::from_secs(10), res).await?;
+        assert!(res.unwrap_err().is::<Cancelled>());
+        assert!(start.elapsed() < Duration::from_secs(1));
+        // nothing is left pending, the next attempt is not cancelled
+        let res = subscriber
+            .subscribe_nondurable_one(
+                Path::from("/local/stuck"),
+                Some(Duration::from_millis(300)),
+            )
+            .await;
+        assert!(!res.unwrap_err().is::<Cancelled>());
+        let v = subscriber
+            .get_once_cancel(Path::from("/local/ok"), None, future::pending())
+            .await?;
+        assert_eq!(v, Value::from(42));
+        let res = subscriber
+            .get_once_cancel(Path::from("/local/stuck"), None, future::ready(()))
+            .await;
+        assert!(res.unwrap_err().is::<Cancelled>());
+        // cancelling doesn't fail another caller waiting on the same path
+        let (tx, rx) = oneshot::channel::<()>();
+        let batch = [Path::from("/local/stuck")];
+        let res = subscriber.subscribe_nondurable_cancel(
+            batch.into_iter(),
+            None,
+            rx.map(|_| ()),
+        );
+        let other = {
+            let subscriber = subscriber.clone();
+            task::spawn(async move {
+                time::sleep(Duration::from_millis(100)).await;
+                let to = Some(Duration::from_secs(1));
+                subscriber.subscribe_nondurable_one(Path::from("/local/stuck"), to).await
+            })
         };
-        let addr = *resolver.local_addr();
-        let cfg = {
-            use crate::config::{self, file, DefaultAuthMech};
-            let cfg = file::ConfigBuilder::default()
-                .addrs(vec![(addr, file::Auth::Anonymous)])
-                .default_auth(DefaultAuthMech::Anonymous)
-                .default_bind_config("local")
-                .build()?;
-            config::Config::from_file(cfg)?
+        task::spawn(async move {
+            time::sleep(Duration::from_millis(200)).await;
+            let _ = tx.send(());
+        });
+        let res = time::timeout(Duration::from_secs(10), res).await?;
+        assert!(res.unwrap_err().is::<Cancelled>());
+        let e = other.await?.unwrap_err();
+        assert!(e.to_string().contains("timed out"), "{e}");
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn silent_publisher() -> Result<()> {
+        use crate::{
+            channel::{self, Channel},
+            protocol::publisher::{Hello, To},
         };
-        let timeout = Duration::from_secs(10);
-        let _cfg = cfg.clone();
-        let pb: JoinHandle<Result<()>> = task::spawn(async move {
-            let publisher = PublisherBuilder::new(_cfg).slack(3).build().await?;
-            let v = publisher.publish(Path::from("/local/foo"), Value::from(42))?;
+        use cross_krb5::ServerCtx;
+        let _ = env_logger::try_init();
+        let (_resolver, cfg) = local_resolver().await?;
+        // a publisher that completes the hello and keeps sending
+        // heartbeats, but never answers a subscribe
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let paddr = listener.local_addr()?;
+        task::spawn(async move {
+            let (mut s, _) = listener.accept().await?;
+            let _: u64 = channel::read_raw::<_, _, 1024>(&mut s).await?;
+            channel::write_raw(&mut s, &3u64).await?;
+            let _: Hello = channel::read_raw::<_, _, 1024>(&mut s).await?;
+            channel::write_raw(&mut s, &Hello::Anonymous(0)).await?;
+            let mut con = Channel::new::<ServerCtx, TcpStream>(None, s);
+            let mut hb = time::interval(Duration::from_millis(100));
             loop {
-                let mut batch = publisher.start_batch();
-                for i in 0..1000 {
-                    v.update(&mut batch, Value::from(i));
+                select_biased! {
+                    m = con.receive::<To>().fuse() => if m.is_err() { break },
+                    _ = hb.tick().fuse() => con.send_one(&PFrom::Heartbeat).await?,
                 }
-                batch.commit(Some(timeout)).await;
-                time::sleep(Duration::from_millis(10)).await;
-                print!(".");
             }
+            Ok::<_, anyhow::Error>(())
         });
-        let _cfg = cfg.clone();
-        let slow_sub: JoinHandle<Result<()>> = task::spawn(async move {
-            use futures::channel::mpsc;
-            let subscriber = SubscriberBuilder::new(_cfg).build()?;
-            let s = subscriber.subscribe(Path::from("/local/foo"));
-            let (tx, rx) = mpsc::channel(3);
-            s.updates(UpdatesFlags::empty(), tx);
-            future::pending::<()>().await;
-            drop(rx);
-            Ok(())
-        });
+        let w = anonymous_writer(&cfg, paddr)?;
+        w.publish([Path::from("/local/silent")]).await?;
+        let subscriber = SubscriberBuilder::new(cfg)
+            .subscribe_timeout(Duration::from_secs(1))
+            .build()?;
+        // no timeout given, the default applies
         let start = Instant::now();
-        let mut last_update = Instant::now();
-        let subscriber = SubscriberBuilder::new(cfg).build()?;
-        let s = subscriber.subscribe(Path::from("/local/foo"));
-        let mut hb = time::interval(Duration::from_secs(1));
-        let (tx, mut rx) = mpsc::channel(3);
-        s.updates(UpdatesFlags::empty(), tx);
-        loop {
-            tokio::select! {
-                _ = hb.tick() => {
-                    if dbg!(last_update.elapsed()) > timeout + Duration::from_secs(1) {
-                        bail!("updates stopped for longer than timeout!")
-                    }
-                    if start.elapsed() > timeout * 5 {
-                        break
-                    }
-                },
-                _ = rx.select_next_some() => {
-                    last_update = Instant::now();
-                    print!("-");
-                }
+        let res = time::timeout(
+            Duration::from_secs(10),
+            subscriber.subscribe_nondurable_one(Path::from("/local/silent"), None),
+        )
+        .await?;
+        let elapsed = start.elapsed();
+        assert!(res.is_err());
+        assert!(elapsed < Duration::from_millis(1500), "took {elapsed:?}");
+        // an explicit timeout fails at its own deadline
+        let budget = Duration::from_millis(300);
+        let start = Instant::now();
+        let res = subscriber
+            .subscribe_nondurable_one(Path::from("/local/silent"), Some(budget))
+            .await;
+        let elapsed = start.elapsed();
+        assert!(res.is_err());
+        assert!(elapsed < budget + Duration::from_millis(250), "took {elapsed:?}");
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn subscribe_streams_results() -> Result<()> {
+        let _ = env_logger::try_init();
+        let (_resolver, cfg) = local_resolver().await?;
+        let publisher = PublisherBuilder::new(cfg.clone()).build().await?;
+        let _v = publisher.publish(Path::from("/local/fast"), 42)?;
+        publisher.flushed().await;
+        // a "publisher" that accepts connections but never says hello
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let paddr = listener.local_addr()?;
+        task::spawn(async move {
+            let mut held = vec![];
+            while let Ok((s, _)) = listener.accept().await {
+                held.push(s)
             }
-        }
-        slow_sub.abort();
-        pb.abort();
+        });
+        let w = anonymous_writer(&cfg, paddr)?;
+        w.publish([Path::from("/local/slow")]).await?;
+        let subscriber = SubscriberBuilder::new(cfg).build()?;
+        let budget = Duration::from_secs(5);
+        let start = Instant::now();
+        let batch = [Path::from("/local/slow"), Path::from("/local/fast")];
+        let mut results =
+            subscriber.subscribe_nondurable(batch.into_iter(), Some(budget)).await;
+        // the fast path is delivered without waiting for the slow one
+        let (path, res) = results.next().await.unwrap();
+        assert_eq!(path, Path::from("/local/fast"));
+        assert_eq!(res?.last(), Event::Update(Value::from(42)));
+        assert!(start.elapsed() < budget / 2);
+        let (path, res) = results.next().await.unwrap();
+        assert_eq!(path, Path::from("/local/slow"));
+        assert!(res.is_err());
         Ok(())
     }
 
@@ -844,3 +3547,291 @@ mod publisher {
         Ok(())
     }
 }
+
+mod channel {
+    use crate::channel::Channel;
+    use anyhow::Result;
+    use cross_krb5::ServerCtx;
+    use rand::{rng, RngExt};
+    use std::{
+        pin::Pin,
+        task::{ready, Context, Poll},
+    };
+    use tokio::{
+        io::{self, AsyncRead, AsyncWrite, DuplexStream, ReadBuf},
+        net::{TcpListener, TcpStream},
+        task,
+    };
+
+    // hands the reader at most a random number of bytes, up to max,
+    // per read, so frames and their headers arrive split at arbitrary
+    // points
+    struct Chunked {
+        inner: DuplexStream,
+        max: usize,
+    }
+
+    impl AsyncRead for Chunked {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let mut tmp = [0u8; 4096];
+            let n = rng().random_range(1..=self.max).min(buf.remaining());
+            let mut limited = ReadBuf::new(&mut tmp[..n]);
+            ready!(Pin::new(&mut self.inner).poll_read(cx, &mut limited))?;
+            buf.put_slice(limited.filled());
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncWrite for Chunked {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.inner).poll_write(cx, buf)
+        }
+
+        fn poll_flush(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    fn chunked_pair(max: usize) -> (Channel, Channel) {
+        assert!(max > 0 && max <= 4096);
+        let (a, b) = io::duplex(1 << 16);
+        let writer = Channel::new::<ServerCtx, DuplexStream>(None, a);
+        let reader = Channel::new::<ServerCtx, Chunked>(None, Chunked { inner: b, max });
+        (writer, reader)
+    }
+
+    fn message(i: usize) -> (u64, String) {
+        (i as u64, "x".repeat(i % 300))
+    }
+
+    // send n messages in frames of random sizes, and check that the
+    // reader gets exactly the same messages back
+    async fn round_trip(max: usize, n: usize) -> Result<()> {
+        let (mut writer, mut reader) = chunked_pair(max);
+        let sender = task::spawn(async move {
+            let mut i = 0;
+            while i < n {
+                let frame = rng().random_range(1..=50);
+                for _ in 0..frame {
+                    if i < n {
+                        writer.queue_send(&message(i))?;
+                        i += 1;
+                    }
+                }
+                writer.flush().await?;
+            }
+            Ok::<_, anyhow::Error>(writer)
+        });
+        let mut batch = Vec::new();
+        let mut i = 0;
+        while i < n {
+            reader.receive_batch::<(u64, String)>(&mut batch).await?;
+            for m in batch.drain(..) {
+                assert_eq!(m, message(i));
+                i += 1;
+            }
+        }
+        let _writer = sender.await??;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn one_byte_at_a_time() -> Result<()> {
+        round_trip(1, 500).await
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn random_chunks() -> Result<()> {
+        for max in [3, 17, 256, 4096] {
+            round_trip(max, 10_000).await?
+        }
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn empty_frame() -> Result<()> {
+        use tokio::io::AsyncWriteExt;
+        let (mut a, b) = io::duplex(1 << 16);
+        let mut reader =
+            Channel::new::<ServerCtx, Chunked>(None, Chunked { inner: b, max: 3 });
+        // an empty frame between two ordinary frames is skipped
+        let mut frame = Vec::new();
+        frame.extend_from_slice(&8u32.to_be_bytes());
+        frame.extend_from_slice(&*crate::utils::pack(&1u64)?);
+        frame.extend_from_slice(&0u32.to_be_bytes());
+        frame.extend_from_slice(&8u32.to_be_bytes());
+        frame.extend_from_slice(&*crate::utils::pack(&2u64)?);
+        a.write_all(&frame).await?;
+        let mut batch = Vec::new();
+        reader.receive_batch::<u64>(&mut batch).await?;
+        assert_eq!(&batch[..], &[1]);
+        batch.clear();
+        reader.receive_batch::<u64>(&mut batch).await?;
+        assert_eq!(&batch[..], &[2]);
+        Ok(())
+    }
+
+    async fn pair() -> Result<(Channel, Channel)> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let (client, server) =
+            futures::future::join(TcpStream::connect(addr), listener.accept()).await;
+        let client = Channel::new::<ServerCtx, TcpStream>(None, client?);
+        let server = Channel::new::<ServerCtx, TcpStream>(None, server?.0);
+        Ok((client, server))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn split_concurrent() -> Result<()> {
+        const N: u64 = 100_000;
+        let (client, server) = pair().await?;
+        let (mut client_read, mut client_write) = client.split();
+        let (mut server_read, mut server_write) = server.split();
+        // both sides write and read at the same time from different tasks
+        let other = task::spawn(async move {
+            let reader = task::spawn(async move {
+                let mut batch = Vec::new();
+                let mut n = 0;
+                while n < N {
+                    server_read.receive_batch::<u64>(&mut batch).await?;
+                    for i in batch.drain(..) {
+                        assert_eq!(i, n);
+                        n += 1;
+                    }
+                }
+                Ok::<_, anyhow::Error>(())
+            });
+            for i in 0..N {
+                server_write.queue_send(&(N + i))?;
+            }
+            server_write.flush().await?;
+            reader.await??;
+            Ok::<_, anyhow::Error>(())
+        });
+        let reader = task::spawn(async move {
+            let mut batch = Vec::new();
+            let mut n = 0;
+            while n < N {
+                client_read.receive_batch::<u64>(&mut batch).await?;
+                for i in batch.drain(..) {
+                    assert_eq!(i, N + n);
+                    n += 1;
+                }
+            }
+            Ok::<_, anyhow::Error>(())
+        });
+        for i in 0..N {
+            client_write.queue_send(&i)?;
+        }
+        client_write.flush().await?;
+        reader.await??;
+        other.await??;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn shutdown() -> Result<()> {
+        const N: u64 = 100_000;
+        let (mut client, mut server) = pair().await?;
+        // nothing is flushed until shutdown
+        for i in 0..N {
+            client.queue_send(&i)?;
+        }
+        client.shutdown().await?;
+        let mut batch = Vec::new();
+        let mut n = 0;
+        while n < N {
+            server.receive_batch::<u64>(&mut batch).await?;
+            for i in batch.drain(..) {
+                assert_eq!(i, n);
+                n += 1;
+            }
+        }
+        // and then the peer sees EOF
+        assert!(server.receive::<u64>().await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn large_frame_header() -> Result<()> {
+        use tokio::io::AsyncWriteExt;
+        // a 64 bit frame followed by an ordinary 32 bit frame
+        async fn send_frames(max: Option<u64>) -> Result<(TcpStream, Channel)> {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            let addr = listener.local_addr()?;
+            let (client, server) =
+                futures::future::join(TcpStream::connect(addr), listener.accept()).await;
+            let mut client = client?;
+            let mut server = Channel::new::<ServerCtx, TcpStream>(None, server?.0);
+            if let Some(max) = max {
+                server.set_max_large_batch(max);
+                server.set_large_frames(true);
+            }
+            let mut frame = Vec::new();
+            frame.extend_from_slice(&0x7FFFFFFFu32.to_be_bytes());
+            frame.extend_from_slice(&16u64.to_be_bytes());
+            frame.extend_from_slice(&*crate::utils::pack(&(1u64, 2u64))?);
+            frame.extend_from_slice(&8u32.to_be_bytes());
+            frame.extend_from_slice(&*crate::utils::pack(&3u64)?);
+            client.write_all(&frame).await?;
+            Ok((client, server))
+        }
+        // refused unless large frames were negotiated
+        let (_client, mut server) = send_frames(None).await?;
+        assert!(server.receive::<u64>().await.is_err());
+        // and refused if it is larger than the limit
+        let (_client, mut server) = send_frames(Some(15)).await?;
+        assert!(server.receive::<u64>().await.is_err());
+        let (_client, mut server) = send_frames(Some(16)).await?;
+        let mut batch = Vec::new();
+        server.receive_batch::<u64>(&mut batch).await?;
+        assert_eq!(&batch[..], &[1, 2]);
+        batch.clear();
+        server.receive_batch::<u64>(&mut batch).await?;
+        assert_eq!(&batch[..], &[3]);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn large_frames() -> Result<()> {
+        let (mut writer, mut reader) = chunked_pair(17);
+        writer.set_max_batch(64);
+        let msgs = (0..20)
+            .map(|i| (i as u64, "x".repeat(if i % 4 == 1 { 1000 } else { i })))
+            .collect::<Vec<_>>();
+        // too big for a frame until large frames are negotiated
+        assert!(writer.queue_send(&msgs[1]).is_err());
+        writer.set_large_frames(true);
+        reader.set_large_frames(true);
+        for m in &msgs {
+            writer.queue_send(m)?;
+        }
+        writer.flush().await?;
+        let mut batch = Vec::new();
+        let mut received = Vec::new();
+        while received.len() < msgs.len() {
+            reader.receive_batch::<(u64, String)>(&mut batch).await?;
+            received.extend(batch.drain(..));
+        }
+        assert_eq!(received, msgs);
+        Ok(())
+    }
+}